@@ -0,0 +1,136 @@
+//! Value arenas for loading the data in bulk from multiple threads.
+//!
+//! `HashTable::reserve_value_arena` moves the values frontier past a range of value slots and
+//! hands the range out as a `ValueArena`. An arena doesn't borrow the table, so every thread can
+//! fill its own one concurrently: hashing the keys, running the value transforms and encoding the
+//! records all happen in `ValueArena::set`. `HashTable::finalize_value_arena` then writes the
+//! records into the reserved slots and inserts the keys into the hash table.
+
+use crate::slot_layout::SlotLayout;
+use crate::{
+    check_value_size, HashTable, Options, StoreError, ValueHeader, HASH_LEN,
+    NEXT_VALUE_LOGICAL_OFFSET, VALUE_SIZE,
+};
+
+/// A range of value slots reserved for a single thread, see the module documentation.
+pub struct ValueArena {
+    thread_id: usize,
+    salt: [u8; 32],
+    options: Options,
+    layout: SlotLayout,
+    /// The logical offset of the first slot of the arena.
+    start: u64,
+    /// The number of slots reserved.
+    capacity: u64,
+    /// The encoded records, back to back, as they will be laid out in the arena.
+    data: Vec<u8>,
+    /// The hashes of the keys written, and the logical offsets of their records.
+    records: Vec<([u8; HASH_LEN], u64)>,
+}
+
+impl ValueArena {
+    pub fn thread_id(&self) -> usize {
+        self.thread_id
+    }
+
+    /// The number of slots still free in the arena.
+    pub fn remaining_slots(&self) -> u64 {
+        self.capacity - self.data.len() as u64 / VALUE_SIZE
+    }
+
+    /// Writes the value into the arena. The value only becomes visible in the table once the arena
+    /// is finalized. Values written through the arenas don't get sequence numbers even if
+    /// `Options::store_seq` is set. Fails with `StoreError::ArenaFull` if the record doesn't fit
+    /// into the remaining slots, in which case the arena is left unchanged.
+    pub fn set(&mut self, key: Vec<u8>, mut value: Vec<u8>) -> Result<(), StoreError> {
+        check_value_size(&value)?;
+        for transform in self.options.transforms.iter() {
+            value = transform.encode(value)?;
+        }
+
+        let hash = self
+            .layout
+            .truncate(self.options.hasher.hash(&self.salt, &key));
+        let mut header = ValueHeader::new(hash, value.len());
+        let body = if self.options.store_keys {
+            header = header.with_key_len(key.len() as u32);
+            [key, value].concat()
+        } else {
+            value
+        };
+
+        if header.num_slots() > self.remaining_slots() {
+            return Err(StoreError::ArenaFull {
+                thread_id: self.thread_id,
+            });
+        }
+        let offset = self.start + self.data.len() as u64;
+        self.data
+            .extend_from_slice(&HashTable::encode_record(&header, body));
+        self.records.push((hash, offset));
+        Ok(())
+    }
+}
+
+impl HashTable {
+    /// Reserves `count` value slots for the thread `thread_id`, see the module documentation.
+    /// Every reserved arena must be passed to `finalize_value_arena`: until then the compaction
+    /// can't move the values frontier past the beginning of the arena. The slots the arena
+    /// doesn't use are left deleted, and get reclaimed by the compaction as usual.
+    ///
+    /// Panics if an arena for the same thread is already reserved.
+    pub fn reserve_value_arena(
+        &mut self,
+        thread_id: usize,
+        count: u64,
+    ) -> Result<ValueArena, StoreError> {
+        self.check_writable()?;
+        assert!(
+            !self.value_arenas.contains_key(&thread_id),
+            "an arena for the thread {} is already reserved",
+            thread_id
+        );
+
+        let start = self.tx.get_num(&*self.file, NEXT_VALUE_LOGICAL_OFFSET)?;
+        for _ in 0..count {
+            self.reserve_value_slot()?;
+        }
+        self.value_arenas
+            .insert(thread_id, (start, start + count * VALUE_SIZE));
+
+        Ok(ValueArena {
+            thread_id,
+            salt: self.salt,
+            options: self.options.clone(),
+            layout: self.layout,
+            start,
+            capacity: count,
+            data: vec![],
+            records: vec![],
+        })
+    }
+
+    /// Writes the records of the arena into the slots reserved for it, and inserts the keys. If
+    /// several arenas contain the same key, the one finalized last wins.
+    pub fn finalize_value_arena(&mut self, arena: ValueArena) -> Result<(), StoreError> {
+        let removed = self.value_arenas.remove(&arena.thread_id);
+        assert_eq!(
+            removed,
+            Some((arena.start, arena.start + arena.capacity * VALUE_SIZE))
+        );
+
+        for (i, slot) in arena.data.chunks_exact(VALUE_SIZE as usize).enumerate() {
+            let offset = arena.start + i as u64 * VALUE_SIZE;
+            self.set_value(offset, slot.try_into().unwrap())?;
+            self.update_delmap(offset, true)?;
+            self.debit_write();
+        }
+
+        for (hash, offset) in arena.records {
+            if let Some(old_offset) = self.ht_set_with_hash(hash, offset + 1)? {
+                self.delete_slot_value(old_offset)?;
+            }
+        }
+        Ok(())
+    }
+}
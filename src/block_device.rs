@@ -0,0 +1,166 @@
+//! The storage the database is read from and written to, see `HashTable::new_in_memory`.
+//!
+//! All the reads and writes of the database go through a `BlockDevice` at explicit offsets, so
+//! that a single handle can be shared by the flushing threads. A `File` is the device of the
+//! databases opened by path; a `MemoryDevice` keeps the bytes in memory, for the tests and the
+//! fuzzing that don't need them to outlive the process. The features that need a file on disk,
+//! i.e. `Options::direct_io`, `Options::use_mmap` and `Options::preallocate_bytes`, are ignored
+//! for the devices that aren't files.
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::sync::{Arc, RwLock};
+
+use crate::{sync_file, Durability};
+
+/// The bytes of a database, addressed by their offsets.
+pub trait BlockDevice: fmt::Debug + Send + Sync {
+    /// Fills `buf` with the bytes at `offset`. Fails with `io::ErrorKind::UnexpectedEof` if the
+    /// device ends before `buf` is filled.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    /// Writes all of `buf` at `offset`, growing the device if it ends before.
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()>;
+
+    /// The number of bytes of the device.
+    fn len(&self) -> io::Result<u64>;
+
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Truncates or extends the device to `len` bytes. The extension reads as zeros.
+    fn set_len(&self, len: u64) -> io::Result<()>;
+
+    /// Makes the writes so far durable to the extent `durability` requires.
+    fn sync(&self, durability: Durability) -> io::Result<()>;
+
+    /// The file behind the device, if there is one.
+    fn as_file(&self) -> Option<&File> {
+        None
+    }
+}
+
+impl BlockDevice for File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        read_exact_at(self, buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        write_all_at(self, buf, offset)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync(&self, durability: Durability) -> io::Result<()> {
+        sync_file(self, durability)
+    }
+
+    fn as_file(&self) -> Option<&File> {
+        Some(self)
+    }
+}
+
+/// Same as `FileExt::read_exact_at` of unix.
+#[cfg(unix)]
+pub(crate) fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+/// Same as `FileExt::read_exact_at` of unix, with `seek_read`, which also moves the cursor of the
+/// file.
+#[cfg(windows)]
+pub(crate) fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                let rest = buf;
+                buf = &mut rest[n..];
+                offset += n as u64;
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Same as `FileExt::write_all_at` of unix.
+#[cfg(unix)]
+pub(crate) fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+/// Same as `FileExt::write_all_at` of unix, with `seek_write`, which also moves the cursor of the
+/// file.
+#[cfg(windows)]
+pub(crate) fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// A device that keeps the bytes in memory. The clones share the same bytes, so a database
+/// dropped and opened again on a clone sees what was flushed, same as with a file.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryDevice(Arc<RwLock<Vec<u8>>>);
+
+impl MemoryDevice {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl BlockDevice for MemoryDevice {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let bytes = self.0.read().unwrap();
+        let data = usize::try_from(offset)
+            .ok()
+            .and_then(|start| bytes.get(start..start.checked_add(buf.len())?))
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        buf.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let mut bytes = self.0.write().unwrap();
+        let start = offset as usize;
+        if bytes.len() < start + buf.len() {
+            bytes.resize(start + buf.len(), 0);
+        }
+        bytes[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.0.read().unwrap().len() as u64)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.0.write().unwrap().resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn sync(&self, _durability: Durability) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,51 @@
+//! An in-memory bloom filter of the hashes of the keys in the table, which lets the lookups of
+//! absent keys return without probing the hash table, see `Options::expected_keys`.
+//!
+//! The filter is keyed by the hashes the table stores, which are uniformly distributed already, so
+//! the positions of the bits are derived from their first 16 bytes by double hashing rather than
+//! by hashing the keys again. A bloom filter can't forget a key, so the deleted keys stay in it
+//! until the table is cleared or reopened, which only costs false positives. The filter isn't
+//! persisted: it's rebuilt on open from the live slots of the hash table.
+
+use crate::HASH_LEN;
+
+/// The number of bits of the filter per expected key, which together with `NUM_HASHES` gives
+/// about 1% of false positives.
+const BITS_PER_KEY: u64 = 10;
+const NUM_HASHES: u64 = 7;
+
+pub(crate) struct BloomFilter {
+    bits: Vec<u64>,
+}
+
+impl BloomFilter {
+    pub(crate) fn new(expected_keys: u64) -> Self {
+        Self {
+            bits: vec![0; (expected_keys * BITS_PER_KEY / 64).max(1) as usize],
+        }
+    }
+
+    pub(crate) fn insert(&mut self, hash: &[u8; HASH_LEN]) {
+        for bit in self.positions(hash) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Returns false if the hash was never inserted since the filter was created or cleared.
+    pub(crate) fn may_contain(&self, hash: &[u8; HASH_LEN]) -> bool {
+        self.positions(hash)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.bits.fill(0);
+    }
+
+    fn positions(&self, hash: &[u8; HASH_LEN]) -> impl Iterator<Item = u64> {
+        let num_bits = self.bits.len() as u64 * 64;
+        let h1 = u64::from_le_bytes(hash[..8].try_into().unwrap());
+        // The step is odd so that it's never zero
+        let h2 = u64::from_le_bytes(hash[8..16].try_into().unwrap()) | 1;
+        (0..NUM_HASHES).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+}
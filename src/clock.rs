@@ -0,0 +1,34 @@
+//! The source of the current time the expiry of the values is checked against, see
+//! `HashTable::set_with_ttl`.
+
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// The number of seconds since the Unix epoch.
+    fn now_unix_secs(&self) -> u64;
+
+    /// The time since the Unix epoch, which the deadlines of the operations are checked against,
+    /// see `Options::op_deadline`. Defaults to the whole seconds of `now_unix_secs`.
+    fn now(&self) -> Duration {
+        Duration::from_secs(self.now_unix_secs())
+    }
+}
+
+/// The wall clock of the system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_unix_secs(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs())
+    }
+
+    fn now(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,74 @@
+//! Separate namespaces of keys within one file, like the column families of RocksDB.
+//!
+//! The keys of a column are hashed with a salt of their own: the salt of the table with the id
+//! of the column plus one xored into its last 8 bytes. The salts of the columns differ from each
+//! other and from the salt of the table by construction, and the hashers hash the salt together
+//! with the key, so the same key bytes are hashed from different inputs in every column. The keys
+//! of two columns then only collide when two hashes do, which the table already relies on never
+//! happening for two keys of the same column.
+//!
+//! Everything but the keyspace is shared with the table: the hash table, the values region, the
+//! free list, the number of entries and the sequence numbers. The walks over the records, e.g.
+//! `HashTable::iter`, yield the entries of all the columns, and the mutations are sent to the
+//! subscribers without the column.
+//!
+//! The records that store the key, see `Options::store_keys`, store the id of the column next to
+//! it, so that the walks that hash the keys anew, e.g. `HashTable::rehash` and
+//! `HashTable::merge_from`, hash them in their column, and `HashTable::export`,
+//! `HashTable::drain` and `HashTable::content_digest` tell the columns apart.
+
+use crate::{HashTable, StoreError, HASH_LEN};
+
+/// The keyspace of a column, see the module documentation.
+pub struct Column<'a> {
+    table: &'a mut HashTable,
+    id: u32,
+}
+
+impl HashTable {
+    /// Returns the column `id`, see the module documentation.
+    pub fn column(&mut self, id: u32) -> Column<'_> {
+        Column { table: self, id }
+    }
+
+    /// Hashes the key in the keyspace of the `column`, or of the table if there is none.
+    pub(crate) fn hash_in(&self, column: Option<u32>, key: &[u8]) -> [u8; HASH_LEN] {
+        let id = match column {
+            Some(id) => id,
+            None => return self.get_hash(key),
+        };
+        let mut salt = self.salt;
+        for (byte, mask) in salt[24..].iter_mut().zip((id as u64 + 1).to_le_bytes()) {
+            *byte ^= mask;
+        }
+        self.layout.truncate(self.options.hasher.hash(&salt, key))
+    }
+}
+
+impl Column<'_> {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Same as `HashTable::get`, in the keyspace of the column.
+    pub fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>, StoreError> {
+        let hash = self.hash(&key);
+        Ok(self.table.get_hashed(hash, &key)?.map(|(_, value)| value))
+    }
+
+    /// Same as `HashTable::set`, in the keyspace of the column.
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), StoreError> {
+        let hash = self.hash(&key);
+        self.table.set_hashed(hash, key, value, None, Some(self.id))
+    }
+
+    /// Same as `HashTable::delete`, in the keyspace of the column.
+    pub fn delete(&mut self, key: Vec<u8>) -> Result<(), StoreError> {
+        let hash = self.hash(&key);
+        self.table.delete_hashed(hash, key)
+    }
+
+    fn hash(&self, key: &[u8]) -> [u8; HASH_LEN] {
+        self.table.hash_in(Some(self.id), key)
+    }
+}
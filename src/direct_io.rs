@@ -0,0 +1,61 @@
+//! Reading and writing the pages of the file around the OS page cache, see `Options::direct_io`.
+//!
+//! Direct IO requires the offset, the length and the memory address of every transfer to be
+//! aligned to the logical block size of the device, which is at most `PAGE_SIZE` on the devices
+//! we run on. The pages are read and written whole at offsets that are multiples of `PAGE_SIZE`,
+//! and the sectors are multiples of `PAGE_SIZE` too, so only the buffers need care: they are
+//! `AlignedPage`s rather than `Vec`s. The rest of the accesses to the file, i.e. the header on
+//! open, the growth of the file, the warmup and the snapshots, go through the regular handle,
+//! which the kernel keeps coherent with the direct one.
+
+use std::fs::{File, OpenOptions};
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+
+use crate::{StoreError, PAGE_SIZE};
+
+/// A page whose memory is aligned to `PAGE_SIZE`, so that it can be transferred with direct IO.
+#[repr(C, align(4096))]
+pub(crate) struct AlignedPage([u8; PAGE_SIZE as usize]);
+
+impl AlignedPage {
+    pub(crate) fn zeroed() -> Box<Self> {
+        Box::new(Self([0; PAGE_SIZE as usize]))
+    }
+}
+
+impl Deref for AlignedPage {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl DerefMut for AlignedPage {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+/// Opens the file with `O_DIRECT` on Linux, and with `F_NOCACHE` on macOS. Elsewhere the file is
+/// opened as usual, so the reads may still be served from the page cache.
+pub(crate) fn open_direct(path: &Path, read_only: bool) -> Result<File, StoreError> {
+    let mut options = OpenOptions::new();
+    options.read(true).write(!read_only);
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.custom_flags(libc::O_DIRECT);
+    }
+    let file = options.open(path)?;
+    #[cfg(target_os = "macos")]
+    {
+        use std::os::unix::io::AsRawFd;
+        // Safety: the descriptor is owned by `file`, which outlives the call.
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_NOCACHE, 1) } == -1 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    Ok(file)
+}
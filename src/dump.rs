@@ -0,0 +1,222 @@
+//! A portable dump of the keys and the values, for moving the data between databases with
+//! different settings, which a copy of the file can't do.
+//!
+//! A dump is `EXPORT_MAGIC_le[8] || EXPORT_VERSION_le[4]`, followed by a record
+//! `key_len_le[4] || key || column_le[8] || expires_at_le[8] || value_len_le[8] || value ||
+//! checksum[DUMP_CHECKSUM_LEN]` per key, and ends with `END_OF_DUMP_le[4]` in place of a key
+//! length, followed by `digest[32]`. The `checksum` is the beginning of the blake3 hash of the rest
+//! of the record, and the `digest` is the blake3 hash of everything before it, so that a corrupted
+//! record is detected before it's imported, and a truncated dump or a missing record once the
+//! whole dump is read. The `column` is the id of the column of the key plus one, see `HashTable::column`, or
+//! zero for the keys of the table. The values are stored as they were set, before the transforms,
+//! and `expires_at` is the expiry of the value, see `HashTable::set_with_ttl`, or zero if it has
+//! none. The sequence numbers are not exported.
+//!
+//! The dumps of version 1 have no `column` nor `expires_at`, and the ones of version 2 have no
+//! `column`: their keys are imported into the table, and the values of version 1 never expire.
+//! The dumps before version 4 have no `checksum` nor `digest`, and are imported unverified.
+
+use std::io::{self, Read, Write};
+
+use crate::{HashTable, Records, StoreError};
+
+const EXPORT_MAGIC: u64 = 0x706d7564766b7466;
+const EXPORT_VERSION: u32 = 4;
+/// The length of the checksum at the end of every record.
+const DUMP_CHECKSUM_LEN: usize = 8;
+/// Written in place of the key length after the last record.
+const END_OF_DUMP: u32 = u32::MAX;
+/// `import` flushes the changes after every this many records.
+pub const IMPORT_FLUSH_RECORDS: u64 = 10000;
+
+impl HashTable {
+    /// Writes all the keys and the values into `w`, see the module documentation. Requires all
+    /// the values to have been written with `Options::store_keys`, otherwise fails with
+    /// `StoreError::KeyNotStored`. The values that already expired are left out. Returns the
+    /// number of records written.
+    pub fn export(&mut self, w: &mut impl Write) -> Result<u64, StoreError> {
+        let mut w = Digesting::new(w);
+        w.write_all(&EXPORT_MAGIC.to_le_bytes())?;
+        w.write_all(&EXPORT_VERSION.to_le_bytes())?;
+        let now = self.options.clock.now_unix_secs();
+        let mut records = Records::new(self)?;
+        let mut count = 0;
+        while let Some((offset, record)) = records.next_record()? {
+            let expires_at = record.header.expires_at;
+            if expires_at.is_some_and(|expires_at| expires_at <= now) {
+                continue;
+            }
+            let key = record.key.ok_or(StoreError::KeyNotStored { offset })?;
+            let value = records.table.decode_value(record.payload)?;
+            w.record.reset();
+            w.write_all(&(key.len() as u32).to_le_bytes())?;
+            w.write_all(&key)?;
+            let column = record.header.column.map_or(0, |column| column as u64 + 1);
+            w.write_all(&column.to_le_bytes())?;
+            w.write_all(&expires_at.unwrap_or(0).to_le_bytes())?;
+            w.write_all(&(value.len() as u64).to_le_bytes())?;
+            w.write_all(&value)?;
+            let checksum = w.record.finalize();
+            w.write_all(&checksum.as_bytes()[..DUMP_CHECKSUM_LEN])?;
+            count += 1;
+        }
+        w.write_all(&END_OF_DUMP.to_le_bytes())?;
+        let digest = w.dump.finalize();
+        w.inner.write_all(digest.as_bytes())?;
+        Ok(count)
+    }
+
+    /// Sets all the keys and the values read from a dump written by `export`, with their columns
+    /// and expiry, and returns the number of records read. The values that expired since the
+    /// export are read, but not set. The changes are flushed after every `IMPORT_FLUSH_RECORDS`
+    /// records and at the end, so an import that was interrupted by a crash can be resumed by
+    /// importing the same dump again.
+    ///
+    /// Every record is checked against its checksum before it's set, and the whole dump against
+    /// its digest before the last flush, see the module documentation. A mismatch fails with
+    /// `StoreError::CorruptDump`. On any failure, the records set since the last flush are rolled
+    /// back, so only the whole batches of verified records stay imported. The pending changes are
+    /// flushed first, so that they aren't rolled back with them.
+    pub fn import(&mut self, r: &mut impl Read) -> Result<u64, StoreError> {
+        self.check_writable()?;
+        self.flush_changes()?;
+        let result = self.import_records(&mut Digesting::new(r));
+        if result.is_err() {
+            self.rollback()?;
+        }
+        result
+    }
+
+    /// Same as `import`, but leaves the changes since the last flush in place on a failure.
+    fn import_records<R: Read>(&mut self, r: &mut Digesting<R>) -> Result<u64, StoreError> {
+        if u64::from_le_bytes(read_array(r)?) != EXPORT_MAGIC {
+            return Err(invalid_dump("not a dump"));
+        }
+        let version = u32::from_le_bytes(read_array(r)?);
+        if !(1..=EXPORT_VERSION).contains(&version) {
+            return Err(invalid_dump("unsupported version"));
+        }
+
+        let now = self.options.clock.now_unix_secs();
+        let mut count = 0;
+        loop {
+            r.record.reset();
+            let key_len = u32::from_le_bytes(read_array(r)?);
+            if key_len == END_OF_DUMP {
+                break;
+            }
+            let key = read_vec(r, key_len as u64)?;
+            let column = match version {
+                3.. => u64::from_le_bytes(read_array(r)?),
+                _ => 0,
+            };
+            let expires_at = if version >= 2 {
+                Some(u64::from_le_bytes(read_array(r)?)).filter(|&expires_at| expires_at != 0)
+            } else {
+                None
+            };
+            let value_len = u64::from_le_bytes(read_array(r)?);
+            let value = read_vec(r, value_len)?;
+            if version >= 4 {
+                let checksum = r.record.finalize();
+                let stored: [u8; DUMP_CHECKSUM_LEN] = read_array(r)?;
+                if stored != checksum.as_bytes()[..DUMP_CHECKSUM_LEN] {
+                    return Err(StoreError::CorruptDump {
+                        reason: "record checksum mismatch",
+                    });
+                }
+            }
+            let column = match column {
+                0 => None,
+                column => {
+                    Some(u32::try_from(column - 1).map_err(|_| invalid_dump("invalid column"))?)
+                }
+            };
+            if expires_at.is_none_or(|expires_at| expires_at > now) {
+                let hash = self.hash_in(column, &key);
+                self.set_hashed(hash, key, value, expires_at, column)?;
+            }
+
+            count += 1;
+            if count % IMPORT_FLUSH_RECORDS == 0 {
+                self.flush_changes()?;
+            }
+        }
+        if version >= 4 {
+            let digest = r.dump.finalize();
+            let stored: [u8; 32] = read_array(&mut r.inner)?;
+            if stored != *digest.as_bytes() {
+                return Err(StoreError::CorruptDump {
+                    reason: "dump digest mismatch",
+                });
+            }
+        }
+        self.flush_changes()?;
+        Ok(count)
+    }
+}
+
+/// Passes the bytes read or written through to `inner`, and hashes them into the digest of the
+/// whole dump and into the checksum of the current record, which is reset at its start.
+struct Digesting<T> {
+    inner: T,
+    dump: blake3::Hasher,
+    record: blake3::Hasher,
+}
+
+impl<T> Digesting<T> {
+    fn new(inner: T) -> Self {
+        Self {
+            inner,
+            dump: blake3::Hasher::new(),
+            record: blake3::Hasher::new(),
+        }
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        self.dump.update(bytes);
+        self.record.update(bytes);
+    }
+}
+
+impl<R: Read> Read for Digesting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.update(&buf[..len]);
+        Ok(len)
+    }
+}
+
+impl<W: Write> Write for Digesting<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn invalid_dump(reason: &str) -> StoreError {
+    io::Error::new(io::ErrorKind::InvalidData, reason).into()
+}
+
+fn read_array<const N: usize>(r: &mut impl Read) -> Result<[u8; N], StoreError> {
+    let mut buf = [0; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_vec(r: &mut impl Read, len: u64) -> Result<Vec<u8>, StoreError> {
+    // Don't trust the length with the allocation, a corrupted one would fail to read anyway
+    let mut buf = vec![];
+    r.take(len).read_to_end(&mut buf)?;
+    if buf.len() as u64 != len {
+        return Err(StoreError::CorruptDump {
+            reason: "truncated record",
+        });
+    }
+    Ok(buf)
+}
@@ -0,0 +1,114 @@
+//! Read-modify-write access to a single key.
+//!
+//! `HashTable::entry` hashes the key and seeks its hash table slot once. The `Entry` keeps the
+//! hash and the slot, and reuses them to read the value and to write the new one, so that a
+//! `get` followed by a `set` of the same key doesn't pay for the hashing and the probing twice.
+
+use crate::{check_value_size, HashTable, Mutation, StoreError, HASH_LEN, NO_VALUE, TOMBSTONE};
+
+/// A key together with the location of its hash table slot, see the module documentation.
+pub struct Entry<'a> {
+    table: &'a mut HashTable,
+    key: Vec<u8>,
+    hash: [u8; HASH_LEN],
+    /// The offset of the hash table slot of the key and the value stored in it, as returned by
+    /// `seek`.
+    slot: (u64, u64),
+    /// The value of the key, once it has been read or written.
+    value: Option<Option<Vec<u8>>>,
+}
+
+impl HashTable {
+    /// Returns the entry of the key, see the module documentation.
+    pub fn entry(&mut self, key: Vec<u8>) -> Result<Entry<'_>, StoreError> {
+        let hash = self.get_hash(&key);
+        let slot = self.seek(hash)?;
+        Ok(Entry {
+            table: self,
+            key,
+            hash,
+            slot,
+            value: None,
+        })
+    }
+}
+
+impl Entry<'_> {
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Returns the value of the key, if present.
+    pub fn get(&mut self) -> Result<Option<Vec<u8>>, StoreError> {
+        if self.value.is_none() {
+            self.table.metrics.add_gets(1);
+            let value = self
+                .table
+                .read_slot_value(&self.key, self.hash, self.slot.1)?
+                .map(|(_, value)| value);
+            // Reading an expired value deletes it
+            self.refresh_slot()?;
+            self.value = Some(value);
+        }
+        Ok(self.value.clone().unwrap())
+    }
+
+    /// If the key is present, passes its value through `f` and sets the result.
+    pub fn and_modify(mut self, f: impl FnOnce(&mut Vec<u8>)) -> Result<Self, StoreError> {
+        if let Some(mut value) = self.get()? {
+            f(&mut value);
+            self.set(value)?;
+        }
+        Ok(self)
+    }
+
+    /// Returns the value of the key, after setting it to the result of `f` if the key is absent.
+    pub fn or_insert_with(mut self, f: impl FnOnce() -> Vec<u8>) -> Result<Vec<u8>, StoreError> {
+        if let Some(value) = self.get()? {
+            return Ok(value);
+        }
+        let value = f();
+        self.set(value.clone())?;
+        Ok(value)
+    }
+
+    /// Same as `HashTable::set`, but writes the cached slot rather than seeking it again.
+    fn set(&mut self, value: Vec<u8>) -> Result<(), StoreError> {
+        let table = &mut *self.table;
+        table.check_writable()?;
+        check_value_size(&value)?;
+        table.metrics.add_sets(1);
+        let mutation = (!table.subscribers.is_empty()).then(|| Mutation::Set {
+            key: self.key.clone(),
+            value: value.clone(),
+        });
+        let seq = table.reserve_seqs(1)?;
+        let slot_value =
+            table.write_entry(self.hash, self.key.clone(), value.clone(), seq, None)?;
+
+        if let Some(old_value) = table.ht_set_at(self.hash, self.slot, slot_value)? {
+            table.delete_slot_value(old_value)?;
+        }
+        table.pending_mutations.extend(mutation);
+        self.refresh_slot()?;
+        self.value = Some(Some(value));
+        Ok(())
+    }
+
+    /// Brings the cached slot up to date after the table was modified. Inserting the key might
+    /// have resized its sector, which moves the key to another slot, and the compaction might
+    /// have moved the value, which changes the value stored in the slot.
+    fn refresh_slot(&mut self) -> Result<(), StoreError> {
+        let (offset, _) = self.slot;
+        let layout = self.table.layout;
+        let data = self.table.read_ht_slot(offset)?;
+        let value = layout.extract_value(&data);
+        let hash = layout.extract_hash(&data);
+        self.slot = if value != NO_VALUE && value != TOMBSTONE && hash == self.hash {
+            (offset, value)
+        } else {
+            self.table.seek(self.hash)?
+        };
+        Ok(())
+    }
+}
@@ -0,0 +1,33 @@
+//! The hash function the keys are hashed with before they're looked up in the hash table, see
+//! `Options::hasher`.
+
+use std::fmt;
+
+use crate::HASH_LEN;
+
+pub trait KeyHasher: fmt::Debug + Send + Sync {
+    /// Identifies the hash function in the header of the file, so that a database is never
+    /// opened with a different one than it was created with. 0 is `Blake3Hasher`, which the
+    /// databases created before the hasher was configurable were hashed with.
+    fn tag(&self) -> u8;
+
+    /// Hashes the key together with the salt of the table. The first sector is chosen by the
+    /// beginning of the hash and the slot by its last 8 bytes, so both have to be uniformly
+    /// distributed.
+    fn hash(&self, salt: &[u8; 32], key: &[u8]) -> [u8; HASH_LEN];
+}
+
+/// The blake3 hash of the salt followed by the key.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Blake3Hasher;
+
+impl KeyHasher for Blake3Hasher {
+    fn tag(&self) -> u8 {
+        0
+    }
+
+    fn hash(&self, salt: &[u8; 32], key: &[u8]) -> [u8; HASH_LEN] {
+        let full_hash: [u8; 32] = blake3::hash([salt.as_ref(), key].concat().as_ref()).into();
+        full_hash[..HASH_LEN].try_into().unwrap()
+    }
+}
@@ -0,0 +1,46 @@
+//! A minimal interface over key-value storage engines, so that the code driving them, such as the
+//! benchmarks, can be written once and run against `HashTable` and other engines alike.
+//!
+//! Same as with `ValueTransform`, the errors are reported as `io::Error`, so that every engine
+//! can implement the trait and be used as a `dyn KvStore`. The errors of `HashTable` are
+//! converted from `StoreError`, which stays available as the inner error.
+
+use std::io;
+
+use crate::HashTable;
+
+pub trait KvStore {
+    fn get(&mut self, key: Vec<u8>) -> io::Result<Option<Vec<u8>>>;
+
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> io::Result<()>;
+
+    /// Deleting a key that isn't present is not an error.
+    fn delete(&mut self, key: Vec<u8>) -> io::Result<()>;
+
+    fn contains_key(&mut self, key: Vec<u8>) -> io::Result<bool>;
+
+    /// Persists all the changes made so far.
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+impl KvStore for HashTable {
+    fn get(&mut self, key: Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+        Ok(HashTable::get(self, key)?)
+    }
+
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> io::Result<()> {
+        Ok(HashTable::set(self, key, value)?)
+    }
+
+    fn delete(&mut self, key: Vec<u8>) -> io::Result<()> {
+        Ok(HashTable::delete(self, key)?)
+    }
+
+    fn contains_key(&mut self, key: Vec<u8>) -> io::Result<bool> {
+        Ok(HashTable::contains_key(self, key)?)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(self.flush_changes()?)
+    }
+}
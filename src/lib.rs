@@ -1,59 +1,773 @@
 use std::collections::BTreeMap;
 use std::convert::TryInto;
+use std::fmt;
 use std::fs::File;
 use std::fs::OpenOptions;
+use std::io;
 use std::io::prelude::*;
 use std::io::SeekFrom;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 
 use blake3;
 
+pub mod arena;
+pub mod block_device;
+mod bloom;
+pub mod clock;
+pub mod column;
+mod direct_io;
+pub mod dump;
+pub mod entry;
+pub mod hasher;
+pub mod kv_store;
+pub mod metrics;
+#[cfg(feature = "near_store_impl")]
+pub mod near_store_impl;
+mod page_cache;
+#[cfg(all(feature = "preallocate", target_os = "linux"))]
+mod preallocate;
+mod slot_layout;
+pub mod snapshot;
+pub mod transforms;
+pub mod verify;
+
+pub use arena::ValueArena;
+pub use block_device::{BlockDevice, MemoryDevice};
+use bloom::BloomFilter;
+pub use clock::Clock;
+pub use column::Column;
+use direct_io::AlignedPage;
+pub use entry::Entry;
+pub use hasher::KeyHasher;
+pub use kv_store::KvStore;
+use metrics::{Metrics, ProbeWindow};
+pub use metrics::{MetricsSnapshot, ProbeStats};
+use page_cache::PageCache;
+use slot_layout::{SlotLayout, MAX_SLOT_SIZE};
+use snapshot::FlushedMappings;
+pub use snapshot::ReadSnapshot;
+pub use transforms::ValueTransform;
+pub use verify::VerifyReport;
+
 const PAGE_TYPE_FREE: u64 = 0;
 const PAGE_TYPE_HT: u64 = 1;
 const PAGE_TYPE_VALUES: u64 = 2;
 const PAGE_TYPE_DELMAP: u64 = 3;
 
-const NUM_FLUSH_THREADS: usize = 1;
+/// The flush only uses more than one thread if every thread gets at least this many changes.
+const MIN_CHANGES_PER_FLUSH_THREAD: usize = 1024;
+/// The largest read `HashTable::warmup` makes.
+const WARMUP_READ_BYTES: u64 = 16 << 20;
+/// The largest read `HashTable::snapshot_to` makes.
+const SNAPSHOT_COPY_BYTES: u64 = 1 << 20;
+const DEFAULT_REPLAY_CHUNK_BYTES: u64 = 64 << 20;
 const PAGE_SIZE: u64 = 4 * 1024;
+/// The size of the slots of the default `SlotLayout`.
 const SLOT_SIZE: u64 = 32;
-const VALUE_SIZE: u64 = 128;
+pub const VALUE_SIZE: u64 = 128;
 const DELMAP_ENTRY_SIZE: u64 = 32;
 const DELS_PER_DELMAP: u64 = 8 * (DELMAP_ENTRY_SIZE - 6);
-const HASH_LEN: usize = 26;
-const SECTOR_SIZE: u64 = 1 << 20;
+pub const HASH_LEN: usize = 26;
+const DEFAULT_SECTOR_SIZE: u64 = 1 << 20;
 const FIRST_SLOT_OFFSET: u64 = 64;
 const FIRST_SECTOR_OFFSET: u64 = 4 * 1024;
-const SLOTS_IN_SECTOR: u64 = (SECTOR_SIZE - FIRST_SLOT_OFFSET) / SLOT_SIZE;
-const EARLY_SECTOR_PERCENT: u64 = 80;
-const MAX_SECTOR_PERCENT: u64 = 90;
+const DEFAULT_EARLY_SECTOR_PERCENT: u64 = 80;
+const DEFAULT_MAX_SECTOR_PERCENT: u64 = 90;
+/// The length of the windows `Options::adaptive_resize` measures the growth of the table over.
+const ADAPTIVE_RESIZE_WINDOW_SECS: u64 = 60 * 60;
+/// How much `Options::adaptive_resize` raises the early resize threshold after a quiet window.
+const ADAPTIVE_RESIZE_STEP_PERCENT: u64 = 2;
 
 const FREE_LIST_OFFSET: u64 = 8;
 const NEXT_VALUE_LOGICAL_OFFSET: u64 = 16;
 const FIRST_VALUE_LOGICAL_OFFSET: u64 = 24;
 const NEXT_VALUE_PHYSICAL_OFFSET: u64 = 32;
+const LAST_SEQ_OFFSET: u64 = 40;
 const NEXT_DELMAP_PHYSICAL_OFFSET: u64 = 48;
+const LEN_OFFSET: u64 = 56;
+const BYTE_ORDER_OFFSET: u64 = 64;
+const SECTOR_SIZE_OFFSET: u64 = 72;
+const SALT_FINGERPRINT_OFFSET: u64 = 80;
+/// A single byte, see `KeyHasher::tag`.
+const HASHER_TAG_OFFSET: u64 = 88;
+const HASH_LEN_OFFSET: u64 = 96;
+const OFFSET_WIDTH_OFFSET: u64 = 104;
+/// 1 if the database was created without the compaction, see `Options::enable_compaction`.
+const COMPACTION_DISABLED_OFFSET: u64 = 112;
+/// The end of the range of the file reserved on disk, see `Options::preallocate_bytes`.
+#[cfg(all(feature = "preallocate", target_os = "linux"))]
+const PREALLOCATED_END_OFFSET: u64 = 120;
+/// 1 if the values may be stored in the hash table slots, see `Options::inline_values`.
+const INLINE_VALUES_OFFSET: u64 = 128;
+/// The tag of the probe strategy of the hash table, see `Options::probe_strategy`.
+const PROBE_STRATEGY_OFFSET: u64 = 136;
+/// The number of bytes written to the file and the log as of the last flush, see
+/// `HashTable::lifetime_bytes_written`.
+const LIFETIME_BYTES_WRITTEN_OFFSET: u64 = 144;
+
+/// All the integers in the database file and in the WAL are stored little-endian regardless of
+/// the host, so the files can be moved between machines of different endianness. This marker is
+/// stored at `BYTE_ORDER_OFFSET` to detect the files written by an implementation that didn't
+/// follow that, which would otherwise be silently misread.
+const BYTE_ORDER_MARK: u64 = 0x0102030405060708;
 
 const NO_VALUE: u64 = 0;
+/// The value of a hash table slot whose entry was deleted with `DeleteStrategy::Tombstone`. The
+/// slot keeps the hash of the deleted entry. It fits in the narrowest offsets, which never get
+/// this large.
+const TOMBSTONE: u64 = (1 << 48) - 1;
+/// With `DeleteStrategy::Tombstone`, a hash table sector is rebuilt without its tombstones once
+/// this many percent of its slots are tombstones.
+const TOMBSTONE_REBUILD_PERCENT: u64 = 20;
+/// The number of records `HashTable::merge_from` sets between its commits, which bounds the
+/// changes held in memory.
+const MERGE_COMMIT_INTERVAL: u64 = 4096;
+
+/// Offset of the key hash within a value record.
+pub const VALUE_HASH_OFFSET: usize = 0;
+/// Offset of the little-endian record length within a value record.
+pub const VALUE_LEN_OFFSET: usize = VALUE_HASH_OFFSET + HASH_LEN;
+/// Offset of the payload within a value record that has no optional fields.
+pub const VALUE_PAYLOAD_OFFSET: usize = VALUE_LEN_OFFSET + 8;
+/// Set in the length field of the records that store an insertion sequence number.
+pub const VALUE_FLAG_SEQ: u64 = 1 << 63;
+/// Set in the length field of the records that store the key.
+pub const VALUE_FLAG_KEY: u64 = 1 << 62;
+/// Set in the length field of the records that end with a checksum.
+pub const VALUE_FLAG_CHECKSUM: u64 = 1 << 61;
+/// Set in the length field of the records that expire, see `HashTable::set_with_ttl`.
+pub const VALUE_FLAG_EXPIRY: u64 = 1 << 60;
+/// Set in the length field of the records that store the key of a column, see `HashTable::column`.
+pub const VALUE_FLAG_COLUMN: u64 = 1 << 59;
+/// The bits of the length field that are used for flags rather than for the length.
+pub const VALUE_FLAGS_MASK: u64 =
+    VALUE_FLAG_SEQ | VALUE_FLAG_KEY | VALUE_FLAG_CHECKSUM | VALUE_FLAG_EXPIRY | VALUE_FLAG_COLUMN;
+/// The length of the checksum in the last bytes of the final slot of a record.
+pub const VALUE_CHECKSUM_LEN: usize = 8;
 
 const WAL_MAGIC: u64 = 718984182412;
+/// Starts the records of a group commit, which are only applied once followed by a
+/// `WAL_COMMIT_MAGIC` marker, see `Options::group_commit`.
+const WAL_GROUP_MAGIC: u64 = 718984182413;
+/// Starts the marker that commits the group records before it.
+const WAL_COMMIT_MAGIC: u64 = 718984182414;
+
+/// The number of mutations a subscriber can lag behind before it gets disconnected, see
+/// `HashTable::subscribe`.
+pub const SUBSCRIBER_CHANNEL_CAPACITY: usize = 1 << 16;
+
+/// The largest value that can be set, before the transforms are applied. A record is assembled in
+/// memory in full whenever it's written or read, and the compaction moves it in one go, so the
+/// limit bounds the memory and the latency of a single operation.
+pub const MAX_VALUE_SIZE: usize = 64 << 20;
 
 const IO_ERROR: &str = "IO error";
+const VALUE_CHECKSUM_MISMATCH: &str = "value checksum mismatch";
+const UNMAPPED_VALUE: &str = "value before the first values sector";
+const UNMAPPED_DELMAP: &str = "value before the first delmap sector";
+const DUPLICATE_KEY: &str = "another record of the same key";
+
+/// Errors returned by the `HashTable` operations.
+#[derive(Debug)]
+pub enum StoreError {
+    /// An IO operation on the database file failed. `offset` is the position in the file that
+    /// was being accessed, if the operation was accessing a particular one.
+    Io {
+        offset: Option<u64>,
+        error: io::Error,
+    },
+    /// The byte order marker in the header of the file is byte-swapped, i.e. the file was written
+    /// on a host with a different endianness by an implementation that didn't store the integers
+    /// little-endian.
+    ByteOrderMismatch,
+    /// The record at the logical `offset` was written without `Options::store_keys`, so its key
+    /// can't be returned. For a value stored inline, see `Options::inline_values`, the `offset` is
+    /// the one of its hash table slot in the file.
+    KeyNotStored { offset: u64 },
+    /// The value doesn't fit into the slots left in the value arena of the thread `thread_id`.
+    ArenaFull { thread_id: usize },
+    /// The database was opened with `HashTable::open_read_only`, and the operation would modify
+    /// it.
+    ReadOnly,
+    /// The database was created with a different salt than the one it's opened with, so none of
+    /// its keys would be found.
+    SaltMismatch,
+    /// The database was created with the `KeyHasher` tagged `stored`, but is opened with
+    /// `Options::hasher` tagged `configured`.
+    HasherMismatch { configured: u8, stored: u8 },
+    /// The database was created with the slots of `stored` `(hash_len, offset_width)`, but is
+    /// opened with `Options::hash_len` and `Options::offset_width` set to `configured`.
+    SlotLayoutMismatch {
+        configured: (usize, usize),
+        stored: (usize, usize),
+    },
+    /// The database was created with `Options::enable_compaction` set to `stored`, but is opened
+    /// with it set to `configured`.
+    CompactionMismatch { configured: bool, stored: bool },
+    /// The database was created with `Options::inline_values` set to `stored`, but is opened with
+    /// it set to `configured`.
+    InlineValuesMismatch { configured: bool, stored: bool },
+    /// The database was created with `Options::probe_strategy` set to `stored`, but is opened
+    /// with it set to `configured`.
+    ProbeStrategyMismatch {
+        configured: ProbeStrategy,
+        stored: ProbeStrategy,
+    },
+    /// The database was created with sectors of `stored` bytes, but is opened with
+    /// `Options::sector_size` set to `configured`.
+    SectorSizeMismatch { configured: u64, stored: u64 },
+    /// The database file is inconsistent at `offset`, see `Options::auto_recover`.
+    Corruption { offset: u64, reason: &'static str },
+    /// Every slot of the hash table sector at `offset` is occupied, so a lookup in it can't
+    /// terminate. The resizes keep the sectors below `Options::max_sector_percent` full, so this
+    /// means the sector or its occupancy counter is corrupted.
+    SectorFull { offset: u64 },
+    /// A scan of the hash table took longer than `Options::op_deadline`. The operation didn't
+    /// change anything.
+    Timeout,
+    /// The hash table points the key with the `hash` at the logical `offset`, which the compaction
+    /// has already moved past, so the value it pointed at is gone.
+    DanglingValue { hash: [u8; HASH_LEN], offset: u64 },
+    /// The value of `len` bytes is larger than `MAX_VALUE_SIZE`.
+    ValueTooLarge { len: usize },
+    /// The table was flushed after the `ReadSnapshot` was taken, see `HashTable::read_snapshot`.
+    SnapshotStale,
+    /// The dump passed to `HashTable::import` doesn't match its checksums, see the `dump` module.
+    CorruptDump { reason: &'static str },
+}
+
+impl StoreError {
+    /// Returns a function that wraps an `io::Error` that happened at `offset`, for use with
+    /// `map_err`.
+    fn at(offset: u64) -> impl FnOnce(io::Error) -> StoreError {
+        move |error| StoreError::Io {
+            offset: Some(offset),
+            error,
+        }
+    }
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Io {
+                offset: Some(offset),
+                error,
+            } => write!(f, "{} at offset {}: {}", IO_ERROR, offset, error),
+            StoreError::Io {
+                offset: None,
+                error,
+            } => write!(f, "{}: {}", IO_ERROR, error),
+            StoreError::ByteOrderMismatch => {
+                write!(f, "the database was written with a different byte order")
+            }
+            StoreError::KeyNotStored { offset } => write!(
+                f,
+                "the record at logical offset {} was written without its key",
+                offset
+            ),
+            StoreError::ArenaFull { thread_id } => {
+                write!(f, "the value arena of the thread {} is full", thread_id)
+            }
+            StoreError::SectorSizeMismatch { configured, stored } => write!(
+                f,
+                "the database has sectors of {} bytes, but {} bytes are configured",
+                stored, configured
+            ),
+            StoreError::ReadOnly => write!(f, "the database is opened read-only"),
+            StoreError::SaltMismatch => {
+                write!(f, "the database was created with a different salt")
+            }
+            StoreError::HasherMismatch { configured, stored } => write!(
+                f,
+                "the database was created with the key hasher {}, but {} is configured",
+                stored, configured
+            ),
+            StoreError::SlotLayoutMismatch { configured, stored } => write!(
+                f,
+                "the database has slots of {}-byte hashes and {}-byte offsets, but {} and {} are \
+                 configured",
+                stored.0, stored.1, configured.0, configured.1
+            ),
+            StoreError::CompactionMismatch { configured, stored } => write!(
+                f,
+                "the database was created with the compaction {}, but it's configured {}",
+                if *stored { "enabled" } else { "disabled" },
+                if *configured { "enabled" } else { "disabled" }
+            ),
+            StoreError::InlineValuesMismatch { configured, stored } => write!(
+                f,
+                "the database was created with the inline values {}, but they're configured {}",
+                if *stored { "enabled" } else { "disabled" },
+                if *configured { "enabled" } else { "disabled" }
+            ),
+            StoreError::ProbeStrategyMismatch { configured, stored } => write!(
+                f,
+                "the database was created with the {:?} probing, but {:?} is configured",
+                stored, configured
+            ),
+            StoreError::Corruption { offset, reason } => {
+                write!(f, "corrupted database at offset {}: {}", offset, reason)
+            }
+            StoreError::Timeout => write!(f, "the operation took longer than its deadline"),
+            StoreError::SectorFull { offset } => write!(
+                f,
+                "every slot of the hash table sector at offset {} is occupied",
+                offset
+            ),
+            StoreError::DanglingValue { hash, offset } => write!(
+                f,
+                "the key hash {:02x?} points at the logical offset {}, which was compacted",
+                hash, offset
+            ),
+            StoreError::ValueTooLarge { len } => write!(
+                f,
+                "the value of {} bytes is larger than the maximum of {} bytes",
+                len, MAX_VALUE_SIZE
+            ),
+            StoreError::SnapshotStale => {
+                write!(f, "the table was flushed after the snapshot was taken")
+            }
+            StoreError::CorruptDump { reason } => write!(f, "corrupted dump: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StoreError::Io { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for StoreError {
+    fn from(error: io::Error) -> Self {
+        StoreError::Io {
+            offset: None,
+            error,
+        }
+    }
+}
+
+impl From<StoreError> for io::Error {
+    fn from(error: StoreError) -> Self {
+        io::Error::other(error)
+    }
+}
 
-fn open_file(path: &Path) -> File {
-    OpenOptions::new()
+fn open_file(path: &Path) -> Result<File, StoreError> {
+    Ok(OpenOptions::new()
         .create(true)
         .write(true)
         .read(true)
-        .open(path)
-        .expect(IO_ERROR)
+        .open(path)?)
+}
+
+/// The kinds of the errors of the operations that `io_retry` retries, which are `EINTR`, `EAGAIN`
+/// and `ETIMEDOUT` on unix.
+const TRANSIENT_ERRORS: [io::ErrorKind; 3] = [
+    io::ErrorKind::Interrupted,
+    io::ErrorKind::WouldBlock,
+    io::ErrorKind::TimedOut,
+];
+
+/// Runs `op` until it succeeds, fails with an error other than `TRANSIENT_ERRORS`, or has been
+/// retried `retry.max_retries` times, and returns its last result. Only meant for the operations
+/// that can be repeated as a whole, e.g. the positional reads and writes.
+fn io_retry<T>(retry: IoRetry, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut backoff = retry.backoff;
+    for _ in 0..retry.max_retries {
+        match op() {
+            Err(err) if TRANSIENT_ERRORS.contains(&err.kind()) => {
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+    op()
+}
+
+/// Makes the writes to `file` durable to the extent `durability` requires. A sync through any
+/// handle of the file covers the writes through all of them.
+fn sync_file(file: &File, durability: Durability) -> io::Result<()> {
+    match durability {
+        Durability::None => {}
+        Durability::Data => file.sync_data()?,
+        Durability::Full => file.sync_all()?,
+    }
+    Ok(())
+}
+
+/// Fails with `StoreError::ValueTooLarge` if the value is larger than `MAX_VALUE_SIZE`.
+fn check_value_size(value: &[u8]) -> Result<(), StoreError> {
+    if value.len() > MAX_VALUE_SIZE {
+        return Err(StoreError::ValueTooLarge { len: value.len() });
+    }
+    Ok(())
+}
+
+/// The fingerprint of the salt stored in the header, see `StoreError::SaltMismatch`. It's never
+/// zero, which is what the files created before it was stored have in its place.
+fn salt_fingerprint(salt: &[u8; 32]) -> u64 {
+    u64::from_le_bytes(blake3::hash(salt).as_bytes()[..8].try_into().unwrap()).max(1)
+}
+
+/// Adds the digest of a pair into the `digest` of the table, both as 256-bit little-endian
+/// integers, wrapping around, see `HashTable::content_digest`.
+fn add_pair_digest(digest: &mut [u8; 32], pair_digest: &[u8; 32]) {
+    let mut carry = 0;
+    for (byte, pair_byte) in digest.iter_mut().zip(pair_digest) {
+        let sum = *byte as u16 + *pair_byte as u16 + carry;
+        *byte = sum as u8;
+        carry = sum >> 8;
+    }
+}
+
+/// Settings that control how the database file is created and accessed.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Grow the file with `set_len` rather than by writing out the zeros of new sectors, so that
+    /// the untouched parts of a sector stay holes in a sparse file and don't consume disk blocks
+    /// until they are written to. Sectors reused from the free list are still zeroed explicitly.
+    pub sparse_sectors: bool,
+    /// The number of new slots that must be occupied across all the sectors since the last resize
+    /// before a sector that is only `early_sector_percent` full gets resized. Larger values space
+    /// the resizes further apart in time.
+    pub resize_spacing_writes: u64,
+    /// The percentage of its slots whose occupation, by entries or tombstones, gets a hash table
+    /// sector resized regardless of `resize_spacing_writes`. Must be below 100, since the lookups
+    /// probe up to an empty slot.
+    pub max_sector_percent: u64,
+    /// The percentage of its slots whose occupation gets a hash table sector resized once
+    /// `resize_spacing_writes` new slots have been occupied since the last resize, so that the
+    /// sectors that fill up at the same pace aren't all resized at once. Must be between 1 and
+    /// `max_sector_percent`.
+    pub early_sector_percent: u64,
+    /// Adapt the early resize threshold to the growth of the table: after every
+    /// `ADAPTIVE_RESIZE_WINDOW_SECS`, as told by `clock`, during which fewer than
+    /// `resize_spacing_writes` new slots were occupied, the threshold is raised by
+    /// `ADAPTIVE_RESIZE_STEP_PERCENT` up to `max_sector_percent`, and after a window with more
+    /// it's reset to `early_sector_percent`. A table that barely grows then fills its sectors
+    /// further, rather than resizing them early to space resizes that are far apart anyway. The
+    /// raised threshold isn't stored, so it's reset on open.
+    pub adaptive_resize: bool,
+    /// How the deleted entries are removed from the hash table. Can differ between the opens of
+    /// the same database. Ignored with `ProbeStrategy::DoubleHashing`, which always leaves
+    /// tombstones.
+    pub delete_strategy: DeleteStrategy,
+    /// The sequence of slots probed for a hash within its sector. It's stored in the header, and
+    /// can't change once the database is created.
+    pub probe_strategy: ProbeStrategy,
+    /// How much the compaction moves per value slot deleted and written, see
+    /// `HashTable::pending_compaction`.
+    pub compaction_pacing: CompactionPacing,
+    /// Keep track of the deleted value slots in the delmap, and compact the values region as the
+    /// values are deleted and overwritten. Without it, no delmap sectors are allocated and the
+    /// space of the deleted and overwritten values is never reclaimed, which suits the write-once
+    /// workloads. The iteration then checks every record against the hash table instead of the
+    /// delmap. It's stored in the header, and can't change once the database is created.
+    pub enable_compaction: bool,
+    /// Store a sequence number in every value written by `set`, see `HashTable::get_with_seq`.
+    pub store_seq: bool,
+    /// Store the key in every value written by `set`, so that the keys can be enumerated with
+    /// `HashTable::iter_keys`. This costs `4 + key.len()` extra bytes per record.
+    pub store_keys: bool,
+    /// The pipeline the values are passed through before they're written, see `transforms`.
+    /// Reading a database requires the same pipeline it was written with.
+    pub transforms: Vec<Arc<dyn ValueTransform>>,
+    /// Repair the header fields that can be rebuilt from the sectors (the file size and the free
+    /// list) when they are found to be inconsistent on open, rather than failing with
+    /// `StoreError::Corruption`. The repairs are returned by `HashTable::repairs`, so that the
+    /// caller can report them.
+    pub auto_recover: bool,
+    /// The size of the sectors the file is divided into. Must be a multiple of `PAGE_SIZE` larger
+    /// than `FIRST_SECTOR_OFFSET`. The database is at least one sector plus the header in size, so
+    /// smaller sectors reduce the footprint of small databases at the cost of more frequent
+    /// allocations. It is stored in the header, and can't change once the database is created.
+    pub sector_size: u64,
+    /// The maximum number of threads `flush_changes` writes the changes with. The changes are
+    /// split between the threads on sector boundaries, and small change sets are written by a
+    /// single thread regardless of this setting.
+    pub flush_threads: usize,
+    /// How durable `flush_changes` and `write_to_log` make the writes before returning.
+    pub durability: Durability,
+    /// The size of the cache of the pages read from the file, which is kept across the flushes.
+    /// Zero disables the cache.
+    pub page_cache_bytes: u64,
+    /// The replay of the log on open flushes the changes every time this many bytes of them have
+    /// been read from the log, so that replaying a log larger than the memory doesn't hold all of
+    /// it at once.
+    pub replay_chunk_bytes: u64,
+    /// The clock the expiry of the values written with `HashTable::set_with_ttl` is checked
+    /// against.
+    pub clock: Arc<dyn Clock>,
+    /// How long a scan of the hash table may take before the operation fails with
+    /// `StoreError::Timeout`, as told by `clock`: the probing of the first lookup of a `get`, `set`
+    /// or `delete`, the walk of a delete to the end of the cluster it shifts back, and the reading
+    /// of a sector that a `set` may resize or a `delete` may rebuild. The clock is checked between
+    /// the fetches of the pages, so a single read that blocks isn't interrupted, but a long probe
+    /// over a slow disk is. The scans all happen before the operation changes anything, so the
+    /// resizes and the rebuilds themselves aren't timed. `None` never times out.
+    pub op_deadline: Option<Duration>,
+    /// Keep the number of slots probed by the latest seeks, see `HashTable::probe_stats`. Off by
+    /// default, since it records every lookup of a key.
+    pub probe_stats: bool,
+    /// Coalesce the commits of a table opened with `HashTable::new_with_wal` into groups: a
+    /// `commit` only appends the changes made since the previous one to the log, unsynced, and
+    /// the group is flushed to the database once the log reaches `GroupCommit::max_bytes` or the
+    /// first commit of the group is `GroupCommit::max_interval` old, as told by `clock`, or when
+    /// it's forced with `HashTable::commit_group`. The log is synced once per group, after a
+    /// marker that commits all of its records is appended, and the recovery replays either the
+    /// whole group or none of it. `None` flushes every commit.
+    pub group_commit: Option<GroupCommit>,
+    /// How the reads and writes of the pages and the appends to the log are retried when they
+    /// fail with an error that is usually transient on networked file systems, i.e. of the kind
+    /// `io::ErrorKind::Interrupted`, `WouldBlock` or `TimedOut`, which `EINTR`, `EAGAIN` and
+    /// `ETIMEDOUT` are on unix. The other errors are returned right away.
+    pub io_retry: IoRetry,
+    /// The hash function of the keys. It's recorded in the header, and can't change once the
+    /// database is created.
+    pub hasher: Arc<dyn KeyHasher>,
+    /// The number of leading bytes of the hashes of the keys stored in the hash table, between 16
+    /// and `HASH_LEN`. Two keys whose hashes agree on them collide, and the later one overwrites
+    /// the earlier, so shorter hashes fit more slots in a sector at the cost of a higher risk of
+    /// collisions. It's stored in the header, and can't change once the database is created.
+    pub hash_len: usize,
+    /// The number of bytes the offsets of the values are stored in, between 6 and 8. A slot is
+    /// `hash_len + offset_width` bytes. It's stored in the header, and can't change once the
+    /// database is created.
+    pub offset_width: usize,
+    /// Store the values of up to `offset_width - 1` bytes in their hash table slots rather than
+    /// in the values region, so that they take no value slot and are read without another read,
+    /// see the `slot_layout` module. Only the values set without the optional header fields are
+    /// stored inline, i.e. without `store_seq`, `store_keys` or a TTL, and the limit applies to
+    /// the value passed through the transforms. It halves the range of the logical offsets of the
+    /// values. It's stored in the header, and can't change once the database is created.
+    pub inline_values: bool,
+    /// Read and write the pages of the file with direct IO, i.e. `O_DIRECT` on Linux and
+    /// `F_NOCACHE` on macOS, so that they bypass the OS page cache and the reads hit the disk,
+    /// e.g. for reproducible benchmarks. Direct IO requires the transfers to be aligned to the
+    /// logical block size of the device in their offset, length and memory; the pages are read
+    /// and written whole at `PAGE_SIZE` offsets from `PAGE_SIZE` aligned buffers, and the sector
+    /// size is a multiple of `PAGE_SIZE`, so devices with blocks of up to `PAGE_SIZE` are
+    /// supported. The file system must support direct IO, which e.g. tmpfs doesn't. Has no
+    /// effect on the reads through the mapping of `use_mmap`, or on the platforms without direct
+    /// IO.
+    pub direct_io: bool,
+    /// The number of keys the table is expected to hold, which sizes an in-memory bloom filter of
+    /// the keys, see the `bloom` module. The lookups of the keys the filter rules out return
+    /// without probing the hash table. The filter takes 10 bits per expected key and has about 1%
+    /// of false positives up to that many keys, more past them. It's built on open by reading the
+    /// whole hash table. Zero disables the filter.
+    pub expected_keys: u64,
+    /// Read the values through a shared memory mapping of the file rather than with `read`. The
+    /// file is mapped again whenever it grows or shrinks.
+    #[cfg(feature = "mmap")]
+    pub use_mmap: bool,
+    /// Reserve this many bytes of disk space for the file with `fallocate` when the database is
+    /// created, so that the sectors allocated later are contiguous on disk and growing into them
+    /// allocates no blocks, see the `preallocate` module. The reservation doesn't change the size
+    /// of the file. Zero reserves nothing, and the option is ignored when opening an existing
+    /// database.
+    #[cfg(all(feature = "preallocate", target_os = "linux"))]
+    pub preallocate_bytes: u64,
+}
+
+/// How durable the writes are once the call that makes them returns, see `Options::durability`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    /// The writes are handed to the OS, and may be lost on a power failure.
+    None,
+    /// The written data is synced to the disk with `File::sync_data`.
+    Data,
+    /// The written data and all the file metadata are synced to the disk with `File::sync_all`.
+    Full,
+}
+
+/// Where the write-ahead log is kept and how it's synced, see `HashTable::new_with_wal`.
+#[derive(Clone, Debug)]
+pub struct WalConfig {
+    /// The path of the log, which may be on another file system than the database.
+    pub path: PathBuf,
+    /// How the log and the database are synced around the replay and the commits. Anything but
+    /// `Durability::None` makes a crash of either device recoverable.
+    pub sync: Durability,
+}
+
+/// When the commits coalesced into a group are flushed, see `Options::group_commit`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GroupCommit {
+    /// The size the log may reach before the group is flushed.
+    pub max_bytes: u64,
+    /// How long after its first commit the group is flushed. It's only checked by the commits, so
+    /// an idle table keeps its group until the next one.
+    pub max_interval: Duration,
+}
+
+/// How the entries are removed from the hash table, see `Options::delete_strategy`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeleteStrategy {
+    /// The entries that follow the deleted one in its cluster are shifted back to close the gap,
+    /// so the table never gets tombstones. A delete touches every slot to the end of the cluster.
+    BackwardShift,
+    /// The slot is marked with a tombstone, which the lookups probe past and the inserts reuse. A
+    /// delete touches a single slot, and the sector is rebuilt once `TOMBSTONE_REBUILD_PERCENT` of
+    /// its slots are tombstones.
+    Tombstone,
+}
+
+/// The sequence of slots probed for a hash, from its home slot, see `Options::probe_strategy`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProbeStrategy {
+    /// The slots that follow the home slot. The entries whose home slots are close pile up into
+    /// long clusters as the sector fills up, which the probing of every hash landing in them has
+    /// to walk.
+    Linear,
+    /// The slots a step apart, where the step is derived from the 8 bytes of the hash before the
+    /// ones that choose the home slot, so that the hashes with the same home slot probe different
+    /// slots. It keeps the probing short at higher loads, at the cost of a slot per page read
+    /// rather than several. The entries can't be shifted back over the gap a delete leaves, so the
+    /// deletes always leave tombstones, see `DeleteStrategy::Tombstone`.
+    DoubleHashing,
+}
+
+impl ProbeStrategy {
+    fn tag(self) -> u64 {
+        match self {
+            ProbeStrategy::Linear => 0,
+            ProbeStrategy::DoubleHashing => 1,
+        }
+    }
+
+    fn from_tag(tag: u64) -> Option<Self> {
+        match tag {
+            0 => Some(ProbeStrategy::Linear),
+            1 => Some(ProbeStrategy::DoubleHashing),
+            _ => None,
+        }
+    }
+}
+
+/// What `HashTable::merge_from` does with the keys present in both tables.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergeConflict {
+    /// The value of the table merged into is kept.
+    KeepExisting,
+    /// The value of the table merged from replaces it.
+    Overwrite,
+}
+
+/// The weights of the compaction credit, see `HashTable::pending_compaction`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompactionPacing {
+    /// The credit earned per value slot deleted.
+    pub delete_credit: i64,
+    /// The credit spent per value slot written.
+    pub write_debit: i64,
+    /// The credit spent per value slot moved by the compaction.
+    pub move_debit: i64,
+}
+
+impl CompactionPacing {
+    /// The number of value slots the compaction moves per value slot written, when as many are
+    /// deleted as written.
+    pub fn moves_per_write(&self) -> f64 {
+        (self.delete_credit - self.write_debit) as f64 / self.move_debit as f64
+    }
+}
+
+impl Default for CompactionPacing {
+    fn default() -> Self {
+        Self {
+            delete_credit: 4,
+            write_debit: 2,
+            move_debit: 1,
+        }
+    }
+}
+
+/// How the transient IO errors are retried, see `Options::io_retry`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IoRetry {
+    /// The number of times an operation is retried before its error is returned.
+    pub max_retries: u32,
+    /// The wait before the first retry, doubled before each of the next ones.
+    pub backoff: Duration,
+}
+
+impl Default for IoRetry {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            backoff: Duration::from_millis(1),
+        }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            sparse_sectors: false,
+            resize_spacing_writes: (DEFAULT_SECTOR_SIZE - FIRST_SLOT_OFFSET) / SLOT_SIZE / 2,
+            max_sector_percent: DEFAULT_MAX_SECTOR_PERCENT,
+            early_sector_percent: DEFAULT_EARLY_SECTOR_PERCENT,
+            adaptive_resize: false,
+            delete_strategy: DeleteStrategy::BackwardShift,
+            probe_strategy: ProbeStrategy::Linear,
+            compaction_pacing: CompactionPacing::default(),
+            enable_compaction: true,
+            store_seq: false,
+            store_keys: false,
+            transforms: vec![],
+            auto_recover: false,
+            sector_size: DEFAULT_SECTOR_SIZE,
+            flush_threads: 1,
+            durability: Durability::None,
+            page_cache_bytes: 0,
+            replay_chunk_bytes: DEFAULT_REPLAY_CHUNK_BYTES,
+            clock: Arc::new(clock::SystemClock),
+            op_deadline: None,
+            probe_stats: false,
+            group_commit: None,
+            io_retry: IoRetry::default(),
+            hasher: Arc::new(hasher::Blake3Hasher),
+            hash_len: HASH_LEN,
+            offset_width: 6,
+            inline_values: false,
+            direct_io: false,
+            expected_keys: 0,
+            #[cfg(feature = "mmap")]
+            use_mmap: false,
+            #[cfg(all(feature = "preallocate", target_os = "linux"))]
+            preallocate_bytes: 0,
+        }
+    }
+}
+
+/// A change to the table, as reported to the subscribers, see `HashTable::subscribe`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mutation {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+    Clear,
 }
 
 pub struct HashTable {
     /// The node's salt for hashes
     salt: [u8; 32],
-    /// The file that stores the database
-    file: File,
+    options: Options,
+    /// The device that stores the database, the file at `file_name` unless the table was opened
+    /// with `new_in_memory`.
+    file: Arc<dyn BlockDevice>,
     file_name: PathBuf,
     /// This structure represents the current transaction. All the reads and writes to the database
     /// are only possible in a context of a transaction.
@@ -66,908 +780,10241 @@ pub struct HashTable {
     /// Number of new slots accross all sectors occupied since the last sector was resized. This is
     /// used to trigger an early resize if the number has been sufficiently large.
     writes_since_resize: u64,
+    /// The early resize threshold in effect, which `Options::adaptive_resize` raises above
+    /// `Options::early_sector_percent`.
+    early_sector_percent: u64,
+    /// The Unix time the current window of `Options::adaptive_resize` started at, and the number
+    /// of new slots occupied since.
+    resize_window: (u64, u64),
+
+    /// The compaction credit, see `pending_compaction`.
+    del_balance: i64,
+    /// The logical ranges of the value arenas that were reserved but not finalized yet, by the
+    /// thread id they were reserved for. The compaction never moves the values frontier into them.
+    value_arenas: BTreeMap<usize, (u64, u64)>,
+    /// The descriptions of the repairs made on open, see `Options::auto_recover`.
+    repairs: Vec<String>,
+    /// The channels the mutations are sent to on flush, see `subscribe`.
+    subscribers: Vec<SyncSender<Mutation>>,
+    /// The mutations made since the last flush, in order. Only tracked while there are
+    /// subscribers.
+    pending_mutations: Vec<Mutation>,
+    /// Set for the tables opened with `open_read_only`, which never write to the file.
+    read_only: bool,
+    /// The log `commit` writes the changes through, for the tables opened with `new_with_wal`.
+    wal: Option<(File, WalConfig)>,
+    /// The commits appended to the log that aren't flushed yet, see `Options::group_commit`.
+    group: Option<PendingGroup>,
+    /// The counters of the operations, see the `metrics` module.
+    metrics: Arc<Metrics>,
+    /// The number of slots probed by the latest seeks, see `probe_stats`, if
+    /// `Options::probe_stats` is set.
+    probe_window: Option<ProbeWindow>,
+    /// The mappings as of the last flush, which the `ReadSnapshot`s read the file through.
+    flushed_mappings: Arc<FlushedMappings>,
+    /// The rest of the state `rollback` restores, as of the last flush.
+    flushed_state: FlushedState,
+    /// The number of flushes started, which the `ReadSnapshot`s check to detect that the file
+    /// changed under them.
+    flushes: Arc<AtomicU64>,
+    /// How the entries are stored in the hash table slots, see `Options::hash_len`.
+    layout: SlotLayout,
+    /// The filter of the hashes in the hash table, see `Options::expected_keys`.
+    bloom: Option<BloomFilter>,
+
+    /// Number of value slots read through `get_value`, so that tests can check which operations
+    /// don't touch the values.
+    #[cfg(test)]
+    value_reads: u64,
+    /// Number of value slots moved by `move_one_value`, whether deleted or not.
+    #[cfg(test)]
+    value_moves: u64,
+    /// Number of keys hashed through `get_hash`, so that tests can check which operations hash
+    /// the key only once.
+    #[cfg(test)]
+    key_hashes: std::cell::Cell<u64>,
+}
 
+/// The state of a `HashTable` as of the last flush that `rollback` restores, other than the
+/// mappings, which are in `FlushedMappings`.
+#[derive(Default)]
+struct FlushedState {
+    /// The changes made on open, e.g. the repairs, which are only written by the first flush.
+    changes: BTreeMap<u64, Vec<u8>>,
+    /// The salt the flushed table is hashed with, which `rehash` changes.
+    salt: [u8; 32],
+    writes_since_resize: u64,
     del_balance: i64,
+    value_arenas: BTreeMap<usize, (u64, u64)>,
+    /// The mappings as of the last commit of a group that isn't flushed yet, which `rollback`
+    /// restores in place of the flushed ones.
+    mappings: Option<Arc<FlushedMappings>>,
+    /// The number of the pending mutations as of the last commit of such a group.
+    mutations: usize,
 }
 
-pub struct FetchedPage {
-    offset: u64,
-    page: Vec<u8>,
-    is_dirty: bool,
+/// A group of commits appended to the log and not flushed yet, see `Options::group_commit`.
+#[derive(Clone)]
+struct PendingGroup {
+    /// When the first commit of the group was made, as told by `Options::clock`.
+    started: Duration,
+    /// The length of the log, where the next commit is appended.
+    log_len: u64,
+    /// The number of the records appended so far.
+    records: u64,
+    /// The hash of their checksums, which the commit marker carries.
+    checksums: blake3::Hasher,
 }
 
-/// `TableTransaction` implements low level interaction with the database file. It allows
-/// reading and writing some number of bytes at particular offsets, and provides consistency.
-/// Specifically, if the process crashes, the writes that have happened before the call to
-/// `commit` either will all be reflected in the state of the database, or all absent.
-/// If the caller reads or writes some number of bytes at a particular offset, the caller must
-/// always read and write exactly the same number of bytes at such offset.
-pub struct TableTransaction {
-    changes: BTreeMap<u64, Vec<u8>>,
-    page: Option<FetchedPage>,
+/// The header at the beginning of every value record. A record is laid out as
+/// `hash[HASH_LEN] || len_le[8] || [seq_le[8]] || [expires_at_le[8]] || [key_len_le[4] ||
+/// [column_le[4]] || key] || payload || padding || [checksum[VALUE_CHECKSUM_LEN]]`, where `len`
+/// is the length of the record without the padding and the checksum, and the padding rounds the
+/// record up to a multiple of `VALUE_SIZE`. The record occupies that many consecutive value slots.
+///
+/// The checksum is the beginning of the blake3 hash of the first `len` bytes of the record. It's
+/// verified whenever the record is read, so that a corrupted value is reported rather than
+/// returned. All the records are written with it, the flag only tells apart the records written
+/// before it was introduced.
+///
+/// The optional fields in square brackets are only present in the records that have the
+/// corresponding flag set in the top bits of the length field (see `VALUE_FLAGS_MASK`). They sit
+/// between the length and the payload, so a record without them has its payload at
+/// `VALUE_PAYLOAD_OFFSET`. The key itself is not part of the header, since it might not fit into
+/// the first slot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ValueHeader {
+    pub hash: [u8; HASH_LEN],
+    /// The length of the header, the optional fields and the payload, without the flags.
+    pub len: u64,
+    /// The insertion sequence number, for the records written with `Options::store_seq`.
+    pub seq: Option<u64>,
+    /// The Unix time in seconds the record expires at, for the records written with
+    /// `HashTable::set_with_ttl`. The records without it never expire.
+    pub expires_at: Option<u64>,
+    /// The length of the key, for the records written with `Options::store_keys`.
+    pub key_len: Option<u32>,
+    /// The id of the column the key belongs to, for the records of the columns that store the
+    /// key, see `HashTable::column`. The walks over the records hash the key with its salt.
+    pub column: Option<u32>,
+    /// Whether the record ends with a checksum.
+    pub checksum: bool,
 }
 
-impl TableTransaction {
-    fn new() -> Self {
+impl ValueHeader {
+    /// Returns the header of a record without optional fields for a payload of `payload_len`
+    /// bytes. The record ends with a checksum.
+    pub fn new(hash: [u8; HASH_LEN], payload_len: usize) -> Self {
         Self {
-            changes: BTreeMap::new(),
-            page: None,
+            hash,
+            len: (VALUE_PAYLOAD_OFFSET + payload_len) as u64,
+            seq: None,
+            expires_at: None,
+            key_len: None,
+            column: None,
+            checksum: true,
         }
     }
 
-    /// Removes all the changes the tx has tracked for the sector.
-    fn reset_sector(&mut self, offset: u64) {
-        let to_remove = self
-            .changes
-            .range(offset..offset + SECTOR_SIZE)
-            .map(|x| *x.0)
-            .collect::<Vec<_>>();
-        for change in to_remove {
-            self.changes.remove(&change);
+    pub fn with_seq(mut self, seq: u64) -> Self {
+        if self.seq.is_none() {
+            self.len += 8;
         }
+        self.seq = Some(seq);
+        self
     }
 
-    /// Stores the intent to write `data` at position `offset`.
-    fn set(&mut self, offset: u64, data: Vec<u8>) {
-        let len = data.len();
-        if let Some(old_value) = self.changes.insert(offset, data) {
-            assert_eq!(old_value.len(), len);
+    pub fn with_expiry(mut self, expires_at: u64) -> Self {
+        if self.expires_at.is_none() {
+            self.len += 8;
         }
+        self.expires_at = Some(expires_at);
+        self
     }
 
-    /// Returns `len` bytes from the position `offset`. If the data at the offset has been
-    /// overwritten as part of this transaction, returns the uncommitted value, otherwise fetches
-    /// it from disk.
-    fn get(&mut self, db_file: &mut File, offset: u64, len: u64) -> Vec<u8> {
-        if let Some(data) = self.changes.get(&offset) {
-            assert_eq!(data.len(), len as usize);
-            return data.clone();
+    pub fn with_key_len(mut self, key_len: u32) -> Self {
+        if let Some(old_key_len) = self.key_len {
+            self.len -= 4 + old_key_len as u64;
         }
-        let within = (offset & (PAGE_SIZE - 1)) as usize;
-        Self::fetch_page(&mut self.page, db_file, offset).page[within..within + len as usize]
-            .to_vec()
+        self.len += 4 + key_len as u64;
+        self.key_len = Some(key_len);
+        self
+    }
+
+    /// Stores the column of the key, which requires the key to be stored too.
+    pub fn with_column(mut self, column: u32) -> Self {
+        if self.column.is_none() {
+            self.len += 4;
+        }
+        self.column = Some(column);
+        self
     }
 
-    pub fn get_num(&mut self, db_file: &mut File, offset: u64) -> u64 {
-        let mut buf: [u8; 8] = [0; 8];
-        buf.copy_from_slice(&self.get(db_file, offset, 8));
-        u64::from_le_bytes(buf)
+    /// Parses the header from the first value slot of a record (or any slice that starts with it).
+    pub fn parse(slot: &[u8]) -> Self {
+        let len_and_flags = u64::from_le_bytes(
+            slot[VALUE_LEN_OFFSET..VALUE_PAYLOAD_OFFSET]
+                .try_into()
+                .unwrap(),
+        );
+        let mut offset = VALUE_PAYLOAD_OFFSET;
+        let seq = if len_and_flags & VALUE_FLAG_SEQ != 0 {
+            offset += 8;
+            Some(u64::from_le_bytes(
+                slot[offset - 8..offset].try_into().unwrap(),
+            ))
+        } else {
+            None
+        };
+        let expires_at = if len_and_flags & VALUE_FLAG_EXPIRY != 0 {
+            offset += 8;
+            Some(u64::from_le_bytes(
+                slot[offset - 8..offset].try_into().unwrap(),
+            ))
+        } else {
+            None
+        };
+        let key_len = if len_and_flags & VALUE_FLAG_KEY != 0 {
+            offset += 4;
+            Some(u32::from_le_bytes(
+                slot[offset - 4..offset].try_into().unwrap(),
+            ))
+        } else {
+            None
+        };
+        let column = if len_and_flags & VALUE_FLAG_COLUMN != 0 {
+            offset += 4;
+            Some(u32::from_le_bytes(
+                slot[offset - 4..offset].try_into().unwrap(),
+            ))
+        } else {
+            None
+        };
+        Self {
+            hash: slot[VALUE_HASH_OFFSET..VALUE_LEN_OFFSET]
+                .try_into()
+                .unwrap(),
+            len: len_and_flags & !VALUE_FLAGS_MASK,
+            seq,
+            expires_at,
+            key_len,
+            column,
+            checksum: len_and_flags & VALUE_FLAG_CHECKSUM != 0,
+        }
     }
 
-    fn maybe_replay_log(&mut self, wal: &mut File) -> bool {
-        let mut buf = [0u8; 8];
-        if let Err(_) = wal.read_exact(&mut buf) {
-            return false;
+    /// Encodes the header together with the optional fields, i.e. everything that precedes the
+    /// key and the payload.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(self.fields_len());
+        ret.extend_from_slice(&self.hash);
+        ret.extend_from_slice(&(self.len | self.flags()).to_le_bytes());
+        if let Some(seq) = self.seq {
+            ret.extend_from_slice(&seq.to_le_bytes());
         }
-        let num = u64::from_le_bytes(buf.clone());
-        for _ in 0..num {
-            if let Err(_) = wal.read_exact(&mut buf) {
-                return false;
-            }
-            let offset = u64::from_le_bytes(buf.clone());
-            if let Err(_) = wal.read_exact(&mut buf) {
-                return false;
-            }
-            let len = u64::from_le_bytes(buf.clone());
-            let mut data = vec![0u8; len as usize];
-            if let Err(_) = wal.read_exact(&mut data) {
-                return false;
-            }
-            self.set(offset, data);
+        if let Some(expires_at) = self.expires_at {
+            ret.extend_from_slice(&expires_at.to_le_bytes());
         }
-        if let Err(_) = wal.read_exact(&mut buf) {
-            return false;
+        if let Some(key_len) = self.key_len {
+            ret.extend_from_slice(&key_len.to_le_bytes());
         }
-        if u64::from_le_bytes(buf) != WAL_MAGIC {
-            return false;
+        if let Some(column) = self.column {
+            ret.extend_from_slice(&column.to_le_bytes());
         }
-        true
+        ret
     }
 
-    fn write_to_log(&mut self, wal: &mut File) {
-        wal.write_all(&(self.changes.len() as u64).to_le_bytes())
-            .expect(IO_ERROR);
-        for (offset, data) in self.changes.iter() {
-            wal.write_all(&offset.to_le_bytes()).expect(IO_ERROR);
-            wal.write_all(&(data.len() as u64).to_le_bytes())
-                .expect(IO_ERROR);
-            wal.write_all(data).expect(IO_ERROR);
+    pub fn flags(&self) -> u64 {
+        let mut flags = 0;
+        if self.seq.is_some() {
+            flags |= VALUE_FLAG_SEQ;
+        }
+        if self.key_len.is_some() {
+            flags |= VALUE_FLAG_KEY;
+        }
+        if self.checksum {
+            flags |= VALUE_FLAG_CHECKSUM;
         }
-        wal.write_all(&WAL_MAGIC.to_le_bytes()).expect(IO_ERROR);
+        if self.expires_at.is_some() {
+            flags |= VALUE_FLAG_EXPIRY;
+        }
+        if self.column.is_some() {
+            flags |= VALUE_FLAG_COLUMN;
+        }
+        flags
     }
 
-    /// Flushes all the changes to disk. Sorts the keys and inserts them in order, which, due to
-    /// the logic of lazily fetching and flushing pages, ensures that each page is only written
-    /// once.
-    fn flush_changes(&mut self, db_path: PathBuf) {
-        let mut changes = BTreeMap::new();
-        std::mem::swap(&mut changes, &mut self.changes);
+    /// The length of the header together with the optional fields, which is also the offset of
+    /// the key within the record.
+    pub fn fields_len(&self) -> usize {
+        VALUE_PAYLOAD_OFFSET
+            + if self.seq.is_some() { 8 } else { 0 }
+            + if self.expires_at.is_some() { 8 } else { 0 }
+            + if self.key_len.is_some() { 4 } else { 0 }
+            + if self.column.is_some() { 4 } else { 0 }
+    }
 
-        let mut changes = changes.into_iter().collect::<Vec<_>>();
+    /// The offset of the payload within the record.
+    pub fn payload_offset(&self) -> usize {
+        self.fields_len() + self.key_len.unwrap_or(0) as usize
+    }
 
-        let changes_grouped = (0..NUM_FLUSH_THREADS)
-            .map(|i| changes.split_off(changes.len() - changes.len() / (NUM_FLUSH_THREADS - i)))
-            .collect::<Vec<_>>();
+    pub fn payload_len(&self) -> u64 {
+        self.len - self.payload_offset() as u64
+    }
 
-        let threads = changes_grouped
-            .into_iter()
-            .map(|changes| {
-                let db_path = db_path.clone();
-                thread::spawn(move || {
-                    let mut db_file = open_file(&db_path);
-                    let mut page = None;
-                    for (offset, data) in changes {
-                        let within = (offset & (PAGE_SIZE - 1)) as usize;
-                        let fetched_page = Self::fetch_page(&mut page, &mut db_file, offset);
-                        fetched_page.page[within..within + data.len()].copy_from_slice(&data);
-                        fetched_page.is_dirty = true;
-                    }
-                    Self::may_be_flush_page(&mut page, &mut db_file);
-                })
-            })
-            .collect::<Vec<_>>();
-        for thread in threads {
-            thread.join().expect(IO_ERROR);
-        }
-        self.page = None;
+    /// The number of value slots the record spans.
+    pub fn num_slots(&self) -> u64 {
+        let checksum_len = if self.checksum {
+            VALUE_CHECKSUM_LEN as u64
+        } else {
+            0
+        };
+        (self.len + checksum_len).div_ceil(VALUE_SIZE)
     }
 
-    /// Ensures that the `fetched_page` is the page that contains the offset, and returns the
-    /// unwrapped `fetched_page`
-    fn fetch_page<'a>(
-        fetched_page: &'a mut Option<FetchedPage>,
-        db_file: &mut File,
-        mut offset: u64,
-    ) -> &'a mut FetchedPage {
-        offset &= !(PAGE_SIZE - 1);
-        if fetched_page.as_ref().map_or(true, |x| x.offset != offset) {
-            Self::may_be_flush_page(fetched_page, db_file);
-            let mut page = vec![0u8; PAGE_SIZE as usize];
-            db_file.seek(SeekFrom::Start(offset)).expect(IO_ERROR);
-            db_file.read_exact(&mut page).expect(IO_ERROR);
-            *fetched_page = Some(FetchedPage {
-                offset,
-                page,
-                is_dirty: false,
-            });
-        }
-        fetched_page.as_mut().unwrap()
+    /// Computes the checksum of the record, which must be at least `len` bytes long.
+    fn compute_checksum(&self, record: &[u8]) -> [u8; VALUE_CHECKSUM_LEN] {
+        blake3::hash(&record[..self.len as usize]).as_bytes()[..VALUE_CHECKSUM_LEN]
+            .try_into()
+            .unwrap()
     }
+}
 
-    fn may_be_flush_page(fetched_page: &mut Option<FetchedPage>, db_file: &mut File) {
-        if let Some(page) = fetched_page {
-            if page.is_dirty {
-                db_file.seek(SeekFrom::Start(page.offset)).expect(IO_ERROR);
-                db_file.write_all(&page.page).expect(IO_ERROR);
-            }
-            *fetched_page = None;
+/// A value record read from the disk, see `ValueHeader` for its layout.
+struct Record {
+    header: ValueHeader,
+    key: Option<Vec<u8>>,
+    payload: Vec<u8>,
+}
+
+impl Record {
+    /// Parses the record from the concatenation of all its value slots, or returns `None` if its
+    /// checksum doesn't match.
+    fn parse(record: &[u8]) -> Option<Self> {
+        let header = ValueHeader::parse(record);
+        if header.checksum
+            && record[record.len() - VALUE_CHECKSUM_LEN..] != header.compute_checksum(record)
+        {
+            return None;
         }
+        Some(Record {
+            header,
+            key: header
+                .key_len
+                .map(|_| record[header.fields_len()..header.payload_offset()].to_vec()),
+            payload: record[header.payload_offset()..header.len as usize].to_vec(),
+        })
     }
 }
 
-impl HashTable {
-    pub fn new(db_path: PathBuf, salt: [u8; 32], wal: Option<&mut File>) -> Self {
-        let mut file = open_file(&db_path);
-
-        let mut ht_mapping = BTreeMap::new();
-        //ht_mapping.insert([0; 26], FIRST_SECTOR_OFFSET);
+/// What the write-ahead log passed to `HashTable::recover` contains.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// Whether the log is whole and its checksum matches. The changes of a log that isn't are not
+    /// applied.
+    pub valid: bool,
+    /// The number of changes in the log, zero if it's not valid.
+    pub records: u64,
+    /// The range of the file offsets the changes write to, empty if there are none or the log is
+    /// not valid.
+    pub offsets: Range<u64>,
+}
 
-        let mut values_mapping = BTreeMap::new();
-        let mut delmap_mapping = BTreeMap::new();
+/// Where a key and its value are stored, see `HashTable::locate`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Location {
+    /// The offset in the file of the hash table slot that points to the value.
+    pub ht_slot_offset: u64,
+    /// The offset in the file of the hash table sector that contains the slot.
+    pub ht_sector_offset: u64,
+    /// The logical offset of the first slot of the value record.
+    pub logical_offset: u64,
+    /// The offset in the file of the first slot of the value record. The following slots are
+    /// only contiguous in the file if the record doesn't cross a values sector boundary.
+    pub physical_offset: u64,
+    /// The number of value slots the record spans.
+    pub num_slots: u64,
+    /// The offset in the file of the delmap entry holding the bit of the first slot of the value
+    /// record, if `Options::enable_compaction` is set.
+    pub delmap_offset: Option<u64>,
+    /// Whether the value is stored in the hash table slot, see `Options::inline_values`. It then
+    /// has no record, and the fields that describe the record are zero.
+    pub inline: bool,
+}
 
-        let file_len = file.metadata().expect(IO_ERROR).len();
-        if file_len < FIRST_SECTOR_OFFSET + SECTOR_SIZE {
-            // This is the first time we create this database
-            const DESIRED_SIZE: u64 = FIRST_SECTOR_OFFSET + SECTOR_SIZE;
-            let mut data = [0; DESIRED_SIZE as usize];
-            data[0..8].copy_from_slice(&DESIRED_SIZE.to_le_bytes());
-            data[NEXT_VALUE_PHYSICAL_OFFSET as usize..NEXT_VALUE_PHYSICAL_OFFSET as usize + 8]
-                .copy_from_slice(&FIRST_SECTOR_OFFSET.to_le_bytes());
-            data[NEXT_DELMAP_PHYSICAL_OFFSET as usize..NEXT_DELMAP_PHYSICAL_OFFSET as usize + 8]
-                .copy_from_slice(&FIRST_SECTOR_OFFSET.to_le_bytes());
-            data[FIRST_SECTOR_OFFSET as usize + 48..FIRST_SECTOR_OFFSET as usize + 56]
-                .copy_from_slice(PAGE_TYPE_HT.to_le_bytes().as_ref());
-            file.seek(SeekFrom::Start(0)).expect(IO_ERROR);
-            file.write_all(&data).expect(IO_ERROR);
-        }
+/// A snapshot of the layout of the database, see `HashTable::stats`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stats {
+    /// The logical offset of the oldest value slot that wasn't compacted yet.
+    pub first_value_logical: u64,
+    /// The logical offset the next value slot will be written at.
+    pub next_value_logical: u64,
+    /// The size of the values region, which is an upper bound of the size of the live records:
+    /// the region also contains the deleted records the compaction hasn't reached yet.
+    pub live_bytes_estimate: u64,
+    pub num_ht_sectors: u64,
+    pub num_value_sectors: u64,
+    pub num_delmap_sectors: u64,
+    pub num_free_sectors: u64,
+    pub file_size: u64,
+}
 
-        let mut tx = TableTransaction::new();
+/// The counters in the header of a database file, see `HashTable::peek_header`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeaderInfo {
+    pub file_size: u64,
+    pub next_value_logical: u64,
+    pub first_value_logical: u64,
+    /// The number of keys stored, see `HashTable::len`.
+    pub len: u64,
+}
 
-        if let Some(wal) = wal {
-            if tx.maybe_replay_log(wal) {
-                tx.flush_changes(db_path.clone());
-            } else {
-                tx = TableTransaction::new();
-            }
-        }
-        let file_size = tx.get_num(&mut file, 0);
+/// What a sector holds, see `SectorInfo`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageType {
+    /// A sector on the free list.
+    Free,
+    /// A sector of the hash table.
+    HashTable,
+    /// A sector of the values region.
+    Values,
+    /// A sector of the map of the deleted value slots.
+    Delmap,
+}
 
-        let mut offset = FIRST_SECTOR_OFFSET;
-        while offset < file_size {
-            let page_type = tx.get_num(&mut file, offset + 48);
-            if page_type == PAGE_TYPE_HT {
-                ht_mapping.insert(tx.get(&mut file, offset, 26).try_into().unwrap(), offset);
-            } else if page_type == PAGE_TYPE_VALUES {
-                values_mapping.insert(tx.get_num(&mut file, offset), offset + VALUE_SIZE);
-            } else if page_type == PAGE_TYPE_DELMAP {
-                delmap_mapping.insert(tx.get_num(&mut file, offset), offset + FIRST_SLOT_OFFSET);
-            } else {
-                assert_eq!(page_type, PAGE_TYPE_FREE);
-            }
+/// A sector of the file, see `HashTable::sectors`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SectorInfo {
+    pub physical_offset: u64,
+    pub page_type: PageType,
+    /// The number of occupied slots of a hash table sector, the logical offset of the first
+    /// value slot or delmap entry of a values or delmap sector, and zero for a free one.
+    pub occupancy_or_logical_start: u64,
+}
 
-            offset += SECTOR_SIZE;
-        }
-        assert_eq!(offset, file_size);
+/// An iterator over the sectors of the file, see `HashTable::sectors`.
+pub struct Sectors<'a> {
+    table: &'a mut HashTable,
+    offset: u64,
+    end: u64,
+}
 
-        HashTable {
-            salt,
-            file,
-            file_name: db_path,
-            tx,
-            ht_mapping,
-            values_mapping,
-            delmap_mapping,
-            writes_since_resize: 0,
-            // `write_value` allocates new sectors whenever cur offset is on the sector boundary,
-            // so setting to a sector boundary will force sector allocation on next write
-            del_balance: 0,
+impl Sectors<'_> {
+    fn next_sector(&mut self) -> Result<Option<SectorInfo>, StoreError> {
+        if self.offset >= self.end {
+            return Ok(None);
         }
+        let offset = self.offset;
+        self.offset += self.table.options.sector_size;
+        let table = &mut *self.table;
+        let (page_type, occupancy_or_logical_start) =
+            match table.tx.get_num(&*table.file, offset + 48)? {
+                PAGE_TYPE_FREE => (PageType::Free, 0),
+                PAGE_TYPE_HT => (
+                    PageType::HashTable,
+                    table.tx.get_num(&*table.file, offset + 32)?,
+                ),
+                PAGE_TYPE_VALUES => (PageType::Values, table.tx.get_num(&*table.file, offset)?),
+                PAGE_TYPE_DELMAP => (PageType::Delmap, table.tx.get_num(&*table.file, offset)?),
+                _ => {
+                    return Err(StoreError::Corruption {
+                        offset: offset + 48,
+                        reason: "unknown page type",
+                    })
+                }
+            };
+        Ok(Some(SectorInfo {
+            physical_offset: offset,
+            page_type,
+            occupancy_or_logical_start,
+        }))
     }
+}
 
-    pub fn write_to_log(&mut self, wal: &mut File) {
-        self.tx.write_to_log(wal);
-    }
+impl Iterator for Sectors<'_> {
+    type Item = Result<SectorInfo, StoreError>;
 
-    pub fn flush_changes(&mut self) {
-        self.tx.flush_changes(self.file_name.clone());
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_sector().transpose()
     }
+}
 
-    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
-        let hash = self.get_hash(&key);
-        let full_value_len = (hash.len() + value.len() + 8) as u64;
-        let full_value_len_rounded_up = (full_value_len + VALUE_SIZE - 1) / VALUE_SIZE * VALUE_SIZE;
-        let full_value = [
-            hash.to_vec(),
-            full_value_len.to_le_bytes().to_vec(),
-            value,
-            vec![0; (full_value_len_rounded_up - full_value_len) as usize],
-        ]
-        .concat();
-        assert_eq!(full_value.len() as u64, full_value_len_rounded_up);
+/// Walks the live records between the logical offsets the values region had when the walk
+/// started, and yields them together with their logical offsets. With `Options::inline_values`,
+/// the values stored in the hash table slots are yielded first, as records without a key, together
+/// with the offsets of their slots in the file, so that the walks that need the keys fail before
+/// they reach the values region.
+struct Records<'a> {
+    table: &'a mut HashTable,
+    offset: u64,
+    end: u64,
+    /// The hash table sectors left to walk for the inline values, the next one last.
+    ht_sectors: Vec<u64>,
+    /// The next slot of the last of `ht_sectors`.
+    slot: u64,
+}
 
-        let offset = self.write_value(full_value[0..128].try_into().unwrap());
-        self.del_balance -= 2;
-        for i in 1..full_value_len_rounded_up / VALUE_SIZE {
-            let _ = self.write_value(
-                full_value[(i * 128) as usize..(i * 128 + 128) as usize]
-                    .try_into()
-                    .unwrap(),
-            );
-            self.del_balance -= 2;
+impl<'a> Records<'a> {
+    fn new(table: &'a mut HashTable) -> Result<Self, StoreError> {
+        let mut records = Self::values_region(table)?;
+        if records.table.layout.inline_values {
+            records.ht_sectors = records.table.ht_mapping.values().rev().copied().collect();
         }
+        Ok(records)
+    }
 
-        if let Some(old_offset) = self.ht_set_with_hash(hash, offset + 1) {
-            self.delete_at_offset(old_offset - 1)
+    /// Same as `new`, but resumes the walk from where the `cursor` was taken, see `cursor`.
+    fn resume(table: &'a mut HashTable, cursor: ScanCursor) -> Self {
+        Self {
+            table,
+            offset: cursor.offset,
+            end: cursor.end,
+            ht_sectors: cursor.ht_sectors,
+            slot: cursor.slot,
         }
     }
 
-    pub fn print_stats(&mut self) {
-        let logical_first_offset = self.tx.get_num(&mut self.file, FIRST_VALUE_LOGICAL_OFFSET);
-        let logical_last_offset = self.tx.get_num(&mut self.file, NEXT_VALUE_LOGICAL_OFFSET);
-        println!(
-            "STATS: first: {} last: {}",
-            logical_first_offset, logical_last_offset
-        );
+    /// Where the walk is, for `resume` to yield the next record first.
+    fn cursor(&self) -> ScanCursor {
+        ScanCursor {
+            offset: self.offset,
+            end: self.end,
+            ht_sectors: self.ht_sectors.clone(),
+            slot: self.slot,
+        }
     }
 
-    pub fn reset_del_balance(&mut self) {
-        self.del_balance = 0;
+    /// Same as `new`, but only walks the values region, without the inline values.
+    fn values_region(table: &'a mut HashTable) -> Result<Self, StoreError> {
+        let offset = table.tx.get_num(&*table.file, FIRST_VALUE_LOGICAL_OFFSET)?;
+        let end = table.tx.get_num(&*table.file, NEXT_VALUE_LOGICAL_OFFSET)?;
+        Ok(Self {
+            table,
+            offset,
+            end,
+            ht_sectors: vec![],
+            slot: 0,
+        })
     }
 
-    pub fn get(&mut self, key: Vec<u8>) -> Option<Vec<u8>> {
-        let hash = self.get_hash(&key);
-        let (_, mut offset) = self.seek(hash);
-
-        if offset == NO_VALUE {
-            return None;
+    fn next_record(&mut self) -> Result<Option<(u64, Record)>, StoreError> {
+        while let Some(&sector_offset) = self.ht_sectors.last() {
+            if self.slot == self.table.slots_in_sector() {
+                self.ht_sectors.pop();
+                self.slot = 0;
+                continue;
+            }
+            let slot_offset = self.table.layout.slot_offset(sector_offset, self.slot);
+            self.slot += 1;
+            let data = self.table.read_ht_slot(slot_offset)?;
+            let layout = self.table.layout;
+            if let Some(payload) = layout.inline_value(layout.extract_value(&data)) {
+                let record = Record {
+                    header: ValueHeader::new(layout.extract_hash(&data), payload.len()),
+                    key: None,
+                    payload,
+                };
+                return Ok(Some((slot_offset, record)));
+            }
         }
-        offset -= 1;
 
-        let logical_first_offset = self.tx.get_num(&mut self.file, FIRST_VALUE_LOGICAL_OFFSET);
-        if offset < logical_first_offset {
-            assert!(false)
+        let delmap = self.table.options.enable_compaction;
+        while self.offset < self.end {
+            // The slots of the deleted records are deleted one by one
+            if delmap && self.table.is_value_at_offset_deleted(self.offset)? {
+                self.offset += VALUE_SIZE;
+                continue;
+            }
+            let offset = self.offset;
+            // Don't get stuck on the same record if it fails to be read
+            self.offset += VALUE_SIZE;
+            let record = self.table.read_record(offset)?;
+            self.offset = offset + record.header.num_slots() * VALUE_SIZE;
+            // Without the delmap, the deleted and overwritten records are still in the values
+            // region, and only the live ones are pointed to by the hash table
+            if !delmap && self.table.seek(record.header.hash)?.1 != offset + 1 {
+                continue;
+            }
+            return Ok(Some((offset, record)));
         }
+        Ok(None)
+    }
+}
 
-        let mut values = vec![self.get_value(offset)];
-        let len = u64::from_le_bytes(values[0][HASH_LEN..HASH_LEN + 8].try_into().unwrap());
-        let mut remaining = len.saturating_sub(VALUE_SIZE);
-        while remaining > 0 {
-            offset += VALUE_SIZE;
-            values.push(self.get_value(offset));
-            remaining = remaining.saturating_sub(VALUE_SIZE);
-        }
+impl Iterator for Records<'_> {
+    type Item = Result<(u64, Record), StoreError>;
 
-        Some(values.concat()[HASH_LEN + 8..len as usize].into())
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
     }
+}
 
-    fn delete_at_offset(&mut self, mut offset: u64) {
-        let first_value = self.get_value(offset);
-        let mut remaining =
-            u64::from_le_bytes(first_value[HASH_LEN..HASH_LEN + 8].try_into().unwrap());
+/// An iterator over the keys of the table, see `HashTable::iter_keys`.
+pub struct Keys<'a> {
+    records: Records<'a>,
+}
 
-        while remaining > 0 {
-            self.delete_value(offset);
-            offset += VALUE_SIZE;
-            remaining = remaining.saturating_sub(VALUE_SIZE);
-            self.del_balance += 4;
-        }
+impl Iterator for Keys<'_> {
+    type Item = Result<Vec<u8>, StoreError>;
 
-        while self.del_balance > 0 {
-            let logical_first_offset = self.tx.get_num(&mut self.file, FIRST_VALUE_LOGICAL_OFFSET);
-            let logical_next_offset = self.tx.get_num(&mut self.file, NEXT_VALUE_LOGICAL_OFFSET);
-            let first_value = self.get_value(logical_first_offset);
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(
+            self.records
+                .next()?
+                .and_then(|(offset, record)| record.key.ok_or(StoreError::KeyNotStored { offset })),
+        )
+    }
+}
 
-            let mut remaining =
-                u64::from_le_bytes(first_value[HASH_LEN..HASH_LEN + 8].try_into().unwrap());
+/// An iterator over the key-value pairs of the table, see `HashTable::iter`.
+pub struct Iter<'a> {
+    records: Records<'a>,
+}
 
-            if logical_next_offset - logical_first_offset - remaining < VALUE_SIZE {
-                // There's only one value, don't move it
-                self.del_balance = 0;
-                break;
-            }
+impl Iterator for Iter<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>), StoreError>;
 
-            if let Some((old_offset, new_offset)) = self.move_one_value() {
-                let (ht_offset, mut stored_offset) =
-                    self.seek(first_value[..HASH_LEN].try_into().unwrap());
-                assert_ne!(stored_offset, NO_VALUE);
-                stored_offset -= 1;
-                assert_eq!(old_offset, stored_offset);
-                self.tx.set(
-                    ht_offset,
-                    [
-                        first_value[..HASH_LEN].as_ref(),
-                        (1 + new_offset).to_le_bytes()[0..6].as_ref(),
-                    ]
-                    .concat(),
-                );
-            }
-            remaining = remaining.saturating_sub(VALUE_SIZE);
-            self.del_balance -= 1;
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.records.next()?.and_then(|(offset, record)| {
+            let key = record.key.ok_or(StoreError::KeyNotStored { offset })?;
+            let value = self.records.table.decode_value(record.payload)?;
+            Ok((key, value))
+        }))
+    }
+}
 
-            while remaining > 0 {
-                self.move_one_value();
-                remaining = remaining.saturating_sub(VALUE_SIZE);
-                self.del_balance -= 1;
-            }
-        }
+/// An iterator over the values of the table with their logical offsets, see
+/// `HashTable::iter_values_by_logical`.
+pub struct ValuesByLogical<'a> {
+    records: Records<'a>,
+}
+
+impl Iterator for ValuesByLogical<'_> {
+    type Item = Result<(u64, Vec<u8>), StoreError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.records.next()?.and_then(|(offset, record)| {
+            let value = self.records.table.decode_value(record.payload)?;
+            Ok((offset, value))
+        }))
     }
+}
 
-    pub fn delete(&mut self, key: Vec<u8>) {
-        let hash = self.get_hash(&key);
-        let (_, mut offset) = self.seek(hash);
+/// An iterator over the key-value pairs whose keys start with a prefix, see
+/// `HashTable::scan_prefix`.
+pub struct ScanPrefix<'a> {
+    records: Records<'a>,
+    prefix: Vec<u8>,
+}
+
+impl Iterator for ScanPrefix<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>), StoreError>;
 
-        if offset != NO_VALUE {
-            offset -= 1;
-            self.delete_at_offset(offset);
-            self.ht_delete_with_hash(hash);
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(entry) = self.records.next() {
+            let entry = entry.and_then(|(offset, record)| {
+                let key = record.key.ok_or(StoreError::KeyNotStored { offset })?;
+                // Only the values that are returned are passed through the transforms
+                if !key.starts_with(&self.prefix) {
+                    return Ok(None);
+                }
+                let value = self.records.table.decode_value(record.payload)?;
+                Ok(Some((key, value)))
+            });
+            if let Some(entry) = entry.transpose() {
+                return Some(entry);
+            }
         }
+        None
     }
+}
 
-    /// Seeks the slot for a particular hash. Returns the offset of the slot, and the value
-    pub fn seek(&mut self, hash: [u8; 26]) -> (u64, u64) {
-        let mut slot = Self::get_slot(&hash);
-
-        // unwrap here is safe, because the ht_mapping always contains 0x0
-        let sector_offset = *self.ht_mapping.range(..=hash).next_back().unwrap().1;
+/// Where a scan of `HashTable::scan_bounded` stopped, to resume it from with
+/// `HashTable::scan_bounded_from`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScanCursor {
+    offset: u64,
+    end: u64,
+    ht_sectors: Vec<u64>,
+    slot: u64,
+}
 
-        loop {
-            let offset = sector_offset + slot * SLOT_SIZE + FIRST_SLOT_OFFSET;
-            let data = self.tx.get(&mut self.file, offset, SLOT_SIZE);
+/// A page of the key-value pairs of `HashTable::scan_bounded`, with the cursor to the rest of
+/// them, if any.
+pub type ScanPage = (Vec<(Vec<u8>, Vec<u8>)>, Option<ScanCursor>);
+
+/// An iterator that removes the key-value pairs from the table as it yields them, see
+/// `HashTable::drain`.
+pub struct Drain<'a> {
+    table: &'a mut HashTable,
+    /// The hashes of the keys left to remove. The hashes stay valid as the compaction moves the
+    /// values, unlike their offsets.
+    hashes: std::vec::IntoIter<[u8; HASH_LEN]>,
+    cleared: bool,
+}
 
-            let value = Self::extract_value(&data);
-            if value == NO_VALUE || data[..HASH_LEN] == hash[..] {
-                return (offset, value);
+impl Iterator for Drain<'_> {
+    /// The column of the pair, `None` for the keyspace of the table, the key and the value.
+    type Item = Result<(Option<u32>, Vec<u8>, Vec<u8>), StoreError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for hash in self.hashes.by_ref() {
+            let record = match self.table.take_record(hash) {
+                Ok(Some(record)) => record,
+                Ok(None) => continue,
+                Err(err) => return Some(Err(err)),
+            };
+            let now = self.table.options.clock.now_unix_secs();
+            let expired = record
+                .header
+                .expires_at
+                .is_some_and(|expires_at| expires_at <= now);
+            if !expired {
+                let key = record.key.expect("checked by drain");
+                let column = record.header.column;
+                return Some(
+                    self.table
+                        .decode_value(record.payload)
+                        .map(|value| (column, key, value)),
+                );
             }
-
-            slot += 1;
-            if slot >= SLOTS_IN_SECTOR {
-                slot = 0
+        }
+        // The compaction leaves the last record in place, and the delmap and the hash table
+        // sectors are never freed by the deletes
+        if !self.cleared {
+            self.cleared = true;
+            if let Err(err) = self.table.clear() {
+                return Some(Err(err));
             }
         }
+        None
     }
+}
 
-    pub fn ht_get(&mut self, key: Vec<u8>) -> Option<u64> {
-        let hash = self.get_hash(&key);
-        let (_offset, value) = self.seek(hash);
-        if value != NO_VALUE {
-            Some(value)
-        } else {
-            None
-        }
-    }
+pub struct FetchedPage {
+    offset: u64,
+    page: Box<AlignedPage>,
+    is_dirty: bool,
+}
 
-    pub fn ht_set(&mut self, key: Vec<u8>, new_value: u64) {
-        let hash = self.get_hash(&key);
-        self.ht_set_with_hash(hash, new_value);
+/// Appends to the log from its current position with positional writes, so that a write retried
+/// after failing partway through writes the same bytes again at the same place, see `io_retry`.
+struct LogAppender<'a> {
+    wal: &'a mut File,
+    retry: IoRetry,
+    position: u64,
+}
+
+impl<'a> LogAppender<'a> {
+    fn new(wal: &'a mut File, retry: IoRetry) -> Result<Self, StoreError> {
+        let position = wal.stream_position()?;
+        Ok(Self {
+            wal,
+            retry,
+            position,
+        })
     }
 
-    fn ht_set_with_hash(&mut self, hash: [u8; 26], new_value: u64) -> Option<u64> {
-        let (offset, old_value) = self.seek(hash);
+    fn append(&mut self, data: &[u8]) -> Result<(), StoreError> {
+        let position = self.position;
+        io_retry(self.retry, || {
+            block_device::write_all_at(self.wal, data, position)
+        })?;
+        self.position += data.len() as u64;
+        Ok(())
+    }
 
-        let data = [hash.as_ref(), &new_value.to_le_bytes()[..6]].concat();
-        assert_eq!(data.len(), SLOT_SIZE as usize);
-        self.tx.set(offset, data);
+    /// Moves the position of the log past the appended bytes.
+    fn finish(self) -> Result<(), StoreError> {
+        self.wal.seek(SeekFrom::Start(self.position))?;
+        Ok(())
+    }
+}
 
-        if old_value == NO_VALUE {
-            let sector_offset =
-                ((offset - FIRST_SECTOR_OFFSET) & !(SECTOR_SIZE - 1)) + FIRST_SECTOR_OFFSET;
+/// `TableTransaction` implements low level interaction with the database file. It allows
+/// reading and writing some number of bytes at particular offsets, and provides consistency.
+/// Specifically, if the process crashes, the writes that have happened before the call to
+/// `commit` either will all be reflected in the state of the database, or all absent.
+/// If the caller reads or writes some number of bytes at a particular offset, the caller must
+/// always read and write exactly the same number of bytes at such offset.
+pub struct TableTransaction {
+    changes: BTreeMap<u64, Vec<u8>>,
+    page: Option<FetchedPage>,
+    /// The pages read from disk, see `Options::page_cache_bytes`. Unlike the changes, the cache
+    /// is kept across the flushes.
+    cache: PageCache,
+    /// The mapping of the file the values are read through, see `Options::use_mmap`. The mapping
+    /// is shared, so the pages `flush_changes` writes are visible through it without remapping.
+    #[cfg(feature = "mmap")]
+    mmap: Option<memmap2::Mmap>,
+    /// The handle the pages are read through with direct IO, see `Options::direct_io`.
+    direct: Option<File>,
+    /// How the reads and writes of the pages are retried, see `Options::io_retry`.
+    retry: IoRetry,
+    /// The number of bytes written to the file and the logs over the lifetime of the database,
+    /// see `HashTable::lifetime_bytes_written`.
+    bytes_written: u64,
+
+    /// Number of `sync_data` / `sync_all` calls made, so that tests can check the durability
+    /// settings are honored.
+    #[cfg(test)]
+    syncs: u64,
+    /// The largest number of bytes of changes the replay of the log held at once.
+    #[cfg(test)]
+    replay_peak_bytes: u64,
+}
 
-            let mut occ = self.tx.get_num(&mut self.file, sector_offset + 32);
-            occ += 1;
+impl TableTransaction {
+    fn new() -> Self {
+        Self {
+            changes: BTreeMap::new(),
+            page: None,
+            cache: PageCache::new(0),
+            #[cfg(feature = "mmap")]
+            mmap: None,
+            direct: None,
+            retry: IoRetry::default(),
+            bytes_written: 0,
+            #[cfg(test)]
+            syncs: 0,
+            #[cfg(test)]
+            replay_peak_bytes: 0,
+        }
+    }
 
-            // If the segment is `MAX_SECTOR_PERCENT` occupied, resize it unconditionally.
-            // Otherwise, resize it if it's `EARLY_SECTOR_PERCENT`, and `SLOTS_IN_SECTOR / 2` new
-            // writes have happened across all sectors since the last resize. The latter is a
-            // heuristic needed to space resizes in time (otherwise sectors grow with approximately
-            // the same speed, and get resized close to each other in time).
-            let resize = occ >= SLOTS_IN_SECTOR * MAX_SECTOR_PERCENT / 100
-                || (occ >= SLOTS_IN_SECTOR * EARLY_SECTOR_PERCENT / 100
-                    && self.writes_since_resize >= SLOTS_IN_SECTOR / 2);
+    /// Makes the writes to `file` durable to the extent `durability` requires.
+    fn sync(&mut self, file: &dyn BlockDevice, durability: Durability) -> Result<(), StoreError> {
+        #[cfg(test)]
+        if durability != Durability::None {
+            self.syncs += 1;
+        }
+        Ok(file.sync(durability)?)
+    }
 
-            if !resize {
-                self.writes_since_resize += 1;
-                self.tx.set(sector_offset + 32, occ.to_le_bytes().to_vec());
-            } else {
-                self.writes_since_resize = 0;
+    /// Removes all the changes the tx has tracked for the sector.
+    fn reset_sector(&mut self, offset: u64, sector_size: u64) {
+        let to_remove = self
+            .changes
+            .range(offset..offset + sector_size)
+            .map(|x| *x.0)
+            .collect::<Vec<_>>();
+        for change in to_remove {
+            self.changes.remove(&change);
+        }
+    }
 
-                // We need to resize the sector. This process is done in three steps:
-                // 1. Collect all the key-value pairs, and their hashes, and wipe out the content
-                //    of the sector.
-                let mut pairs: Vec<([u8; 26], u64)> = vec![];
-                for slot in 0..SLOTS_IN_SECTOR {
-                    let slot_offset = sector_offset + slot * SLOT_SIZE + FIRST_SLOT_OFFSET;
-                    let data = self.tx.get(&mut self.file, slot_offset, SLOT_SIZE);
-                    let value = Self::extract_value(&data);
-                    if value != NO_VALUE {
-                        pairs.push((
-                            data[..HASH_LEN].try_into().unwrap(),
-                            Self::extract_value(&data),
-                        ))
-                    }
-                    self.tx.set(slot_offset, vec![0; SLOT_SIZE as usize]);
-                }
-                self.tx.set(sector_offset + 32, vec![0; 8]);
+    /// Stores the intent to write `data` at position `offset`.
+    fn set(&mut self, offset: u64, data: Vec<u8>) {
+        self.cache.remove(offset & !(PAGE_SIZE - 1));
+        let len = data.len();
+        if let Some(old_value) = self.changes.insert(offset, data) {
+            assert_eq!(old_value.len(), len);
+        }
+    }
 
-                // 2. Sort the hashes, and find the median hash. Create a new sector with such a key.
-                pairs.sort_unstable();
-                let median_hash = pairs[pairs.len() / 2].0;
+    /// Returns `len` bytes from the position `offset`. If the data at the offset has been
+    /// overwritten as part of this transaction, returns the uncommitted value, otherwise fetches
+    /// it from disk.
+    fn get(
+        &mut self,
+        db_file: &dyn BlockDevice,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>, StoreError> {
+        let mut data = vec![0; len as usize];
+        self.read_into(db_file, offset, &mut data)?;
+        Ok(data)
+    }
 
-                let sector_offset = self.allocate_sector(
-                    vec![
-                        median_hash.to_vec(),
-                        vec![0u8; 8 + 8 + 6],
-                        PAGE_TYPE_HT.to_le_bytes().to_vec(),
-                        vec![0u8; 8],
-                    ],
-                    FIRST_SLOT_OFFSET,
-                    SLOT_SIZE,
-                );
-                self.ht_mapping.insert(median_hash, sector_offset);
+    /// Same as `get`, but copies the bytes into `buf`, whose length is the number of bytes to
+    /// read, rather than allocating them.
+    fn read_into(
+        &mut self,
+        db_file: &dyn BlockDevice,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<(), StoreError> {
+        let len = buf.len();
+        if let Some(data) = self.changes.get(&offset) {
+            assert_eq!(data.len(), len);
+            buf.copy_from_slice(data);
+            return Ok(());
+        }
+        let within = (offset & (PAGE_SIZE - 1)) as usize;
+        let page_offset = offset & !(PAGE_SIZE - 1);
+        if let Some(page) = self.cache.get(page_offset) {
+            buf.copy_from_slice(&page[within..within + len]);
+            return Ok(());
+        }
+        let db_file = match &self.direct {
+            Some(direct) => direct,
+            None => db_file,
+        };
+        let page = &Self::fetch_page(
+            &mut self.page,
+            db_file,
+            self.retry,
+            offset,
+            &mut self.bytes_written,
+        )?
+        .page;
+        buf.copy_from_slice(&page[within..within + len]);
+        self.cache.insert(page_offset, page);
+        Ok(())
+    }
 
-                // 3. Reinsert the data
-                for (h, v) in pairs {
-                    self.ht_set_with_hash(h, v);
-                }
+    /// Copies the bytes at the position `offset` into `buf` through the mapping of the file, and
+    /// returns whether it did. Doesn't if they are past the end of the mapping or have been
+    /// overwritten as part of this transaction, in which case they must be read with `get`.
+    #[cfg(feature = "mmap")]
+    fn read_mapped(&self, offset: u64, buf: &mut [u8]) -> bool {
+        if self.changes.contains_key(&offset) {
+            return false;
+        }
+        let data = self
+            .mmap
+            .as_ref()
+            .and_then(|mmap| mmap.get(offset as usize..offset as usize + buf.len()));
+        match data {
+            Some(data) => {
+                buf.copy_from_slice(data);
+                true
             }
-            None
-        } else {
-            Some(old_value)
+            None => false,
         }
     }
 
-    pub fn ht_delete(&mut self, key: Vec<u8>) {
-        let hash = self.get_hash(&key);
-        self.ht_delete_with_hash(hash)
+    /// Maps the file again if `Options::use_mmap` is set, so that the mapping covers all of it.
+    /// Must be called after any change of the file length: accessing a mapped page past the end
+    /// of the file raises `SIGBUS`. The devices that aren't files are never mapped.
+    #[cfg(feature = "mmap")]
+    fn remap(&mut self, db_file: &dyn BlockDevice, options: &Options) -> Result<(), StoreError> {
+        self.mmap = None;
+        if let (true, Some(db_file)) = (options.use_mmap, db_file.as_file()) {
+            // Safety: the file is only modified through `write`, whose effect the shared mapping
+            // reflects, and the mapping is dropped before the file is truncated.
+            self.mmap = Some(unsafe { memmap2::Mmap::map(db_file)? });
+        }
+        Ok(())
     }
 
-    fn ht_delete_with_hash(&mut self, hash: [u8; 26]) {
-        let (mut target_offset, old_value) = self.seek(hash);
-        if old_value != NO_VALUE {
-            let sector_offset =
-                ((target_offset - FIRST_SECTOR_OFFSET) & !(SECTOR_SIZE - 1)) + FIRST_SECTOR_OFFSET;
+    /// Same as `get`, but returns the bytes in an array rather than allocating them.
+    fn get_fixed<const N: usize>(
+        &mut self,
+        db_file: &dyn BlockDevice,
+        offset: u64,
+    ) -> Result<[u8; N], StoreError> {
+        let mut data = [0u8; N];
+        self.read_into(db_file, offset, &mut data)?;
+        Ok(data)
+    }
 
-            let occ = self.tx.get_num(&mut self.file, sector_offset + 32) - 1;
-            self.tx.set(sector_offset + 32, occ.to_le_bytes().to_vec());
+    pub fn get_num(&mut self, db_file: &dyn BlockDevice, offset: u64) -> Result<u64, StoreError> {
+        Ok(u64::from_le_bytes(self.get_fixed(db_file, offset)?))
+    }
 
-            let mut cur_offset = target_offset;
-            loop {
-                cur_offset += SLOT_SIZE;
-                if ((cur_offset - FIRST_SECTOR_OFFSET) & (SECTOR_SIZE - 1)) == 0 {
-                    cur_offset -= SECTOR_SIZE - FIRST_SLOT_OFFSET;
+    /// Reads the log written by `write_to_log`, and applies the changes in it only if the whole
+    /// log is there and its checksum matches. The log is read twice: first to check the checksum
+    /// without keeping the changes, and then to apply them. With `flush`, they are flushed every
+    /// `Options::replay_chunk_bytes`, so that the memory used doesn't grow with the log. The
+    /// changes flushed by a replay that is interrupted are flushed again by the next one, since
+    /// the log stays the same until it's checkpointed. Returns what the log contains; the last
+    /// chunk, or all the changes without `flush`, is left in the transaction. A valid log that
+    /// writes past the end of the file is rejected before anything is applied: the sectors are
+    /// grown before the changes to them are logged, so the log doesn't belong to the file. The
+    /// records of a group commit are replayed in order, and only if the marker that commits them
+    /// all follows them, so a group that wasn't committed as a whole is left out entirely.
+    fn maybe_replay_log(
+        &mut self,
+        wal: &mut File,
+        db_file: &Arc<dyn BlockDevice>,
+        db_path: &Path,
+        options: &Options,
+        flush: bool,
+    ) -> Result<RecoveryReport, StoreError> {
+        let start = wal.stream_position()?;
+        let report = Self::check_log(&mut io::BufReader::new(&mut *wal));
+        if !report.valid {
+            return Ok(report);
+        }
+        if report.offsets.end > db_file.len()? {
+            return Err(StoreError::Corruption {
+                offset: report.offsets.end,
+                reason: "the write-ahead log writes past the end of the file",
+            });
+        }
+        wal.seek(SeekFrom::Start(start))?;
+        let mut wal = io::BufReader::new(wal);
+        let mut buf = [0u8; 8];
+        let mut chunk_bytes = 0;
+        loop {
+            wal.read_exact(&mut buf)?;
+            let magic = u64::from_le_bytes(buf);
+            if magic == WAL_COMMIT_MAGIC {
+                break;
+            }
+            wal.read_exact(&mut buf)?;
+            let num = u64::from_le_bytes(buf);
+            for _ in 0..num {
+                wal.read_exact(&mut buf)?;
+                let offset = u64::from_le_bytes(buf);
+                wal.read_exact(&mut buf)?;
+                let len = u64::from_le_bytes(buf);
+                let mut data = vec![0u8; len as usize];
+                wal.read_exact(&mut data)?;
+                self.set(offset, data);
+                chunk_bytes += len;
+                #[cfg(test)]
+                {
+                    self.replay_peak_bytes = self.replay_peak_bytes.max(chunk_bytes);
                 }
-
-                let data = self.tx.get(&mut self.file, cur_offset, SLOT_SIZE);
-                if Self::extract_value(&data) == NO_VALUE {
-                    self.tx.set(target_offset, vec![0; SLOT_SIZE as usize]);
-                    break;
+                if flush && chunk_bytes >= options.replay_chunk_bytes {
+                    self.flush_changes(db_file, db_path, options, options.durability)?;
+                    chunk_bytes = 0;
                 }
-                let desired_offset = sector_offset
-                    + FIRST_SLOT_OFFSET
-                    + SLOT_SIZE * Self::get_slot(&data[0..26].try_into().unwrap());
+            }
+            // Past the checksum
+            wal.read_exact(&mut [0u8; 32])?;
+            if magic == WAL_MAGIC {
+                break;
+            }
+        }
+        Ok(report)
+    }
 
-                let adjust = |x| {
-                    if x < desired_offset {
-                        x + SECTOR_SIZE - FIRST_SLOT_OFFSET
-                    } else {
-                        x
-                    }
+    /// Returns what the log contains, which is only valid if the log is whole and its checksums
+    /// match: either a single record, see `write_to_log`, or the records of a group commit
+    /// followed by the marker that commits them, see `Options::group_commit`. Only holds one
+    /// change in memory at a time.
+    fn check_log(wal: &mut impl Read) -> RecoveryReport {
+        let invalid = RecoveryReport::default();
+        let mut magic = match Self::read_u64(wal) {
+            Some(magic) => magic,
+            None => return invalid,
+        };
+        if magic == WAL_MAGIC {
+            return match Self::check_record(wal) {
+                Some((report, _)) => report,
+                None => invalid,
+            };
+        }
+        let mut report = RecoveryReport {
+            valid: true,
+            ..RecoveryReport::default()
+        };
+        let mut records = 0u64;
+        let mut checksums = blake3::Hasher::new();
+        while magic == WAL_GROUP_MAGIC {
+            let (record, checksum) = match Self::check_record(wal) {
+                Some(record) => record,
+                None => return invalid,
+            };
+            if record.records > 0 {
+                report.offsets = if report.records == 0 {
+                    record.offsets
+                } else {
+                    report.offsets.start.min(record.offsets.start)
+                        ..report.offsets.end.max(record.offsets.end)
                 };
+            }
+            report.records += record.records;
+            checksums.update(&checksum);
+            records += 1;
+            magic = match Self::read_u64(wal) {
+                Some(magic) => magic,
+                None => return invalid,
+            };
+        }
+        if magic != WAL_COMMIT_MAGIC || records == 0 || Self::read_u64(wal) != Some(records) {
+            return invalid;
+        }
+        checksums.update(&records.to_le_bytes());
+        let mut checksum = [0u8; 32];
+        if wal.read_exact(&mut checksum).is_err() || checksum != *checksums.finalize().as_bytes() {
+            return invalid;
+        }
+        report
+    }
 
-                if adjust(cur_offset) > adjust(target_offset) {
-                    self.tx.set(target_offset, data);
-                    target_offset = cur_offset;
-                }
+    fn read_u64(wal: &mut impl Read) -> Option<u64> {
+        let mut buf = [0u8; 8];
+        wal.read_exact(&mut buf).ok()?;
+        Some(u64::from_le_bytes(buf))
+    }
+
+    /// Checks a record past its magic, and returns what it contains together with its checksum.
+    fn check_record(wal: &mut impl Read) -> Option<(RecoveryReport, [u8; 32])> {
+        let mut buf = [0u8; 8];
+        let mut hasher = blake3::Hasher::new();
+        if wal.read_exact(&mut buf).is_err() {
+            return None;
+        }
+        hasher.update(&buf);
+        let num = u64::from_le_bytes(buf);
+        let mut offsets: Option<Range<u64>> = None;
+        for _ in 0..num {
+            if wal.read_exact(&mut buf).is_err() {
+                return None;
+            }
+            hasher.update(&buf);
+            let offset = u64::from_le_bytes(buf);
+            if wal.read_exact(&mut buf).is_err() {
+                return None;
             }
+            hasher.update(&buf);
+            let len = u64::from_le_bytes(buf);
+            match io::copy(&mut wal.take(len), &mut hasher) {
+                Ok(copied) if copied == len => {}
+                _ => return None,
+            }
+            offsets = Some(match offsets {
+                Some(range) => range.start.min(offset)..range.end.max(offset + len),
+                None => offset..offset + len,
+            });
+        }
+        let mut checksum = [0u8; 32];
+        if wal.read_exact(&mut checksum).is_err() || checksum != *hasher.finalize().as_bytes() {
+            return None;
         }
+        let report = RecoveryReport {
+            valid: true,
+            records: num,
+            offsets: offsets.unwrap_or_default(),
+        };
+        Some((report, checksum))
     }
 
-    fn is_value_at_offset_deleted(&mut self, logical_offset: u64) -> bool {
-        let (sector_logical_offset, sector_physical_offset) = self
-            .delmap_mapping
-            .range(..=logical_offset)
-            .next_back()
-            .unwrap();
-        let file_offset = sector_physical_offset
-            + (logical_offset - sector_logical_offset) / VALUE_SIZE / DELS_PER_DELMAP
-                * DELMAP_ENTRY_SIZE;
+    /// Writes the changes as `WAL_MAGIC || num || (offset || len || data) * num || checksum`,
+    /// where the checksum is the blake3 hash of everything between the magic and itself.
+    fn write_to_log(&mut self, wal: &mut File) -> Result<(), StoreError> {
+        let (written, _) = Self::write_log_record(
+            wal,
+            self.retry,
+            WAL_MAGIC,
+            self.changes.len(),
+            self.changes.iter(),
+        )?;
+        self.bytes_written += written;
+        Ok(())
+    }
 
-        let offset_within_delmap = (logical_offset / VALUE_SIZE) % DELS_PER_DELMAP;
-        let cur_delmap = self.tx.get(&mut self.file, file_offset, DELMAP_ENTRY_SIZE);
+    /// Writes a record of the `num` changes laid out as in `write_to_log`, starting with `magic`.
+    /// Returns the number of bytes written and the checksum of the record.
+    fn write_log_record<'a>(
+        wal: &mut File,
+        retry: IoRetry,
+        magic: u64,
+        num: usize,
+        changes: impl Iterator<Item = (&'a u64, &'a Vec<u8>)>,
+    ) -> Result<(u64, [u8; 32]), StoreError> {
+        let mut appender = LogAppender::new(wal, retry)?;
+        appender.append(&magic.to_le_bytes())?;
+        let mut hasher = blake3::Hasher::new();
+        let mut write = |data: &[u8]| -> Result<(), StoreError> {
+            hasher.update(data);
+            appender.append(data)
+        };
+        write(&(num as u64).to_le_bytes())?;
+        let mut written = 8 + 8 + 32;
+        for (offset, data) in changes {
+            write(&offset.to_le_bytes())?;
+            write(&(data.len() as u64).to_le_bytes())?;
+            write(data)?;
+            written += 16 + data.len() as u64;
+        }
+        let checksum = *hasher.finalize().as_bytes();
+        appender.append(&checksum)?;
+        appender.finish()?;
+        Ok((written, checksum))
+    }
 
-        cur_delmap[offset_within_delmap as usize / 8] & (1 << (offset_within_delmap % 8)) == 0
+    /// Writes the marker that commits the `records` group records before it, laid out as
+    /// `WAL_COMMIT_MAGIC || records || checksum`, where the checksum is the blake3 hash of the
+    /// checksums of the records followed by their number. Returns the number of bytes written.
+    fn write_commit_marker(
+        wal: &mut File,
+        retry: IoRetry,
+        records: u64,
+        mut checksums: blake3::Hasher,
+    ) -> Result<u64, StoreError> {
+        let mut appender = LogAppender::new(wal, retry)?;
+        appender.append(&WAL_COMMIT_MAGIC.to_le_bytes())?;
+        appender.append(&records.to_le_bytes())?;
+        checksums.update(&records.to_le_bytes());
+        appender.append(checksums.finalize().as_bytes())?;
+        appender.finish()?;
+        Ok(8 + 8 + 32)
     }
 
-    fn move_one_value(&mut self) -> Option<(u64, u64)> {
-        let logical_offset = self.tx.get_num(&mut self.file, FIRST_VALUE_LOGICAL_OFFSET);
+    /// The number of bytes `flush_changes` writes: every page with a change is written once, in
+    /// full.
+    fn flush_bytes(&self) -> u64 {
+        let mut pages = self
+            .changes
+            .keys()
+            .map(|offset| offset & !(PAGE_SIZE - 1))
+            .collect::<Vec<_>>();
+        pages.dedup();
+        pages.len() as u64 * PAGE_SIZE
+    }
 
-        let new_logical_offset = logical_offset + VALUE_SIZE;
-        self.tx.set(
-            FIRST_VALUE_LOGICAL_OFFSET,
-            new_logical_offset.to_le_bytes().to_vec(),
+    /// Flushes all the changes to disk. Sorts the keys and inserts them in order, which, due to
+    /// the logic of lazily fetching and flushing pages, ensures that each page is only written
+    /// once. If any of the flushing threads fails, the first error is returned after all of them
+    /// have finished, and all the changes are kept, so that the flush can be retried. Every file
+    /// handle written through is synced as `durability` requires. The threads write through
+    /// `db_file`, or through handles of their own opened at `db_path` with direct IO.
+    fn flush_changes(
+        &mut self,
+        db_file: &Arc<dyn BlockDevice>,
+        db_path: &Path,
+        options: &Options,
+        durability: Durability,
+    ) -> Result<(), StoreError> {
+        let mut changes = BTreeMap::new();
+        std::mem::swap(&mut changes, &mut self.changes);
+        for offset in changes.keys() {
+            self.cache.remove(offset & !(PAGE_SIZE - 1));
+        }
+
+        let changes_grouped = Self::split_changes(
+            changes.into_iter().collect(),
+            options.flush_threads,
+            options.sector_size,
         );
 
-        let ret = if !self.is_value_at_offset_deleted(logical_offset) {
-            let value = self.get_value(logical_offset);
-            let new_offset = self.write_value(value);
-            Some((logical_offset, new_offset))
+        let threads = changes_grouped
+            .into_iter()
+            .map(|changes| {
+                let db_file = db_file.clone();
+                let db_path = db_path.to_path_buf();
+                let direct_io = options.direct_io && db_file.as_file().is_some();
+                let retry = options.io_retry;
+                thread::spawn(move || {
+                    let result = Self::write_changes(&changes, db_file, &db_path, direct_io, retry);
+                    (changes, result)
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut ret = Ok(());
+        let mut written_changes = vec![];
+        for thread in threads {
+            let (changes, result) = thread.join().expect("flush thread panicked");
+            let result = result.and_then(|(db_file, written)| {
+                self.bytes_written += written;
+                self.sync(&*db_file, durability)
+            });
+            if ret.is_ok() {
+                ret = result;
+            }
+            written_changes.push(changes);
+        }
+        if ret.is_err() {
+            for changes in written_changes {
+                self.changes.extend(changes);
+            }
+        }
+        self.page = None;
+        ret
+    }
+
+    /// Writes the sorted `changes` of a flushing thread, see `flush_changes`. Returns the handle
+    /// written through, and the number of bytes written.
+    fn write_changes(
+        changes: &[(u64, Vec<u8>)],
+        db_file: Arc<dyn BlockDevice>,
+        db_path: &Path,
+        direct_io: bool,
+        retry: IoRetry,
+    ) -> Result<(Arc<dyn BlockDevice>, u64), StoreError> {
+        let db_file: Arc<dyn BlockDevice> = if direct_io {
+            Arc::new(direct_io::open_direct(db_path, false)?)
         } else {
-            None
+            db_file
         };
+        let mut page = None;
+        let mut written = 0;
+        for (offset, data) in changes {
+            let within = (offset & (PAGE_SIZE - 1)) as usize;
+            let fetched_page =
+                Self::fetch_page(&mut page, &*db_file, retry, *offset, &mut written)?;
+            fetched_page.page[within..within + data.len()].copy_from_slice(data);
+            fetched_page.is_dirty = true;
+        }
+        Self::may_be_flush_page(&mut page, &*db_file, retry, &mut written)?;
+        Ok((db_file, written))
+    }
 
-        if new_logical_offset % (SECTOR_SIZE - VALUE_SIZE) == 0 {
-            // The page that was holding the value being moved is now free
-            let (&sector_logical_offset, &sector_physical_offset) = self
-                .values_mapping
-                .range(..=logical_offset)
-                .next_back()
+    /// Splits the sorted `changes` into at most `flush_threads` groups of roughly the same size.
+    /// The groups are split on sector boundaries, so that no two of them touch the same page.
+    fn split_changes(
+        changes: Vec<(u64, Vec<u8>)>,
+        flush_threads: usize,
+        sector_size: u64,
+    ) -> Vec<Vec<(u64, Vec<u8>)>> {
+        let num_groups = flush_threads.min(changes.len() / MIN_CHANGES_PER_FLUSH_THREAD);
+        if num_groups <= 1 {
+            return vec![changes];
+        }
+
+        // The header is treated as sector 0, and the actual sectors are numbered from 1
+        let sector_of = |offset: u64| (offset + sector_size - FIRST_SECTOR_OFFSET) / sector_size;
+        let group_size = changes.len().div_ceil(num_groups);
+        let mut groups = vec![];
+        let mut group = vec![];
+        for (offset, data) in changes {
+            let sector_changed = group
+                .last()
+                .is_some_and(|(last, _): &(u64, Vec<u8>)| sector_of(*last) != sector_of(offset));
+            if group.len() >= group_size && sector_changed {
+                groups.push(std::mem::take(&mut group));
+            }
+            group.push((offset, data));
+        }
+        groups.push(group);
+        groups
+    }
+
+    /// Ensures that the `fetched_page` is the page that contains the offset, and returns the
+    /// unwrapped `fetched_page`. The bytes of the previous page, if it's written, are added to
+    /// `written`.
+    fn fetch_page<'a>(
+        fetched_page: &'a mut Option<FetchedPage>,
+        db_file: &dyn BlockDevice,
+        retry: IoRetry,
+        mut offset: u64,
+        written: &mut u64,
+    ) -> Result<&'a mut FetchedPage, StoreError> {
+        offset &= !(PAGE_SIZE - 1);
+        if fetched_page.as_ref().map_or(true, |x| x.offset != offset) {
+            Self::may_be_flush_page(fetched_page, db_file, retry, written)?;
+            // The buffer of the previous page is reused, so that the reads don't allocate
+            let mut page = match fetched_page.take() {
+                Some(previous) => previous.page,
+                None => AlignedPage::zeroed(),
+            };
+            io_retry(retry, || db_file.read_at(&mut page[..], offset))
+                .map_err(StoreError::at(offset))?;
+            *fetched_page = Some(FetchedPage {
+                offset,
+                page,
+                is_dirty: false,
+            });
+        }
+        Ok(fetched_page.as_mut().unwrap())
+    }
+
+    fn may_be_flush_page(
+        fetched_page: &mut Option<FetchedPage>,
+        db_file: &dyn BlockDevice,
+        retry: IoRetry,
+        written: &mut u64,
+    ) -> Result<(), StoreError> {
+        if let Some(page) = fetched_page {
+            if page.is_dirty {
+                io_retry(retry, || db_file.write_at(&page.page[..], page.offset))
+                    .map_err(StoreError::at(page.offset))?;
+                *written += PAGE_SIZE;
+            }
+            *fetched_page = None;
+        }
+        Ok(())
+    }
+}
+
+impl HashTable {
+    pub fn new(
+        db_path: PathBuf,
+        salt: [u8; 32],
+        wal: Option<&mut File>,
+    ) -> Result<Self, StoreError> {
+        Self::new_with_options(db_path, salt, wal, Options::default())
+    }
+
+    pub fn new_with_options(
+        db_path: PathBuf,
+        salt: [u8; 32],
+        wal: Option<&mut File>,
+        options: Options,
+    ) -> Result<Self, StoreError> {
+        Ok(Self::open_table(db_path, None, salt, wal, true, options, false)?.0)
+    }
+
+    pub fn new_with_options_or_panic(
+        db_path: PathBuf,
+        salt: [u8; 32],
+        wal: Option<&mut File>,
+        options: Options,
+    ) -> Self {
+        Self::new_with_options(db_path, salt, wal, options).expect(IO_ERROR)
+    }
+
+    /// Opens the database without a write-ahead log, creating it if it doesn't exist.
+    pub fn open(db_path: PathBuf, salt: [u8; 32]) -> Result<Self, StoreError> {
+        Self::open_with_options(db_path, salt, Options::default())
+    }
+
+    pub fn open_with_options(
+        db_path: PathBuf,
+        salt: [u8; 32],
+        options: Options,
+    ) -> Result<Self, StoreError> {
+        Ok(Self::open_table(db_path, None, salt, None, false, options, false)?.0)
+    }
+
+    /// Opens the database like `open`, and reads the changes of the write-ahead log `wal` if it's
+    /// valid, without flushing them: the table reads as if they were made since the last flush,
+    /// and the report says what the log contains, so that the caller can inspect them and decide
+    /// whether to `flush_changes`. Dropping the table leaves the file as it was. Unlike the
+    /// replay of `new`, all the changes are held in memory.
+    pub fn recover(
+        db_path: PathBuf,
+        salt: [u8; 32],
+        wal: &mut File,
+    ) -> Result<(Self, RecoveryReport), StoreError> {
+        Self::recover_with_options(db_path, salt, wal, Options::default())
+    }
+
+    pub fn recover_with_options(
+        db_path: PathBuf,
+        salt: [u8; 32],
+        wal: &mut File,
+        options: Options,
+    ) -> Result<(Self, RecoveryReport), StoreError> {
+        Self::open_table(db_path, None, salt, Some(wal), false, options, false)
+    }
+
+    /// Opens an existing database without ever writing to the file: the file is opened without
+    /// write permissions, and all the operations that would modify the database fail with
+    /// `StoreError::ReadOnly`.
+    pub fn open_read_only(db_path: PathBuf, salt: [u8; 32]) -> Result<Self, StoreError> {
+        Self::open_read_only_with_options(db_path, salt, Options::default())
+    }
+
+    pub fn open_read_only_with_options(
+        db_path: PathBuf,
+        salt: [u8; 32],
+        options: Options,
+    ) -> Result<Self, StoreError> {
+        Ok(Self::open_table(db_path, None, salt, None, false, options, true)?.0)
+    }
+
+    /// Creates a database that lives in memory rather than in a file, and is gone once the table
+    /// is dropped, see the `block_device` module. Meant for the tests and the fuzzing.
+    pub fn new_in_memory(salt: [u8; 32]) -> Result<Self, StoreError> {
+        Self::new_in_memory_with_options(salt, Options::default())
+    }
+
+    pub fn new_in_memory_with_options(
+        salt: [u8; 32],
+        options: Options,
+    ) -> Result<Self, StoreError> {
+        Self::open_device(Arc::new(MemoryDevice::new()), salt, options)
+    }
+
+    /// Opens the database stored on `device`, creating it if the device is empty. Opening it
+    /// again on the same device, e.g. a clone of a `MemoryDevice`, reads what was flushed.
+    pub fn open_device(
+        device: Arc<dyn BlockDevice>,
+        salt: [u8; 32],
+        options: Options,
+    ) -> Result<Self, StoreError> {
+        Ok(Self::open_table(
+            PathBuf::new(),
+            Some(device),
+            salt,
+            None,
+            false,
+            options,
+            false,
+        )?
+        .0)
+    }
+
+    /// Opens the database, and replays the write-ahead log if there is one. With `flush_wal`, the
+    /// changes of the log are flushed before the database is read, otherwise they are left
+    /// pending. The database is read from `device` if given, and from the file at `db_path`
+    /// otherwise.
+    fn open_table(
+        db_path: PathBuf,
+        device: Option<Arc<dyn BlockDevice>>,
+        salt: [u8; 32],
+        wal: Option<&mut File>,
+        flush_wal: bool,
+        options: Options,
+        read_only: bool,
+    ) -> Result<(Self, RecoveryReport), StoreError> {
+        let sector_size = options.sector_size;
+        assert!(
+            sector_size & (PAGE_SIZE - 1) == 0 && sector_size > FIRST_SECTOR_OFFSET,
+            "the sector size must be a multiple of {} larger than {}",
+            PAGE_SIZE,
+            FIRST_SECTOR_OFFSET
+        );
+        let pacing = options.compaction_pacing;
+        assert!(
+            pacing.write_debit >= 0
+                && pacing.move_debit > 0
+                && pacing.delete_credit > pacing.write_debit + pacing.move_debit,
+            "the compaction must move more than one value slot per value slot written"
+        );
+        assert!(
+            options.early_sector_percent > 0
+                && options.early_sector_percent <= options.max_sector_percent
+                && options.max_sector_percent < 100,
+            "the sectors must be resized early at 1 percent or more of their slots, and at the \
+             latest below 100 percent"
+        );
+        let layout = SlotLayout {
+            hash_len: options.hash_len,
+            offset_width: options.offset_width,
+            inline_values: options.inline_values,
+            probe: options.probe_strategy,
+        };
+        assert!(
+            (16..=HASH_LEN).contains(&layout.hash_len) && (6..=8).contains(&layout.offset_width),
+            "the hashes must be 16 to {} bytes long, and the offsets 6 to 8 bytes wide",
+            HASH_LEN
+        );
+
+        let file: Arc<dyn BlockDevice> = match device {
+            Some(device) => device,
+            None if read_only => Arc::new(OpenOptions::new().read(true).open(&db_path)?),
+            None => Arc::new(open_file(&db_path)?),
+        };
+
+        let mut ht_mapping = BTreeMap::new();
+        //ht_mapping.insert([0; 26], FIRST_SECTOR_OFFSET);
+
+        let mut values_mapping = BTreeMap::new();
+        let mut delmap_mapping = BTreeMap::new();
+
+        let file_len = file.len()?;
+        // A database with sectors smaller than configured can be shorter than a single configured
+        // sector, and must not be mistaken for a new one
+        let mut stored_sector_size = [0u8; 8];
+        if file_len >= FIRST_SECTOR_OFFSET {
+            file.read_at(&mut stored_sector_size, SECTOR_SIZE_OFFSET)
+                .map_err(StoreError::at(SECTOR_SIZE_OFFSET))?;
+        }
+        if file_len < FIRST_SECTOR_OFFSET + sector_size && stored_sector_size == [0; 8] {
+            // This is the first time we create this database
+            if read_only {
+                return Err(StoreError::ReadOnly);
+            }
+            let desired_size = FIRST_SECTOR_OFFSET + sector_size;
+            let mut data = vec![0; desired_size as usize];
+            data[0..8].copy_from_slice(&desired_size.to_le_bytes());
+            data[NEXT_VALUE_PHYSICAL_OFFSET as usize..NEXT_VALUE_PHYSICAL_OFFSET as usize + 8]
+                .copy_from_slice(&FIRST_SECTOR_OFFSET.to_le_bytes());
+            data[NEXT_DELMAP_PHYSICAL_OFFSET as usize..NEXT_DELMAP_PHYSICAL_OFFSET as usize + 8]
+                .copy_from_slice(&FIRST_SECTOR_OFFSET.to_le_bytes());
+            data[BYTE_ORDER_OFFSET as usize..BYTE_ORDER_OFFSET as usize + 8]
+                .copy_from_slice(&BYTE_ORDER_MARK.to_le_bytes());
+            data[SECTOR_SIZE_OFFSET as usize..SECTOR_SIZE_OFFSET as usize + 8]
+                .copy_from_slice(&sector_size.to_le_bytes());
+            data[SALT_FINGERPRINT_OFFSET as usize..SALT_FINGERPRINT_OFFSET as usize + 8]
+                .copy_from_slice(&salt_fingerprint(&salt).to_le_bytes());
+            data[HASHER_TAG_OFFSET as usize] = options.hasher.tag();
+            data[HASH_LEN_OFFSET as usize..HASH_LEN_OFFSET as usize + 8]
+                .copy_from_slice(&(layout.hash_len as u64).to_le_bytes());
+            data[OFFSET_WIDTH_OFFSET as usize..OFFSET_WIDTH_OFFSET as usize + 8]
+                .copy_from_slice(&(layout.offset_width as u64).to_le_bytes());
+            data[COMPACTION_DISABLED_OFFSET as usize..COMPACTION_DISABLED_OFFSET as usize + 8]
+                .copy_from_slice(&(!options.enable_compaction as u64).to_le_bytes());
+            data[INLINE_VALUES_OFFSET as usize..INLINE_VALUES_OFFSET as usize + 8]
+                .copy_from_slice(&(options.inline_values as u64).to_le_bytes());
+            data[PROBE_STRATEGY_OFFSET as usize..PROBE_STRATEGY_OFFSET as usize + 8]
+                .copy_from_slice(&options.probe_strategy.tag().to_le_bytes());
+            data[FIRST_SECTOR_OFFSET as usize + 48..FIRST_SECTOR_OFFSET as usize + 56]
+                .copy_from_slice(PAGE_TYPE_HT.to_le_bytes().as_ref());
+            #[cfg(all(feature = "preallocate", target_os = "linux"))]
+            if let (true, Some(file)) = (options.preallocate_bytes > 0, file.as_file()) {
+                preallocate::preallocate(file, options.preallocate_bytes)?;
+                data[PREALLOCATED_END_OFFSET as usize..PREALLOCATED_END_OFFSET as usize + 8]
+                    .copy_from_slice(&options.preallocate_bytes.to_le_bytes());
+            }
+            let data = if options.sparse_sectors {
+                // Only the header and the prelude of the first sector are non-zero
+                file.set_len(desired_size)?;
+                &data[..(FIRST_SECTOR_OFFSET + FIRST_SLOT_OFFSET) as usize]
+            } else {
+                &data[..]
+            };
+            file.write_at(data, 0).map_err(StoreError::at(0))?;
+        }
+
+        let mut tx = TableTransaction::new();
+
+        let mut report = RecoveryReport::default();
+        if let Some(wal) = wal {
+            report = tx.maybe_replay_log(wal, &file, &db_path, &options, flush_wal)?;
+            if !report.valid {
+                tx = TableTransaction::new();
+            } else if flush_wal {
+                tx.flush_changes(&file, &db_path, &options, options.durability)?;
+            }
+        }
+        tx.cache = PageCache::new(options.page_cache_bytes);
+        tx.retry = options.io_retry;
+        if options.direct_io && file.as_file().is_some() {
+            tx.direct = Some(direct_io::open_direct(&db_path, read_only)?);
+        }
+        #[cfg(feature = "mmap")]
+        tx.remap(&*file, &options)?;
+        match tx.get_num(&*file, BYTE_ORDER_OFFSET)? {
+            BYTE_ORDER_MARK => {}
+            // The file was created before the marker was introduced
+            0 => tx.set(BYTE_ORDER_OFFSET, BYTE_ORDER_MARK.to_le_bytes().to_vec()),
+            _ => return Err(StoreError::ByteOrderMismatch),
+        }
+        match tx.get_num(&*file, SECTOR_SIZE_OFFSET)? {
+            // The file was created before the sector size was configurable
+            0 if sector_size == DEFAULT_SECTOR_SIZE => {
+                tx.set(SECTOR_SIZE_OFFSET, sector_size.to_le_bytes().to_vec())
+            }
+            0 => {
+                return Err(StoreError::SectorSizeMismatch {
+                    configured: sector_size,
+                    stored: DEFAULT_SECTOR_SIZE,
+                })
+            }
+            stored if stored != sector_size => {
+                return Err(StoreError::SectorSizeMismatch {
+                    configured: sector_size,
+                    stored,
+                })
+            }
+            _ => {}
+        }
+        match tx.get_num(&*file, SALT_FINGERPRINT_OFFSET)? {
+            // The file was created before the fingerprint was stored
+            0 => tx.set(
+                SALT_FINGERPRINT_OFFSET,
+                salt_fingerprint(&salt).to_le_bytes().to_vec(),
+            ),
+            stored if stored != salt_fingerprint(&salt) => return Err(StoreError::SaltMismatch),
+            _ => {}
+        }
+        let stored_hasher = tx.get(&*file, HASHER_TAG_OFFSET, 1)?[0];
+        if stored_hasher != options.hasher.tag() {
+            return Err(StoreError::HasherMismatch {
+                configured: options.hasher.tag(),
+                stored: stored_hasher,
+            });
+        }
+        let stored_layout = (
+            tx.get_num(&*file, HASH_LEN_OFFSET)? as usize,
+            tx.get_num(&*file, OFFSET_WIDTH_OFFSET)? as usize,
+        );
+        let configured_layout = (layout.hash_len, layout.offset_width);
+        match stored_layout {
+            // The file was created before the slot layout was configurable
+            (0, 0) if configured_layout == (HASH_LEN, 6) => {
+                tx.set(HASH_LEN_OFFSET, (HASH_LEN as u64).to_le_bytes().to_vec());
+                tx.set(OFFSET_WIDTH_OFFSET, 6u64.to_le_bytes().to_vec());
+            }
+            (0, 0) => {
+                let default = SlotLayout::default();
+                return Err(StoreError::SlotLayoutMismatch {
+                    configured: configured_layout,
+                    stored: (default.hash_len, default.offset_width),
+                });
+            }
+            stored if stored != configured_layout => {
+                return Err(StoreError::SlotLayoutMismatch {
+                    configured: configured_layout,
+                    stored,
+                })
+            }
+            _ => {}
+        }
+        // The files created before the compaction could be disabled store 0
+        let stored_compaction = tx.get_num(&*file, COMPACTION_DISABLED_OFFSET)? == 0;
+        if stored_compaction != options.enable_compaction {
+            return Err(StoreError::CompactionMismatch {
+                configured: options.enable_compaction,
+                stored: stored_compaction,
+            });
+        }
+        let stored_inline_values = tx.get_num(&*file, INLINE_VALUES_OFFSET)? == 1;
+        if stored_inline_values != options.inline_values {
+            return Err(StoreError::InlineValuesMismatch {
+                configured: options.inline_values,
+                stored: stored_inline_values,
+            });
+        }
+        // The files created before the probe strategy could be chosen store 0, i.e. linear
+        let stored_probe = match ProbeStrategy::from_tag(tx.get_num(&*file, PROBE_STRATEGY_OFFSET)?)
+        {
+            Some(stored_probe) => stored_probe,
+            None => {
+                return Err(StoreError::Corruption {
+                    offset: PROBE_STRATEGY_OFFSET,
+                    reason: "unknown probe strategy",
+                })
+            }
+        };
+        if stored_probe != options.probe_strategy {
+            return Err(StoreError::ProbeStrategyMismatch {
+                configured: options.probe_strategy,
+                stored: stored_probe,
+            });
+        }
+        // Whatever the replay of the log wrote is on top of the stored count
+        tx.bytes_written += tx.get_num(&*file, LIFETIME_BYTES_WRITTEN_OFFSET)?;
+
+        let mut repairs = vec![];
+
+        // The file can legitimately be longer than the header says if the process died after a
+        // new sector was written out but before the header was flushed.
+        let mut file_size = tx.get_num(&*file, 0)?;
+        let file_len = file.len()?;
+        if file_size < FIRST_SECTOR_OFFSET + sector_size
+            || file_size % sector_size != FIRST_SECTOR_OFFSET
+            || file_size > file_len
+        {
+            if !options.auto_recover {
+                return Err(StoreError::Corruption {
+                    offset: 0,
+                    reason: "the file size in the header doesn't match the file",
+                });
+            }
+            let new_file_size =
+                FIRST_SECTOR_OFFSET + (file_len - FIRST_SECTOR_OFFSET) / sector_size * sector_size;
+            repairs.push(format!(
+                "file size in the header changed from {} to {}",
+                file_size, new_file_size
+            ));
+            file_size = new_file_size;
+            tx.set(0, file_size.to_le_bytes().to_vec());
+        }
+
+        let mut free_sectors = vec![];
+        let mut total_occupancy = 0;
+        let mut offset = FIRST_SECTOR_OFFSET;
+        while offset < file_size {
+            let page_type = tx.get_num(&*file, offset + 48)?;
+            if page_type == PAGE_TYPE_HT {
+                ht_mapping.insert(tx.get(&*file, offset, 26)?.try_into().unwrap(), offset);
+                total_occupancy += tx.get_num(&*file, offset + 32)?;
+            } else if page_type == PAGE_TYPE_VALUES {
+                values_mapping.insert(tx.get_num(&*file, offset)?, offset + VALUE_SIZE);
+            } else if page_type == PAGE_TYPE_DELMAP {
+                delmap_mapping.insert(tx.get_num(&*file, offset)?, offset + FIRST_SLOT_OFFSET);
+            } else if page_type == PAGE_TYPE_FREE {
+                free_sectors.push(offset);
+            } else {
+                return Err(StoreError::Corruption {
+                    offset: offset + 48,
+                    reason: "unknown page type",
+                });
+            }
+
+            offset += sector_size;
+        }
+
+        // Every sector on the free list must be a free one, which also rules out cycles as long
+        // as the list is no longer than the number of free sectors
+        let mut free_list_len = 0;
+        let mut free_offset = tx.get_num(&*file, FREE_LIST_OFFSET)?;
+        let mut free_list_valid = true;
+        while free_offset != 0 {
+            if free_list_len == free_sectors.len()
+                || free_sectors.binary_search(&free_offset).is_err()
+            {
+                free_list_valid = false;
+                break;
+            }
+            free_list_len += 1;
+            free_offset = tx.get_num(&*file, free_offset + 56)?;
+        }
+        if !free_list_valid {
+            if !options.auto_recover {
+                return Err(StoreError::Corruption {
+                    offset: FREE_LIST_OFFSET,
+                    reason: "the free list contains a sector that isn't free",
+                });
+            }
+            repairs.push(format!(
+                "free list rebuilt from {} free sectors",
+                free_sectors.len()
+            ));
+            let mut next = 0u64;
+            for &sector in free_sectors.iter().rev() {
+                tx.set(sector + 56, next.to_le_bytes().to_vec());
+                next = sector;
+            }
+            tx.set(FREE_LIST_OFFSET, next.to_le_bytes().to_vec());
+        }
+
+        // The databases created before the number of entries was stored have zero in its place
+        if tx.get_num(&*file, LEN_OFFSET)? == 0 && total_occupancy != 0 {
+            tx.set(LEN_OFFSET, total_occupancy.to_le_bytes().to_vec());
+        }
+
+        // The changes made on open are only to the header, so the mappings are as on the disk
+        let flushed_mappings = Arc::new(FlushedMappings::new(
+            &ht_mapping,
+            &values_mapping,
+            &delmap_mapping,
+        ));
+        let flushed_state = FlushedState {
+            changes: tx.changes.clone(),
+            salt,
+            ..FlushedState::default()
+        };
+        let early_sector_percent = options.early_sector_percent;
+        let resize_window = (options.clock.now_unix_secs(), 0);
+        let bloom = (options.expected_keys > 0).then(|| BloomFilter::new(options.expected_keys));
+        let probe_window = options.probe_stats.then(ProbeWindow::default);
+        let mut table = HashTable {
+            salt,
+            options,
+            file,
+            file_name: db_path,
+            tx,
+            ht_mapping,
+            values_mapping,
+            delmap_mapping,
+            writes_since_resize: 0,
+            early_sector_percent,
+            resize_window,
+            // `write_value` allocates new sectors whenever cur offset is on the sector boundary,
+            // so setting to a sector boundary will force sector allocation on next write
+            del_balance: 0,
+            value_arenas: BTreeMap::new(),
+            repairs,
+            subscribers: vec![],
+            pending_mutations: vec![],
+            read_only,
+            wal: None,
+            group: None,
+            metrics: Arc::default(),
+            probe_window,
+            flushed_mappings,
+            flushed_state,
+            flushes: Arc::default(),
+            layout,
+            bloom,
+            #[cfg(test)]
+            value_reads: 0,
+            #[cfg(test)]
+            value_moves: 0,
+            #[cfg(test)]
+            key_hashes: std::cell::Cell::new(0),
+        };
+        table.rebuild_bloom()?;
+        Ok((table, report))
+    }
+
+    /// Same as `new`, but panics on IO errors. Meant for benchmarks and tools that have no way to
+    /// handle them anyway.
+    pub fn new_or_panic(db_path: PathBuf, salt: [u8; 32], wal: Option<&mut File>) -> Self {
+        Self::new(db_path, salt, wal).expect(IO_ERROR)
+    }
+
+    pub fn write_to_log(&mut self, wal: &mut File) -> Result<(), StoreError> {
+        self.tx.write_to_log(wal)?;
+        self.tx.sync(wal, self.options.durability)
+    }
+
+    pub fn write_to_log_or_panic(&mut self, wal: &mut File) {
+        self.write_to_log(wal).expect(IO_ERROR)
+    }
+
+    /// Flushes the changes. The commits of a group that isn't flushed yet are committed as a
+    /// whole first, see `commit_group`.
+    pub fn flush_changes(&mut self) -> Result<(), StoreError> {
+        if self.group.is_some() {
+            return self.commit_with(true, true);
+        }
+        self.flush_changes_with(self.options.durability)
+    }
+
+    /// Same as `flush_changes`, but never syncs the file regardless of `Options::durability`, for
+    /// the writers that flush often and make the writes durable once with `barrier`. Until then
+    /// the flushed changes may be lost or only partly written on a crash, which may leave the
+    /// database inconsistent, so the crash safety only holds as of the last `barrier`.
+    pub fn flush_changes_no_sync(&mut self) -> Result<(), StoreError> {
+        if self.group.is_some() {
+            return self.commit_with(false, true);
+        }
+        self.flush_changes_with(Durability::None)
+    }
+
+    /// Makes all the writes to the database made so far durable with a single `sync_all`, see
+    /// `flush_changes_no_sync`, and then syncs the log of a table opened with `new_with_wal`, see
+    /// `commit_no_sync`. The changes that weren't flushed aren't written.
+    pub fn barrier(&mut self) -> Result<(), StoreError> {
+        self.check_writable()?;
+        self.tx.sync(&*self.file, Durability::Full)?;
+        if let Some((wal_file, _)) = &self.wal {
+            self.tx.sync(wal_file, Durability::Full)?;
+        }
+        Ok(())
+    }
+
+    fn flush_changes_with(&mut self, durability: Durability) -> Result<(), StoreError> {
+        self.check_writable()?;
+        self.flushes.fetch_add(1, Ordering::SeqCst);
+        if !self.tx.changes.is_empty() {
+            // The count is stored as of the end of this flush, so it includes the pages about to
+            // be written, among which is the page of the count itself
+            self.tx.set(LIFETIME_BYTES_WRITTEN_OFFSET, vec![0; 8]);
+            let lifetime = self.tx.bytes_written + self.tx.flush_bytes();
+            self.tx.set(
+                LIFETIME_BYTES_WRITTEN_OFFSET,
+                lifetime.to_le_bytes().to_vec(),
+            );
+        }
+        self.tx
+            .flush_changes(&self.file, &self.file_name, &self.options, durability)?;
+        self.flushed_mappings = Arc::new(FlushedMappings::new(
+            &self.ht_mapping,
+            &self.values_mapping,
+            &self.delmap_mapping,
+        ));
+        self.flushed_state = FlushedState {
+            changes: BTreeMap::new(),
+            salt: self.salt,
+            writes_since_resize: self.writes_since_resize,
+            del_balance: self.del_balance,
+            value_arenas: self.value_arenas.clone(),
+            mappings: None,
+            mutations: 0,
+        };
+
+        let mutations = std::mem::take(&mut self.pending_mutations);
+        for mutation in mutations {
+            // A subscriber that is full or gone is dropped, see `subscribe`
+            self.subscribers
+                .retain(|subscriber| subscriber.try_send(mutation.clone()).is_ok());
+        }
+        Ok(())
+    }
+
+    /// Discards the changes made since the last flush, or since the last commit of a group that
+    /// isn't flushed yet, so that the table reads as it did right after it. The mutations made
+    /// since are never sent to the subscribers, and the value arenas reserved since can't be
+    /// finalized anymore. The sectors the file grew by since are left past the file size in the
+    /// header, and get reused by the next growth. The bloom filter is rebuilt, since `clear` and
+    /// `rehash` empty it.
+    pub fn rollback(&mut self) -> Result<(), StoreError> {
+        self.check_writable()?;
+        self.tx.changes = self.flushed_state.changes.clone();
+        self.tx.page = None;
+        self.salt = self.flushed_state.salt;
+        let mappings = self
+            .flushed_state
+            .mappings
+            .clone()
+            .unwrap_or_else(|| self.flushed_mappings.clone());
+        self.ht_mapping = mappings.ht_mapping.clone();
+        self.values_mapping = mappings.values_mapping.clone();
+        self.delmap_mapping = mappings.delmap_mapping.clone();
+        self.writes_since_resize = self.flushed_state.writes_since_resize;
+        self.del_balance = self.flushed_state.del_balance;
+        self.value_arenas = self.flushed_state.value_arenas.clone();
+        self.pending_mutations
+            .truncate(self.flushed_state.mutations);
+        self.rebuild_bloom()
+    }
+
+    pub fn flush_changes_or_panic(&mut self) {
+        self.flush_changes().expect(IO_ERROR)
+    }
+
+    /// Flushes the changes, and then resets the `wal` to an empty log, so that it doesn't grow
+    /// with every `write_to_log`. The log only contains the bytes to write at given offsets, so
+    /// if the process dies between the flush and the reset, replaying the old log on the next
+    /// open just writes the same bytes again.
+    pub fn checkpoint(&mut self, wal: &mut File) -> Result<(), StoreError> {
+        self.flush_changes()?;
+        self.reset_log(wal, self.options.durability)
+    }
+
+    /// Resets the `wal` to an empty log, and syncs it as `durability` requires.
+    fn reset_log(&mut self, wal: &mut File, durability: Durability) -> Result<(), StoreError> {
+        wal.set_len(0)?;
+        wal.seek(SeekFrom::Start(0))?;
+        TableTransaction::write_log_record(wal, self.tx.retry, WAL_MAGIC, 0, std::iter::empty())?;
+        self.tx.sync(wal, durability)
+    }
+
+    /// Opens the database together with the write-ahead log at `wal.path`, creating the log if
+    /// it doesn't exist, and replays it. The log and the database may be on different devices,
+    /// so the recovery orders the syncs of the two as `wal.sync` requires: the log is synced
+    /// before its magic and checksum are trusted, so that a log a crash of its device could still
+    /// lose is never applied; the database is synced after the replay is flushed; and only then
+    /// is the log reset. The changes are then made durable with `commit`, which keeps the same
+    /// order.
+    pub fn new_with_wal(
+        db_path: PathBuf,
+        salt: [u8; 32],
+        wal: WalConfig,
+        options: Options,
+    ) -> Result<Self, StoreError> {
+        let mut wal_file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&wal.path)?;
+        sync_file(&wal_file, wal.sync)?;
+        let mut table = Self::open_table(
+            db_path,
+            None,
+            salt,
+            Some(&mut wal_file),
+            true,
+            options,
+            false,
+        )?
+        .0;
+        table.tx.sync(&*table.file, wal.sync)?;
+        table.reset_log(&mut wal_file, wal.sync)?;
+        table.wal = Some((wal_file, wal));
+        Ok(table)
+    }
+
+    /// Makes the changes since the last flush durable through the log the table was opened with
+    /// by `new_with_wal`: the changes are written to the log, which is synced, then flushed to the
+    /// database, which is synced, and then the log is reset, with the syncs done as
+    /// `WalConfig::sync` requires. A crash at any point leaves either the state before the commit
+    /// or a whole log, which the next `new_with_wal` replays. Without a log, the changes are just
+    /// flushed. With `Options::group_commit`, the changes are appended to the log instead, and
+    /// only made durable when the group they're in is flushed.
+    pub fn commit(&mut self) -> Result<(), StoreError> {
+        self.commit_with(true, false)
+    }
+
+    /// Commits the changes like `commit`, and then flushes the group of commits they're in
+    /// regardless of `Options::group_commit`: a marker that commits all the records of the group
+    /// is appended to the log, which is synced, then the changes are flushed to the database,
+    /// which is synced, and then the log is reset. A crash before the log is synced loses the
+    /// whole group, and one after it leaves a log that the next `new_with_wal` replays whole.
+    /// Without group commit, this is `commit`.
+    pub fn commit_group(&mut self) -> Result<(), StoreError> {
+        self.commit_with(true, true)
+    }
+
+    /// Same as `commit`, but syncs neither the log nor the database, for the writers that commit
+    /// often and make the commits durable once with `barrier`. The crash safety only holds as of
+    /// the last `barrier`: until then, a crash may leave the log reset while the changes it held
+    /// aren't on the disk yet, or the database partly written. Without a log, this is
+    /// `flush_changes_no_sync`.
+    pub fn commit_no_sync(&mut self) -> Result<(), StoreError> {
+        self.commit_with(false, false)
+    }
+
+    /// With `force`, the group of commits is flushed regardless of its thresholds.
+    fn commit_with(&mut self, synced: bool, force: bool) -> Result<(), StoreError> {
+        self.check_writable()?;
+        let flush = if synced {
+            self.options.durability
+        } else {
+            Durability::None
+        };
+        let (mut wal_file, wal) = match self.wal.take() {
+            Some(wal) => wal,
+            None => return self.flush_changes_with(flush),
+        };
+        let sync = if synced { wal.sync } else { Durability::None };
+        let result = match self.options.group_commit {
+            Some(group_commit) => {
+                self.group_commit_through(&mut wal_file, group_commit, sync, flush, force)
+            }
+            None => self.commit_through(&mut wal_file, sync, flush),
+        };
+        self.wal = Some((wal_file, wal));
+        result
+    }
+
+    /// Appends the changes made since the previous commit of the group to the `wal` as a group
+    /// record, unsynced, and flushes the group if it's due or `force`d. The first commit of a
+    /// group logs all the changes since the last flush, in place of the empty log it was reset to.
+    fn group_commit_through(
+        &mut self,
+        wal: &mut File,
+        group_commit: GroupCommit,
+        sync: Durability,
+        flush: Durability,
+        force: bool,
+    ) -> Result<(), StoreError> {
+        let now = self.options.clock.now();
+        let first = self.group.is_none();
+        let mut group = self.group.take().unwrap_or_else(|| PendingGroup {
+            started: now,
+            log_len: 0,
+            records: 0,
+            checksums: blake3::Hasher::new(),
+        });
+        let changes = self
+            .tx
+            .changes
+            .iter()
+            .filter(|&(offset, data)| first || self.flushed_state.changes.get(offset) != Some(data))
+            .collect::<Vec<_>>();
+        if !changes.is_empty() {
+            wal.seek(SeekFrom::Start(group.log_len))?;
+            let (written, checksum) = TableTransaction::write_log_record(
+                wal,
+                self.tx.retry,
+                WAL_GROUP_MAGIC,
+                changes.len(),
+                changes.into_iter(),
+            )?;
+            self.tx.bytes_written += written;
+            group.log_len += written;
+            group.records += 1;
+            group.checksums.update(&checksum);
+        }
+        let due = force
+            || group.log_len >= group_commit.max_bytes
+            || now.saturating_sub(group.started) >= group_commit.max_interval;
+        if !due {
+            if group.records > 0 {
+                self.group = Some(group);
+                self.flushed_state = FlushedState {
+                    changes: self.tx.changes.clone(),
+                    salt: self.salt,
+                    writes_since_resize: self.writes_since_resize,
+                    del_balance: self.del_balance,
+                    value_arenas: self.value_arenas.clone(),
+                    mappings: Some(Arc::new(FlushedMappings::new(
+                        &self.ht_mapping,
+                        &self.values_mapping,
+                        &self.delmap_mapping,
+                    ))),
+                    mutations: self.pending_mutations.len(),
+                };
+            }
+            return Ok(());
+        }
+        if group.records > 0 {
+            wal.seek(SeekFrom::Start(group.log_len))?;
+            self.tx.bytes_written += TableTransaction::write_commit_marker(
+                wal,
+                self.tx.retry,
+                group.records,
+                group.checksums,
+            )?;
+            self.tx.sync(wal, sync)?;
+        }
+        self.flush_changes_with(flush)?;
+        self.tx.sync(&*self.file, sync)?;
+        self.reset_log(wal, sync)
+    }
+
+    fn commit_through(
+        &mut self,
+        wal: &mut File,
+        sync: Durability,
+        flush: Durability,
+    ) -> Result<(), StoreError> {
+        self.log_changes(wal, sync)?;
+        self.flush_changes_with(flush)?;
+        self.tx.sync(&*self.file, sync)?;
+        self.reset_log(wal, sync)
+    }
+
+    /// Writes the changes to the `wal` in place of the empty log it was reset to, and syncs it as
+    /// `sync` requires. A torn write leaves a log whose checksum doesn't match.
+    fn log_changes(&mut self, wal: &mut File, sync: Durability) -> Result<(), StoreError> {
+        wal.seek(SeekFrom::Start(0))?;
+        self.tx.write_to_log(wal)?;
+        self.tx.sync(wal, sync)
+    }
+
+    /// Writes a point-in-time copy of the database to `dest`, which can be opened with the same
+    /// salt. The pending changes are flushed first, and the table can't change while it's being
+    /// copied since it's borrowed. Only the bytes the file size in the header accounts for are
+    /// copied, so any slack past them is left out. The copy is synced to the disk before
+    /// returning, regardless of `Options::durability`.
+    pub fn snapshot_to(&mut self, dest: &Path) -> Result<(), StoreError> {
+        if !self.read_only {
+            self.flush_changes()?;
+        }
+        let file_size = self.tx.get_num(&*self.file, 0)?;
+
+        let file_len = self.file.len()?;
+        if file_len < file_size {
+            return Err(StoreError::Corruption {
+                offset: file_len,
+                reason: "the file is shorter than the file size in the header",
+            });
+        }
+
+        let mut dest_file = File::create(dest)?;
+        let mut buf = vec![0; SNAPSHOT_COPY_BYTES as usize];
+        let mut copied = 0;
+        while copied < file_size {
+            let len = (file_size - copied).min(buf.len() as u64) as usize;
+            self.file
+                .read_at(&mut buf[..len], copied)
+                .map_err(StoreError::at(copied))?;
+            dest_file.write_all(&buf[..len])?;
+            copied += len as u64;
+        }
+        dest_file.sync_all()?;
+        Ok(())
+    }
+
+    /// Returns the free sectors at the end of the file to the file system by truncating it. Only
+    /// the sectors on the free list that no mapping refers to are truncated. The pending changes
+    /// are flushed first, and the shorter file size is flushed before the file is truncated, so
+    /// that the header never claims more sectors than the file has.
+    pub fn shrink_to_fit(&mut self) -> Result<(), StoreError> {
+        self.flush_changes()?;
+        let sector_size = self.options.sector_size;
+
+        let mut free_sectors = vec![];
+        let mut free_offset = self.tx.get_num(&*self.file, FREE_LIST_OFFSET)?;
+        while free_offset != 0 {
+            free_sectors.push(free_offset);
+            free_offset = self.tx.get_num(&*self.file, free_offset + 56)?;
+        }
+
+        let is_referenced = |sector: u64| {
+            self.ht_mapping.values().any(|&offset| offset == sector)
+                || self
+                    .values_mapping
+                    .values()
+                    .any(|&offset| offset - VALUE_SIZE == sector)
+                || self
+                    .delmap_mapping
+                    .values()
+                    .any(|&offset| offset - FIRST_SLOT_OFFSET == sector)
+        };
+        let file_size = self.tx.get_num(&*self.file, 0)?;
+        let mut new_file_size = file_size;
+        while new_file_size > FIRST_SECTOR_OFFSET + sector_size {
+            let last_sector = new_file_size - sector_size;
+            if !free_sectors.contains(&last_sector) || is_referenced(last_sector) {
+                break;
+            }
+            new_file_size = last_sector;
+        }
+        if new_file_size == file_size {
+            return Ok(());
+        }
+
+        // Relink the free list without the truncated sectors, keeping the order of the rest
+        let mut next = 0u64;
+        for &sector in free_sectors.iter().rev() {
+            if sector < new_file_size {
+                self.tx.set(sector + 56, next.to_le_bytes().to_vec());
+                next = sector;
+            }
+        }
+        self.tx.set(FREE_LIST_OFFSET, next.to_le_bytes().to_vec());
+        self.tx.set(0, new_file_size.to_le_bytes().to_vec());
+        self.flush_changes()?;
+
+        #[cfg(feature = "mmap")]
+        {
+            self.tx.mmap = None;
+        }
+        self.file
+            .set_len(new_file_size)
+            .map_err(StoreError::at(new_file_size))?;
+        self.tx.cache.remove_range(new_file_size, file_size);
+        #[cfg(feature = "mmap")]
+        self.tx.remap(&*self.file, &self.options)?;
+        Ok(())
+    }
+
+    /// Reads all the hash table sectors, and with `delmap` the delmap sectors too, in large
+    /// sequential reads, so that the lookups that follow don't read them from a cold disk one page
+    /// at a time. The pages are kept in the page cache as far as `Options::page_cache_bytes`
+    /// allows, and otherwise only end up in the cache of the OS. Returns the number of bytes read.
+    pub fn warmup(&mut self, delmap: bool) -> Result<u64, StoreError> {
+        let sector_size = self.options.sector_size;
+        let mut sectors = self.ht_mapping.values().copied().collect::<Vec<_>>();
+        if delmap {
+            // The delmap sectors are mapped by the offset of their first entry
+            sectors.extend(
+                self.delmap_mapping
+                    .values()
+                    .map(|&offset| offset - FIRST_SLOT_OFFSET),
+            );
+        }
+        sectors.sort_unstable();
+
+        let mut bytes = 0;
+        let mut buf = vec![];
+        let mut i = 0;
+        while i < sectors.len() {
+            // The adjacent sectors are read together
+            let start = sectors[i];
+            let mut end = start + sector_size;
+            i += 1;
+            while i < sectors.len() && sectors[i] == end && end - start < WARMUP_READ_BYTES {
+                end += sector_size;
+                i += 1;
+            }
+
+            buf.resize((end - start) as usize, 0);
+            self.file
+                .read_at(&mut buf, start)
+                .map_err(StoreError::at(start))?;
+            for (j, page) in buf.chunks_exact(PAGE_SIZE as usize).enumerate() {
+                self.tx.cache.insert(start + j as u64 * PAGE_SIZE, page);
+            }
+            bytes += end - start;
+        }
+        Ok(bytes)
+    }
+
+    /// Grows the file by `n` sectors at once and puts them on the free list, so that the sectors
+    /// allocated afterwards are taken from there instead of growing the file one sector at a
+    /// time. The new sectors are holes in the file until they are used. Same as with the other
+    /// changes, the new file size and the free list are written on the next flush, and the
+    /// sectors of a file that is longer than its header says are reclaimed on the next growth.
+    pub fn reserve_sectors(&mut self, n: u64) -> Result<(), StoreError> {
+        self.check_writable()?;
+        let sector_size = self.options.sector_size;
+        let file_size = self.tx.get_num(&*self.file, 0)?;
+        let new_file_size = file_size + n * sector_size;
+
+        #[cfg(feature = "mmap")]
+        {
+            self.tx.mmap = None;
+        }
+        self.file
+            .set_len(new_file_size)
+            .map_err(StoreError::at(file_size))?;
+        self.tx.cache.remove_range(file_size, new_file_size);
+        #[cfg(feature = "mmap")]
+        self.tx.remap(&*self.file, &self.options)?;
+        self.tx.set(0, new_file_size.to_le_bytes().to_vec());
+
+        // Pushed in the reverse order, so that they're allocated in the order of the offsets
+        let mut offset = new_file_size;
+        while offset > file_size {
+            offset -= sector_size;
+            self.free_sector(offset)?;
+        }
+        Ok(())
+    }
+
+    /// Returns a receiver of all the `set`s, `delete`s and `clear`s, sent in order once
+    /// `flush_changes` writes them. Deleting a key that isn't present is not reported. The values
+    /// written through the value arenas are not reported either.
+    ///
+    /// The writer never blocks on a subscriber: the channel holds up to
+    /// `SUBSCRIBER_CHANNEL_CAPACITY` mutations, and a subscriber that falls behind by more than
+    /// that is disconnected, so its receiver sees the end of the stream rather than a stream with
+    /// a gap in it.
+    pub fn subscribe(&mut self) -> Receiver<Mutation> {
+        let (sender, receiver) = std::sync::mpsc::sync_channel(SUBSCRIBER_CHANNEL_CAPACITY);
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Moves the database to `new_path` and reopens it there. All the pending changes are flushed
+    /// and the file is closed before it's moved, so no other handle may be using it. The file is
+    /// renamed, or, when `new_path` is on a different device, copied, synced and only then
+    /// removed, so that a crash in between leaves a whole file at one of the paths at least. A
+    /// database in memory is written to a file at `new_path` instead.
+    ///
+    /// The log of a table opened with `new_with_wal` is reset once the changes are flushed, since
+    /// replaying it against the new location would be wrong, and the table is reopened with the
+    /// same `WalConfig`. The logs passed to `write_to_log` and `checkpoint` aren't known to the
+    /// table, so they're obsolete after the move, and must be truncated by the caller.
+    pub fn move_to(mut self, new_path: PathBuf) -> Result<HashTable, StoreError> {
+        self.flush_changes()?;
+        let mut wal = self.wal.take();
+        if let Some((wal_file, config)) = &mut wal {
+            self.reset_log(wal_file, config.sync)?;
+        }
+        let in_memory = self.file.as_file().is_none();
+        if in_memory {
+            self.snapshot_to(&new_path)?;
+        }
+
+        let HashTable {
+            salt,
+            options,
+            file,
+            file_name,
+            subscribers,
+            ..
+        } = self;
+        drop(file);
+
+        if !in_memory {
+            if let Err(error) = std::fs::rename(&file_name, &new_path) {
+                if error.kind() != io::ErrorKind::CrossesDevices {
+                    return Err(error.into());
+                }
+                std::fs::copy(&file_name, &new_path)?;
+                File::open(&new_path)?.sync_all()?;
+                std::fs::remove_file(&file_name)?;
+            }
+        }
+
+        let mut table = match wal {
+            Some((_, wal)) => HashTable::new_with_wal(new_path, salt, wal, options)?,
+            None => HashTable::new_with_options(new_path, salt, None, options)?,
+        };
+        table.subscribers = subscribers;
+        Ok(table)
+    }
+
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), StoreError> {
+        self.set_with_expiry(key, value, None)
+    }
+
+    /// Same as `set`, but returns the previous value of the key if it was present, like
+    /// `HashMap::insert`. The previous value is read before its record is overwritten or deleted,
+    /// and an expired one is not returned.
+    pub fn replace(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>, StoreError> {
+        self.check_writable()?;
+        let hash = self.get_hash(&key);
+        let (_, slot_value) = self.seek(hash)?;
+        let old_value = self
+            .read_slot_value(&key, hash, slot_value)?
+            .map(|(_, value)| value);
+        self.set_hashed(hash, key, value, None, None)?;
+        Ok(old_value)
+    }
+
+    /// Appends `suffix` to the value of the key, or sets the key to `suffix` if it's absent. The
+    /// value keeps its expiry, and gets a new sequence number with `Options::store_seq`, like with
+    /// `set`. The whole record is read, since its checksum covers all of it, but if the longer
+    /// record still fits in the slots of the current one, i.e. the suffix fits in the padding of
+    /// its last slot, only the slots that change are written: the first one, which holds the
+    /// length, the ones the suffix lands in, and the last one, which holds the checksum.
+    /// Otherwise, and with `Options::transforms`, which encode the value as a whole, the value is
+    /// set anew.
+    pub fn append(&mut self, key: Vec<u8>, suffix: &[u8]) -> Result<(), StoreError> {
+        self.check_writable()?;
+        let hash = self.get_hash(&key);
+        let (_, slot_value) = self.seek(hash)?;
+        let (old_header, mut value) = match self.read_slot_value(&key, hash, slot_value)? {
+            Some((header, value)) => (Some(header), value),
+            None => (None, vec![]),
+        };
+        value.extend_from_slice(suffix);
+        let expires_at = old_header.as_ref().and_then(|header| header.expires_at);
+        let old_header = match old_header {
+            Some(header)
+                if self.options.transforms.is_empty() && !self.layout.is_inline(slot_value) =>
+            {
+                header
+            }
+            _ => return self.set_hashed(hash, key, value, expires_at, None),
+        };
+
+        check_value_size(&value)?;
+        let mutation = (!self.subscribers.is_empty()).then(|| Mutation::Set {
+            key: key.clone(),
+            value: value.clone(),
+        });
+        let seq = self.reserve_seqs(1)?;
+        let (header, body) = self.encode_entry(hash, key, value, seq, expires_at, None)?;
+        // The record written with other options can have its payload elsewhere
+        if header.num_slots() == old_header.num_slots()
+            && header.payload_offset() == old_header.payload_offset()
+        {
+            let offset = slot_value - 1;
+            let last_slot = header.num_slots() - 1;
+            let appended = old_header.len / VALUE_SIZE..=(header.len - 1) / VALUE_SIZE;
+            let record = Self::encode_record(&header, body);
+            for (i, slot) in record.chunks_exact(VALUE_SIZE as usize).enumerate() {
+                let i = i as u64;
+                if i == 0 || appended.contains(&i) || (header.checksum && i == last_slot) {
+                    self.set_value(offset + i * VALUE_SIZE, slot.try_into().unwrap())?;
+                }
+            }
+        } else {
+            let slot_value = self.write_slot_value(&header, body)?;
+            if let Some(old_value) = self.ht_set_with_hash(hash, slot_value)? {
+                self.delete_slot_value(old_value)?;
+            }
+        }
+        self.metrics.add_sets(1);
+        self.pending_mutations.extend(mutation);
+        Ok(())
+    }
+
+    /// Same as `set`, but the value expires at the Unix time `expires_at_unix_secs`, as told by
+    /// `Options::clock`. Once expired, the key is deleted by the first `get` or `get_many` that
+    /// reads it, and is reported as absent by them from then on. `contains_key` and the iterators
+    /// don't read the expiry, so they still report the expired keys that weren't read yet.
+    pub fn set_with_ttl(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at_unix_secs: u64,
+    ) -> Result<(), StoreError> {
+        self.set_with_expiry(key, value, Some(expires_at_unix_secs))
+    }
+
+    fn set_with_expiry(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    ) -> Result<(), StoreError> {
+        let hash = self.get_hash(&key);
+        self.set_hashed(hash, key, value, expires_at, None)
+    }
+
+    /// Same as `set_with_expiry`, given the hash of the key, which belongs to the `column` if
+    /// there is one, see `column`.
+    pub(crate) fn set_hashed(
+        &mut self,
+        hash: [u8; HASH_LEN],
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+        column: Option<u32>,
+    ) -> Result<(), StoreError> {
+        self.check_writable()?;
+        check_value_size(&value)?;
+        self.metrics.add_sets(1);
+        let mutation = (!self.subscribers.is_empty()).then(|| Mutation::Set {
+            key: key.clone(),
+            value: value.clone(),
+        });
+        let (_, old_offset) = self.seek_for_set(hash)?;
+        let seq = self.reserve_seqs(1)?;
+        let (header, body) = self.encode_entry(hash, key, value, seq, expires_at, column)?;
+
+        if !self.update_in_place(old_offset, &header, &body)? {
+            let slot_value = self.write_slot_value(&header, body)?;
+            if let Some(old_value) = self.ht_set_with_hash(hash, slot_value)? {
+                self.delete_slot_value(old_value)?;
+            }
+        }
+        self.pending_mutations.extend(mutation);
+        Ok(())
+    }
+
+    /// Overwrites the record of the key with the `header` hash, given the value `old_offset` of its
+    /// hash table slot, if it's present and spans as many value slots as the new record, and
+    /// returns whether it did. The record keeps its slots, so nothing is allocated in the values
+    /// region, deleted or compacted, and the hash table is left as it is.
+    fn update_in_place(
+        &mut self,
+        old_offset: u64,
+        header: &ValueHeader,
+        body: &[u8],
+    ) -> Result<bool, StoreError> {
+        if old_offset == NO_VALUE
+            || self.layout.is_inline(old_offset)
+            || self.inline_fits(header, body)
+        {
+            return Ok(false);
+        }
+        let offset = old_offset - 1;
+        let old_header = ValueHeader::parse(&self.get_value(offset)?);
+        if old_header.num_slots() != header.num_slots() {
+            return Ok(false);
+        }
+        let full_value = Self::encode_record(header, body.to_vec());
+        for (i, slot) in full_value.chunks_exact(VALUE_SIZE as usize).enumerate() {
+            self.set_value(offset + i as u64 * VALUE_SIZE, slot.try_into().unwrap())?;
+        }
+        Ok(true)
+    }
+
+    pub fn set_or_panic(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.set(key, value).expect(IO_ERROR)
+    }
+
+    /// Sets all the `pairs`, with the same result as calling `set` for each of them in order,
+    /// down to the bytes of the file: the replaced values are deleted and compacted after every
+    /// pair, as `set` does. The keys are hashed once, up front, and the pages of their home slots
+    /// are read in the order of the hashes, so that the pages of each sector are fetched together
+    /// and, with `Options::page_cache_bytes`, the sets find them cached. A failure leaves the
+    /// pairs before it set.
+    pub fn set_batch(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<(), StoreError> {
+        self.check_writable()?;
+        for (_, value) in pairs.iter() {
+            check_value_size(value)?;
+        }
+        let hashes = pairs
+            .iter()
+            .map(|(key, _)| self.get_hash(key))
+            .collect::<Vec<_>>();
+        let mut sorted = hashes.clone();
+        sorted.sort_unstable();
+        for hash in sorted {
+            // unwrap here is safe, because the ht_mapping always contains 0x0
+            let sector_offset = *self.ht_mapping.range(..=hash).next_back().unwrap().1;
+            self.read_ht_slot(self.layout.slot_offset(sector_offset, self.get_slot(&hash)))?;
+        }
+        for ((key, value), hash) in pairs.into_iter().zip(hashes) {
+            self.set_hashed(hash, key, value, None, None)?;
+        }
+        Ok(())
+    }
+
+    /// Reserves `count` consecutive sequence numbers and returns the first one, if
+    /// `Options::store_seq` is set.
+    fn reserve_seqs(&mut self, count: u64) -> Result<Option<u64>, StoreError> {
+        if !self.options.store_seq {
+            return Ok(None);
+        }
+        let last_seq = self.tx.get_num(&*self.file, LAST_SEQ_OFFSET)?;
+        self.tx
+            .set(LAST_SEQ_OFFSET, (last_seq + count).to_le_bytes().to_vec());
+        Ok(Some(last_seq + 1))
+    }
+
+    /// Transforms the value and writes the record of the key with the `hash`, without touching the
+    /// hash table. Returns the value of its hash table slot, see `write_slot_value`.
+    fn write_entry(
+        &mut self,
+        hash: [u8; HASH_LEN],
+        key: Vec<u8>,
+        value: Vec<u8>,
+        seq: Option<u64>,
+        expires_at: Option<u64>,
+    ) -> Result<u64, StoreError> {
+        let (header, body) = self.encode_entry(hash, key, value, seq, expires_at, None)?;
+        self.write_slot_value(&header, body)
+    }
+
+    /// Whether the record with the `header` and the `body` is stored inline, see
+    /// `Options::inline_values`.
+    fn inline_fits(&self, header: &ValueHeader, body: &[u8]) -> bool {
+        self.layout.inline_values
+            && header.seq.is_none()
+            && header.expires_at.is_none()
+            && header.key_len.is_none()
+            && body.len() <= self.layout.inline_capacity()
+    }
+
+    /// Returns the value of the hash table slot that stores the record: the value itself if it's
+    /// stored inline, otherwise the logical offset of the record, written by `write_record`, plus
+    /// one.
+    fn write_slot_value(&mut self, header: &ValueHeader, body: Vec<u8>) -> Result<u64, StoreError> {
+        if self.inline_fits(header, &body) {
+            return Ok(self.layout.encode_inline(&body));
+        }
+        Ok(self.write_record(header, body)? + 1)
+    }
+
+    /// Passes the value through the transforms, and returns the header of its record and the
+    /// body that follows the header, see `write_record`. The `column` is only stored together
+    /// with the key.
+    fn encode_entry(
+        &self,
+        hash: [u8; HASH_LEN],
+        key: Vec<u8>,
+        mut value: Vec<u8>,
+        seq: Option<u64>,
+        expires_at: Option<u64>,
+        column: Option<u32>,
+    ) -> Result<(ValueHeader, Vec<u8>), StoreError> {
+        for transform in self.options.transforms.iter() {
+            value = transform.encode(value)?;
+        }
+
+        let mut header = ValueHeader::new(hash, value.len());
+        if let Some(seq) = seq {
+            header = header.with_seq(seq);
+        }
+        if let Some(expires_at) = expires_at {
+            header = header.with_expiry(expires_at);
+        }
+        let body = if self.options.store_keys {
+            header = header.with_key_len(key.len() as u32);
+            if let Some(column) = column {
+                header = header.with_column(column);
+            }
+            [key, value].concat()
+        } else {
+            value
+        };
+        Ok((header, body))
+    }
+
+    /// Sets the value of the key to `new` if its current value is `expected`, where `None` means
+    /// the key is absent. Returns whether the value was set. Since all the changes belong to the
+    /// same transaction until they're flushed, the check and the write are committed together.
+    pub fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Vec<u8>,
+    ) -> Result<bool, StoreError> {
+        if self.get(key.clone())? != expected {
+            return Ok(false);
+        }
+        self.set(key, new)?;
+        Ok(true)
+    }
+
+    /// Writes the record into consecutive value slots, and returns the logical offset of its
+    /// first slot. The `body` is the part of the record that follows the header, i.e. the key
+    /// (if the header says it's stored) followed by the payload.
+    fn write_record(&mut self, header: &ValueHeader, body: Vec<u8>) -> Result<u64, StoreError> {
+        let full_value = Self::encode_record(header, body);
+        let full_value_len_rounded_up = full_value.len() as u64;
+
+        let offset = self.write_value(full_value[0..128].try_into().unwrap())?;
+        self.debit_write();
+        for i in 1..full_value_len_rounded_up / VALUE_SIZE {
+            let _ = self.write_value(
+                full_value[(i * 128) as usize..(i * 128 + 128) as usize]
+                    .try_into()
+                    .unwrap(),
+            )?;
+            self.debit_write();
+        }
+        Ok(offset)
+    }
+
+    /// Encodes the record and pads it to a whole number of value slots, see `write_record`.
+    fn encode_record(header: &ValueHeader, body: Vec<u8>) -> Vec<u8> {
+        let full_value_len_rounded_up = header.num_slots() * VALUE_SIZE;
+        let mut full_value = [
+            header.encode(),
+            body,
+            vec![0; (full_value_len_rounded_up - header.len) as usize],
+        ]
+        .concat();
+        assert_eq!(full_value.len() as u64, full_value_len_rounded_up);
+        if header.checksum {
+            let checksum = header.compute_checksum(&full_value);
+            let checksum_offset = full_value.len() - VALUE_CHECKSUM_LEN;
+            full_value[checksum_offset..].copy_from_slice(&checksum);
+        }
+        full_value
+    }
+
+    /// Reads the record that starts at the logical `offset`. Fails with `StoreError::Corruption`
+    /// if its checksum doesn't match.
+    fn read_record(&mut self, offset: u64) -> Result<Record, StoreError> {
+        let mut values = vec![self.get_value(offset)?];
+        let header = ValueHeader::parse(&values[0]);
+        for i in 1..header.num_slots() {
+            values.push(self.get_value(offset + i * VALUE_SIZE)?);
+        }
+
+        match Record::parse(&values.concat()) {
+            Some(record) => Ok(record),
+            None => Err(StoreError::Corruption {
+                offset: self.value_physical_offset(offset)?,
+                reason: VALUE_CHECKSUM_MISMATCH,
+            }),
+        }
+    }
+
+    /// Same as `read_record`, but appends the payload of the record to `out` rather than
+    /// allocating it, and returns the header. The value slots are read one at a time into the same
+    /// buffer.
+    fn read_payload_into(
+        &mut self,
+        offset: u64,
+        out: &mut Vec<u8>,
+    ) -> Result<ValueHeader, StoreError> {
+        let mut slot = self.get_value(offset)?;
+        let header = ValueHeader::parse(&slot);
+        let len = header.len as usize;
+        let payload_offset = header.payload_offset();
+        let mut hasher = blake3::Hasher::new();
+        for i in 0..header.num_slots() {
+            if i > 0 {
+                slot = self.get_value(offset + i * VALUE_SIZE)?;
+            }
+            let start = (i * VALUE_SIZE) as usize;
+            let end = start + VALUE_SIZE as usize;
+            hasher.update(&slot[..len.clamp(start, end) - start]);
+            if payload_offset.max(start) < len.min(end) {
+                out.extend_from_slice(
+                    &slot[payload_offset.max(start) - start..len.min(end) - start],
+                );
+            }
+        }
+        // The checksum is at the end of the last slot
+        if header.checksum
+            && slot[VALUE_SIZE as usize - VALUE_CHECKSUM_LEN..]
+                != hasher.finalize().as_bytes()[..VALUE_CHECKSUM_LEN]
+        {
+            return Err(StoreError::Corruption {
+                offset: self.value_physical_offset(offset)?,
+                reason: VALUE_CHECKSUM_MISMATCH,
+            });
+        }
+        Ok(header)
+    }
+
+    /// Removes all the entries. Every sector except for the first hash table sector goes to the
+    /// free list, so that refilling the database reuses them rather than growing the file. The
+    /// sequence numbers are not reset, so they stay unique across the clears.
+    pub fn clear(&mut self) -> Result<(), StoreError> {
+        self.check_writable()?;
+        let ht_sectors = self.ht_mapping.values().copied().collect::<Vec<_>>();
+        for sector_offset in ht_sectors {
+            if sector_offset != FIRST_SECTOR_OFFSET {
+                self.free_sector(sector_offset)?;
+            }
+        }
+        let values_sectors = self.values_mapping.values().copied().collect::<Vec<_>>();
+        for sector_physical_offset in values_sectors {
+            self.free_sector(sector_physical_offset - VALUE_SIZE)?;
+        }
+        let delmap_sectors = self.delmap_mapping.values().copied().collect::<Vec<_>>();
+        for sector_physical_offset in delmap_sectors {
+            self.free_sector(sector_physical_offset - FIRST_SLOT_OFFSET)?;
+        }
+
+        self.init_sector(
+            FIRST_SECTOR_OFFSET,
+            vec![
+                vec![0u8; HASH_LEN],
+                vec![0u8; 8 + 8 + 6],
+                PAGE_TYPE_HT.to_le_bytes().to_vec(),
+                vec![0u8; 8],
+            ],
+            FIRST_SLOT_OFFSET,
+            self.layout.slot_size(),
+            false,
+        );
+        self.ht_mapping.clear();
+        self.ht_mapping.insert([0; HASH_LEN], FIRST_SECTOR_OFFSET);
+        self.values_mapping.clear();
+        self.delmap_mapping.clear();
+        if let Some(bloom) = &mut self.bloom {
+            bloom.clear();
+        }
+
+        for (offset, value) in [
+            (NEXT_VALUE_LOGICAL_OFFSET, 0),
+            (FIRST_VALUE_LOGICAL_OFFSET, 0),
+            (NEXT_VALUE_PHYSICAL_OFFSET, FIRST_SECTOR_OFFSET),
+            (NEXT_DELMAP_PHYSICAL_OFFSET, FIRST_SECTOR_OFFSET),
+            (LEN_OFFSET, 0),
+        ] {
+            self.tx.set(offset, u64::to_le_bytes(value).to_vec());
+        }
+        self.del_balance = 0;
+        self.writes_since_resize = 0;
+        if !self.subscribers.is_empty() {
+            self.pending_mutations.push(Mutation::Clear);
+        }
+        Ok(())
+    }
+
+    /// Changes the salt the keys are hashed with, rewriting all the records and the hash table.
+    /// Requires all the values to have been written with `Options::store_keys`, and fails with
+    /// `StoreError::KeyNotStored` before changing anything otherwise. The records keep their
+    /// sequence numbers and columns, and are all held in memory while they're rewritten. Two
+    /// records of the same key fail with `StoreError::Corruption`.
+    ///
+    /// Everything is rewritten within the current transaction: until `flush_changes`, the file
+    /// still holds the table hashed with the old salt, which is what a crash leaves behind, and
+    /// `rollback` restores the old salt. Once flushed, the table must be reopened with `new_salt`.
+    pub fn rehash(&mut self, new_salt: [u8; 32]) -> Result<(), StoreError> {
+        self.check_writable()?;
+        let mut records = vec![];
+        for record in Records::new(self)? {
+            let (
+                offset,
+                Record {
+                    header,
+                    key,
+                    payload,
+                },
+            ) = record?;
+            let key = key.ok_or(StoreError::KeyNotStored { offset })?;
+            records.push((offset, header, key, payload));
+        }
+
+        // The content doesn't change, so the subscribers aren't told about the clear
+        let pending_mutations = std::mem::take(&mut self.pending_mutations);
+        self.clear()?;
+        self.pending_mutations = pending_mutations;
+
+        self.salt = new_salt;
+        self.tx.set(
+            SALT_FINGERPRINT_OFFSET,
+            salt_fingerprint(&new_salt).to_le_bytes().to_vec(),
+        );
+        for (old_offset, header, key, payload) in records {
+            let hash = self.hash_in(header.column, &key);
+            let header = ValueHeader {
+                hash,
+                checksum: true,
+                ..header
+            };
+            let offset = self.write_record(&header, [key, payload].concat())?;
+            if self.ht_set_with_hash(hash, offset + 1)?.is_some() {
+                return Err(StoreError::Corruption {
+                    offset: old_offset,
+                    reason: DUPLICATE_KEY,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the live records of `other` into this table, and returns how many were set. The keys
+    /// present in both tables are resolved as `conflict` says. Requires all the values of `other`
+    /// to have been written with `Options::store_keys`, and fails with `StoreError::KeyNotStored`
+    /// otherwise. The keys are hashed anew with the salt of this table, in the same columns as in
+    /// `other`, see `column`, and the values are decoded with the transforms of `other` and encoded
+    /// with the ones of this table. The expiry times are kept, and the records that already expired
+    /// are skipped.
+    ///
+    /// The changes are committed with `commit` every `MERGE_COMMIT_INTERVAL` records and at the
+    /// end, so a failure leaves the records set up to the last commit in place.
+    pub fn merge_from(
+        &mut self,
+        other: &mut HashTable,
+        conflict: MergeConflict,
+    ) -> Result<u64, StoreError> {
+        self.check_writable()?;
+        let now = other.options.clock.now_unix_secs();
+        let mut records = Records::new(other)?;
+        let mut merged = 0;
+        while let Some((offset, record)) = records.next_record()? {
+            let expires_at = record.header.expires_at;
+            if expires_at.is_some_and(|expires_at| expires_at <= now) {
+                continue;
+            }
+            let key = record.key.ok_or(StoreError::KeyNotStored { offset })?;
+            let column = record.header.column;
+            let hash = self.hash_in(column, &key);
+            if conflict == MergeConflict::KeepExisting && self.get_hashed(hash, &key)?.is_some() {
+                continue;
+            }
+            let value = records.table.decode_value(record.payload)?;
+            self.set_hashed(hash, key, value, expires_at, column)?;
+            merged += 1;
+            if merged % MERGE_COMMIT_INTERVAL == 0 {
+                self.commit()?;
+            }
+        }
+        self.commit()?;
+        Ok(merged)
+    }
+
+    /// The number of occupied slots in each of the hash table sectors, in the order of their
+    /// hashes.
+    pub fn sector_occupancies(&mut self) -> Result<Vec<u64>, StoreError> {
+        Ok(self
+            .ht_sector_occupancy()?
+            .into_iter()
+            .map(|(_, occupancy, _)| occupancy)
+            .collect())
+    }
+
+    /// The lowest hash, the number of occupied slots and the number of slots of each of the hash
+    /// table sectors, in the order of their hashes. A sector resizes once its occupancy together
+    /// with its tombstones reaches `Options::max_sector_percent` of its slots, or the early
+    /// threshold of them if it's been a while since the last resize.
+    pub fn ht_sector_occupancy(&mut self) -> Result<Vec<([u8; HASH_LEN], u64, u64)>, StoreError> {
+        let sectors = self
+            .ht_mapping
+            .iter()
+            .map(|(&hash, &sector_offset)| (hash, sector_offset))
+            .collect::<Vec<_>>();
+        let capacity = self.slots_in_sector();
+        sectors
+            .into_iter()
+            .map(|(hash, sector_offset)| {
+                let occupancy = self.tx.get_num(&*self.file, sector_offset + 32)?;
+                Ok((hash, occupancy, capacity))
+            })
+            .collect()
+    }
+
+    /// Estimates the number of slots a successful lookup reads at the current load. Each sector
+    /// is an open-addressing table, for which the expected number of probes at load factor `a` is
+    /// `(1 + 1 / (1 - a)) / 2` with linear probing, and `ln(1 / (1 - a)) / a` with double hashing,
+    /// see `Options::probe_strategy`. The tombstones lengthen the probes as much as the entries
+    /// do, so they count towards the load. The estimates of the sectors are averaged weighted by
+    /// their occupancy, since that's how likely a lookup is to land in each of them.
+    pub fn expected_probe_length(&mut self) -> Result<f64, StoreError> {
+        let sectors = self.ht_mapping.values().copied().collect::<Vec<_>>();
+        let slots_in_sector = self.slots_in_sector();
+        let mut total = 0;
+        let mut weighted_sum = 0.0;
+        for sector_offset in sectors {
+            let occ = self.tx.get_num(&*self.file, sector_offset + 32)?;
+            let tombstones = self.tx.get_num(&*self.file, sector_offset + 40)?;
+            if occ == 0 {
+                continue;
+            }
+            let load = (occ + tombstones) as f64 / slots_in_sector as f64;
+            let probes = match self.layout.probe {
+                ProbeStrategy::Linear => (1.0 + 1.0 / (1.0 - load)) / 2.0,
+                ProbeStrategy::DoubleHashing => (1.0 / (1.0 - load)).ln() / load,
+            };
+            total += occ;
+            weighted_sum += occ as f64 * probes;
+        }
+        if total == 0 {
+            return Ok(1.0);
+        }
+        Ok(weighted_sum / total as f64)
+    }
+
+    /// Returns the number of sectors on the free list, which the new sectors are taken from before
+    /// the file is grown. Walks the free list, and fails if it's longer than the file has sectors,
+    /// i.e. if it's cyclic.
+    pub fn free_sector_count(&mut self) -> Result<u64, StoreError> {
+        let file_size = self.tx.get_num(&*self.file, 0)?;
+        let num_sectors = file_size.saturating_sub(FIRST_SECTOR_OFFSET) / self.options.sector_size;
+
+        let mut count = 0;
+        let mut free_offset = self.tx.get_num(&*self.file, FREE_LIST_OFFSET)?;
+        while free_offset != 0 {
+            if count == num_sectors {
+                return Err(StoreError::Corruption {
+                    offset: FREE_LIST_OFFSET,
+                    reason: "the free list is longer than the file has sectors",
+                });
+            }
+            count += 1;
+            free_offset = self.tx.get_num(&*self.file, free_offset + 56)?;
+        }
+        Ok(count)
+    }
+
+    /// Returns the number of bytes written to the database file by the flushes, a page per page
+    /// changed, and to the logs by `write_to_log` and the commits, over the lifetime of the
+    /// database, e.g. to track the wear of an SSD. The count is stored with every flush, so the
+    /// bytes written since the last flush of a table that was dropped without flushing again are
+    /// lost, and the count never decreases.
+    pub fn lifetime_bytes_written(&self) -> u64 {
+        self.tx.bytes_written
+    }
+
+    /// Returns the bounds of the values region and the number of sectors of every type. Counting
+    /// the free sectors walks the free list, see `free_sector_count`.
+    pub fn stats(&mut self) -> Result<Stats, StoreError> {
+        let first_value_logical = self.tx.get_num(&*self.file, FIRST_VALUE_LOGICAL_OFFSET)?;
+        let next_value_logical = self.tx.get_num(&*self.file, NEXT_VALUE_LOGICAL_OFFSET)?;
+        let num_free_sectors = self.free_sector_count()?;
+
+        Ok(Stats {
+            first_value_logical,
+            next_value_logical,
+            live_bytes_estimate: next_value_logical - first_value_logical,
+            num_ht_sectors: self.ht_mapping.len() as u64,
+            num_value_sectors: self.values_mapping.len() as u64,
+            num_delmap_sectors: self.delmap_mapping.len() as u64,
+            num_free_sectors,
+            file_size: self.tx.get_num(&*self.file, 0)?,
+        })
+    }
+
+    /// Reads the counters in the header of the database at `db_path` as of the last flush,
+    /// without opening the database: the file is opened for reading only, and only its header is
+    /// read, rather than every sector as on open. The files created before the number of keys was
+    /// stored have zero in its place until they're opened.
+    pub fn peek_header(db_path: &Path) -> Result<HeaderInfo, StoreError> {
+        let mut header = vec![0u8; FIRST_SECTOR_OFFSET as usize];
+        File::open(db_path)?
+            .read_exact(&mut header)
+            .map_err(StoreError::at(0))?;
+        let num = |offset: u64| {
+            u64::from_le_bytes(
+                header[offset as usize..offset as usize + 8]
+                    .try_into()
+                    .unwrap(),
+            )
+        };
+        if ![0, BYTE_ORDER_MARK].contains(&num(BYTE_ORDER_OFFSET)) {
+            return Err(StoreError::ByteOrderMismatch);
+        }
+        Ok(HeaderInfo {
+            file_size: num(0),
+            next_value_logical: num(NEXT_VALUE_LOGICAL_OFFSET),
+            first_value_logical: num(FIRST_VALUE_LOGICAL_OFFSET),
+            len: num(LEN_OFFSET),
+        })
+    }
+
+    /// Returns an iterator over the sectors of the file in their physical order, from the first
+    /// one up to the file size in the header when the iterator is created, the same way the
+    /// sectors are read on open. Meant for the tools that inspect the layout of the file. Reads
+    /// the changes that are not flushed yet, same as the rest of the table.
+    pub fn sectors(&mut self) -> Result<Sectors<'_>, StoreError> {
+        let end = self.tx.get_num(&*self.file, 0)?;
+        Ok(Sectors {
+            table: self,
+            offset: FIRST_SECTOR_OFFSET,
+            end,
+        })
+    }
+
+    /// The size of the file divided by the total size of the live keys and values, which accounts
+    /// for the padding of the records to whole value slots, the record headers, the hash table
+    /// slots and the unused space in the sectors. The keys are only counted if they're stored, see
+    /// `Options::store_keys`, and the values are counted as stored, after the transforms. Walks all
+    /// the records, and the hash table for the values stored inline. Returns infinity for an empty
+    /// table.
+    pub fn space_amplification(&mut self) -> Result<f64, StoreError> {
+        let mut live_bytes = 0;
+        for entry in Records::new(self)? {
+            let (_, record) = entry?;
+            live_bytes += record.header.key_len.unwrap_or(0) as u64 + record.payload.len() as u64;
+        }
+        let file_size = self.tx.get_num(&*self.file, 0)?;
+        Ok(file_size as f64 / live_bytes as f64)
+    }
+
+    /// Returns a digest of the live contents of the table, for comparing the states of tables,
+    /// e.g. on different nodes. Every live key-value pair is hashed with blake3 on its own, and the
+    /// hashes are added together as 256-bit integers, so the digest doesn't depend on the order of
+    /// the pairs, nor on the layout of the file or the history that led to it. Unlike with XOR,
+    /// two equal hashes don't cancel each other out. The values are hashed as they were
+    /// set, before the transforms, and the expired ones are left out. Includes the pending
+    /// changes. Reads the whole hash table and every record.
+    ///
+    /// The pairs written with `Options::store_keys` are identified by their keys and columns, see
+    /// `column`, and the others by the hashes of their keys, which depend on the salt,
+    /// `Options::hasher` and `Options::hash_len`: the digests of the tables without the keys only
+    /// compare equal if those are the same.
+    pub fn content_digest(&mut self) -> Result<[u8; 32], StoreError> {
+        let now = self.options.clock.now_unix_secs();
+        let sectors = self.ht_mapping.values().copied().collect::<Vec<_>>();
+        let mut digest = [0u8; 32];
+        for sector_offset in sectors {
+            for slot in 0..self.slots_in_sector() {
+                let slot_offset = self.layout.slot_offset(sector_offset, slot);
+                let data = self
+                    .tx
+                    .get(&*self.file, slot_offset, self.layout.slot_size())?;
+                let slot_value = self.layout.extract_value(&data);
+                if slot_value == NO_VALUE || slot_value == TOMBSTONE {
+                    continue;
+                }
+                let (key, column, payload) = match self.layout.inline_value(slot_value) {
+                    Some(payload) => (None, None, payload),
+                    None => {
+                        let record = self.read_record(slot_value - 1)?;
+                        let expires_at = record.header.expires_at;
+                        if expires_at.is_some_and(|expires_at| expires_at <= now) {
+                            continue;
+                        }
+                        (record.key, record.header.column, record.payload)
+                    }
+                };
+                let value = self.decode_value(payload)?;
+
+                // The keys and the hashes are told apart, so that neither can pass for the other
+                let mut hasher = blake3::Hasher::new();
+                match key {
+                    Some(key) => {
+                        match column {
+                            Some(column) => {
+                                hasher.update(&[2]);
+                                hasher.update(&column.to_le_bytes());
+                            }
+                            None => {
+                                hasher.update(&[1]);
+                            }
+                        }
+                        hasher.update(&(key.len() as u64).to_le_bytes());
+                        hasher.update(&key);
+                    }
+                    None => {
+                        hasher.update(&[0]);
+                        hasher.update(&self.layout.extract_hash(&data));
+                    }
+                }
+                hasher.update(&(value.len() as u64).to_le_bytes());
+                hasher.update(&value);
+                add_pair_digest(&mut digest, hasher.finalize().as_bytes());
+            }
+        }
+        Ok(digest)
+    }
+
+    pub fn print_stats(&mut self) -> Result<(), StoreError> {
+        let stats = self.stats()?;
+        println!(
+            "STATS: first: {} last: {} sectors: {} ht, {} values, {} delmap, {} free, file size: {}",
+            stats.first_value_logical,
+            stats.next_value_logical,
+            stats.num_ht_sectors,
+            stats.num_value_sectors,
+            stats.num_delmap_sectors,
+            stats.num_free_sectors,
+            stats.file_size
+        );
+        Ok(())
+    }
+
+    /// The number of new slots occupied since the last sector resize, see
+    /// `Options::resize_spacing_writes`.
+    pub fn writes_since_resize(&self) -> u64 {
+        self.writes_since_resize
+    }
+
+    /// The repairs made when the database was opened with `Options::auto_recover`.
+    pub fn repairs(&self) -> &[String] {
+        &self.repairs
+    }
+
+    /// The compaction credit, which is negative if the compaction has moved more than it was
+    /// credited for. Every value slot deleted, written and moved from the beginning of the values
+    /// region to its end adjusts it by the weights in `Options::compaction_pacing`. The compaction
+    /// runs after every delete, for as long as the credit is positive.
+    ///
+    /// A workload that deletes as many value slots as it writes makes the compaction move
+    /// `k = CompactionPacing::moves_per_write()` slots per slot written. For the values region to
+    /// stop growing, the moves must free a slot per slot written, so at least `1 / k` of the
+    /// slots moved must be deleted ones. This keeps the values region within roughly
+    /// `k / (k - 1)` times the live values, and is why `k` must be larger than 1. Lower `k` makes
+    /// the compaction compete less with the writes, at the cost of a larger file.
+    pub fn pending_compaction(&self) -> i64 {
+        self.del_balance
+    }
+
+    pub fn reset_del_balance(&mut self) {
+        self.del_balance = 0;
+    }
+
+    pub fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(self.get_with_header(key)?.map(|(_, value)| value))
+    }
+
+    pub fn get_or_panic(&mut self, key: Vec<u8>) -> Option<Vec<u8>> {
+        self.get(key).expect(IO_ERROR)
+    }
+
+    /// Same as `get`, but clears `out` and appends the value to it, and returns whether the key is
+    /// present, so that a caller that reads in a loop reuses the capacity of `out` rather than
+    /// getting a new `Vec` for every value. Without transforms, the value slots are read into a
+    /// buffer on the stack and the payload is copied from them to `out` directly, so the value is
+    /// never allocated.
+    pub fn get_into(&mut self, key: Vec<u8>, out: &mut Vec<u8>) -> Result<bool, StoreError> {
+        out.clear();
+        self.metrics.add_gets(1);
+        let hash = self.get_hash(&key);
+        let slot_value = self.seek_present(hash)?;
+        if slot_value == NO_VALUE {
+            return Ok(false);
+        }
+        if !self.options.transforms.is_empty() || self.layout.is_inline(slot_value) {
+            let value = self.read_slot_value(&key, hash, slot_value)?;
+            if let Some((_, value)) = &value {
+                out.extend_from_slice(value);
+            }
+            return Ok(value.is_some());
+        }
+        let offset = slot_value - 1;
+        self.check_not_dangling(hash, offset, None)?;
+        let header = self.read_payload_into(offset, out)?;
+        if self.expire_if_due(&key, &header, offset)? {
+            out.clear();
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Returns the values of all the `keys`, in the same order. The lookups are done in the order
+    /// of the hashes, so that the hash table sectors are visited one at a time. A key that occurs
+    /// several times is only looked up once. The `FIRST_VALUE_LOGICAL_OFFSET` is read once for the
+    /// whole batch, and again only after an expired value is deleted, which may move it.
+    pub fn get_many(&mut self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>, StoreError> {
+        let mut positions = BTreeMap::<_, Vec<usize>>::new();
+        for (i, key) in keys.iter().enumerate() {
+            positions.entry(self.get_hash(key)).or_default().push(i);
+        }
+        self.metrics.add_gets(keys.len() as u64);
+
+        let mut logical_first_offset = self.tx.get_num(&*self.file, FIRST_VALUE_LOGICAL_OFFSET)?;
+        let mut values = vec![None; keys.len()];
+        for (hash, positions) in positions {
+            let slot_value = self.seek_present(hash)?;
+            let value = self
+                .read_slot_value_with(
+                    &keys[positions[0]],
+                    hash,
+                    slot_value,
+                    Some(logical_first_offset),
+                )?
+                .map(|(_, value)| value);
+            if value.is_none() && slot_value != NO_VALUE {
+                logical_first_offset = self.tx.get_num(&*self.file, FIRST_VALUE_LOGICAL_OFFSET)?;
+            }
+            for i in positions {
+                values[i] = value.clone();
+            }
+        }
+        Ok(values)
+    }
+
+    /// Returns whether the key is present. Only the hash table is consulted, so unlike `get` no
+    /// value slots are read regardless of the size of the value.
+    pub fn contains_key(&mut self, key: Vec<u8>) -> Result<bool, StoreError> {
+        let hash = self.get_hash(&key);
+        Ok(self.seek_present(hash)? != NO_VALUE)
+    }
+
+    /// Returns where the key and its value are stored, for debugging. Only reads the first slot of
+    /// the value.
+    pub fn locate(&mut self, key: Vec<u8>) -> Result<Option<Location>, StoreError> {
+        let hash = self.get_hash(&key);
+        let (ht_slot_offset, offset) = self.seek(hash)?;
+        let ht_sector_offset = *self.ht_mapping.range(..=hash).next_back().unwrap().1;
+        if offset == NO_VALUE {
+            return Ok(None);
+        }
+        if self.layout.is_inline(offset) {
+            return Ok(Some(Location {
+                ht_slot_offset,
+                ht_sector_offset,
+                logical_offset: 0,
+                physical_offset: 0,
+                num_slots: 0,
+                delmap_offset: None,
+                inline: true,
+            }));
+        }
+        let logical_offset = offset - 1;
+        let header = ValueHeader::parse(&self.get_value(logical_offset)?);
+
+        Ok(Some(Location {
+            ht_slot_offset,
+            ht_sector_offset,
+            logical_offset,
+            physical_offset: self.value_physical_offset(logical_offset)?,
+            num_slots: header.num_slots(),
+            delmap_offset: self
+                .options
+                .enable_compaction
+                .then(|| self.delmap_offset(logical_offset))
+                .transpose()?,
+            inline: false,
+        }))
+    }
+
+    /// Returns an iterator over the keys, in no particular order. Requires all the values to have
+    /// been written with `Options::store_keys`, yields `StoreError::KeyNotStored` for the ones that
+    /// weren't. The table must not be modified while iterating.
+    pub fn iter_keys(&mut self) -> Result<Keys<'_>, StoreError> {
+        Ok(Keys {
+            records: Records::new(self)?,
+        })
+    }
+
+    /// Returns an iterator over the key-value pairs, in no particular order. Same as with
+    /// `iter_keys`, the values must have been written with `Options::store_keys`. The range of the
+    /// values region to walk is fixed when the iterator is created.
+    pub fn iter(&mut self) -> Result<Iter<'_>, StoreError> {
+        Ok(Iter {
+            records: Records::new(self)?,
+        })
+    }
+
+    /// Returns an iterator over the values together with their logical offsets, in the order of
+    /// the offsets. The walk is the one of `iter`, but doesn't require the keys to be stored. The
+    /// order doesn't depend on the hashes, so two tables with the same history yield the same
+    /// sequence, but it's the current logical order rather than the insertion order: the
+    /// compaction moves the live values from the beginning of the values region to its end,
+    /// which reorders them. The values stored inline in the hash table slots, see
+    /// `Options::inline_values`, aren't in the values region and aren't yielded.
+    pub fn iter_values_by_logical(&mut self) -> Result<ValuesByLogical<'_>, StoreError> {
+        Ok(ValuesByLogical {
+            records: Records::values_region(self)?,
+        })
+    }
+
+    /// Returns an iterator over the key-value pairs whose keys start with `prefix`, in no
+    /// particular order. The hashing doesn't preserve the order of the keys, so this walks all the
+    /// records the same way `iter` does, and has the same requirements.
+    pub fn scan_prefix(&mut self, prefix: &[u8]) -> Result<ScanPrefix<'_>, StoreError> {
+        Ok(ScanPrefix {
+            records: Records::new(self)?,
+            prefix: prefix.to_vec(),
+        })
+    }
+
+    /// Returns the first key-value pairs of a walk like the one of `iter`, as many as fit into
+    /// `max_bytes` counting the lengths of the keys and the values, together with the cursor to
+    /// resume the walk from with `scan_bounded_from`, or `None` once it's done. A page holds at
+    /// least one pair, even one larger than `max_bytes`, so that every page makes progress. Same
+    /// as with `iter`, the values must have been written with `Options::store_keys`, and the
+    /// range of the values region to walk is fixed by the first page. The table must not be
+    /// modified until the scan is done.
+    pub fn scan_bounded(&mut self, max_bytes: usize) -> Result<ScanPage, StoreError> {
+        Self::scan_page(Records::new(self)?, max_bytes)
+    }
+
+    /// Same as `scan_bounded`, but resumes the scan where the `cursor` was returned.
+    pub fn scan_bounded_from(
+        &mut self,
+        cursor: ScanCursor,
+        max_bytes: usize,
+    ) -> Result<ScanPage, StoreError> {
+        Self::scan_page(Records::resume(self, cursor), max_bytes)
+    }
+
+    fn scan_page(mut records: Records<'_>, max_bytes: usize) -> Result<ScanPage, StoreError> {
+        let mut pairs = vec![];
+        let mut bytes = 0;
+        loop {
+            let cursor = records.cursor();
+            let (offset, record) = match records.next_record()? {
+                Some(entry) => entry,
+                None => return Ok((pairs, None)),
+            };
+            let key = record.key.ok_or(StoreError::KeyNotStored { offset })?;
+            let value = records.table.decode_value(record.payload)?;
+            let size = key.len() + value.len();
+            if !pairs.is_empty() && bytes + size > max_bytes {
+                return Ok((pairs, Some(cursor)));
+            }
+            bytes += size;
+            pairs.push((key, value));
+        }
+    }
+
+    /// Returns the value together with the sequence number assigned to it when it was set. Values
+    /// written without `Options::store_seq` have the sequence number 0, which is never assigned.
+    pub fn get_with_seq(&mut self, key: Vec<u8>) -> Result<Option<(Vec<u8>, u64)>, StoreError> {
+        Ok(self
+            .get_with_header(key)?
+            .map(|(header, value)| (value, header.seq.unwrap_or(0))))
+    }
+
+    /// The number of keys stored.
+    pub fn len(&mut self) -> Result<u64, StoreError> {
+        self.tx.get_num(&*self.file, LEN_OFFSET)
+    }
+
+    pub fn is_empty(&mut self) -> Result<bool, StoreError> {
+        Ok(self.len()? == 0)
+    }
+
+    /// The number of the last sequence number assigned, or 0 if none were.
+    pub fn last_seq(&mut self) -> Result<u64, StoreError> {
+        self.tx.get_num(&*self.file, LAST_SEQ_OFFSET)
+    }
+
+    fn get_with_header(
+        &mut self,
+        key: Vec<u8>,
+    ) -> Result<Option<(ValueHeader, Vec<u8>)>, StoreError> {
+        let hash = self.get_hash(&key);
+        self.get_hashed(hash, &key)
+    }
+
+    /// Same as `get_with_header`, given the hash of the key.
+    pub(crate) fn get_hashed(
+        &mut self,
+        hash: [u8; HASH_LEN],
+        key: &[u8],
+    ) -> Result<Option<(ValueHeader, Vec<u8>)>, StoreError> {
+        self.metrics.add_gets(1);
+        let slot_value = self.seek_present(hash)?;
+        self.read_slot_value(key, hash, slot_value)
+    }
+
+    /// Reads the value of the key with the `hash`, given the value of its hash table slot as
+    /// returned by `seek`.
+    fn read_slot_value(
+        &mut self,
+        key: &[u8],
+        hash: [u8; HASH_LEN],
+        slot_value: u64,
+    ) -> Result<Option<(ValueHeader, Vec<u8>)>, StoreError> {
+        self.read_slot_value_with(key, hash, slot_value, None)
+    }
+
+    /// Same as `read_slot_value`, but with the `FIRST_VALUE_LOGICAL_OFFSET` the caller has read,
+    /// if any, see `check_not_dangling`.
+    fn read_slot_value_with(
+        &mut self,
+        key: &[u8],
+        hash: [u8; HASH_LEN],
+        slot_value: u64,
+        logical_first_offset: Option<u64>,
+    ) -> Result<Option<(ValueHeader, Vec<u8>)>, StoreError> {
+        if slot_value == NO_VALUE {
+            return Ok(None);
+        }
+        if let Some(value) = self.layout.inline_value(slot_value) {
+            let header = ValueHeader::new(hash, value.len());
+            return Ok(Some((header, self.decode_value(value)?)));
+        }
+        let offset = slot_value - 1;
+        self.check_not_dangling(hash, offset, logical_first_offset)?;
+
+        let Record {
+            header, payload, ..
+        } = self.read_record(offset)?;
+        if self.expire_if_due(key, &header, offset)? {
+            return Ok(None);
+        }
+        Ok(Some((header, self.decode_value(payload)?)))
+    }
+
+    /// Fails with `StoreError::DanglingValue` if the hash table slot of the `hash` points at a
+    /// logical `offset` the compaction has moved past, or that no values sector maps. The
+    /// `FIRST_VALUE_LOGICAL_OFFSET` is read unless the caller passes it.
+    fn check_not_dangling(
+        &mut self,
+        hash: [u8; HASH_LEN],
+        offset: u64,
+        logical_first_offset: Option<u64>,
+    ) -> Result<(), StoreError> {
+        let logical_first_offset = match logical_first_offset {
+            Some(logical_first_offset) => logical_first_offset,
+            None => self.tx.get_num(&*self.file, FIRST_VALUE_LOGICAL_OFFSET)?,
+        };
+        if offset < logical_first_offset || self.value_physical_offset(offset).is_err() {
+            return Err(StoreError::DanglingValue { hash, offset });
+        }
+        Ok(())
+    }
+
+    /// Returns whether the record of the key at the logical `offset` has expired, and deletes it
+    /// if so, see `set_with_ttl`. The expired records of a read-only table are left in place.
+    fn expire_if_due(
+        &mut self,
+        key: &[u8],
+        header: &ValueHeader,
+        offset: u64,
+    ) -> Result<bool, StoreError> {
+        let now = self.options.clock.now_unix_secs();
+        let expired = header
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= now);
+        if !expired {
+            return Ok(false);
+        }
+        if !self.read_only {
+            self.delete_at_offset(offset)?;
+            self.ht_delete_with_hash(header.hash)?;
+            if !self.subscribers.is_empty() {
+                self.pending_mutations
+                    .push(Mutation::Delete { key: key.to_vec() });
+            }
+        }
+        Ok(true)
+    }
+
+    /// Passes the payload of a record through the transforms, in the reverse order.
+    fn decode_value(&self, mut value: Vec<u8>) -> Result<Vec<u8>, StoreError> {
+        for transform in self.options.transforms.iter().rev() {
+            value = transform.decode(value)?;
+        }
+        Ok(value)
+    }
+
+    /// Deletes the record a hash table slot with the value `slot_value` points to. An inline
+    /// value has no record.
+    fn delete_slot_value(&mut self, slot_value: u64) -> Result<(), StoreError> {
+        if self.layout.is_inline(slot_value) {
+            return Ok(());
+        }
+        self.delete_at_offset(slot_value - 1)
+    }
+
+    fn delete_at_offset(&mut self, offset: u64) -> Result<(), StoreError> {
+        self.mark_record_deleted(offset)?;
+        self.compact_values()
+    }
+
+    /// Marks all the slots of the record at the logical offset as deleted, and credits the
+    /// compaction for them. Does nothing without `Options::enable_compaction`, the record just
+    /// stays in the values region.
+    fn mark_record_deleted(&mut self, offset: u64) -> Result<(), StoreError> {
+        if !self.options.enable_compaction {
+            return Ok(());
+        }
+        let num_slots = ValueHeader::parse(&self.get_value(offset)?).num_slots();
+        for i in 0..num_slots {
+            self.delete_value(offset + i * VALUE_SIZE)?;
+            self.del_balance += self.options.compaction_pacing.delete_credit;
+        }
+        Ok(())
+    }
+
+    /// Debits the compaction credit for a value slot written.
+    fn debit_write(&mut self) {
+        if self.options.enable_compaction {
+            self.del_balance -= self.options.compaction_pacing.write_debit;
+        }
+    }
+
+    /// Moves the values from the beginning of the values region to the frontier while there's
+    /// compaction credit left, see `del_balance`.
+    fn compact_values(&mut self) -> Result<(), StoreError> {
+        while self.del_balance > 0 {
+            let logical_first_offset = self.tx.get_num(&*self.file, FIRST_VALUE_LOGICAL_OFFSET)?;
+            if self
+                .value_arenas
+                .values()
+                .any(|&(start, _)| start <= logical_first_offset)
+            {
+                // The slots of an arena that is not finalized are not written yet
+                self.del_balance = 0;
+                break;
+            }
+            let logical_next_offset = self.tx.get_num(&*self.file, NEXT_VALUE_LOGICAL_OFFSET)?;
+            let first_header = ValueHeader::parse(&self.get_value(logical_first_offset)?);
+
+            if logical_next_offset - logical_first_offset <= first_header.num_slots() * VALUE_SIZE {
+                // There's only one value, don't move it
+                self.del_balance = 0;
+                break;
+            }
+
+            let moved = self.move_first_record(&first_header)?;
+            self.del_balance -= self.options.compaction_pacing.move_debit * moved as i64;
+        }
+        Ok(())
+    }
+
+    /// Moves the record at the beginning of the values region, whose header is `first_header`, to
+    /// the end of the region and points its hash table slot to it, or just drops it from the
+    /// region if it's deleted. Returns the number of value slots moved.
+    fn move_first_record(&mut self, first_header: &ValueHeader) -> Result<u64, StoreError> {
+        let num_slots = first_header.num_slots();
+        if let Some((old_offset, new_offset)) = self.move_one_value()? {
+            let (ht_offset, mut stored_offset) = self.seek(first_header.hash)?;
+            assert_ne!(stored_offset, NO_VALUE);
+            stored_offset -= 1;
+            assert_eq!(old_offset, stored_offset);
+            self.tx.set(
+                ht_offset,
+                self.layout.encode(&first_header.hash, 1 + new_offset),
+            );
+        }
+        for _ in 1..num_slots {
+            self.move_one_value()?;
+        }
+        Ok(num_slots)
+    }
+
+    /// Compacts the whole values region at once, rather than as the deletes pay for it: the
+    /// records are moved from the beginning of the region to its end until the last deleted
+    /// value slot is past the beginning, so that the region only holds live records. Every live
+    /// record in front of the last deleted slot is moved once. The moves are regular changes, so
+    /// they can be made in the middle of a batch of changes, and are written by the next flush.
+    /// Stops early at a value arena that is not finalized, since its slots are not written yet.
+    /// The compaction credit is reset, since nothing is left to compact. Does nothing without
+    /// `Options::enable_compaction`.
+    pub fn compact(&mut self) -> Result<(), StoreError> {
+        self.check_writable()?;
+        if !self.options.enable_compaction {
+            return Ok(());
+        }
+        let logical_first_offset = self.tx.get_num(&*self.file, FIRST_VALUE_LOGICAL_OFFSET)?;
+        let logical_next_offset = self.tx.get_num(&*self.file, NEXT_VALUE_LOGICAL_OFFSET)?;
+        let mut last_deleted = None;
+        for offset in (logical_first_offset..logical_next_offset).step_by(VALUE_SIZE as usize) {
+            if self.is_value_at_offset_deleted(offset)? {
+                last_deleted = Some(offset);
+            }
+        }
+
+        if let Some(last_deleted) = last_deleted {
+            loop {
+                let logical_first_offset =
+                    self.tx.get_num(&*self.file, FIRST_VALUE_LOGICAL_OFFSET)?;
+                let in_arena = self
+                    .value_arenas
+                    .values()
+                    .any(|&(start, _)| start <= logical_first_offset);
+                if logical_first_offset > last_deleted || in_arena {
+                    break;
+                }
+                let first_header = ValueHeader::parse(&self.get_value(logical_first_offset)?);
+                self.move_first_record(&first_header)?;
+            }
+        }
+        self.del_balance = 0;
+        Ok(())
+    }
+
+    /// Returns an iterator that yields every key-value pair together with its column, see `column`,
+    /// and deletes it, in no particular order. Once the iterator is exhausted, the table is
+    /// cleared, so all its sectors are freed. Requires all the values to have been written with
+    /// `Options::store_keys`, and fails with `StoreError::KeyNotStored` before deleting anything
+    /// otherwise. If the iterator is dropped early, the pairs it hasn't yielded stay in the table.
+    pub fn drain(&mut self) -> Result<Drain<'_>, StoreError> {
+        let mut hashes = vec![];
+        for entry in Records::new(self)? {
+            let (offset, record) = entry?;
+            if record.key.is_none() {
+                return Err(StoreError::KeyNotStored { offset });
+            }
+            hashes.push(record.header.hash);
+        }
+        Ok(Drain {
+            table: self,
+            hashes: hashes.into_iter(),
+            cleared: false,
+        })
+    }
+
+    /// Deletes the record of the `hash` if it's present, and returns it.
+    fn take_record(&mut self, hash: [u8; HASH_LEN]) -> Result<Option<Record>, StoreError> {
+        self.check_writable()?;
+        let slot = self.seek_for_delete(hash)?;
+        if slot.1 == NO_VALUE {
+            return Ok(None);
+        }
+        self.metrics.add_delete();
+        let record = match self.layout.inline_value(slot.1) {
+            Some(payload) => Record {
+                header: ValueHeader::new(hash, payload.len()),
+                key: None,
+                payload,
+            },
+            None => self.read_record(slot.1 - 1)?,
+        };
+        self.delete_slot_value(slot.1)?;
+        self.ht_delete_at(slot)?;
+        if !self.subscribers.is_empty() {
+            if let Some(key) = record.key.clone() {
+                self.pending_mutations.push(Mutation::Delete { key });
+            }
+        }
+        Ok(Some(record))
+    }
+
+    /// Same as `get` followed by `delete`, but hashes the key and seeks its slot only once.
+    pub fn get_and_delete(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>, StoreError> {
+        self.check_writable()?;
+        self.metrics.add_gets(1);
+        self.metrics.add_delete();
+        let hash = self.get_hash(&key);
+        let slot = self.seek_for_delete(hash)?;
+        // Reading an expired value deletes it, and then there's nothing left to delete
+        let value = match self.read_slot_value(&key, hash, slot.1)? {
+            Some((_, value)) => value,
+            None => return Ok(None),
+        };
+
+        self.delete_slot_value(slot.1)?;
+        self.ht_delete_at(slot)?;
+        if !self.subscribers.is_empty() {
+            self.pending_mutations.push(Mutation::Delete { key });
+        }
+        Ok(Some(value))
+    }
+
+    pub fn delete(&mut self, key: Vec<u8>) -> Result<(), StoreError> {
+        let hash = self.get_hash(&key);
+        self.delete_hashed(hash, key)
+    }
+
+    pub fn delete_or_panic(&mut self, key: Vec<u8>) {
+        self.delete(key).expect(IO_ERROR)
+    }
+
+    /// Deletes all the `keys`, with the same result as calling `delete` for each of them in order.
+    /// All the records are marked deleted and their hash table slots cleared first, and the
+    /// compaction runs once at the end for the credit of all of them, rather than after every
+    /// delete, so that it doesn't move the records that later deletes of the batch drop anyway.
+    pub fn delete_batch(&mut self, keys: Vec<Vec<u8>>) -> Result<(), StoreError> {
+        self.check_writable()?;
+        for key in keys {
+            self.metrics.add_delete();
+            let hash = self.get_hash(&key);
+            let slot = self.seek_for_delete(hash)?;
+            if slot.1 == NO_VALUE {
+                continue;
+            }
+            if !self.layout.is_inline(slot.1) {
+                self.mark_record_deleted(slot.1 - 1)?;
+            }
+            self.ht_delete_at(slot)?;
+            if !self.subscribers.is_empty() {
+                self.pending_mutations.push(Mutation::Delete { key });
+            }
+        }
+        self.compact_values()
+    }
+
+    /// Same as `delete`, given the hash of the key as `seek` takes it, for the callers that hold
+    /// the hash but not the key anymore. The hash is truncated to `Options::hash_len` first.
+    /// Returns whether the hash was present. The subscribers are only told about the delete if the
+    /// key is stored, see `Options::store_keys`, since it can't be recovered from the hash.
+    pub fn delete_by_hash(&mut self, hash: [u8; HASH_LEN]) -> Result<bool, StoreError> {
+        let hash = self.layout.truncate(hash);
+        Ok(self.take_record(hash)?.is_some())
+    }
+
+    /// Same as `delete`, given the hash of the key.
+    pub(crate) fn delete_hashed(
+        &mut self,
+        hash: [u8; HASH_LEN],
+        key: Vec<u8>,
+    ) -> Result<(), StoreError> {
+        self.check_writable()?;
+        self.metrics.add_delete();
+        let slot = self.seek_for_delete(hash)?;
+
+        if slot.1 != NO_VALUE {
+            self.delete_slot_value(slot.1)?;
+            self.ht_delete_at(slot)?;
+            if !self.subscribers.is_empty() {
+                self.pending_mutations.push(Mutation::Delete { key });
+            }
+        }
+        Ok(())
+    }
+
+    /// Seeks the slot for a particular hash. Returns the offset of the slot, and the value. The
+    /// value of an absent hash is `NO_VALUE`, and its slot is the first tombstone probed past if
+    /// there was one, so that inserting the hash reuses it.
+    pub fn seek(&mut self, hash: [u8; 26]) -> Result<(u64, u64), StoreError> {
+        self.seek_until(hash, None)
+    }
+
+    /// Same as `seek`, but fails with `StoreError::Timeout` once the `deadline` passes, see
+    /// `Options::op_deadline`.
+    fn seek_until(
+        &mut self,
+        hash: [u8; 26],
+        deadline: Option<Duration>,
+    ) -> Result<(u64, u64), StoreError> {
+        let mut slot = self.get_slot(&hash);
+        let step = self.layout.probe_step(&hash, self.slots_in_sector());
+
+        // unwrap here is safe, because the ht_mapping always contains 0x0
+        let sector_offset = *self.ht_mapping.range(..=hash).next_back().unwrap().1;
+        #[cfg(debug_assertions)]
+        {
+            self.check_page_type(sector_offset, PAGE_TYPE_HT)?;
+        }
+
+        let mut first_tombstone = None;
+        let mut page = None;
+        // Every slot is probed at most once, so that a full sector can't make the lookup spin
+        for probes in 1..=self.slots_in_sector() {
+            let offset = self.layout.slot_offset(sector_offset, slot);
+            self.check_deadline(deadline, offset, &mut page)?;
+            let data = self.read_ht_slot(offset)?;
+
+            let value = self.layout.extract_value(&data);
+            if value == TOMBSTONE {
+                first_tombstone.get_or_insert(offset);
+            } else if value == NO_VALUE {
+                self.record_probes(probes);
+                return Ok((first_tombstone.unwrap_or(offset), NO_VALUE));
+            } else if self.layout.extract_hash(&data) == hash {
+                self.record_probes(probes);
+                return Ok((offset, value));
+            }
+
+            slot = (slot + step) % self.slots_in_sector();
+        }
+        self.record_probes(self.slots_in_sector());
+        match first_tombstone {
+            Some(offset) => Ok((offset, NO_VALUE)),
+            None => Err(StoreError::SectorFull {
+                offset: sector_offset,
+            }),
+        }
+    }
+
+    /// Counts a seek that probed `probes` slots towards `probe_stats`, if `Options::probe_stats`
+    /// is set.
+    fn record_probes(&mut self, probes: u64) {
+        if let Some(probe_window) = &mut self.probe_window {
+            probe_window.record(probes);
+        }
+    }
+
+    /// Same as `seek`, but only returns the value, which is `NO_VALUE` without probing for the
+    /// hashes the bloom filter rules out, see `Options::expected_keys`. Meant for the lookups,
+    /// since it doesn't return the slot to insert an absent hash at.
+    fn seek_present(&mut self, hash: [u8; HASH_LEN]) -> Result<u64, StoreError> {
+        if let Some(bloom) = &self.bloom {
+            if !bloom.may_contain(&hash) {
+                return Ok(NO_VALUE);
+            }
+        }
+        Ok(self.seek_until(hash, self.op_deadline())?.1)
+    }
+
+    /// The time by which a scan starting now must be done, see `Options::op_deadline`.
+    fn op_deadline(&self) -> Option<Duration> {
+        self.options
+            .op_deadline
+            .map(|op_deadline| self.options.clock.now() + op_deadline)
+    }
+
+    /// Fails with `StoreError::Timeout` if the `deadline` has passed, checking the clock only
+    /// before the first read of every page: `offset` is the one about to be read, and `page` the
+    /// page of the previous check.
+    fn check_deadline(
+        &self,
+        deadline: Option<Duration>,
+        offset: u64,
+        page: &mut Option<u64>,
+    ) -> Result<(), StoreError> {
+        let deadline = match deadline {
+            Some(deadline) => deadline,
+            None => return Ok(()),
+        };
+        if *page == Some(offset / PAGE_SIZE) {
+            return Ok(());
+        }
+        *page = Some(offset / PAGE_SIZE);
+        if self.options.clock.now() > deadline {
+            return Err(StoreError::Timeout);
+        }
+        Ok(())
+    }
+
+    /// Same as `seek`, for a delete: with a deadline, the cluster after the slot of the hash is
+    /// walked to its end as well, since the backward shift rewrites it, so that the delete times
+    /// out before it changes anything rather than in the middle of the shift. Likewise, the sector
+    /// is read in full if the tombstone of the delete makes it rebuilt.
+    fn seek_for_delete(&mut self, hash: [u8; 26]) -> Result<(u64, u64), StoreError> {
+        let deadline = self.op_deadline();
+        let slot = self.seek_until(hash, deadline)?;
+        if deadline.is_none() || slot.1 == NO_VALUE {
+            return Ok(slot);
+        }
+        let sector_offset = self.sector_start(slot.0);
+        if !self.shifts_on_delete() {
+            let tombstones = self.tx.get_num(&*self.file, sector_offset + 40)? + 1;
+            if tombstones >= self.slots_in_sector() * TOMBSTONE_REBUILD_PERCENT / 100 {
+                self.read_ht_sector_until(sector_offset, deadline)?;
+            }
+            return Ok(slot);
+        }
+        let slots_in_sector = self.slots_in_sector();
+        let mut cur = self.layout.slot_index(sector_offset, slot.0);
+        let mut page = Some(slot.0 / PAGE_SIZE);
+        loop {
+            cur = (cur + 1) % slots_in_sector;
+            let offset = self.layout.slot_offset(sector_offset, cur);
+            self.check_deadline(deadline, offset, &mut page)?;
+            let data = self.read_ht_slot(offset)?;
+            if self.layout.extract_value(&data) == NO_VALUE {
+                return Ok(slot);
+            }
+        }
+    }
+
+    /// Same as `seek`, for a set: with a deadline, the sector of an absent hash is read in full as
+    /// well if inserting the hash may resize it, so that the set times out before it changes
+    /// anything rather than once the value is written.
+    fn seek_for_set(&mut self, hash: [u8; 26]) -> Result<(u64, u64), StoreError> {
+        let deadline = self.op_deadline();
+        let slot = self.seek_until(hash, deadline)?;
+        if deadline.is_none() || slot.1 != NO_VALUE {
+            return Ok(slot);
+        }
+        let data = self.read_ht_slot(slot.0)?;
+        if self.layout.extract_value(&data) == TOMBSTONE {
+            // Reusing a tombstone never resizes
+            return Ok(slot);
+        }
+        let sector_offset = self.sector_start(slot.0);
+        let occ = self.tx.get_num(&*self.file, sector_offset + 32)?;
+        let tombstones = self.tx.get_num(&*self.file, sector_offset + 40)?;
+        // The early threshold may be adapted by the insert, but never below either of these
+        let percent = self
+            .early_sector_percent
+            .min(self.options.early_sector_percent)
+            .min(self.options.max_sector_percent);
+        if occ + tombstones + 1 >= self.slots_in_sector() * percent / 100 {
+            self.read_ht_sector_until(sector_offset, deadline)?;
+        }
+        Ok(slot)
+    }
+
+    /// Reads every slot of the hash table sector, failing with `StoreError::Timeout` once the
+    /// `deadline` passes. Called before a sector is rebuilt, since the rebuild isn't timed, see
+    /// `rebuild_ht_sector`.
+    fn read_ht_sector_until(
+        &mut self,
+        sector_offset: u64,
+        deadline: Option<Duration>,
+    ) -> Result<(), StoreError> {
+        let mut page = None;
+        for slot in 0..self.slots_in_sector() {
+            let offset = self.layout.slot_offset(sector_offset, slot);
+            self.check_deadline(deadline, offset, &mut page)?;
+            self.read_ht_slot(offset)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the deletes shift the entries back rather than leave tombstones, see
+    /// `Options::delete_strategy`.
+    fn shifts_on_delete(&self) -> bool {
+        self.options.delete_strategy == DeleteStrategy::BackwardShift
+            && self.layout.probe == ProbeStrategy::Linear
+    }
+
+    /// Fills the bloom filter with the hashes of the live slots of the hash table, see
+    /// `Options::expected_keys`.
+    fn rebuild_bloom(&mut self) -> Result<(), StoreError> {
+        let mut bloom = match self.bloom.take() {
+            Some(bloom) => bloom,
+            None => return Ok(()),
+        };
+        bloom.clear();
+        let ht_sectors = self.ht_mapping.values().copied().collect::<Vec<_>>();
+        for sector_offset in ht_sectors {
+            for slot in 0..self.slots_in_sector() {
+                let data = self.read_ht_slot(self.layout.slot_offset(sector_offset, slot))?;
+                let value = self.layout.extract_value(&data);
+                if value != NO_VALUE && value != TOMBSTONE {
+                    bloom.insert(&self.layout.extract_hash(&data));
+                }
+            }
+        }
+        self.bloom = Some(bloom);
+        Ok(())
+    }
+
+    /// Reads the hash table slot at `offset` into the beginning of an array, rather than
+    /// allocating it, since the lookups read many of them.
+    fn read_ht_slot(&mut self, offset: u64) -> Result<[u8; MAX_SLOT_SIZE], StoreError> {
+        let mut data = [0u8; MAX_SLOT_SIZE];
+        let slot_size = self.layout.slot_size() as usize;
+        self.tx
+            .read_into(&*self.file, offset, &mut data[..slot_size])?;
+        Ok(data)
+    }
+
+    pub fn ht_get(&mut self, key: Vec<u8>) -> Result<Option<u64>, StoreError> {
+        let hash = self.get_hash(&key);
+        let value = self.seek_present(hash)?;
+        if value != NO_VALUE {
+            Ok(Some(value))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn ht_get_or_panic(&mut self, key: Vec<u8>) -> Option<u64> {
+        self.ht_get(key).expect(IO_ERROR)
+    }
+
+    pub fn ht_set(&mut self, key: Vec<u8>, new_value: u64) -> Result<(), StoreError> {
+        self.check_writable()?;
+        let hash = self.get_hash(&key);
+        self.ht_set_with_hash(hash, new_value)?;
+        Ok(())
+    }
+
+    pub fn ht_set_or_panic(&mut self, key: Vec<u8>, new_value: u64) {
+        self.ht_set(key, new_value).expect(IO_ERROR)
+    }
+
+    fn ht_set_with_hash(
+        &mut self,
+        hash: [u8; 26],
+        new_value: u64,
+    ) -> Result<Option<u64>, StoreError> {
+        let slot = self.seek(hash)?;
+        self.ht_set_at(hash, slot, new_value)
+    }
+
+    /// Same as `ht_set_with_hash`, given the slot `seek` returned for the hash. Nothing may
+    /// modify the hash table between the `seek` and this call.
+    fn ht_set_at(
+        &mut self,
+        hash: [u8; 26],
+        slot: (u64, u64),
+        new_value: u64,
+    ) -> Result<Option<u64>, StoreError> {
+        let old_value = self.ht_write_slot(hash, slot, new_value)?;
+        if old_value.is_none() {
+            let len = self.tx.get_num(&*self.file, LEN_OFFSET)? + 1;
+            self.tx.set(LEN_OFFSET, len.to_le_bytes().to_vec());
+        }
+        Ok(old_value)
+    }
+
+    /// Same as `ht_set_with_hash`, but doesn't count the new entries, so that it can be used to
+    /// reinsert the entries when a sector is resized.
+    fn ht_set_slot(&mut self, hash: [u8; 26], new_value: u64) -> Result<Option<u64>, StoreError> {
+        let slot = self.seek(hash)?;
+        self.ht_write_slot(hash, slot, new_value)
+    }
+
+    /// Writes the value into the slot `seek` returned for the hash, and resizes the sector if it
+    /// gets too full.
+    fn ht_write_slot(
+        &mut self,
+        hash: [u8; 26],
+        (offset, old_value): (u64, u64),
+        new_value: u64,
+    ) -> Result<Option<u64>, StoreError> {
+        let slot_size = self.layout.slot_size();
+        let reuses_tombstone = old_value == NO_VALUE
+            && self
+                .layout
+                .extract_value(&self.tx.get(&*self.file, offset, slot_size)?)
+                == TOMBSTONE;
+        self.tx.set(offset, self.layout.encode(&hash, new_value));
+
+        if old_value == NO_VALUE {
+            if let Some(bloom) = &mut self.bloom {
+                bloom.insert(&hash);
+            }
+            let sector_offset = self.sector_start(offset);
+
+            let mut occ = self.tx.get_num(&*self.file, sector_offset + 32)?;
+            occ += 1;
+            // The number of tombstones is stored after the occupancy
+            let tombstones = self.tx.get_num(&*self.file, sector_offset + 40)?;
+            if reuses_tombstone {
+                // The probed slots stay the same, so the sector doesn't get any fuller
+                self.tx.set(sector_offset + 32, occ.to_le_bytes().to_vec());
+                self.tx
+                    .set(sector_offset + 40, (tombstones - 1).to_le_bytes().to_vec());
+                return Ok(None);
+            }
+
+            // If the segment is `max_sector_percent` occupied, resize it unconditionally.
+            // Otherwise, resize it if it's `early_sector_percent`, and `resize_spacing_writes` new
+            // writes have happened across all sectors since the last resize. The latter is a
+            // heuristic needed to space resizes in time (otherwise sectors grow with approximately
+            // the same speed, and get resized close to each other in time).
+            // The tombstones lengthen the probes as much as the entries do, so they count here.
+            if self.options.adaptive_resize {
+                self.adapt_early_sector_percent();
+            }
+            let slots_in_sector = self.slots_in_sector();
+            let load = occ + tombstones;
+            let resize = load >= slots_in_sector * self.options.max_sector_percent / 100
+                || (load >= slots_in_sector * self.early_sector_percent / 100
+                    && self.writes_since_resize >= self.options.resize_spacing_writes);
+
+            if !resize {
+                self.writes_since_resize += 1;
+                self.tx.set(sector_offset + 32, occ.to_le_bytes().to_vec());
+            } else {
+                self.writes_since_resize = 0;
+                self.metrics.add_resize();
+                self.rebuild_ht_sector(sector_offset, true)?;
+            }
+            Ok(None)
+        } else {
+            Ok(Some(old_value))
+        }
+    }
+
+    /// Counts a new slot occupied towards the current window of `Options::adaptive_resize`, or
+    /// adapts the early resize threshold to the window and starts the next one if it's over.
+    fn adapt_early_sector_percent(&mut self) {
+        let now = self.options.clock.now_unix_secs();
+        let (start, writes) = self.resize_window;
+        if now < start + ADAPTIVE_RESIZE_WINDOW_SECS {
+            self.resize_window.1 += 1;
+            return;
+        }
+        self.early_sector_percent = if writes < self.options.resize_spacing_writes {
+            (self.early_sector_percent + ADAPTIVE_RESIZE_STEP_PERCENT)
+                .min(self.options.max_sector_percent)
+        } else {
+            self.options.early_sector_percent
+        };
+        self.resize_window = (now, 1);
+    }
+
+    /// Reinserts the entries of the hash table sector into a wiped sector, which drops its
+    /// tombstones. With `split`, the sector is resized: the upper half of its hashes moves to a
+    /// new sector.
+    fn rebuild_ht_sector(&mut self, sector_offset: u64, split: bool) -> Result<(), StoreError> {
+        // This process is done in three steps:
+        // 1. Collect all the key-value pairs, and their hashes, and wipe out the content
+        //    of the sector.
+        //    This isn't timed, since the sector is resized after the write that fills it. The
+        //    timed operations read the sector before they write, see `seek_for_set`.
+        let mut pairs: Vec<([u8; 26], u64)> = vec![];
+        let slot_size = self.layout.slot_size();
+        for slot in 0..self.slots_in_sector() {
+            let slot_offset = self.layout.slot_offset(sector_offset, slot);
+            let data = self.tx.get(&*self.file, slot_offset, slot_size)?;
+            let value = self.layout.extract_value(&data);
+            if value != NO_VALUE && value != TOMBSTONE {
+                pairs.push((self.layout.extract_hash(&data), value))
+            }
+        }
+        for slot in 0..self.slots_in_sector() {
+            let slot_offset = self.layout.slot_offset(sector_offset, slot);
+            self.tx.set(slot_offset, vec![0; slot_size as usize]);
+        }
+        self.tx.set(sector_offset + 32, vec![0; 8]);
+        self.tx.set(sector_offset + 40, vec![0; 8]);
+
+        // 2. Sort the hashes, and find the median hash. Create a new sector with such a key.
+        if split {
+            pairs.sort_unstable();
+            let median_hash = pairs[pairs.len() / 2].0;
+
+            let sector_offset = self.allocate_sector(
+                vec![
+                    median_hash.to_vec(),
+                    vec![0u8; 8 + 8 + 6],
+                    PAGE_TYPE_HT.to_le_bytes().to_vec(),
+                    vec![0u8; 8],
+                ],
+                FIRST_SLOT_OFFSET,
+                self.layout.slot_size(),
+            )?;
+            self.ht_mapping.insert(median_hash, sector_offset);
+        }
+
+        // 3. Reinsert the data. The reinserts aren't lookups, so they're left out of
+        //    `probe_stats`.
+        let probe_window = self.probe_window.take();
+        let result = pairs
+            .into_iter()
+            .try_for_each(|(h, v)| self.ht_set_slot(h, v).map(drop));
+        self.probe_window = probe_window;
+        result
+    }
+
+    pub fn ht_delete(&mut self, key: Vec<u8>) -> Result<(), StoreError> {
+        self.check_writable()?;
+        let hash = self.get_hash(&key);
+        self.ht_delete_with_hash(hash)
+    }
+
+    pub fn ht_delete_or_panic(&mut self, key: Vec<u8>) {
+        self.ht_delete(key).expect(IO_ERROR)
+    }
+
+    fn ht_delete_with_hash(&mut self, hash: [u8; 26]) -> Result<(), StoreError> {
+        let slot = self.seek(hash)?;
+        self.ht_delete_at(slot)
+    }
+
+    /// Same as `ht_delete_with_hash`, given the slot `seek` returned for the hash. Nothing may
+    /// insert or delete the entries of the hash table between the `seek` and this call. The
+    /// compaction only changes the values of the slots, so it may run in between.
+    fn ht_delete_at(&mut self, slot: (u64, u64)) -> Result<(), StoreError> {
+        let (target_offset, old_value) = slot;
+        if old_value != NO_VALUE {
+            let sector_offset = self.sector_start(target_offset);
+            let slot_size = self.layout.slot_size();
+
+            let occ = self.tx.get_num(&*self.file, sector_offset + 32)? - 1;
+            self.tx.set(sector_offset + 32, occ.to_le_bytes().to_vec());
+
+            let len = self.tx.get_num(&*self.file, LEN_OFFSET)? - 1;
+            self.tx.set(LEN_OFFSET, len.to_le_bytes().to_vec());
+
+            if !self.shifts_on_delete() {
+                let data = self.tx.get(&*self.file, target_offset, slot_size)?;
+                let hash = self.layout.extract_hash(&data);
+                self.tx
+                    .set(target_offset, self.layout.encode(&hash, TOMBSTONE));
+
+                let tombstones = self.tx.get_num(&*self.file, sector_offset + 40)? + 1;
+                if tombstones >= self.slots_in_sector() * TOMBSTONE_REBUILD_PERCENT / 100 {
+                    self.rebuild_ht_sector(sector_offset, false)?;
+                } else {
+                    self.tx
+                        .set(sector_offset + 40, tombstones.to_le_bytes().to_vec());
+                }
+                return Ok(());
+            }
+
+            // The tombstones keep their hashes, so they're shifted like the entries
+            let slots_in_sector = self.slots_in_sector();
+            let mut target = self.layout.slot_index(sector_offset, target_offset);
+            let mut cur = target;
+            loop {
+                cur = (cur + 1) % slots_in_sector;
+                let cur_offset = self.layout.slot_offset(sector_offset, cur);
+
+                let data = self.tx.get(&*self.file, cur_offset, slot_size)?;
+                if self.layout.extract_value(&data) == NO_VALUE {
+                    let target_offset = self.layout.slot_offset(sector_offset, target);
+                    self.tx.set(target_offset, vec![0; slot_size as usize]);
+                    break;
+                }
+                let desired = self.get_slot(&self.layout.extract_hash(&data));
+
+                let adjust = |x| {
+                    if x < desired {
+                        x + slots_in_sector
+                    } else {
+                        x
+                    }
+                };
+
+                if adjust(cur) > adjust(target) {
+                    let target_offset = self.layout.slot_offset(sector_offset, target);
+                    self.tx.set(target_offset, data);
+                    target = cur;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The offset in the file of the delmap entry holding the bit of the value slot at the
+    /// logical offset.
+    /// Fails if no delmap sector maps the offset, see `value_physical_offset`.
+    fn delmap_offset(&self, logical_offset: u64) -> Result<u64, StoreError> {
+        let (sector_logical_offset, sector_physical_offset) = self
+            .delmap_mapping
+            .range(..=logical_offset)
+            .next_back()
+            .ok_or(StoreError::Corruption {
+                offset: FIRST_VALUE_LOGICAL_OFFSET,
+                reason: UNMAPPED_DELMAP,
+            })?;
+        Ok(sector_physical_offset
+            + (logical_offset - sector_logical_offset) / VALUE_SIZE / DELS_PER_DELMAP
+                * DELMAP_ENTRY_SIZE)
+    }
+
+    fn is_value_at_offset_deleted(&mut self, logical_offset: u64) -> Result<bool, StoreError> {
+        let file_offset = self.delmap_offset(logical_offset)?;
+        #[cfg(debug_assertions)]
+        {
+            self.check_page_type(file_offset, PAGE_TYPE_DELMAP)?;
+        }
+        let offset_within_delmap = (logical_offset / VALUE_SIZE) % DELS_PER_DELMAP;
+        let cur_delmap = self.tx.get(&*self.file, file_offset, DELMAP_ENTRY_SIZE)?;
+
+        Ok(cur_delmap[offset_within_delmap as usize / 8] & (1 << (offset_within_delmap % 8)) == 0)
+    }
+
+    fn move_one_value(&mut self) -> Result<Option<(u64, u64)>, StoreError> {
+        let logical_offset = self.tx.get_num(&*self.file, FIRST_VALUE_LOGICAL_OFFSET)?;
+
+        #[cfg(test)]
+        {
+            self.value_moves += 1;
+        }
+        let new_logical_offset = logical_offset + VALUE_SIZE;
+        self.tx.set(
+            FIRST_VALUE_LOGICAL_OFFSET,
+            new_logical_offset.to_le_bytes().to_vec(),
+        );
+
+        let ret = if !self.is_value_at_offset_deleted(logical_offset)? {
+            let value = self.get_value(logical_offset)?;
+            let new_offset = self.write_value(value)?;
+            self.metrics.add_relocation();
+            Some((logical_offset, new_offset))
+        } else {
+            None
+        };
+
+        if new_logical_offset.is_multiple_of(self.values_sector_span()) {
+            // The page that was holding the value being moved is now free
+            let (&sector_logical_offset, &sector_physical_offset) = self
+                .values_mapping
+                .range(..=logical_offset)
+                .next_back()
+                .unwrap();
+
+            assert_eq!(
+                new_logical_offset,
+                sector_logical_offset + self.values_sector_span()
+            );
+            self.free_sector(sector_physical_offset - VALUE_SIZE)?;
+            self.values_mapping.remove(&sector_logical_offset);
+        }
+
+        if new_logical_offset.is_multiple_of(self.delmap_sector_span()) {
+            // The page that was holding the delmap being moved is now free
+            let (&sector_logical_offset, &sector_physical_offset) = self
+                .delmap_mapping
+                .range(..=logical_offset)
+                .next_back()
+                .unwrap();
+
+            assert_eq!(
+                new_logical_offset,
+                sector_logical_offset + self.delmap_sector_span()
+            );
+            self.free_sector(sector_physical_offset - FIRST_SLOT_OFFSET)?;
+            self.delmap_mapping.remove(&sector_logical_offset);
+        }
+
+        Ok(ret)
+    }
+
+    fn get_value(&mut self, logical_offset: u64) -> Result<[u8; VALUE_SIZE as usize], StoreError> {
+        #[cfg(test)]
+        {
+            self.value_reads += 1;
+        }
+        let physical_offset = self.value_physical_offset(logical_offset)?;
+        #[cfg(debug_assertions)]
+        {
+            self.check_page_type(physical_offset, PAGE_TYPE_VALUES)?;
+        }
+        #[cfg(feature = "mmap")]
+        {
+            let mut value = [0u8; VALUE_SIZE as usize];
+            if self.tx.read_mapped(physical_offset, &mut value) {
+                return Ok(value);
+            }
+        }
+        self.tx.get_fixed(&*self.file, physical_offset)
+    }
+
+    /// The offset in the file of the value slot at the logical offset. Fails if no values sector
+    /// maps the offset, which a hash table slot can only point at when it's dangling, e.g. after
+    /// the compaction freed the sector under it.
+    fn value_physical_offset(&self, logical_offset: u64) -> Result<u64, StoreError> {
+        let (sector_logical_offset, sector_physical_offset) = self
+            .values_mapping
+            .range(..=logical_offset)
+            .next_back()
+            .ok_or(StoreError::Corruption {
+                offset: FIRST_VALUE_LOGICAL_OFFSET,
+                reason: UNMAPPED_VALUE,
+            })?;
+        Ok(sector_physical_offset + logical_offset - sector_logical_offset)
+    }
+
+    fn write_value(&mut self, data: [u8; VALUE_SIZE as usize]) -> Result<u64, StoreError> {
+        let (cur_offset, physical_offset) = self.reserve_value_slot()?;
+        self.tx.set(physical_offset, data.to_vec());
+        self.update_delmap(cur_offset, true)?;
+        Ok(cur_offset)
+    }
+
+    /// Overwrites the value slot at the logical offset, which must have been reserved already.
+    fn set_value(
+        &mut self,
+        logical_offset: u64,
+        data: [u8; VALUE_SIZE as usize],
+    ) -> Result<(), StoreError> {
+        let physical_offset = self.value_physical_offset(logical_offset)?;
+        self.tx.set(physical_offset, data.to_vec());
+        Ok(())
+    }
+
+    /// Moves the values frontier one slot forward, allocating the values and, with
+    /// `Options::enable_compaction`, the delmap sectors as needed. Returns the logical and the
+    /// physical offsets of the new slot, which is neither written nor marked as live in the
+    /// delmap.
+    fn reserve_value_slot(&mut self) -> Result<(u64, u64), StoreError> {
+        let cur_offset = self.tx.get_num(&*self.file, NEXT_VALUE_LOGICAL_OFFSET)?;
+        let mut next_value_physical_offset =
+            self.tx.get_num(&*self.file, NEXT_VALUE_PHYSICAL_OFFSET)?;
+        let mut next_delmap_physical_offset =
+            self.tx.get_num(&*self.file, NEXT_DELMAP_PHYSICAL_OFFSET)?;
+
+        self.tx.set(
+            NEXT_VALUE_LOGICAL_OFFSET,
+            (cur_offset + VALUE_SIZE).to_le_bytes().to_vec(),
+        );
+
+        if next_value_physical_offset % self.options.sector_size == FIRST_SECTOR_OFFSET {
+            next_value_physical_offset = self.allocate_sector(
+                vec![
+                    cur_offset.to_le_bytes().to_vec(),
+                    vec![0u8; 40],
+                    PAGE_TYPE_VALUES.to_le_bytes().to_vec(),
+                    vec![0u8; 8],
+                    vec![0u8; 64],
+                ],
+                VALUE_SIZE,
+                VALUE_SIZE,
+            )? + VALUE_SIZE;
+            self.values_mapping
+                .insert(cur_offset, next_value_physical_offset);
+        }
+
+        let physical_offset = next_value_physical_offset;
+        next_value_physical_offset += VALUE_SIZE;
+        self.tx.set(
+            NEXT_VALUE_PHYSICAL_OFFSET,
+            next_value_physical_offset.to_le_bytes().to_vec(),
+        );
+
+        let offset_within_delmap = (cur_offset / VALUE_SIZE) % DELS_PER_DELMAP;
+        if self.options.enable_compaction && offset_within_delmap == 0 {
+            if next_delmap_physical_offset % self.options.sector_size == FIRST_SECTOR_OFFSET {
+                next_delmap_physical_offset = self.allocate_sector(
+                    vec![
+                        cur_offset.to_le_bytes().to_vec(),
+                        vec![0u8; 40],
+                        PAGE_TYPE_DELMAP.to_le_bytes().to_vec(),
+                        vec![0u8; 8],
+                    ],
+                    FIRST_SLOT_OFFSET,
+                    DELMAP_ENTRY_SIZE,
+                )? + FIRST_SLOT_OFFSET;
+                self.delmap_mapping
+                    .insert(cur_offset, next_delmap_physical_offset);
+            }
+            next_delmap_physical_offset += DELMAP_ENTRY_SIZE;
+            self.tx.set(
+                NEXT_DELMAP_PHYSICAL_OFFSET,
+                next_delmap_physical_offset.to_le_bytes().to_vec(),
+            );
+        }
+
+        Ok((cur_offset, physical_offset))
+    }
+
+    fn delete_value(&mut self, logical_offset: u64) -> Result<(), StoreError> {
+        self.update_delmap(logical_offset, false)
+    }
+
+    /// Sets the bit of the value slot at the logical offset in the delmap, i.e. marks the slot as
+    /// either live or deleted. Does nothing without `Options::enable_compaction`.
+    fn update_delmap(&mut self, logical_offset: u64, live: bool) -> Result<(), StoreError> {
+        if !self.options.enable_compaction {
+            return Ok(());
+        }
+        let file_offset = self.delmap_offset(logical_offset)?;
+        #[cfg(debug_assertions)]
+        {
+            self.check_page_type(file_offset, PAGE_TYPE_DELMAP)?;
+        }
+        let offset_within_delmap = (logical_offset / VALUE_SIZE) % DELS_PER_DELMAP;
+
+        let mut cur_delmap = self.tx.get(&*self.file, file_offset, DELMAP_ENTRY_SIZE)?;
+        let bit = (1 << (offset_within_delmap % 8)) as u8;
+        if live {
+            cur_delmap[offset_within_delmap as usize / 8] |= bit;
+        } else {
+            cur_delmap[offset_within_delmap as usize / 8] &= !bit;
+        }
+        self.tx.set(file_offset, cur_delmap);
+        Ok(())
+    }
+
+    // `prelude` should be split into vectors of the same size / alignment as will later be used by
+    // the user of the page. It is expected that the prelude will have 8 bytes vectors at offsets
+    // 48 and 56, the one at 48 containing the type of the page.
+    fn allocate_sector(
+        &mut self,
+        prelude: Vec<Vec<u8>>,
+        expected_prelude_size: u64,
+        el_size: u64,
+    ) -> Result<u64, StoreError> {
+        let mut file_size = self.tx.get_num(&*self.file, 0)?;
+
+        let cur_free_offset = self.tx.get_num(&*self.file, FREE_LIST_OFFSET)?;
+        let mut is_hole = false;
+        let ret = if cur_free_offset != 0 {
+            let new_free_offset = self.tx.get_num(&*self.file, cur_free_offset + 56)?;
+            self.tx
+                .set(FREE_LIST_OFFSET, new_free_offset.to_le_bytes().to_vec());
+            cur_free_offset
+        } else {
+            #[cfg(feature = "mmap")]
+            {
+                self.tx.mmap = None;
+            }
+            #[cfg(all(feature = "preallocate", target_os = "linux"))]
+            let preallocated = self.grow_into_preallocated(file_size)?;
+            #[cfg(not(all(feature = "preallocate", target_os = "linux")))]
+            let preallocated = false;
+            if preallocated {
+                is_hole = true;
+            } else if self.options.sparse_sectors {
+                // Truncating first discards whatever might be past the logical end of the file,
+                // so the new sector is guaranteed to be a hole that reads as zeros.
+                self.file
+                    .set_len(file_size)
+                    .map_err(StoreError::at(file_size))?;
+                self.file
+                    .set_len(file_size + self.options.sector_size)
+                    .map_err(StoreError::at(file_size))?;
+                is_hole = true;
+            } else {
+                self.file
+                    .write_at(
+                        vec![0; self.options.sector_size as usize].as_ref(),
+                        file_size,
+                    )
+                    .map_err(StoreError::at(file_size))?;
+            }
+
+            self.tx
+                .cache
+                .remove_range(file_size, file_size + self.options.sector_size);
+            #[cfg(feature = "mmap")]
+            self.tx.remap(&*self.file, &self.options)?;
+            file_size += self.options.sector_size;
+            self.tx.set(0, file_size.to_le_bytes().to_vec());
+
+            file_size - self.options.sector_size
+        };
+
+        self.init_sector(ret, prelude, expected_prelude_size, el_size, is_hole);
+        Ok(ret)
+    }
+
+    /// Writes the `prelude` at the beginning of the sector at `sector_offset`, and zeroes the rest
+    /// of it in `el_size` chunks, unless the sector `is_hole` and thus already reads as zeros.
+    fn init_sector(
+        &mut self,
+        sector_offset: u64,
+        prelude: Vec<Vec<u8>>,
+        expected_prelude_size: u64,
+        el_size: u64,
+        is_hole: bool,
+    ) {
+        self.tx
+            .reset_sector(sector_offset, self.options.sector_size);
+
+        let mut offset = sector_offset;
+        for v in prelude {
+            let v_len = v.len() as u64;
+            self.tx.set(offset, v);
+            offset += v_len;
+        }
+
+        assert_eq!(offset - sector_offset, expected_prelude_size);
+
+        while !is_hole && offset % self.options.sector_size != FIRST_SECTOR_OFFSET {
+            // The elements never straddle the pages, see the `slot_layout` module
+            if offset % PAGE_SIZE + el_size > PAGE_SIZE {
+                offset = (offset | (PAGE_SIZE - 1)) + 1;
+                continue;
+            }
+            self.tx.set(offset, vec![0u8; el_size as usize]);
+            offset += el_size;
+        }
+    }
+
+    fn free_sector(&mut self, offset: u64) -> Result<(), StoreError> {
+        assert_eq!(offset % self.options.sector_size, FIRST_SECTOR_OFFSET);
+        self.tx
+            .set(offset + 48, PAGE_TYPE_FREE.to_le_bytes().to_vec());
+        let cur_free_offset = self.tx.get_num(&*self.file, FREE_LIST_OFFSET)?;
+        self.tx
+            .set(offset + 56, cur_free_offset.to_le_bytes().to_vec());
+        self.tx.set(FREE_LIST_OFFSET, offset.to_le_bytes().to_vec());
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<(), StoreError> {
+        if self.read_only {
+            Err(StoreError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn get_hash(&self, key: &[u8]) -> [u8; HASH_LEN] {
+        #[cfg(test)]
+        self.key_hashes.set(self.key_hashes.get() + 1);
+        self.layout
+            .truncate(self.options.hasher.hash(&self.salt, key))
+    }
+
+    fn get_slot(&self, hash: &[u8; 26]) -> u64 {
+        self.layout.home_slot(hash, self.slots_in_sector())
+    }
+
+    /// Panics unless the sector holding the `offset` has the page type `expected`, to catch the
+    /// offsets mapped to the wrong kind of sector where they're used rather than where the data
+    /// they read turns out wrong. Only checked in debug builds, since it reads the prelude of the
+    /// sector on every access.
+    #[cfg(debug_assertions)]
+    fn check_page_type(&mut self, offset: u64, expected: u64) -> Result<(), StoreError> {
+        let sector_offset = self.sector_start(offset);
+        let found = self.tx.get_num(&*self.file, sector_offset + 48)?;
+        assert_eq!(
+            found, expected,
+            "page type mismatch at offset {}: found {}, expected {}",
+            offset, found, expected
+        );
+        Ok(())
+    }
+
+    /// The offset of the sector that contains `offset`.
+    fn sector_start(&self, offset: u64) -> u64 {
+        let sector_size = self.options.sector_size;
+        (offset - FIRST_SECTOR_OFFSET) / sector_size * sector_size + FIRST_SECTOR_OFFSET
+    }
+
+    /// The number of slots in a hash table sector.
+    fn slots_in_sector(&self) -> u64 {
+        self.layout.slots_in_sector(self.options.sector_size)
+    }
+
+    /// The length of the logical range of values stored in a single values sector.
+    fn values_sector_span(&self) -> u64 {
+        self.options.sector_size - VALUE_SIZE
+    }
+
+    /// The length of the logical range of values covered by a single delmap sector.
+    fn delmap_sector_span(&self) -> u64 {
+        (self.options.sector_size - FIRST_SLOT_OFFSET) / DELMAP_ENTRY_SIZE
+            * DELS_PER_DELMAP
+            * VALUE_SIZE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use rand::{Rng, SeedableRng};
+    use std::collections::{HashMap, HashSet};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_sanity_db_free_list() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut db = HashTable::new(
+            tmp_dir.path().join("db"),
+            rand::thread_rng().gen::<[u8; 32]>(),
+            None,
+        )
+        .unwrap();
+
+        for i in 0..4 {
+            assert_eq!(
+                db.allocate_sector(vec![vec![0u8; VALUE_SIZE as usize]], VALUE_SIZE, VALUE_SIZE)
+                    .unwrap(),
+                (1 + i) * DEFAULT_SECTOR_SIZE + FIRST_SECTOR_OFFSET
+            );
+        }
+
+        for i in 0..4 {
+            db.free_sector(2 * DEFAULT_SECTOR_SIZE + FIRST_SECTOR_OFFSET)
+                .unwrap();
+            db.free_sector(4 * DEFAULT_SECTOR_SIZE + FIRST_SECTOR_OFFSET)
+                .unwrap();
+
+            assert_eq!(
+                db.allocate_sector(vec![vec![0u8; VALUE_SIZE as usize]], VALUE_SIZE, VALUE_SIZE)
+                    .unwrap(),
+                4 * DEFAULT_SECTOR_SIZE + FIRST_SECTOR_OFFSET
+            );
+
+            assert_eq!(
+                db.allocate_sector(vec![vec![0u8; VALUE_SIZE as usize]], VALUE_SIZE, VALUE_SIZE)
+                    .unwrap(),
+                2 * DEFAULT_SECTOR_SIZE + FIRST_SECTOR_OFFSET
+            );
+
+            assert_eq!(
+                db.allocate_sector(vec![vec![0u8; VALUE_SIZE as usize]], VALUE_SIZE, VALUE_SIZE)
+                    .unwrap(),
+                (5 + i) * DEFAULT_SECTOR_SIZE + FIRST_SECTOR_OFFSET
+            );
+        }
+    }
+
+    #[test]
+    fn test_sanity_db_sparse_sectors() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let salt = rand::thread_rng().gen::<[u8; 32]>();
+        let options = Options {
+            sparse_sectors: true,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(tmp_dir.path().join("db"), salt, None, options.clone())
+                .unwrap();
+
+        let sector = db
+            .allocate_sector(vec![vec![0u8; VALUE_SIZE as usize]], VALUE_SIZE, VALUE_SIZE)
+            .unwrap();
+        assert_eq!(sector, DEFAULT_SECTOR_SIZE + FIRST_SECTOR_OFFSET);
+        // Only the prelude is written, the rest of the sector is left to the filesystem
+        assert_eq!(
+            db.tx
+                .changes
+                .range(sector..sector + DEFAULT_SECTOR_SIZE)
+                .count(),
+            1
+        );
+        for offset in
+            (sector + VALUE_SIZE..sector + DEFAULT_SECTOR_SIZE).step_by(PAGE_SIZE as usize)
+        {
+            assert_eq!(
+                db.tx.get(&*db.file, offset, VALUE_SIZE).unwrap(),
+                vec![0u8; VALUE_SIZE as usize]
+            );
+        }
+
+        for i in 0u64..100 {
+            db.set(i.to_le_bytes().to_vec(), vec![i as u8; 200])
+                .unwrap();
+        }
+        db.flush_changes().unwrap();
+
+        let mut db =
+            HashTable::new_with_options(tmp_dir.path().join("db"), salt, None, options).unwrap();
+        assert_eq!(
+            db.tx
+                .get(
+                    &*db.file,
+                    sector + DEFAULT_SECTOR_SIZE - VALUE_SIZE,
+                    VALUE_SIZE
+                )
+                .unwrap(),
+            vec![0u8; VALUE_SIZE as usize]
+        );
+        for i in 0u64..100 {
+            assert_eq!(
+                db.get(i.to_le_bytes().to_vec()).unwrap(),
+                Some(vec![i as u8; 200])
+            );
+        }
+    }
+
+    #[test]
+    fn test_sanity_db_values() {
+        #[cfg(debug_assertions)]
+        const ITERS: usize = 20000;
+        #[cfg(not(debug_assertions))]
+        const ITERS: usize = 500000;
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut db = HashTable::new(
+            tmp_dir.path().join("db"),
+            rand::thread_rng().gen::<[u8; 32]>(),
+            None,
+        )
+        .unwrap();
+
+        let mut byte: u8 = 17;
+        let mut first_offset = db.write_value([byte; 128]).unwrap();
+        let mut next_offset = first_offset + 128;
+        let mut next_del_offset = first_offset;
+        let mut next_del_byte = byte;
+
+        for iter in 0..(ITERS * 3) {
+            byte = (byte + 1) % 250;
+
+            assert_eq!(db.write_value([byte; 128]).unwrap(), next_offset);
+            next_offset += 128;
+
+            if iter >= ITERS {
+                assert_eq!(db.get_value(next_del_offset).unwrap(), [next_del_byte; 128]);
+                next_del_byte = (next_del_byte + 1) % 250;
+
+                if (next_del_offset / 128) % 2 == 1 {
+                    db.delete_value(next_del_offset).unwrap();
+                }
+                next_del_offset += 128;
+            }
+
+            if iter >= ITERS * 2 {
+                let maybe_offsets = db.move_one_value().unwrap();
+                if (first_offset / 128) % 2 == 0 {
+                    assert_eq!(maybe_offsets, Some((first_offset, next_offset)));
+                    next_offset += 128;
+                } else {
+                    assert_eq!(maybe_offsets, None);
+                }
+
+                first_offset += 128;
+            }
+        }
+    }
+
+    #[test]
+    fn test_sanity_db_values_sector_boundary() {
+        const VALUES_IN_SECTOR: u64 = (DEFAULT_SECTOR_SIZE - VALUE_SIZE) / VALUE_SIZE;
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut db = HashTable::new(
+            tmp_dir.path().join("db"),
+            rand::thread_rng().gen::<[u8; 32]>(),
+            None,
+        )
+        .unwrap();
+
+        let mut fillers = vec![];
+        let mut add_fillers = |db: &mut HashTable, n: u64| {
+            for _ in 0..n {
+                let key = (fillers.len() as u64).to_le_bytes().to_vec();
+                db.set(key.clone(), vec![fillers.len() as u8; 10]).unwrap();
+                fillers.push(key);
+            }
+        };
+        let logical_offset = |db: &mut HashTable, key: &Vec<u8>| {
+            let hash = db.get_hash(key);
+            db.seek(hash).unwrap().1 - 1
+        };
+
+        // A three slots value that ends exactly at the end of the first values sector.
+        add_fillers(&mut db, VALUES_IN_SECTOR - 3);
+        let ending = (
+            b"ending".to_vec(),
+            vec![1u8; 3 * VALUE_SIZE as usize - 40 - VALUE_CHECKSUM_LEN],
+        );
+        db.set(ending.0.clone(), ending.1.clone()).unwrap();
+        assert_eq!(
+            logical_offset(&mut db, &ending.0),
+            (VALUES_IN_SECTOR - 3) * VALUE_SIZE
+        );
+        assert_eq!(db.values_mapping.len(), 1);
+
+        // A four slots value that spans the boundary between the second and the third sectors.
+        add_fillers(&mut db, VALUES_IN_SECTOR - 2);
+        let spanning = (
+            b"spanning".to_vec(),
+            vec![2u8; 4 * VALUE_SIZE as usize - 40 - VALUE_CHECKSUM_LEN],
+        );
+        db.set(spanning.0.clone(), spanning.1.clone()).unwrap();
+        assert_eq!(
+            logical_offset(&mut db, &spanning.0),
+            (2 * VALUES_IN_SECTOR - 2) * VALUE_SIZE
+        );
+        assert_eq!(db.values_mapping.len(), 3);
+
+        assert_eq!(db.get(ending.0.clone()).unwrap(), Some(ending.1.clone()));
+        assert_eq!(
+            db.get(spanning.0.clone()).unwrap(),
+            Some(spanning.1.clone())
+        );
+
+        // Deleting the fillers makes `move_one_value` relocate both values across the sector
+        // boundaries, and frees the sectors they were moved from.
+        for key in fillers.iter() {
+            db.delete(key.clone()).unwrap();
+            assert_eq!(db.get(ending.0.clone()).unwrap(), Some(ending.1.clone()));
+            assert_eq!(
+                db.get(spanning.0.clone()).unwrap(),
+                Some(spanning.1.clone())
+            );
+        }
+        assert!(logical_offset(&mut db, &ending.0) >= VALUES_IN_SECTOR * VALUE_SIZE);
+        assert!(logical_offset(&mut db, &spanning.0) >= 2 * VALUES_IN_SECTOR * VALUE_SIZE);
+        assert!(!db.values_mapping.contains_key(&0));
+
+        db.delete(ending.0.clone()).unwrap();
+        assert_eq!(db.get(ending.0.clone()).unwrap(), None);
+        assert_eq!(
+            db.get(spanning.0.clone()).unwrap(),
+            Some(spanning.1.clone())
+        );
+        db.delete(spanning.0.clone()).unwrap();
+        assert_eq!(db.get(spanning.0.clone()).unwrap(), None);
+
+        // Reopening must see the same sectors as the in-memory mappings.
+        db.flush_changes().unwrap();
+        let values_mapping = db.values_mapping.clone();
+        let delmap_mapping = db.delmap_mapping.clone();
+        let db = HashTable::new(tmp_dir.path().join("db"), db.salt, None).unwrap();
+        assert_eq!(db.values_mapping, values_mapping);
+        assert_eq!(db.delmap_mapping, delmap_mapping);
+    }
+
+    fn check_get_set(db: &mut HashTable) {
+        db.set(vec![1, 2, 3, 4], vec![5, 6, 7, 8]).unwrap();
+        assert_eq!(db.get(vec![1, 2, 3, 4]).unwrap(), Some(vec![5, 6, 7, 8]));
+        assert_eq!(db.get(vec![1, 2, 3, 5]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_sanity_db_get_set() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut db = HashTable::new(
+            tmp_dir.path().join("db"),
+            rand::thread_rng().gen::<[u8; 32]>(),
+            None,
+        )
+        .unwrap();
+        check_get_set(&mut db);
+    }
+
+    #[test]
+    fn test_sanity_db_in_memory() {
+        let mut db = HashTable::new_in_memory(rand::thread_rng().gen::<[u8; 32]>()).unwrap();
+        check_get_set(&mut db);
+
+        // Enough values to resize the hash table and allocate several values sectors
+        let device = MemoryDevice::new();
+        let options = || Options {
+            sector_size: 16 * PAGE_SIZE,
+            flush_threads: 2,
+            ..Options::default()
+        };
+        let mut db = HashTable::open_device(Arc::new(device.clone()), [0; 32], options()).unwrap();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(584);
+        let mut expected = HashMap::new();
+        for i in 0..20_000u32 {
+            let key = (i % 5000).to_le_bytes().to_vec();
+            if rng.gen_range(0..4) == 0 {
+                db.delete(key.clone()).unwrap();
+                expected.remove(&key);
+            } else {
+                let value = vec![i as u8; rng.gen_range(1..200)];
+                db.set(key.clone(), value.clone()).unwrap();
+                expected.insert(key, value);
+            }
+            if i % 5000 == 4999 {
+                db.flush_changes().unwrap();
+            }
+        }
+        assert!(db.ht_mapping.len() > 1);
+        let snapshot = db.read_snapshot().unwrap();
+        for (key, value) in expected.iter() {
+            assert_eq!(snapshot.get(key.clone()).unwrap().as_ref(), Some(value));
+        }
+        assert!(db.verify().unwrap().is_consistent());
+        drop(db);
+
+        // The bytes outlive the table on the device, like a file
+        let mut db = HashTable::open_device(Arc::new(device.clone()), [0; 32], options()).unwrap();
+        assert_eq!(db.len().unwrap(), expected.len() as u64);
+        for i in 0..5000u32 {
+            let key = i.to_le_bytes().to_vec();
+            assert_eq!(db.get(key.clone()).unwrap(), expected.get(&key).cloned());
+        }
+        let file_size = db.stats().unwrap().file_size;
+        assert_eq!(device.len().unwrap(), file_size);
+
+        // And can be written out to a file
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut db = db.move_to(tmp_dir.path().join("db")).unwrap();
+        assert_eq!(db.len().unwrap(), expected.len() as u64);
+        check_get_set(&mut db);
+    }
+
+    #[test]
+    fn test_sanity_db_value_header() {
+        let header = ValueHeader::new([7; HASH_LEN], 300);
+        assert_eq!(header.len, 334);
+        assert_eq!(header.payload_len(), 300);
+        assert_eq!(header.num_slots(), 3);
+        assert_eq!(ValueHeader::parse(&header.encode()), header);
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut db = HashTable::new(
+            tmp_dir.path().join("db"),
+            rand::thread_rng().gen::<[u8; 32]>(),
+            None,
+        )
+        .unwrap();
+
+        let key = vec![1, 2, 3];
+        let value = (0..200).map(|x| x as u8).collect::<Vec<_>>();
+        db.set(key.clone(), value.clone()).unwrap();
+
+        // Decode the record straight from the value slots, the same way external tools would.
+        let hash = db.get_hash(&key);
+        let (_, offset) = db.seek(hash).unwrap();
+        let first_slot = db.get_value(offset - 1).unwrap();
+        let header = ValueHeader::parse(&first_slot);
+        assert_eq!(header.hash, hash);
+        assert_eq!(header.payload_len(), value.len() as u64);
+        assert_eq!(header.num_slots(), 2);
+
+        let record = [first_slot, db.get_value(offset - 1 + VALUE_SIZE).unwrap()].concat();
+        assert_eq!(
+            &record[VALUE_PAYLOAD_OFFSET..header.len as usize],
+            db.get(key).unwrap().unwrap().as_slice()
+        );
+    }
+
+    #[test]
+    fn test_sanity_db_seq() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let salt = rand::thread_rng().gen::<[u8; 32]>();
+        let mut db = HashTable::new(tmp_dir.path().join("db"), salt, None).unwrap();
+
+        db.set(b"plain".to_vec(), b"value".to_vec()).unwrap();
+        assert_eq!(
+            db.get_with_seq(b"plain".to_vec()).unwrap(),
+            Some((b"value".to_vec(), 0))
+        );
+        db.flush_changes().unwrap();
+
+        let options = Options {
+            store_seq: true,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(tmp_dir.path().join("db"), salt, None, options.clone())
+                .unwrap();
+        assert_eq!(
+            db.get_with_seq(b"plain".to_vec()).unwrap(),
+            Some((b"value".to_vec(), 0))
+        );
+        assert_eq!(db.last_seq().unwrap(), 0);
+
+        let mut last_seq = 0;
+        for i in 0u64..100 {
+            db.set(i.to_le_bytes().to_vec(), vec![i as u8; i as usize * 3])
+                .unwrap();
+            let (value, seq) = db.get_with_seq(i.to_le_bytes().to_vec()).unwrap().unwrap();
+            assert_eq!(value, vec![i as u8; i as usize * 3]);
+            assert!(seq > last_seq);
+            assert_eq!(seq, db.last_seq().unwrap());
+            last_seq = seq;
+        }
+
+        // Rewriting a key assigns it a new sequence number, and the counter survives a reopen
+        db.flush_changes().unwrap();
+        let mut db =
+            HashTable::new_with_options(tmp_dir.path().join("db"), salt, None, options).unwrap();
+        assert_eq!(db.last_seq().unwrap(), last_seq);
+        db.set(0u64.to_le_bytes().to_vec(), b"new".to_vec())
+            .unwrap();
+        assert_eq!(
+            db.get_with_seq(0u64.to_le_bytes().to_vec()).unwrap(),
+            Some((b"new".to_vec(), last_seq + 1))
+        );
+        assert_eq!(
+            db.get_with_seq(1u64.to_le_bytes().to_vec())
+                .unwrap()
+                .unwrap()
+                .1,
+            2
+        );
+    }
+
+    #[test]
+    fn test_sanity_db_move_to() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let old_path = tmp_dir.path().join("db");
+        let new_path = tmp_dir.path().join("moved").join("db");
+        std::fs::create_dir(new_path.parent().unwrap()).unwrap();
+
+        let mut db =
+            HashTable::new(old_path.clone(), rand::thread_rng().gen::<[u8; 32]>(), None).unwrap();
+        for i in 0u64..1000 {
+            db.set(i.to_le_bytes().to_vec(), vec![i as u8; (i % 300) as usize])
+                .unwrap();
+        }
+
+        let mut db = db.move_to(new_path.clone()).unwrap();
+        assert!(!old_path.exists());
+        assert!(new_path.exists());
+
+        for i in 0u64..1000 {
+            assert_eq!(
+                db.get(i.to_le_bytes().to_vec()).unwrap(),
+                Some(vec![i as u8; (i % 300) as usize])
+            );
+        }
+
+        // The log is reset once the pending changes are flushed, and the moved table keeps
+        // committing through it
+        let wal = WalConfig {
+            path: tmp_dir.path().join("wal"),
+            sync: Durability::Full,
+        };
+        let open_with_wal = |path: PathBuf| {
+            HashTable::new_with_wal(path, [0; 32], wal.clone(), Options::default()).unwrap()
+        };
+        let mut db = open_with_wal(old_path.clone());
+        db.set(b"a".to_vec(), b"1".to_vec()).unwrap();
+        db.commit().unwrap();
+        db.set(b"b".to_vec(), b"2".to_vec()).unwrap();
+        let new_path = tmp_dir.path().join("moved").join("db_with_wal");
+        let mut db = db.move_to(new_path.clone()).unwrap();
+        assert!(!old_path.exists());
+        assert_eq!(std::fs::metadata(&wal.path).unwrap().len(), 8 + 8 + 32);
+        assert!(db.wal.is_some());
+        db.set(b"c".to_vec(), b"3".to_vec()).unwrap();
+        db.commit().unwrap();
+        drop(db);
+        let mut db = open_with_wal(new_path);
+        for (key, value) in [(b"a", b"1"), (b"b", b"2"), (b"c", b"3")] {
+            assert_eq!(db.get(key.to_vec()).unwrap(), Some(value.to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_sanity_db_io_error() {
+        /// Fails the writes once `fail` is set.
+        #[derive(Debug, Default)]
+        struct FailingDevice {
+            bytes: MemoryDevice,
+            fail: std::sync::atomic::AtomicBool,
+        }
+
+        impl BlockDevice for FailingDevice {
+            fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+                self.bytes.read_at(buf, offset)
+            }
+
+            fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+                if self.fail.load(Ordering::SeqCst) {
+                    return Err(io::Error::other("injected"));
+                }
+                self.bytes.write_at(buf, offset)
+            }
+
+            fn len(&self) -> io::Result<u64> {
+                self.bytes.len()
+            }
+
+            fn set_len(&self, len: u64) -> io::Result<()> {
+                self.bytes.set_len(len)
+            }
+
+            fn sync(&self, durability: Durability) -> io::Result<()> {
+                self.bytes.sync(durability)
+            }
+        }
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        assert!(matches!(
+            HashTable::new(tmp_dir.path().join("missing").join("db"), [0; 32], None),
+            Err(StoreError::Io { offset: None, .. })
+        ));
+
+        let device = Arc::new(FailingDevice::default());
+        let mut db = HashTable::open_device(device.clone(), [0; 32], Options::default()).unwrap();
+        db.set(vec![1, 2, 3], vec![4, 5, 6]).unwrap();
+
+        // The error of the write of a page says which page it was
+        device.fail.store(true, Ordering::SeqCst);
+        assert!(matches!(
+            db.flush_changes(),
+            Err(StoreError::Io {
+                offset: Some(offset),
+                ..
+            }) if offset % PAGE_SIZE == 0
+        ));
+
+        // The changes of the failed flush are kept, and written by the next one
+        device.fail.store(false, Ordering::SeqCst);
+        db.flush_changes().unwrap();
+        drop(db);
+        let mut db = HashTable::open_device(device, [0; 32], Options::default()).unwrap();
+        assert_eq!(db.get(vec![1, 2, 3]).unwrap(), Some(vec![4, 5, 6]));
+    }
+
+    #[test]
+    fn test_sanity_db_contains_key() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut db = HashTable::new(tmp_dir.path().join("db"), [0; 32], None).unwrap();
+
+        // Push the 900-byte value across the boundary of the first values sector.
+        let per_sector = (DEFAULT_SECTOR_SIZE - VALUE_SIZE) / VALUE_SIZE;
+        for i in 0..per_sector - 3 {
+            db.set(i.to_le_bytes().to_vec(), vec![]).unwrap();
+        }
+        db.set(b"large".to_vec(), vec![7; 900]).unwrap();
+        db.flush_changes().unwrap();
+
+        let value_reads = db.value_reads;
+        assert!(db.contains_key(b"large".to_vec()).unwrap());
+        assert!(db.contains_key(0u64.to_le_bytes().to_vec()).unwrap());
+        assert!(!db.contains_key(b"missing".to_vec()).unwrap());
+        assert_eq!(db.value_reads, value_reads);
+
+        for key in [
+            b"large".to_vec(),
+            0u64.to_le_bytes().to_vec(),
+            b"missing".to_vec(),
+        ] {
+            assert_eq!(
+                db.contains_key(key.clone()).unwrap(),
+                db.get(key).unwrap().is_some()
+            );
+        }
+        assert!(db.value_reads > value_reads);
+
+        db.delete(b"large".to_vec()).unwrap();
+        assert!(!db.contains_key(b"large".to_vec()).unwrap());
+    }
+
+    /// Appends a tag on encode, and fails to decode values that don't end with it.
+    #[derive(Debug)]
+    struct Tag(u8);
+
+    impl ValueTransform for Tag {
+        fn encode(&self, value: Vec<u8>) -> io::Result<Vec<u8>> {
+            Ok([value, vec![self.0]].concat())
+        }
+
+        fn decode(&self, mut value: Vec<u8>) -> io::Result<Vec<u8>> {
+            if value.pop() != Some(self.0) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "bad tag"));
+            }
+            Ok(value)
+        }
+    }
+
+    #[derive(Debug)]
+    struct Xor(u8);
+
+    impl ValueTransform for Xor {
+        fn encode(&self, value: Vec<u8>) -> io::Result<Vec<u8>> {
+            Ok(value.into_iter().map(|x| x ^ self.0).collect())
+        }
+
+        fn decode(&self, value: Vec<u8>) -> io::Result<Vec<u8>> {
+            self.encode(value)
+        }
+    }
+
+    #[test]
+    fn test_sanity_db_transforms() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let options = Options {
+            transforms: vec![Arc::new(Tag(1)), Arc::new(Xor(0xff)), Arc::new(Tag(2))],
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(tmp_dir.path().join("db"), [0; 32], None, options).unwrap();
+
+        for i in 0u64..1000 {
+            db.set(i.to_le_bytes().to_vec(), vec![i as u8; (i % 300) as usize])
+                .unwrap();
+        }
+        for i in 0u64..1000 {
+            assert_eq!(
+                db.get(i.to_le_bytes().to_vec()).unwrap(),
+                Some(vec![i as u8; (i % 300) as usize])
+            );
+        }
+
+        // The record stores the encoded value: the first tag is flipped by the xor
+        let hash = db.get_hash(&5u64.to_le_bytes());
+        let (_, offset) = db.seek(hash).unwrap();
+        let Record {
+            header, payload, ..
+        } = db.read_record(offset - 1).unwrap();
+        assert_eq!(header.payload_len(), 5 + 2);
+        assert_eq!(payload, vec![!5u8, !5, !5, !5, !5, !1, 2]);
+
+        // Decoding in the encoding order fails on the first tag
+        let reversed = Options {
+            transforms: vec![Arc::new(Tag(2)), Arc::new(Xor(0xff)), Arc::new(Tag(1))],
+            ..Options::default()
+        };
+        db.flush_changes().unwrap();
+        drop(db);
+        let mut db =
+            HashTable::new_with_options(tmp_dir.path().join("db"), [0; 32], None, reversed)
+                .unwrap();
+        assert!(db.get(5u64.to_le_bytes().to_vec()).is_err());
+    }
+
+    #[cfg(all(feature = "lz4", feature = "chacha20"))]
+    #[test]
+    fn test_sanity_db_compress_then_encrypt() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let options = Options {
+            transforms: vec![
+                Arc::new(transforms::Lz4),
+                Arc::new(transforms::ChaCha20::new(rand::thread_rng().gen())),
+            ],
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(tmp_dir.path().join("db"), [0; 32], None, options).unwrap();
+
+        db.set(b"key".to_vec(), vec![7; 10000]).unwrap();
+        assert_eq!(db.get(b"key".to_vec()).unwrap(), Some(vec![7; 10000]));
+
+        // Compression happened before encryption, otherwise the value wouldn't shrink
+        let hash = db.get_hash(b"key");
+        let (_, offset) = db.seek(hash).unwrap();
+        let Record { header, .. } = db.read_record(offset - 1).unwrap();
+        assert!(header.payload_len() < 1000);
+    }
+
+    #[test]
+    fn test_sanity_db_len() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("db");
+        let mut db = HashTable::new(path.clone(), [0; 32], None).unwrap();
+        assert!(db.is_empty().unwrap());
+
+        // Enough keys to resize the first sector several times
+        const N: u64 = 100000;
+        const M: u64 = 30000;
+        for i in 0..N {
+            db.set(i.to_le_bytes().to_vec(), vec![1]).unwrap();
+        }
+        // Overwrites don't change the number of keys
+        for i in 0..1000u64 {
+            db.set(i.to_le_bytes().to_vec(), vec![2]).unwrap();
+        }
+        for i in 0..M {
+            db.delete((2 * i).to_le_bytes().to_vec()).unwrap();
+        }
+        // Deleting a missing key doesn't either
+        db.delete(N.to_le_bytes().to_vec()).unwrap();
+        assert_eq!(db.len().unwrap(), N - M);
+        assert!(db.ht_mapping.len() > 1);
+
+        db.flush_changes().unwrap();
+        drop(db);
+        let mut db = HashTable::new(path.clone(), [0; 32], None).unwrap();
+        assert_eq!(db.len().unwrap(), N - M);
+
+        // A database without the counter gets it recomputed from the sector occupancies
+        db.tx.set(LEN_OFFSET, vec![0; 8]);
+        db.flush_changes().unwrap();
+        drop(db);
+        let mut db = HashTable::new(path, [0; 32], None).unwrap();
+        assert_eq!(db.len().unwrap(), N - M);
+        assert!(!db.is_empty().unwrap());
+    }
+
+    #[test]
+    fn test_sanity_db_clear() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("db");
+        let mut db = HashTable::new(path.clone(), [0; 32], None).unwrap();
+
+        let fill = |db: &mut HashTable| {
+            for i in 0u64..50000 {
+                db.set(i.to_le_bytes().to_vec(), vec![i as u8; (i % 300) as usize])
+                    .unwrap();
+            }
+            for i in 0u64..10000 {
+                db.delete((i * 3).to_le_bytes().to_vec()).unwrap();
+            }
+            db.flush_changes().unwrap();
+        };
+
+        fill(&mut db);
+        let file_len = std::fs::metadata(&path).unwrap().len();
+        assert!(db.ht_mapping.len() > 1);
+
+        db.clear().unwrap();
+        assert!(db.is_empty().unwrap());
+        for i in 0u64..50000 {
+            assert_eq!(db.get(i.to_le_bytes().to_vec()).unwrap(), None);
+        }
+        db.flush_changes().unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), file_len);
+
+        fill(&mut db);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), file_len);
+        assert_eq!(db.len().unwrap(), 40000);
+
+        // The cleared state survives reopening
+        db.clear().unwrap();
+        db.flush_changes().unwrap();
+        drop(db);
+        let mut db = HashTable::new(path, [0; 32], None).unwrap();
+        assert!(db.is_empty().unwrap());
+        assert_eq!(db.get(1u64.to_le_bytes().to_vec()).unwrap(), None);
+        assert_eq!(db.ht_mapping.len(), 1);
+        assert!(db.values_mapping.is_empty());
+    }
+
+    #[test]
+    fn test_sanity_db_expected_probe_length() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut db = HashTable::new(tmp_dir.path().join("db"), [0; 32], None).unwrap();
+        assert_eq!(db.expected_probe_length().unwrap(), 1.0);
+
+        // Fill the only sector up to the point where it would be resized
+        let threshold = db.slots_in_sector() * db.options.early_sector_percent / 100 - 1;
+        let mut last_estimate = 1.0;
+        for i in 0..threshold {
+            db.ht_set(i.to_le_bytes().to_vec(), 1).unwrap();
+            if (i + 1) % 1000 == 0 {
+                let estimate = db.expected_probe_length().unwrap();
+                assert!(estimate > last_estimate);
+                last_estimate = estimate;
+            }
+        }
+        assert_eq!(db.sector_occupancies().unwrap(), vec![threshold]);
+        // At 80% load the formula gives 3 probes
+        assert!((db.expected_probe_length().unwrap() - 3.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_sanity_db_expected_probe_length_tombstones() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let options = Options {
+            delete_strategy: DeleteStrategy::Tombstone,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(tmp_dir.path().join("db"), [0; 32], None, options).unwrap();
+        let threshold = db.slots_in_sector() * db.options.early_sector_percent / 100 - 1;
+        for i in 0..threshold {
+            db.ht_set(i.to_le_bytes().to_vec(), 1).unwrap();
+        }
+        // The tombstones left by the deletes keep the load, and so the estimate, where it was
+        for i in 0..threshold / 8 {
+            db.ht_delete(i.to_le_bytes().to_vec()).unwrap();
+        }
+        assert_eq!(
+            db.sector_occupancies().unwrap(),
+            vec![threshold - threshold / 8]
+        );
+        assert!((db.expected_probe_length().unwrap() - 3.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_sanity_db_expected_probe_length_double_hashing() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let options = Options {
+            probe_strategy: ProbeStrategy::DoubleHashing,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(tmp_dir.path().join("db"), [0; 32], None, options).unwrap();
+        assert_eq!(db.expected_probe_length().unwrap(), 1.0);
+
+        let threshold = db.slots_in_sector() * db.options.early_sector_percent / 100 - 1;
+        let mut last_estimate = 1.0;
+        for i in 0..threshold {
+            db.ht_set(i.to_le_bytes().to_vec(), 1).unwrap();
+            if (i + 1) % 1000 == 0 {
+                let estimate = db.expected_probe_length().unwrap();
+                assert!(estimate > last_estimate);
+                last_estimate = estimate;
+            }
+        }
+        assert_eq!(db.sector_occupancies().unwrap(), vec![threshold]);
+        // At 80% load the formula gives about 2 probes, fewer than linear probing's 3
+        assert!((db.expected_probe_length().unwrap() - 5f64.ln() / 0.8).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_sanity_db_byte_order() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("db");
+        let mut db = HashTable::new(path.clone(), [0; 32], None).unwrap();
+        db.set(vec![1, 2, 3], vec![4, 5, 6]).unwrap();
+        db.flush_changes().unwrap();
+        drop(db);
+
+        let write_mark = |mark: [u8; 8]| {
+            let mut file = open_file(&path).unwrap();
+            file.seek(SeekFrom::Start(BYTE_ORDER_OFFSET)).unwrap();
+            file.write_all(&mark).unwrap();
+        };
+
+        // A file written with big-endian integers
+        write_mark(BYTE_ORDER_MARK.to_be_bytes());
+        assert!(matches!(
+            HashTable::new(path.clone(), [0; 32], None),
+            Err(StoreError::ByteOrderMismatch)
+        ));
+
+        // A file created before the marker gets it on the next flush
+        write_mark([0; 8]);
+        let mut db = HashTable::new(path.clone(), [0; 32], None).unwrap();
+        assert_eq!(db.get(vec![1, 2, 3]).unwrap(), Some(vec![4, 5, 6]));
+        db.flush_changes().unwrap();
+        drop(db);
+        let mut file = open_file(&path).unwrap();
+        let mut mark = [0u8; 8];
+        file.seek(SeekFrom::Start(BYTE_ORDER_OFFSET)).unwrap();
+        file.read_exact(&mut mark).unwrap();
+        assert_eq!(mark, BYTE_ORDER_MARK.to_le_bytes());
+    }
+
+    #[test]
+    fn test_sanity_db_store_keys() {
+        let header = ValueHeader::new([7; HASH_LEN], 300)
+            .with_seq(5)
+            .with_key_len(100);
+        assert_eq!(header.len, 34 + 8 + 4 + 100 + 300);
+        assert_eq!(header.payload_len(), 300);
+        assert_eq!(ValueHeader::parse(&header.encode()), header);
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("db");
+        let mut db = HashTable::new(path.clone(), [0; 32], None).unwrap();
+        db.set(b"legacy".to_vec(), vec![1; 10]).unwrap();
+        db.flush_changes().unwrap();
+        drop(db);
+
+        let options = Options {
+            store_keys: true,
+            ..Options::default()
+        };
+        let mut db = HashTable::new_with_options(path, [0; 32], None, options).unwrap();
+        assert_eq!(db.get(b"legacy".to_vec()).unwrap(), Some(vec![1; 10]));
+        assert!(matches!(
+            db.iter_keys().unwrap().next(),
+            Some(Err(StoreError::KeyNotStored { offset: 0 }))
+        ));
+        db.delete(b"legacy".to_vec()).unwrap();
+
+        let mut expected = HashSet::new();
+        for i in 0u64..20000 {
+            // Keys long enough to span several slots
+            let key = vec![i as u8; (i % 300) as usize]
+                .into_iter()
+                .chain(i.to_le_bytes())
+                .collect::<Vec<_>>();
+            db.set(key.clone(), vec![i as u8; (i % 200) as usize])
+                .unwrap();
+            expected.insert(key);
+        }
+        // Delete and overwrite some of the keys, which also compacts the values
+        for i in 0u64..5000 {
+            let key = expected.iter().next().unwrap().clone();
+            if i % 2 == 0 {
+                db.delete(key.clone()).unwrap();
+                expected.remove(&key);
+            } else {
+                db.set(key, vec![]).unwrap();
+            }
+        }
+
+        let keys = db
+            .iter_keys()
+            .unwrap()
+            .collect::<Result<HashSet<_>, _>>()
+            .unwrap();
+        assert_eq!(keys, expected);
+        for key in keys {
+            assert!(db.get(key).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn test_sanity_db_value_arenas() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("db");
+        let mut db = HashTable::new(path.clone(), [0; 32], None).unwrap();
+        db.set(b"before".to_vec(), vec![1; 10]).unwrap();
+        // Overwritten by the second arena
+        db.set(1u64.to_le_bytes().to_vec(), vec![1; 10]).unwrap();
+
+        // Big enough for the arenas to span several values sectors
+        const PER_THREAD: u64 = 20000;
+        let arenas = (0..2)
+            .map(|thread_id| db.reserve_value_arena(thread_id, PER_THREAD * 3))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let frontier = db.tx.get_num(&*db.file, NEXT_VALUE_LOGICAL_OFFSET).unwrap();
+
+        let threads = arenas
+            .into_iter()
+            .map(|mut arena| {
+                thread::spawn(move || {
+                    let first = arena.thread_id() as u64 * PER_THREAD;
+                    for i in first..first + PER_THREAD {
+                        arena
+                            .set(i.to_le_bytes().to_vec(), vec![i as u8; (i % 300) as usize])
+                            .unwrap();
+                    }
+                    arena
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut arenas = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect::<Vec<_>>();
+        // The record header doesn't fit either
+        let remaining_bytes = (arenas[0].remaining_slots() * VALUE_SIZE) as usize;
+        assert!(matches!(
+            arenas[0].set(b"big".to_vec(), vec![0; remaining_bytes]),
+            Err(StoreError::ArenaFull { thread_id: 0 })
+        ));
+
+        for arena in arenas {
+            db.finalize_value_arena(arena).unwrap();
+        }
+        // Finalizing doesn't move the frontier, the values written afterwards go after the arenas
+        assert_eq!(
+            db.tx.get_num(&*db.file, NEXT_VALUE_LOGICAL_OFFSET).unwrap(),
+            frontier
+        );
+        db.set(b"after".to_vec(), vec![2; 10]).unwrap();
+        assert_eq!(db.len().unwrap(), 2 * PER_THREAD + 2);
+
+        // Deleting compacts the values through the arenas, including their unused slots
+        for i in 0..PER_THREAD {
+            db.delete(i.to_le_bytes().to_vec()).unwrap();
+        }
+
+        db.flush_changes().unwrap();
+        drop(db);
+        let mut db = HashTable::new(path, [0; 32], None).unwrap();
+        assert_eq!(db.get(b"before".to_vec()).unwrap(), Some(vec![1; 10]));
+        assert_eq!(db.get(b"after".to_vec()).unwrap(), Some(vec![2; 10]));
+        for i in 0..PER_THREAD {
+            assert_eq!(db.get(i.to_le_bytes().to_vec()).unwrap(), None);
+        }
+        for i in PER_THREAD..2 * PER_THREAD {
+            assert_eq!(
+                db.get(i.to_le_bytes().to_vec()).unwrap(),
+                Some(vec![i as u8; (i % 300) as usize])
+            );
+        }
+    }
+
+    #[test]
+    fn test_sanity_db_iter() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let options = Options {
+            store_keys: true,
+            transforms: vec![Arc::new(Xor(0x55))],
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(tmp_dir.path().join("db"), [0; 32], None, options).unwrap();
+
+        let mut rng = rand::thread_rng();
+        let mut expected = HashMap::new();
+        for _ in 0..10000 {
+            let key = (0..rng.gen_range(1..100))
+                .map(|_| rng.gen())
+                .collect::<Vec<u8>>();
+            let value = (0..rng.gen_range(0..500))
+                .map(|_| rng.gen())
+                .collect::<Vec<u8>>();
+            db.set(key.clone(), value.clone()).unwrap();
+            expected.insert(key, value);
+        }
+        let keys = expected.keys().cloned().collect::<Vec<_>>();
+        for key in keys.iter().step_by(2) {
+            db.delete(key.clone()).unwrap();
+            expected.remove(key);
+        }
+
+        let pairs = db
+            .iter()
+            .unwrap()
+            .collect::<Result<HashMap<_, _>, _>>()
+            .unwrap();
+        assert_eq!(pairs.len(), db.len().unwrap() as usize);
+        assert_eq!(pairs, expected);
+    }
+
+    #[test]
+    fn test_sanity_db_locate() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut db = HashTable::new(tmp_dir.path().join("db"), [0; 32], None).unwrap();
+        assert_eq!(db.locate(b"missing".to_vec()).unwrap(), None);
+
+        db.set(b"small".to_vec(), vec![1; 10]).unwrap();
+        db.set(b"large".to_vec(), vec![2; 1000]).unwrap();
+
+        let location = db.locate(b"large".to_vec()).unwrap().unwrap();
+        // Both are in the first and only values sector, right after its prelude
+        let values_sector = *db.values_mapping.get(&0).unwrap();
+        assert_eq!(location.logical_offset, VALUE_SIZE);
+        assert_eq!(location.physical_offset, values_sector + VALUE_SIZE);
+        // 34 bytes of the header and 1000 of the value
+        assert_eq!(location.num_slots, 9);
+        assert_eq!(location.ht_sector_offset, FIRST_SECTOR_OFFSET);
+        assert!(location.ht_slot_offset >= FIRST_SECTOR_OFFSET + FIRST_SLOT_OFFSET);
+        assert!(location.ht_slot_offset < FIRST_SECTOR_OFFSET + DEFAULT_SECTOR_SIZE);
+
+        // The reported slot and offsets point at the record
+        let slot = db
+            .tx
+            .get(&*db.file, location.ht_slot_offset, db.layout.slot_size())
+            .unwrap();
+        assert_eq!(db.layout.extract_value(&slot), location.logical_offset + 1);
+        let first_slot = db
+            .tx
+            .get(&*db.file, location.physical_offset, VALUE_SIZE)
+            .unwrap();
+        assert_eq!(ValueHeader::parse(&first_slot).hash, db.get_hash(b"large"));
+        let sector_of = |offset: u64| {
+            FIRST_SECTOR_OFFSET
+                + (offset - FIRST_SECTOR_OFFSET) / DEFAULT_SECTOR_SIZE * DEFAULT_SECTOR_SIZE
+        };
+        let page_type = |db: &mut HashTable, offset: u64| {
+            db.tx.get_num(&*db.file, sector_of(offset) + 48).unwrap()
+        };
+        assert_eq!(
+            page_type(&mut db, location.physical_offset),
+            PAGE_TYPE_VALUES
+        );
+        // The first slot of the record is the second bit of the first delmap entry
+        let delmap_offset = location.delmap_offset.unwrap();
+        assert_eq!(page_type(&mut db, delmap_offset), PAGE_TYPE_DELMAP);
+        let delmap_sector = *db.delmap_mapping.get(&0).unwrap();
+        assert_eq!(delmap_offset, delmap_sector);
+        let delmap_entry = db
+            .tx
+            .get(&*db.file, delmap_offset, DELMAP_ENTRY_SIZE)
+            .unwrap();
+        assert_eq!(delmap_entry[0] & 0b10, 0b10);
+
+        let small = db.locate(b"small".to_vec()).unwrap().unwrap();
+        assert_eq!(small.logical_offset, 0);
+        assert_eq!(small.num_slots, 1);
+    }
+
+    #[test]
+    fn test_sanity_db_auto_recover() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("db");
+        let mut db = HashTable::new(path.clone(), [0; 32], None).unwrap();
+        let mut map = HashMap::new();
+        for i in 0..3000u32 {
+            let key = i.to_le_bytes().to_vec();
+            let value = vec![i as u8; 1000];
+            db.set(key.clone(), value.clone()).unwrap();
+            map.insert(key, value);
+        }
+        db.flush_changes().unwrap();
+        drop(db);
+
+        let mut file = open_file(&path).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(&12345u64.to_le_bytes()).unwrap();
+        file.seek(SeekFrom::Start(FREE_LIST_OFFSET)).unwrap();
+        file.write_all(&7u64.to_le_bytes()).unwrap();
+        drop(file);
+
+        assert!(matches!(
+            HashTable::new(path.clone(), [0; 32], None),
+            Err(StoreError::Corruption { offset: 0, .. })
+        ));
+
+        let options = Options {
+            auto_recover: true,
+            ..Options::default()
+        };
+        let mut db = HashTable::new_with_options(path.clone(), [0; 32], None, options).unwrap();
+        assert_eq!(db.repairs().len(), 2);
+        for (key, value) in map.iter() {
+            assert_eq!(db.get(key.clone()).unwrap().as_ref(), Some(value));
+        }
+        db.set(b"after".to_vec(), b"recovery".to_vec()).unwrap();
+        db.flush_changes().unwrap();
+        drop(db);
+
+        // The repairs were persisted, so the file now opens without the flag
+        let mut db = HashTable::new(path, [0; 32], None).unwrap();
+        assert!(db.repairs().is_empty());
+        assert_eq!(db.len().unwrap(), map.len() as u64 + 1);
+        assert_eq!(
+            db.get(b"after".to_vec()).unwrap(),
+            Some(b"recovery".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_sanity_db_sector_size() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("db");
+        let options = Options {
+            sector_size: 2 * PAGE_SIZE,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(path.clone(), [0; 32], None, options.clone()).unwrap();
+        db.flush_changes().unwrap();
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            FIRST_SECTOR_OFFSET + 2 * PAGE_SIZE
+        );
+
+        // Enough to resize the hash table sectors, and to wrap the values and the delmap sectors
+        // many times as the overwrites get compacted
+        let mut rng = rand::thread_rng();
+        let mut map = HashMap::new();
+        for i in 0..20000u32 {
+            let key = (i % 2000).to_le_bytes().to_vec();
+            let value = vec![i as u8; rng.gen_range(0..300)];
+            db.set(key.clone(), value.clone()).unwrap();
+            map.insert(key, value);
+        }
+        db.flush_changes().unwrap();
+        drop(db);
+
+        let mut db = HashTable::new_with_options(path.clone(), [0; 32], None, options).unwrap();
+        assert_eq!(db.len().unwrap(), map.len() as u64);
+        for (key, value) in map.iter() {
+            assert_eq!(db.get(key.clone()).unwrap().as_ref(), Some(value));
+        }
+        drop(db);
+
+        assert!(matches!(
+            HashTable::new(path, [0; 32], None),
+            Err(StoreError::SectorSizeMismatch {
+                configured: DEFAULT_SECTOR_SIZE,
+                stored: 8192,
+            })
+        ));
+    }
+
+    #[test]
+    fn test_sanity_db_flush_threads() {
+        let changes = (0..10000u64)
+            .map(|i| (FIRST_SECTOR_OFFSET + i * 1000, vec![0; 8]))
+            .collect::<Vec<_>>();
+        let groups = TableTransaction::split_changes(changes, 8, DEFAULT_SECTOR_SIZE);
+        assert!(groups.len() > 1 && groups.len() <= 8);
+        for pair in groups.windows(2) {
+            let last = pair[0].last().unwrap().0;
+            let first = pair[1][0].0;
+            assert!(
+                (last - FIRST_SECTOR_OFFSET) / DEFAULT_SECTOR_SIZE
+                    < (first - FIRST_SECTOR_OFFSET) / DEFAULT_SECTOR_SIZE
+            );
+        }
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        let write = |name: &str, flush_threads: usize| {
+            let path = tmp_dir.path().join(name);
+            let options = Options {
+                flush_threads,
+                ..Options::default()
+            };
+            let mut db = HashTable::new_with_options(path.clone(), [0; 32], None, options).unwrap();
+            let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+            for _ in 0..100000 {
+                let key = rng.gen::<u32>().to_le_bytes().to_vec();
+                let value = vec![rng.gen::<u8>(); rng.gen_range(0..200)];
+                db.set(key, value).unwrap();
+            }
+            db.flush_changes().unwrap();
+            std::fs::read(path).unwrap()
+        };
+        assert!(write("single", 1) == write("parallel", 8));
+    }
+
+    #[test]
+    fn test_sanity_db_subscribe() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut db = HashTable::new(tmp_dir.path().join("db"), [0; 32], None).unwrap();
+        db.set(b"before".to_vec(), b"subscribing".to_vec()).unwrap();
+        let receiver = db.subscribe();
+        let consumer = thread::spawn(move || receiver.into_iter().collect::<Vec<_>>());
+
+        db.set(b"a".to_vec(), b"1".to_vec()).unwrap();
+        db.set(b"b".to_vec(), b"2".to_vec()).unwrap();
+        db.flush_changes().unwrap();
+        db.set(b"a".to_vec(), b"3".to_vec()).unwrap();
+        db.delete(b"b".to_vec()).unwrap();
+        db.delete(b"missing".to_vec()).unwrap();
+        db.flush_changes().unwrap();
+        db.clear().unwrap();
+        // Not flushed, so not reported
+        db.set(b"c".to_vec(), b"4".to_vec()).unwrap();
+        db.flush_changes().unwrap();
+        db.set(b"d".to_vec(), b"5".to_vec()).unwrap();
+        drop(db);
+
+        let set = |key: &[u8], value: &[u8]| Mutation::Set {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        };
+        assert_eq!(
+            consumer.join().unwrap(),
+            vec![
+                set(b"a", b"1"),
+                set(b"b", b"2"),
+                set(b"a", b"3"),
+                Mutation::Delete { key: b"b".to_vec() },
+                Mutation::Clear,
+                set(b"c", b"4"),
+            ]
+        );
+
+        // A subscriber that doesn't keep up gets disconnected rather than blocking the writer
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut db = HashTable::new(tmp_dir.path().join("db"), [0; 32], None).unwrap();
+        let receiver = db.subscribe();
+        for i in 0..SUBSCRIBER_CHANNEL_CAPACITY as u32 + 1 {
+            db.set(i.to_le_bytes().to_vec(), vec![]).unwrap();
+        }
+        db.flush_changes().unwrap();
+        assert_eq!(receiver.iter().count(), SUBSCRIBER_CHANNEL_CAPACITY);
+    }
+
+    #[test]
+    fn test_sanity_db_flush_threads_same_page() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("db");
+        HashTable::new(path.clone(), [0; 32], None)
+            .unwrap()
+            .flush_changes()
+            .unwrap();
+
+        // Splitting the changes in half would put the middle two, which are 8 bytes apart in the
+        // same page, into different threads. The changes past them are in the next sector, so
+        // that the split is made right after them instead.
+        let options = Options {
+            flush_threads: 2,
+            ..Options::default()
+        };
+        let num_changes = 2 * MIN_CHANGES_PER_FLUSH_THREAD as u64;
+        let middle = num_changes / 2;
+        let offset = |i: u64| {
+            if i <= middle {
+                FIRST_SECTOR_OFFSET + 8 + 8 * i
+            } else {
+                FIRST_SECTOR_OFFSET + options.sector_size + 8 * (i - middle)
+            }
+        };
+        assert_eq!(offset(middle - 1) / PAGE_SIZE, offset(middle) / PAGE_SIZE);
+
+        let mut tx = TableTransaction::new();
+        for i in 0..num_changes {
+            tx.set(offset(i), (i + 1).to_le_bytes().to_vec());
+        }
+        let groups = TableTransaction::split_changes(
+            tx.changes.clone().into_iter().collect(),
+            options.flush_threads,
+            options.sector_size,
+        );
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].len() as u64, middle + 1);
+        assert_eq!(groups[0].last().unwrap().0, offset(middle));
+        assert_eq!(groups[1][0].0, offset(middle + 1));
+        let file: Arc<dyn BlockDevice> = Arc::new(open_file(&path).unwrap());
+        file.set_len(FIRST_SECTOR_OFFSET + 2 * options.sector_size)
+            .unwrap();
+        tx.flush_changes(&file, &path, &options, options.durability)
+            .unwrap();
+
+        let file = open_file(&path).unwrap();
+        for i in 0..num_changes {
+            assert_eq!(tx.get_num(&file, offset(i)).unwrap(), i + 1);
+        }
+    }
+
+    #[test]
+    fn test_sanity_db_read_only() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("db");
+        assert!(matches!(
+            HashTable::open_read_only(path.clone(), [0; 32]),
+            Err(StoreError::Io { .. })
+        ));
+
+        let mut db = HashTable::new(path.clone(), [0; 32], None).unwrap();
+        for i in 0..1000u32 {
+            db.set(i.to_le_bytes().to_vec(), vec![i as u8; 100])
+                .unwrap();
+        }
+        db.flush_changes().unwrap();
+        drop(db);
+
+        let mut permissions = std::fs::metadata(&path).unwrap().permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&path, permissions).unwrap();
+        let contents = std::fs::read(&path).unwrap();
+
+        let mut db = HashTable::open_read_only(path.clone(), [0; 32]).unwrap();
+        for i in 0..1000u32 {
+            assert_eq!(
+                db.get(i.to_le_bytes().to_vec()).unwrap(),
+                Some(vec![i as u8; 100])
+            );
+        }
+        assert!(db.ht_get(0u32.to_le_bytes().to_vec()).unwrap().is_some());
+        assert!(matches!(
+            db.set(b"new".to_vec(), vec![]),
+            Err(StoreError::ReadOnly)
+        ));
+        assert!(matches!(
+            db.delete(0u32.to_le_bytes().to_vec()),
+            Err(StoreError::ReadOnly)
+        ));
+        assert!(matches!(db.flush_changes(), Err(StoreError::ReadOnly)));
+        drop(db);
+        assert!(std::fs::read(&path).unwrap() == contents);
+    }
+
+    #[test]
+    fn test_sanity_db_wal_checksum() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("db");
+        let wal_path = tmp_dir.path().join("wal");
+        let mut db = HashTable::new(path.clone(), [0; 32], None).unwrap();
+        db.set(b"committed".to_vec(), b"1".to_vec()).unwrap();
+        db.flush_changes().unwrap();
+        db.set(b"logged".to_vec(), b"2".to_vec()).unwrap();
+        db.write_to_log(&mut File::create(&wal_path).unwrap())
+            .unwrap();
+        drop(db);
+
+        let mut wal = std::fs::read(&wal_path).unwrap();
+        let middle = wal.len() / 2;
+        wal[middle] ^= 1;
+        std::fs::write(&wal_path, &wal).unwrap();
+        let mut db = HashTable::new(
+            path.clone(),
+            [0; 32],
+            Some(&mut File::open(&wal_path).unwrap()),
+        )
+        .unwrap();
+        assert_eq!(db.get(b"committed".to_vec()).unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"logged".to_vec()).unwrap(), None);
+        drop(db);
+
+        // The intact log is replayed
+        wal[middle] ^= 1;
+        std::fs::write(&wal_path, &wal).unwrap();
+        let mut db =
+            HashTable::new(path, [0; 32], Some(&mut File::open(&wal_path).unwrap())).unwrap();
+        assert_eq!(db.get(b"committed".to_vec()).unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"logged".to_vec()).unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn test_sanity_db_recover() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("db");
+        let wal_path = tmp_dir.path().join("wal");
+        let mut db = HashTable::open(path.clone(), [0; 32]).unwrap();
+        db.set(b"committed".to_vec(), b"1".to_vec()).unwrap();
+        db.flush_changes().unwrap();
+        db.set(b"logged".to_vec(), b"2".to_vec()).unwrap();
+        let records = db.tx.changes.len() as u64;
+        let start = *db.tx.changes.keys().next().unwrap();
+        let (last, data) = db.tx.changes.iter().next_back().unwrap();
+        let end = last + data.len() as u64;
+        db.write_to_log(&mut File::create(&wal_path).unwrap())
+            .unwrap();
+        drop(db);
+        let contents = std::fs::read(&path).unwrap();
+
+        let (mut db, report) =
+            HashTable::recover(path.clone(), [0; 32], &mut File::open(&wal_path).unwrap()).unwrap();
+        assert_eq!(
+            report,
+            RecoveryReport {
+                valid: true,
+                records,
+                offsets: start..end,
+            }
+        );
+        // The changes are pending, not written
+        assert_eq!(db.get(b"logged".to_vec()).unwrap(), Some(b"2".to_vec()));
+        drop(db);
+        assert!(std::fs::read(&path).unwrap() == contents);
+
+        let (mut db, _) =
+            HashTable::recover(path.clone(), [0; 32], &mut File::open(&wal_path).unwrap()).unwrap();
+        db.flush_changes().unwrap();
+        drop(db);
+        let mut db = HashTable::open(path, [0; 32]).unwrap();
+        assert_eq!(db.get(b"committed".to_vec()).unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"logged".to_vec()).unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn test_sanity_db_recover_corrupt_wal() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("db");
+        let wal_path = tmp_dir.path().join("wal");
+        let mut db = HashTable::open(path.clone(), [0; 32]).unwrap();
+        db.set(b"committed".to_vec(), b"1".to_vec()).unwrap();
+        db.flush_changes().unwrap();
+        db.set(b"logged".to_vec(), b"2".to_vec()).unwrap();
+        db.write_to_log(&mut File::create(&wal_path).unwrap())
+            .unwrap();
+        drop(db);
+        let contents = std::fs::read(&path).unwrap();
+
+        let mut wal = std::fs::read(&wal_path).unwrap();
+        let middle = wal.len() / 2;
+        wal[middle] ^= 1;
+        std::fs::write(&wal_path, &wal).unwrap();
+        let (mut db, report) =
+            HashTable::recover(path.clone(), [0; 32], &mut File::open(&wal_path).unwrap()).unwrap();
+        assert_eq!(report, RecoveryReport::default());
+        assert!(!report.valid);
+        assert_eq!(db.get(b"committed".to_vec()).unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"logged".to_vec()).unwrap(), None);
+        db.flush_changes().unwrap();
+        drop(db);
+        assert!(std::fs::read(&path).unwrap() == contents);
+    }
+
+    #[test]
+    fn test_sanity_db_wal_past_end() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("db");
+        let wal_path = tmp_dir.path().join("wal");
+        let mut db = HashTable::new(path.clone(), [0; 32], None).unwrap();
+        db.set(b"committed".to_vec(), b"1".to_vec()).unwrap();
+        db.flush_changes().unwrap();
+        db.set(b"logged".to_vec(), b"2".to_vec()).unwrap();
+        let file_len = db.file.len().unwrap();
+        db.tx.set(file_len + DEFAULT_SECTOR_SIZE, vec![1; 8]);
+        db.write_to_log(&mut File::create(&wal_path).unwrap())
+            .unwrap();
+        drop(db);
+        let contents = std::fs::read(&path).unwrap();
+
+        let result = HashTable::new(
+            path.clone(),
+            [0; 32],
+            Some(&mut File::open(&wal_path).unwrap()),
+        );
+        assert!(matches!(
+            result,
+            Err(StoreError::Corruption { offset, .. }) if offset == file_len + DEFAULT_SECTOR_SIZE + 8
+        ));
+        assert!(matches!(
+            HashTable::recover(path.clone(), [0; 32], &mut File::open(&wal_path).unwrap()),
+            Err(StoreError::Corruption { .. })
+        ));
+        assert!(std::fs::read(&path).unwrap() == contents);
+        let mut db = HashTable::new(path, [0; 32], None).unwrap();
+        assert_eq!(db.get(b"committed".to_vec()).unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"logged".to_vec()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_sanity_db_delete_by_hash() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let options = Options {
+            hash_len: 20,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(tmp_dir.path().join("db"), [0; 32], None, options).unwrap();
+        for i in 0..100u32 {
+            db.set(i.to_le_bytes().to_vec(), vec![i as u8; 200])
+                .unwrap();
+        }
+        let key = 7u32.to_le_bytes().to_vec();
+        // The full hash, which the table truncates
+        let hash = db.options.hasher.hash(&db.salt, &key);
+        assert_ne!(db.seek(db.get_hash(&key)).unwrap().1, NO_VALUE);
+
+        assert!(db.delete_by_hash(hash).unwrap());
+        assert_eq!(db.get(key.clone()).unwrap(), None);
+        assert_eq!(db.seek(db.get_hash(&key)).unwrap().1, NO_VALUE);
+        assert!(!db.delete_by_hash(hash).unwrap());
+        assert_eq!(db.len().unwrap(), 99);
+        assert_eq!(
+            db.get(8u32.to_le_bytes().to_vec()).unwrap(),
+            Some(vec![8; 200])
+        );
+        assert!(db.verify().unwrap().is_consistent());
+    }
+
+    #[test]
+    fn test_sanity_db_replace() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut db = HashTable::new(tmp_dir.path().join("db"), [0; 32], None).unwrap();
+        assert_eq!(db.replace(b"key".to_vec(), vec![1; 100]).unwrap(), None);
+        // Overwritten in place, and then moved to a larger record
+        assert_eq!(
+            db.replace(b"key".to_vec(), vec![2; 100]).unwrap(),
+            Some(vec![1; 100])
+        );
+        assert_eq!(
+            db.replace(b"key".to_vec(), vec![3; 1000]).unwrap(),
+            Some(vec![2; 100])
+        );
+        assert_eq!(db.get(b"key".to_vec()).unwrap(), Some(vec![3; 1000]));
+        db.delete(b"key".to_vec()).unwrap();
+        assert_eq!(db.replace(b"key".to_vec(), vec![4]).unwrap(), None);
+        assert_eq!(db.len().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_sanity_db_wal_commit() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("db");
+        let wal = WalConfig {
+            path: tmp_dir.path().join("wal"),
+            sync: Durability::Full,
+        };
+        let open = || {
+            HashTable::new_with_wal(path.clone(), [0; 32], wal.clone(), Options::default()).unwrap()
+        };
+        // Only the steps of `commit` up to the crash
+        let crash_after_log = |mut db: HashTable| {
+            let (mut wal_file, wal) = db.wal.take().unwrap();
+            db.log_changes(&mut wal_file, wal.sync).unwrap();
+            db.wal = Some((wal_file, wal));
+            db
+        };
+
+        let mut db = open();
+        db.set(b"a".to_vec(), b"1".to_vec()).unwrap();
+        db.commit().unwrap();
+        // A sync of the log, one of the database, and one of the log reset
+        assert!(db.tx.syncs >= 3);
+        assert_eq!(std::fs::metadata(&wal.path).unwrap().len(), 8 + 8 + 32);
+
+        // The crash happens before the flush
+        db.set(b"b".to_vec(), b"2".to_vec()).unwrap();
+        drop(crash_after_log(db));
+        let mut db = open();
+        assert_eq!(db.get(b"a".to_vec()).unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"b".to_vec()).unwrap(), Some(b"2".to_vec()));
+        assert_eq!(std::fs::metadata(&wal.path).unwrap().len(), 8 + 8 + 32);
+
+        // The crash happens after the flush, but before the log is reset
+        db.set(b"c".to_vec(), b"3".to_vec()).unwrap();
+        let mut db = crash_after_log(db);
+        db.flush_changes().unwrap();
+        drop(db);
+        let mut db = open();
+        assert_eq!(db.get(b"b".to_vec()).unwrap(), Some(b"2".to_vec()));
+        assert_eq!(db.get(b"c".to_vec()).unwrap(), Some(b"3".to_vec()));
+
+        // The crash tears the log, so the commit never happened
+        db.set(b"d".to_vec(), b"4".to_vec()).unwrap();
+        drop(crash_after_log(db));
+        let wal_len = std::fs::metadata(&wal.path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&wal.path)
+            .unwrap()
+            .set_len(wal_len - 1)
+            .unwrap();
+        let mut db = open();
+        assert_eq!(db.get(b"c".to_vec()).unwrap(), Some(b"3".to_vec()));
+        assert_eq!(db.get(b"d".to_vec()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_sanity_db_checkpoint() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("db");
+        let wal_path = tmp_dir.path().join("wal");
+        let mut wal = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(&wal_path)
+            .unwrap();
+        let mut db = HashTable::new(path.clone(), [0; 32], None).unwrap();
+        db.set(b"key".to_vec(), b"old".to_vec()).unwrap();
+        db.write_to_log(&mut wal).unwrap();
+        db.checkpoint(&mut wal).unwrap();
+        let empty_len = wal.metadata().unwrap().len();
+
+        // Replaying the log written before the checkpoint would bring the old value back
+        db.set(b"key".to_vec(), b"new".to_vec()).unwrap();
+        db.flush_changes().unwrap();
+        drop(db);
+
+        let mut db =
+            HashTable::new(path, [0; 32], Some(&mut File::open(&wal_path).unwrap())).unwrap();
+        assert_eq!(db.get(b"key".to_vec()).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(empty_len, 8 + 8 + 32);
+    }
+
+    #[test]
+    fn test_sanity_db_durability() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let wal_path = tmp_dir.path().join("wal");
+        for (durability, flush_threads) in [
+            (Durability::None, 1),
+            (Durability::Data, 1),
+            (Durability::Full, 4),
+        ] {
+            let options = Options {
+                durability,
+                flush_threads,
+                ..Options::default()
+            };
+            let path = tmp_dir.path().join(format!("{:?}", durability));
+            let mut db = HashTable::new_with_options(path, [0; 32], None, options).unwrap();
+            for i in 0..100000u32 {
+                db.set(i.to_le_bytes().to_vec(), vec![]).unwrap();
+            }
+            db.write_to_log(&mut File::create(&wal_path).unwrap())
+                .unwrap();
+            let log_syncs = db.tx.syncs;
+            db.flush_changes().unwrap();
+            let flush_syncs = db.tx.syncs - log_syncs;
+
+            if durability == Durability::None {
+                assert_eq!((log_syncs, flush_syncs), (0, 0));
+            } else {
+                // One sync of the log, and one of every file handle the flush wrote through
+                assert_eq!(log_syncs, 1);
+                assert!((1..=flush_threads as u64).contains(&flush_syncs));
+            }
+        }
+        assert_eq!(Options::default().durability, Durability::None);
+    }
+
+    #[test]
+    fn test_sanity_db_compare_and_swap() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut db = HashTable::new(tmp_dir.path().join("db"), [0; 32], None).unwrap();
+        let key = b"seq".to_vec();
+
+        // The key is absent
+        assert!(!db
+            .compare_and_swap(key.clone(), Some(b"0".to_vec()), b"1".to_vec())
+            .unwrap());
+        assert_eq!(db.get(key.clone()).unwrap(), None);
+        assert!(db
+            .compare_and_swap(key.clone(), None, b"1".to_vec())
+            .unwrap());
+        assert_eq!(db.get(key.clone()).unwrap(), Some(b"1".to_vec()));
+
+        // The key is present
+        assert!(!db
+            .compare_and_swap(key.clone(), None, b"2".to_vec())
+            .unwrap());
+        assert!(!db
+            .compare_and_swap(key.clone(), Some(b"0".to_vec()), b"2".to_vec())
+            .unwrap());
+        assert_eq!(db.get(key.clone()).unwrap(), Some(b"1".to_vec()));
+        assert!(db
+            .compare_and_swap(key.clone(), Some(b"1".to_vec()), b"2".to_vec())
+            .unwrap());
+        assert_eq!(db.get(key.clone()).unwrap(), Some(b"2".to_vec()));
+        assert_eq!(db.len().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_sanity_db_set_batch() {
+        use rand::seq::SliceRandom;
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        let options = Options {
+            store_seq: true,
+            ..Options::default()
+        };
+        let mut batched = HashTable::new_with_options(
+            tmp_dir.path().join("batched"),
+            [0; 32],
+            None,
+            options.clone(),
+        )
+        .unwrap();
+        let mut sequential =
+            HashTable::new_with_options(tmp_dir.path().join("sequential"), [0; 32], None, options)
+                .unwrap();
+
+        let mut rng = rand::thread_rng();
+        let mut keys = vec![];
+        for round in 0..3u32 {
+            // Every round overwrites some of the keys of the previous ones, and has duplicates
+            let mut pairs = (0..20000u32)
+                .map(|i| {
+                    let key = (round * 10000 + i % 15000).to_le_bytes().to_vec();
+                    (key, vec![rng.gen::<u8>(); rng.gen_range(0..300)])
+                })
+                .collect::<Vec<_>>();
+            pairs.shuffle(&mut rng);
+
+            for (key, value) in pairs.iter() {
+                sequential.set(key.clone(), value.clone()).unwrap();
+                keys.push(key.clone());
+            }
+            batched.set_batch(pairs).unwrap();
+        }
+
+        assert_eq!(batched.len().unwrap(), sequential.len().unwrap());
+        for key in keys {
+            assert_eq!(
+                batched.get_with_seq(key.clone()).unwrap(),
+                sequential.get_with_seq(key).unwrap()
+            );
+        }
+        batched.flush_changes().unwrap();
+        sequential.flush_changes().unwrap();
+        drop(batched);
+        drop(sequential);
+        assert!(
+            std::fs::read(tmp_dir.path().join("batched")).unwrap()
+                == std::fs::read(tmp_dir.path().join("sequential")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_sanity_db_get_many() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut db = HashTable::new(tmp_dir.path().join("db"), [0; 32], None).unwrap();
+        let mut rng = rand::thread_rng();
+        for i in 0..5000u32 {
+            db.set(
+                i.to_le_bytes().to_vec(),
+                vec![i as u8; rng.gen_range(0..500)],
+            )
+            .unwrap();
+        }
+        for i in (0..5000u32).step_by(7) {
+            db.delete(i.to_le_bytes().to_vec()).unwrap();
+        }
+
+        // Includes missing keys, and the same key twice
+        let keys = (0..200)
+            .map(|_| rng.gen_range(0..6000u32).to_le_bytes().to_vec())
+            .chain([vec![], 1u32.to_le_bytes().to_vec()])
+            .collect::<Vec<_>>();
+        let values = db.get_many(&keys).unwrap();
+        assert_eq!(values.len(), keys.len());
+        for (key, value) in keys.into_iter().zip(values) {
+            assert_eq!(value, db.get(key).unwrap());
+        }
+        assert!(db.get_many(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sanity_db_stats() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut db = HashTable::new(tmp_dir.path().join("db"), [0; 32], None).unwrap();
+        let stats = db.stats().unwrap();
+        assert_eq!(
+            (
+                stats.num_ht_sectors,
+                stats.num_value_sectors,
+                stats.num_delmap_sectors,
+                stats.num_free_sectors
+            ),
+            (1, 0, 0, 0)
+        );
+        assert_eq!(stats.file_size, FIRST_SECTOR_OFFSET + DEFAULT_SECTOR_SIZE);
+
+        // Every empty value takes a single slot, so this spills into a second values sector
+        let values_in_sector = (DEFAULT_SECTOR_SIZE - VALUE_SIZE) / VALUE_SIZE;
+        for i in 0..values_in_sector + 10 {
+            db.set(i.to_le_bytes().to_vec(), vec![]).unwrap();
+        }
+        let stats = db.stats().unwrap();
+        assert_eq!(
+            (
+                stats.num_ht_sectors,
+                stats.num_value_sectors,
+                stats.num_delmap_sectors,
+                stats.num_free_sectors
+            ),
+            (1, 2, 1, 0)
+        );
+        assert_eq!(
+            stats.file_size,
+            FIRST_SECTOR_OFFSET + 4 * DEFAULT_SECTOR_SIZE
+        );
+        assert_eq!(stats.first_value_logical, 0);
+        assert_eq!(
+            stats.next_value_logical,
+            (values_in_sector + 10) * VALUE_SIZE
+        );
+        assert_eq!(stats.live_bytes_estimate, stats.next_value_logical);
+
+        db.clear().unwrap();
+        let stats = db.stats().unwrap();
+        assert_eq!(
+            (
+                stats.num_ht_sectors,
+                stats.num_value_sectors,
+                stats.num_delmap_sectors,
+                stats.num_free_sectors
+            ),
+            (1, 0, 0, 3)
+        );
+        assert_eq!(
+            stats.file_size,
+            FIRST_SECTOR_OFFSET + 4 * DEFAULT_SECTOR_SIZE
+        );
+    }
+
+    #[test]
+    fn test_sanity_db_sectors() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut db = HashTable::new(tmp_dir.path().join("db"), [0; 32], None).unwrap();
+        let sectors = |db: &mut HashTable| {
+            db.sectors()
+                .unwrap()
+                .map(|sector| {
+                    let sector = sector.unwrap();
+                    (sector.page_type, sector.occupancy_or_logical_start)
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sectors(&mut db), vec![(PageType::HashTable, 0)]);
+
+        // Same workload as in `test_sanity_db_stats`
+        let values_in_sector = (DEFAULT_SECTOR_SIZE - VALUE_SIZE) / VALUE_SIZE;
+        let num_keys = values_in_sector + 10;
+        for i in 0..num_keys {
+            db.set(i.to_le_bytes().to_vec(), vec![]).unwrap();
+        }
+        let mut found = sectors(&mut db);
+        found.sort_by_key(|&(page_type, start)| (page_type as u8, start));
+        assert_eq!(
+            found,
+            vec![
+                (PageType::HashTable, num_keys),
+                (PageType::Values, 0),
+                (PageType::Values, values_in_sector * VALUE_SIZE),
+                (PageType::Delmap, 0),
+            ]
+        );
+        let offsets = db
+            .sectors()
+            .unwrap()
+            .map(|sector| sector.unwrap().physical_offset)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            offsets,
+            (0..4)
+                .map(|i| FIRST_SECTOR_OFFSET + i * DEFAULT_SECTOR_SIZE)
+                .collect::<Vec<_>>()
+        );
+
+        db.clear().unwrap();
+        let mut found = sectors(&mut db);
+        found.sort_by_key(|&(page_type, start)| (page_type as u8, start));
+        assert_eq!(
+            found,
+            vec![
+                (PageType::Free, 0),
+                (PageType::Free, 0),
+                (PageType::Free, 0),
+                (PageType::HashTable, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sanity_db_peek_header() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("db");
+        let mut db = HashTable::new(path.clone(), [0; 32], None).unwrap();
+        for i in 0..1000u32 {
+            db.set(i.to_le_bytes().to_vec(), vec![i as u8; i as usize % 300])
+                .unwrap();
+        }
+        for i in 0..100u32 {
+            db.delete((i * 3).to_le_bytes().to_vec()).unwrap();
+        }
+        db.flush_changes().unwrap();
+        let stats = db.stats().unwrap();
+        let len = db.len().unwrap();
+        drop(db);
+
+        let mut permissions = std::fs::metadata(&path).unwrap().permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&path, permissions).unwrap();
+        let header = HashTable::peek_header(&path).unwrap();
+        assert_eq!(
+            header,
+            HeaderInfo {
+                file_size: stats.file_size,
+                next_value_logical: stats.next_value_logical,
+                first_value_logical: stats.first_value_logical,
+                len,
+            }
+        );
+        assert_eq!(header.len, 900);
+        let mut db = HashTable::open_read_only(path, [0; 32]).unwrap();
+        assert_eq!(db.len().unwrap(), header.len);
+    }
+
+    #[test]
+    fn test_sanity_db_ht_sector_occupancy() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut db = HashTable::new(tmp_dir.path().join("db"), [0; 32], None).unwrap();
+        let capacity = db.slots_in_sector();
+        assert_eq!(
+            db.ht_sector_occupancy().unwrap(),
+            vec![([0; HASH_LEN], 0, capacity)]
+        );
+
+        let num_keys = capacity;
+        for i in 0..num_keys {
+            db.ht_set(i.to_le_bytes().to_vec(), 1).unwrap();
+        }
+        let occupancy = db.ht_sector_occupancy().unwrap();
+        assert!(occupancy.len() > 1);
+        assert_eq!(occupancy[0].0, [0; HASH_LEN]);
+        assert!(occupancy.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        for &(_, occ, sector_capacity) in occupancy.iter() {
+            assert_eq!(sector_capacity, capacity);
+            assert!(occ < capacity * db.options.max_sector_percent / 100);
+        }
+        assert_eq!(
+            occupancy.iter().map(|&(_, occ, _)| occ).sum::<u64>(),
+            num_keys
+        );
+    }
+
+    #[test]
+    fn test_sanity_db_shrink_to_fit() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("db");
+        let mut db = HashTable::new(path.clone(), [0; 32], None).unwrap();
+        let sector = |i: u64| FIRST_SECTOR_OFFSET + i * DEFAULT_SECTOR_SIZE;
+        for i in 1..=10 {
+            assert_eq!(
+                db.allocate_sector(vec![vec![0u8; VALUE_SIZE as usize]], VALUE_SIZE, VALUE_SIZE)
+                    .unwrap(),
+                sector(i)
+            );
+        }
+        for i in [9, 4, 10, 8] {
+            db.free_sector(sector(i)).unwrap();
+        }
+        db.flush_changes().unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+        assert_eq!(len, sector(11));
+
+        db.shrink_to_fit().unwrap();
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            len - 3 * DEFAULT_SECTOR_SIZE
+        );
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.file_size, sector(8));
+        assert_eq!(stats.num_free_sectors, 1);
+
+        // Nothing more to truncate
+        db.shrink_to_fit().unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), sector(8));
+
+        // The file is still consistent, and grows again from its new end
+        drop(db);
+        let mut db = HashTable::new(path, [0; 32], None).unwrap();
+        for expected in [sector(4), sector(8)] {
+            assert_eq!(
+                db.allocate_sector(vec![vec![0u8; VALUE_SIZE as usize]], VALUE_SIZE, VALUE_SIZE)
+                    .unwrap(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_sanity_db_page_cache() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let options = Options {
+            page_cache_bytes: 16 * PAGE_SIZE,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(tmp_dir.path().join("db"), [0; 32], None, options).unwrap();
+        let mut map = HashMap::new();
+        for i in 0..10000u32 {
+            db.set(i.to_le_bytes().to_vec(), vec![1; 100]).unwrap();
+            map.insert(i, vec![1; 100]);
+        }
+        db.flush_changes().unwrap();
+        for i in 0..10000u32 {
+            assert_eq!(
+                db.get(i.to_le_bytes().to_vec()).unwrap().as_ref(),
+                map.get(&i)
+            );
+        }
+        assert_eq!(db.tx.cache.len(), 16);
+
+        // The pages cached before the writes must not shadow them, neither before nor after the
+        // flush
+        let mut rng = rand::thread_rng();
+        for round in 0..4 {
+            for _ in 0..2000 {
+                let i = rng.gen_range(0..10000u32);
+                if rng.gen_bool(0.2) {
+                    db.delete(i.to_le_bytes().to_vec()).unwrap();
+                    map.remove(&i);
+                } else {
+                    let value = vec![round; rng.gen_range(0..300)];
+                    db.set(i.to_le_bytes().to_vec(), value.clone()).unwrap();
+                    map.insert(i, value);
+                }
+                let j = rng.gen_range(0..10000u32);
+                assert_eq!(
+                    db.get(j.to_le_bytes().to_vec()).unwrap().as_ref(),
+                    map.get(&j)
+                );
+            }
+            db.flush_changes().unwrap();
+            for i in 0..10000u32 {
+                assert_eq!(
+                    db.get(i.to_le_bytes().to_vec()).unwrap().as_ref(),
+                    map.get(&i)
+                );
+            }
+        }
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_sanity_db_mmap() {
+        let temp_dir = TempDir::new("test_sanity_db_mmap").unwrap();
+        let path = temp_dir.path().join("db");
+        let options = Options {
+            sector_size: 16 * PAGE_SIZE,
+            use_mmap: true,
+            ..Options::default()
+        };
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut expected = HashMap::new();
+        {
+            let mut db =
+                HashTable::new_with_options(path.clone(), [0; 32], None, options.clone()).unwrap();
+            // Enough values to grow the file by many sectors, each of which must be remapped
+            for i in 0..2000u32 {
+                let key = i.to_le_bytes().to_vec();
+                let value = (0..rng.gen_range(0..300))
+                    .map(|_| rng.gen())
+                    .collect::<Vec<u8>>();
+                db.set(key.clone(), value.clone()).unwrap();
+                expected.insert(key, value);
+                if i % 100 == 0 {
+                    db.flush_changes().unwrap();
+                }
+            }
+            db.flush_changes().unwrap();
+            for (key, value) in expected.iter() {
+                assert_eq!(db.get(key.clone()).unwrap().as_ref(), Some(value));
+            }
+            assert!(db.tx.mmap.is_some());
+        }
+
+        let mut with_mmap =
+            HashTable::new_with_options(path.clone(), [0; 32], None, options.clone()).unwrap();
+        let mut without_mmap = HashTable::new_with_options(
+            path,
+            [0; 32],
+            None,
+            Options {
+                use_mmap: false,
+                ..options
+            },
+        )
+        .unwrap();
+        assert!(without_mmap.tx.mmap.is_none());
+        for (key, value) in expected.iter() {
+            assert_eq!(with_mmap.get(key.clone()).unwrap().as_ref(), Some(value));
+            assert_eq!(without_mmap.get(key.clone()).unwrap().as_ref(), Some(value));
+        }
+    }
+
+    #[cfg(feature = "near_store_impl")]
+    #[test]
+    fn test_sanity_db_near_store_impl() {
+        use crate::near_store_impl::NearStoreDatabase;
+        use near_store::db::{DBTransaction, Database};
+        use near_store::DBCol;
+
+        let temp_dir = TempDir::new("test_sanity_db_near_store_impl").unwrap();
+        let options = Options {
+            store_keys: true,
+            ..Options::default()
+        };
+        let path = temp_dir.path().join("db");
+        let table = HashTable::new_with_options(path.clone(), [0; 32], None, options).unwrap();
+        let db = NearStoreDatabase::new(table);
+
+        let mut tx = DBTransaction::new();
+        tx.set(DBCol::Block, b"key".to_vec(), b"block".to_vec());
+        tx.set(DBCol::BlockHeader, b"key".to_vec(), b"header".to_vec());
+        tx.set(
+            DBCol::BlockHeader,
+            b"other".to_vec(),
+            b"other header".to_vec(),
+        );
+        tx.set(DBCol::ChunkExtra, b"gone".to_vec(), b"value".to_vec());
+        tx.delete(DBCol::ChunkExtra, b"gone".to_vec());
+        db.write(tx).unwrap();
+
+        // The same key in different columns maps to different entries
+        assert_eq!(
+            db.get_raw_bytes(DBCol::Block, b"key").unwrap(),
+            Some(b"block".to_vec())
+        );
+        assert_eq!(
+            db.get_raw_bytes(DBCol::BlockHeader, b"key").unwrap(),
+            Some(b"header".to_vec())
+        );
+        assert_eq!(db.get_raw_bytes(DBCol::ChunkExtra, b"gone").unwrap(), None);
+        assert_eq!(db.get_raw_bytes(DBCol::ChunkExtra, b"key").unwrap(), None);
+
+        let headers = db
+            .iter(DBCol::BlockHeader)
+            .map(|entry| {
+                let (key, value) = entry.unwrap();
+                (key.to_vec(), value.to_vec())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            headers,
+            vec![
+                (b"key".to_vec(), b"header".to_vec()),
+                (b"other".to_vec(), b"other header".to_vec())
+            ]
+        );
+
+        // A batch with an op that fails is rolled back as a whole
+        let mut tx = DBTransaction::new();
+        tx.set(DBCol::Block, b"partial".to_vec(), b"block".to_vec());
+        tx.set(
+            DBCol::Block,
+            b"too large".to_vec(),
+            vec![0; MAX_VALUE_SIZE + 1],
+        );
+        assert!(db.write(tx).is_err());
+        db.flush().unwrap();
+        assert_eq!(db.get_raw_bytes(DBCol::Block, b"partial").unwrap(), None);
+
+        // The batch was flushed, so the values survive reopening the table
+        drop(db.into_inner());
+        let mut table = HashTable::new(path, [0; 32], None).unwrap();
+        assert_eq!(
+            table
+                .get([&[DBCol::Block as u8], b"key".as_ref()].concat())
+                .unwrap(),
+            Some(b"block".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_sanity_db_kv_store() {
+        fn exercise(store: &mut dyn KvStore) {
+            for i in 0..1000u32 {
+                store
+                    .set(i.to_le_bytes().to_vec(), i.to_be_bytes().to_vec())
+                    .unwrap();
+            }
+            for i in (0..1000u32).step_by(3) {
+                store.delete(i.to_le_bytes().to_vec()).unwrap();
+            }
+            // Deleting a missing key is not an error
+            store.delete(b"missing".to_vec()).unwrap();
+            store.flush().unwrap();
+
+            for i in 0..1000u32 {
+                let key = i.to_le_bytes().to_vec();
+                let expected = (i % 3 != 0).then(|| i.to_be_bytes().to_vec());
+                assert_eq!(store.contains_key(key.clone()).unwrap(), expected.is_some());
+                assert_eq!(store.get(key).unwrap(), expected);
+            }
+        }
+
+        let temp_dir = TempDir::new("test_sanity_db_kv_store").unwrap();
+        let path = temp_dir.path().join("db");
+        let mut db = HashTable::new(path.clone(), [0; 32], None).unwrap();
+        exercise(&mut db);
+        assert_eq!(db.len().unwrap(), 666);
+        drop(db);
+
+        let mut db = HashTable::new(path, [0; 32], None).unwrap();
+        assert_eq!(
+            KvStore::get(&mut db, 1u32.to_le_bytes().to_vec()).unwrap(),
+            Some(1u32.to_be_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn test_sanity_db_value_checksum() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("db");
+        let mut db = HashTable::new(path.clone(), [0; 32], None).unwrap();
+        db.set(b"intact".to_vec(), vec![1; 300]).unwrap();
+        db.set(b"corrupted".to_vec(), vec![2; 300]).unwrap();
+        db.flush_changes().unwrap();
+        let location = db.locate(b"corrupted".to_vec()).unwrap().unwrap();
+        assert_eq!(location.num_slots, 3);
+        drop(db);
+
+        // Flip a bit of the payload in the second slot of the record
+        let offset = location.physical_offset + VALUE_SIZE + 10;
+        let mut file = open_file(&path).unwrap();
+        let mut byte = [0u8];
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.read_exact(&mut byte).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(&[byte[0] ^ 1]).unwrap();
+        drop(file);
+
+        let mut db = HashTable::new(path, [0; 32], None).unwrap();
+        assert_eq!(db.get(b"intact".to_vec()).unwrap(), Some(vec![1; 300]));
+        match db.get(b"corrupted".to_vec()) {
+            Err(StoreError::Corruption { offset, .. }) => {
+                assert_eq!(offset, location.physical_offset)
+            }
+            result => panic!("expected a corruption error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_sanity_db_sector_full() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("db");
+        let options = Options {
+            sector_size: 2 * PAGE_SIZE,
+            ..Options::default()
+        };
+        let db = HashTable::new_with_options(path.clone(), [0; 32], None, options.clone()).unwrap();
+        let slots_in_sector = db.slots_in_sector();
+        drop(db);
+
+        // Occupy every slot of the only hash table sector with a hash nothing is looked up by
+        let slot = [[0xff; HASH_LEN].as_ref(), &1u64.to_le_bytes()[..6]].concat();
+        let mut file = open_file(&path).unwrap();
+        file.seek(SeekFrom::Start(FIRST_SECTOR_OFFSET + FIRST_SLOT_OFFSET))
+            .unwrap();
+        file.write_all(&slot.repeat(slots_in_sector as usize))
+            .unwrap();
+        drop(file);
+
+        let mut db = HashTable::new_with_options(path, [0; 32], None, options).unwrap();
+        assert!(matches!(
+            db.seek([0; HASH_LEN]),
+            Err(StoreError::SectorFull {
+                offset: FIRST_SECTOR_OFFSET
+            })
+        ));
+        assert!(matches!(
+            db.get(b"key".to_vec()),
+            Err(StoreError::SectorFull { .. })
+        ));
+    }
+
+    #[test]
+    fn test_sanity_db_dangling_value() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut db = HashTable::new(tmp_dir.path().join("db"), [0; 32], None).unwrap();
+        for i in 0..100u32 {
+            db.set(i.to_le_bytes().to_vec(), vec![1; 10]).unwrap();
+        }
+        for i in 0..90u32 {
+            db.delete(i.to_le_bytes().to_vec()).unwrap();
+        }
+        let first_value_logical = db.stats().unwrap().first_value_logical;
+        assert!(first_value_logical > 0);
+
+        // Point a live key at the slot the first value occupied before it was compacted
+        let key = 99u32.to_le_bytes().to_vec();
+        db.ht_set(key.clone(), 1).unwrap();
+        let expected_hash = db.get_hash(&key);
+        match db.get(key.clone()) {
+            Err(StoreError::DanglingValue { hash, offset }) => {
+                assert_eq!(hash, expected_hash);
+                assert_eq!(offset, 0);
+            }
+            result => panic!("expected a dangling value error, got {:?}", result),
+        }
+        assert!(matches!(
+            db.get_many(&[key]),
+            Err(StoreError::DanglingValue { offset: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn test_sanity_db_rehash() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("db");
+        let options = Options {
+            store_keys: true,
+            store_seq: true,
+            ..Options::default()
+        };
+        let (old_salt, new_salt) = ([1; 32], [2; 32]);
+        let mut db =
+            HashTable::new_with_options(path.clone(), old_salt, None, options.clone()).unwrap();
+        let mut expected = HashMap::new();
+        for i in 0..2000u32 {
+            let key = i.to_le_bytes().to_vec();
+            let value = vec![i as u8; i as usize % 300];
+            db.set(key.clone(), value.clone()).unwrap();
+            expected.insert(key, value);
+        }
+        for i in (0..2000u32).step_by(7) {
+            db.delete(i.to_le_bytes().to_vec()).unwrap();
+            expected.remove(i.to_le_bytes().as_slice());
+        }
+        db.flush_changes().unwrap();
+        let seq = db
+            .get_with_seq(5u32.to_le_bytes().to_vec())
+            .unwrap()
+            .unwrap()
+            .1;
+
+        db.rehash(new_salt).unwrap();
+        assert_eq!(db.len().unwrap(), expected.len() as u64);
+        for (key, value) in expected.iter() {
+            assert_eq!(db.get(key.clone()).unwrap().as_ref(), Some(value));
+        }
+        db.flush_changes().unwrap();
+        drop(db);
+
+        let mut db =
+            HashTable::new_with_options(path.clone(), new_salt, None, options.clone()).unwrap();
+        for (key, value) in expected.iter() {
+            assert_eq!(db.get(key.clone()).unwrap().as_ref(), Some(value));
+        }
+        assert_eq!(
+            db.get_with_seq(5u32.to_le_bytes().to_vec()).unwrap(),
+            Some((expected[&5u32.to_le_bytes().to_vec()].clone(), seq))
+        );
+        drop(db);
+
+        // The table can't be opened with the old salt any more
+        assert!(matches!(
+            HashTable::new_with_options(path, old_salt, None, options),
+            Err(StoreError::SaltMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_sanity_db_rehash_requires_keys() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut db = HashTable::new(tmp_dir.path().join("db"), [1; 32], None).unwrap();
+        db.set(b"key".to_vec(), b"value".to_vec()).unwrap();
+        assert!(matches!(
+            db.rehash([2; 32]),
+            Err(StoreError::KeyNotStored { .. })
+        ));
+        assert_eq!(db.get(b"key".to_vec()).unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_sanity_db_rehash_rollback() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("db");
+        let options = Options {
+            store_keys: true,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(path.clone(), [1; 32], None, options.clone()).unwrap();
+        db.set(b"key".to_vec(), b"value".to_vec()).unwrap();
+        db.flush_changes().unwrap();
+
+        db.rehash([2; 32]).unwrap();
+        db.rollback().unwrap();
+        assert_eq!(db.get(b"key".to_vec()).unwrap(), Some(b"value".to_vec()));
+        db.set(b"other".to_vec(), b"other value".to_vec()).unwrap();
+        db.flush_changes().unwrap();
+        drop(db);
+
+        // The table is still hashed with the old salt
+        let mut db = HashTable::new_with_options(path, [1; 32], None, options).unwrap();
+        assert_eq!(db.get(b"key".to_vec()).unwrap(), Some(b"value".to_vec()));
+        assert_eq!(
+            db.get(b"other".to_vec()).unwrap(),
+            Some(b"other value".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_sanity_db_ttl() {
+        #[derive(Debug)]
+        struct TestClock(std::sync::atomic::AtomicU64);
+
+        impl Clock for TestClock {
+            fn now_unix_secs(&self) -> u64 {
+                self.0.load(std::sync::atomic::Ordering::SeqCst)
+            }
+        }
+
+        let clock = Arc::new(TestClock(1000.into()));
+        let tmp_dir = TempDir::new("example").unwrap();
+        let options = Options {
+            clock: clock.clone(),
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(tmp_dir.path().join("db"), [0; 32], None, options).unwrap();
+        db.set_with_ttl(b"expired".to_vec(), vec![1; 300], 500)
+            .unwrap();
+        db.set_with_ttl(b"expiring".to_vec(), vec![2; 10], 2000)
+            .unwrap();
+        db.set(b"forever".to_vec(), vec![3; 10]).unwrap();
+        assert_eq!(db.len().unwrap(), 3);
+
+        let location = db.locate(b"expired".to_vec()).unwrap().unwrap();
+        assert_eq!(db.get(b"expired".to_vec()).unwrap(), None);
+        assert_eq!(db.len().unwrap(), 2);
+        assert!(!db.contains_key(b"expired".to_vec()).unwrap());
+        for i in 0..location.num_slots {
+            assert!(db
+                .is_value_at_offset_deleted(location.logical_offset + i * VALUE_SIZE)
+                .unwrap());
+        }
+
+        assert_eq!(db.get(b"expiring".to_vec()).unwrap(), Some(vec![2; 10]));
+        clock.0.store(2000, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(
+            db.get_many(&[b"expiring".to_vec(), b"forever".to_vec()])
+                .unwrap(),
+            vec![None, Some(vec![3; 10])]
+        );
+        assert_eq!(db.len().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_sanity_db_entry() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let options = Options {
+            sector_size: 4 * PAGE_SIZE,
+            resize_spacing_writes: 10,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(tmp_dir.path().join("db"), [0; 32], None, options).unwrap();
+        let increment = |value: &mut Vec<u8>| {
+            let counter = u64::from_le_bytes(value[..].try_into().unwrap());
+            *value = (counter + 1).to_le_bytes().to_vec();
+        };
+
+        for i in 0..1000u64 {
+            // Enough other keys to resize the sectors, and make the compaction move the counter
+            db.set(i.to_le_bytes().to_vec(), vec![1; 100]).unwrap();
+            if i % 3 == 0 {
+                db.delete((i / 3).to_le_bytes().to_vec()).unwrap();
+            }
+
+            let key_hashes = db.key_hashes.get();
+            let counter = db
+                .entry(b"counter".to_vec())
+                .unwrap()
+                .and_modify(increment)
+                .unwrap()
+                .or_insert_with(|| 1u64.to_le_bytes().to_vec())
+                .unwrap();
+            assert_eq!(db.key_hashes.get(), key_hashes + 1);
+            assert_eq!(counter, (i + 1).to_le_bytes().to_vec());
+        }
+        assert!(db.ht_mapping.len() > 1);
+        assert_eq!(
+            db.get(b"counter".to_vec()).unwrap(),
+            Some(1000u64.to_le_bytes().to_vec())
+        );
+
+        let mut entry = db.entry(b"absent".to_vec()).unwrap();
+        assert_eq!(entry.key(), b"absent");
+        assert_eq!(entry.get().unwrap(), None);
+        let entry = entry.and_modify(increment).unwrap();
+        assert_eq!(entry.or_insert_with(|| vec![7]).unwrap(), vec![7]);
+        assert_eq!(db.get(b"absent".to_vec()).unwrap(), Some(vec![7]));
+        assert_eq!(db.len().unwrap(), 1000 - 334 + 2);
+    }
+
+    #[test]
+    fn test_sanity_db_large_values() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("db");
+        let mut db = HashTable::new(path.clone(), [0; 32], None).unwrap();
+        // Spans several values sectors
+        let large = (0..4 << 20)
+            .map(|i: u32| (i % 251) as u8)
+            .collect::<Vec<u8>>();
+        db.set(b"before".to_vec(), vec![1; 10]).unwrap();
+        db.set(b"large".to_vec(), large.clone()).unwrap();
+        db.set(b"after".to_vec(), vec![2; 10]).unwrap();
+        assert_eq!(db.get(b"large".to_vec()).unwrap().as_ref(), Some(&large));
+        db.flush_changes().unwrap();
+        drop(db);
+
+        let mut db = HashTable::new(path, [0; 32], None).unwrap();
+        assert_eq!(db.get(b"large".to_vec()).unwrap().as_ref(), Some(&large));
+        // Make the compaction move the large value to the frontier
+        db.delete(b"before".to_vec()).unwrap();
+        for i in 0..10000u32 {
+            db.set(i.to_le_bytes().to_vec(), vec![3; 100]).unwrap();
+            db.delete(i.to_le_bytes().to_vec()).unwrap();
+        }
+        assert!(db.stats().unwrap().first_value_logical > large.len() as u64);
+        assert_eq!(db.get(b"large".to_vec()).unwrap().as_ref(), Some(&large));
+        assert_eq!(db.get(b"after".to_vec()).unwrap(), Some(vec![2; 10]));
+        db.delete(b"large".to_vec()).unwrap();
+        assert_eq!(db.get(b"large".to_vec()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_sanity_db_value_too_large() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut db = HashTable::new(tmp_dir.path().join("db"), [0; 32], None).unwrap();
+        db.set(b"max".to_vec(), vec![1; MAX_VALUE_SIZE]).unwrap();
+        assert!(matches!(
+            db.set(b"over".to_vec(), vec![1; MAX_VALUE_SIZE + 1]),
+            Err(StoreError::ValueTooLarge { len }) if len == MAX_VALUE_SIZE + 1
+        ));
+        assert!(matches!(
+            db.set_batch(vec![
+                (b"small".to_vec(), vec![1; 10]),
+                (b"over".to_vec(), vec![1; MAX_VALUE_SIZE + 1])
+            ]),
+            Err(StoreError::ValueTooLarge { .. })
+        ));
+        assert_eq!(db.get(b"over".to_vec()).unwrap(), None);
+        assert_eq!(db.get(b"small".to_vec()).unwrap(), None);
+        assert_eq!(db.len().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_sanity_db_snapshot_to() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let snapshot_path = tmp_dir.path().join("snapshot");
+        let mut db = HashTable::new(tmp_dir.path().join("db"), [1; 32], None).unwrap();
+        let mut expected = HashMap::new();
+        for i in 0..20000u32 {
+            let key = i.to_le_bytes().to_vec();
+            let value = vec![i as u8; i as usize % 200];
+            db.set(key.clone(), value.clone()).unwrap();
+            expected.insert(key, value);
+        }
+        // Left pending until the snapshot flushes them
+        db.delete(0u32.to_le_bytes().to_vec()).unwrap();
+        expected.remove(0u32.to_le_bytes().as_slice());
+
+        db.snapshot_to(&snapshot_path).unwrap();
+        let file_size = db.stats().unwrap().file_size;
+        assert_eq!(std::fs::metadata(&snapshot_path).unwrap().len(), file_size);
+
+        // Changes made after the snapshot don't affect it
+        db.clear().unwrap();
+        db.flush_changes().unwrap();
+
+        let mut snapshot = HashTable::new(snapshot_path, [1; 32], None).unwrap();
+        assert_eq!(snapshot.len().unwrap(), expected.len() as u64);
+        for (key, value) in expected.iter() {
+            assert_eq!(snapshot.get(key.clone()).unwrap().as_ref(), Some(value));
+        }
+    }
+
+    #[test]
+    fn test_sanity_db_export_import() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let source_options = Options {
+            store_keys: true,
+            sector_size: 16 * PAGE_SIZE,
+            ..Options::default()
+        };
+        let mut source = HashTable::new_with_options(
+            tmp_dir.path().join("source"),
+            [1; 32],
+            None,
+            source_options,
+        )
+        .unwrap();
+        let mut expected = HashMap::new();
+        for i in 0..15000u32 {
+            let key = vec![i as u8; 1 + i as usize % 50];
+            let value = i.to_le_bytes().repeat(i as usize % 100);
+            source.set(key.clone(), value.clone()).unwrap();
+            expected.insert(key, value);
+        }
+
+        let mut dump = vec![];
+        assert_eq!(source.export(&mut dump).unwrap(), expected.len() as u64);
+
+        // A different salt and sector size, and the keys aren't even stored
+        let mut target = HashTable::new(tmp_dir.path().join("target"), [2; 32], None).unwrap();
+        target.set(b"existing".to_vec(), b"value".to_vec()).unwrap();
+        assert_eq!(
+            target.import(&mut &dump[..]).unwrap(),
+            expected.len() as u64
+        );
+        assert_eq!(target.len().unwrap(), expected.len() as u64 + 1);
+        for (key, value) in expected.iter() {
+            assert_eq!(target.get(key.clone()).unwrap().as_ref(), Some(value));
+        }
+
+        // A truncated dump is detected
+        let mut target = HashTable::new(tmp_dir.path().join("truncated"), [2; 32], None).unwrap();
+        assert!(target.import(&mut &dump[..dump.len() - 4]).is_err());
+        assert!(target.import(&mut &b"not a dump at all"[..]).is_err());
+
+        // So is a flipped byte, in a length, a value or the digest. The dump is shorter than
+        // `IMPORT_FLUSH_RECORDS`, so all the records set before are rolled back.
+        assert!(expected.len() < dump::IMPORT_FLUSH_RECORDS as usize);
+        for offset in [13, dump.len() / 2, dump.len() - 1] {
+            let mut corrupted = dump.clone();
+            corrupted[offset] ^= 1;
+            let path = tmp_dir.path().join(format!("corrupted_{}", offset));
+            let mut target = HashTable::new(path, [2; 32], None).unwrap();
+            assert!(matches!(
+                target.import(&mut &corrupted[..]),
+                Err(StoreError::CorruptDump { .. })
+            ));
+            assert_eq!(target.len().unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn test_sanity_db_export_import_ttl() {
+        #[derive(Debug)]
+        struct TestClock(AtomicU64);
+
+        impl Clock for TestClock {
+            fn now_unix_secs(&self) -> u64 {
+                self.0.load(Ordering::SeqCst)
+            }
+        }
+
+        let clock = Arc::new(TestClock(1000.into()));
+        let tmp_dir = TempDir::new("example").unwrap();
+        let options = Options {
+            store_keys: true,
+            clock: clock.clone(),
+            ..Options::default()
+        };
+        let open = |name: &str| {
+            HashTable::new_with_options(tmp_dir.path().join(name), [0; 32], None, options.clone())
+                .unwrap()
+        };
+        let mut source = open("source");
+        source.set(b"forever".to_vec(), b"1".to_vec()).unwrap();
+        source
+            .set_with_ttl(b"expired".to_vec(), b"2".to_vec(), 1500)
+            .unwrap();
+        source
+            .set_with_ttl(b"later".to_vec(), b"3".to_vec(), 3000)
+            .unwrap();
+
+        // The expired values are left out, and the others keep their expiry
+        clock.0.store(2000, Ordering::SeqCst);
+        let mut dump = vec![];
+        assert_eq!(source.export(&mut dump).unwrap(), 2);
+        let mut target = open("target");
+        assert_eq!(target.import(&mut &dump[..]).unwrap(), 2);
+        assert_eq!(target.get(b"expired".to_vec()).unwrap(), None);
+        assert_eq!(target.get(b"later".to_vec()).unwrap(), Some(b"3".to_vec()));
+        clock.0.store(4000, Ordering::SeqCst);
+        assert_eq!(target.get(b"later".to_vec()).unwrap(), None);
+        assert_eq!(
+            target.get(b"forever".to_vec()).unwrap(),
+            Some(b"1".to_vec())
+        );
+
+        // The dumps of version 1 have no expiry
+        let dump_v1 = [
+            b"ftkvdump".as_ref(),
+            &1u32.to_le_bytes(),
+            &3u32.to_le_bytes(),
+            b"old",
+            &1u64.to_le_bytes(),
+            b"4",
+            &u32::MAX.to_le_bytes(),
+        ]
+        .concat();
+        let mut target = open("target_v1");
+        assert_eq!(target.import(&mut &dump_v1[..]).unwrap(), 1);
+        assert_eq!(target.get(b"old".to_vec()).unwrap(), Some(b"4".to_vec()));
+    }
+
+    #[test]
+    fn test_sanity_db_metrics() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let options = Options {
+            sector_size: 16 * PAGE_SIZE,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(tmp_dir.path().join("db"), [1; 32], None, options).unwrap();
+        assert_eq!(db.metrics_snapshot(), MetricsSnapshot::default());
+
+        let keys = (0..5000u32)
+            .map(|i| i.to_le_bytes().to_vec())
+            .collect::<Vec<_>>();
+        for key in keys.iter() {
+            db.set(key.clone(), key.clone()).unwrap();
+        }
+        // One more sector than the first one means at least one resize
+        assert!(db.metrics_snapshot().resizes > 0);
+
+        for key in keys.iter() {
+            db.get(key.clone()).unwrap();
+        }
+        db.get_many(&keys[..10]).unwrap();
+        for key in keys[..4000].iter() {
+            db.delete(key.clone()).unwrap();
+        }
+        db.flush_changes().unwrap();
+
+        let snapshot = db.metrics_snapshot();
+        assert_eq!(snapshot.sets, 5000);
+        assert_eq!(snapshot.gets, 5010);
+        assert_eq!(snapshot.deletes, 4000);
+        assert!(snapshot.relocations > 0);
+        // The counters outlive the table
+        let metrics = db.metrics();
+        drop(db);
+        assert_eq!(metrics.snapshot(), snapshot);
+    }
+
+    #[test]
+    fn test_sanity_db_compaction_pacing() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let run = |name: &str, compaction_pacing: CompactionPacing| {
+            let options = Options {
+                compaction_pacing,
+                ..Options::default()
+            };
+            let mut db =
+                HashTable::new_with_options(tmp_dir.path().join(name), [1; 32], None, options)
+                    .unwrap();
+            for i in 0..3000u32 {
+                db.set(i.to_le_bytes().to_vec(), vec![i as u8; 50]).unwrap();
+            }
+            assert_eq!(
+                db.pending_compaction(),
+                -3000 * compaction_pacing.write_debit
+            );
+            for i in 0..2000u32 {
+                db.delete(i.to_le_bytes().to_vec()).unwrap();
+            }
+            for i in 2000..3000u32 {
+                assert_eq!(
+                    db.get(i.to_le_bytes().to_vec()).unwrap(),
+                    Some(vec![i as u8; 50])
+                );
+            }
+            db.value_moves
+        };
+
+        let eager = run("eager", CompactionPacing::default());
+        let lazy_pacing = CompactionPacing {
+            delete_credit: 7,
+            write_debit: 4,
+            move_debit: 2,
+        };
+        assert!(lazy_pacing.moves_per_write() < CompactionPacing::default().moves_per_write());
+        let lazy = run("lazy", lazy_pacing);
+        assert!(0 < lazy && lazy < eager, "{} {}", lazy, eager);
+    }
+
+    #[test]
+    fn test_sanity_db_verify() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let db_path = tmp_dir.path().join("db");
+        let options = Options {
+            sector_size: 16 * PAGE_SIZE,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(db_path.clone(), [1; 32], None, options.clone()).unwrap();
+        for i in 0..5000u32 {
+            db.set(i.to_le_bytes().to_vec(), vec![i as u8; i as usize % 300])
+                .unwrap();
+        }
+        for i in 0..1000u32 {
+            db.delete(i.to_le_bytes().to_vec()).unwrap();
+        }
+        db.flush_changes().unwrap();
+        let report = db.verify().unwrap();
+        assert!(report.is_consistent(), "{:?}", report.discrepancies);
+        assert_eq!(report.slots_checked, 4000);
+
+        let sector_offset = db
+            .locate(4000u32.to_le_bytes().to_vec())
+            .unwrap()
+            .unwrap()
+            .ht_sector_offset;
+        drop(db);
+        let mut file = OpenOptions::new().write(true).open(&db_path).unwrap();
+        file.seek(SeekFrom::Start(sector_offset + 32)).unwrap();
+        let occupancy = u64::from_le_bytes(
+            std::fs::read(&db_path).unwrap()[sector_offset as usize + 32..][..8]
+                .try_into()
+                .unwrap(),
+        );
+        file.write_all(&(occupancy + 7).to_le_bytes()).unwrap();
+        drop(file);
+
+        let mut db = HashTable::new_with_options(db_path, [1; 32], None, options).unwrap();
+        let report = db.verify().unwrap();
+        assert_eq!(
+            report.discrepancies,
+            vec![verify::Discrepancy::Occupancy {
+                sector_offset,
+                stored: occupancy + 7,
+                actual: occupancy,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_sanity_db_read_snapshot() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut db = HashTable::new(tmp_dir.path().join("db"), [1; 32], None).unwrap();
+        let flushed_value = |i: u32| vec![i as u8; i as usize % 300];
+        for i in 0..2000u32 {
+            db.set(i.to_le_bytes().to_vec(), flushed_value(i)).unwrap();
+        }
+        db.flush_changes().unwrap();
+        let snapshot = Arc::new(db.read_snapshot().unwrap());
+
+        let readers = (0..4)
+            .map(|_| {
+                let snapshot = snapshot.clone();
+                thread::spawn(move || {
+                    for _ in 0..3 {
+                        for i in 0..4000u32 {
+                            let expected = (i < 2000).then(|| flushed_value(i));
+                            assert_eq!(snapshot.get(i.to_le_bytes().to_vec()).unwrap(), expected);
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        // None of this is flushed while the readers run, so they don't see any of it
+        for i in 0..4000u32 {
+            db.set(i.to_le_bytes().to_vec(), vec![!(i as u8); 10])
+                .unwrap();
+        }
+        for i in 0..500u32 {
+            db.delete(i.to_le_bytes().to_vec()).unwrap();
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        assert!(!snapshot.is_stale());
+        db.flush_changes().unwrap();
+        assert!(snapshot.is_stale());
+        assert!(matches!(
+            snapshot.get(1000u32.to_le_bytes().to_vec()),
+            Err(StoreError::SnapshotStale)
+        ));
+
+        let snapshot = db.read_snapshot().unwrap();
+        for i in 0..4000u32 {
+            let expected = (i >= 500).then(|| vec![!(i as u8); 10]);
+            assert_eq!(snapshot.get(i.to_le_bytes().to_vec()).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_sanity_db_scan_prefix() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let options = Options {
+            store_keys: true,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(tmp_dir.path().join("db"), [1; 32], None, options).unwrap();
+        let mut expected = HashMap::new();
+        for account in ["alice", "alicia", "bob"] {
+            for node in 0..100u32 {
+                let key = [account.as_bytes(), &node.to_le_bytes()].concat();
+                let value = vec![node as u8; node as usize * 3];
+                db.set(key.clone(), value.clone()).unwrap();
+                if account == "alice" && node % 10 != 0 {
+                    expected.insert(key, value);
+                }
+            }
+        }
+        for node in (0..100u32).step_by(10) {
+            db.delete([b"alice".as_ref(), &node.to_le_bytes()].concat())
+                .unwrap();
+        }
+
+        let scanned = db
+            .scan_prefix(b"alice")
+            .unwrap()
+            .collect::<Result<HashMap<_, _>, _>>()
+            .unwrap();
+        assert_eq!(scanned, expected);
+        assert_eq!(db.scan_prefix(b"ali").unwrap().count(), 190);
+        assert_eq!(db.scan_prefix(b"").unwrap().count(), 290);
+        assert_eq!(db.scan_prefix(b"carol").unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_sanity_db_get_and_delete() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let separate_path = tmp_dir.path().join("separate");
+        let combined_path = tmp_dir.path().join("combined");
+        let mut separate = HashTable::new(separate_path.clone(), [1; 32], None).unwrap();
+        let mut combined = HashTable::new(combined_path.clone(), [1; 32], None).unwrap();
+        for db in [&mut separate, &mut combined] {
+            for i in 0..3000u32 {
+                db.set(i.to_le_bytes().to_vec(), vec![i as u8; i as usize % 400])
+                    .unwrap();
+            }
+        }
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(539);
+        for _ in 0..2000 {
+            // Some of the keys are deleted more than once, or were never set
+            let key = rng.gen_range(0..3500u32).to_le_bytes().to_vec();
+            let value = separate.get(key.clone()).unwrap();
+            separate.delete(key.clone()).unwrap();
+
+            let key_hashes = combined.key_hashes.get();
+            assert_eq!(combined.get_and_delete(key).unwrap(), value);
+            assert_eq!(combined.key_hashes.get(), key_hashes + 1);
+        }
+
+        assert_eq!(separate.len().unwrap(), combined.len().unwrap());
+        assert_eq!(separate.stats().unwrap(), combined.stats().unwrap());
+        assert_eq!(separate.pending_compaction(), combined.pending_compaction());
+        separate.flush_changes().unwrap();
+        combined.flush_changes().unwrap();
+        assert!(std::fs::read(separate_path).unwrap() == std::fs::read(combined_path).unwrap());
+    }
+
+    #[test]
+    fn test_sanity_db_reserve_sectors() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let db_path = tmp_dir.path().join("db");
+        let options = Options {
+            sector_size: 16 * PAGE_SIZE,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(db_path.clone(), [1; 32], None, options.clone()).unwrap();
+        let initial_size = std::fs::metadata(&db_path).unwrap().len();
+        db.reserve_sectors(100).unwrap();
+        let reserved_size = std::fs::metadata(&db_path).unwrap().len();
+        assert_eq!(reserved_size, initial_size + 100 * 16 * PAGE_SIZE);
+        assert_eq!(db.stats().unwrap().file_size, reserved_size);
+        assert_eq!(db.stats().unwrap().num_free_sectors, 100);
+
+        for i in 0..4000u32 {
+            db.set(i.to_le_bytes().to_vec(), vec![i as u8; 1000])
+                .unwrap();
+        }
+        db.flush_changes().unwrap();
+        let stats = db.stats().unwrap();
+        assert!(stats.num_free_sectors < 30, "{:?}", stats);
+        assert_eq!(stats.file_size, reserved_size);
+        assert_eq!(std::fs::metadata(&db_path).unwrap().len(), reserved_size);
+
+        drop(db);
+        let mut db = HashTable::new_with_options(db_path, [1; 32], None, options).unwrap();
+        for i in 0..4000u32 {
+            assert_eq!(
+                db.get(i.to_le_bytes().to_vec()).unwrap(),
+                Some(vec![i as u8; 1000])
+            );
+        }
+    }
+
+    #[test]
+    fn test_sanity_db_scan_bounded() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let options = Options {
+            store_keys: true,
+            sector_size: 16 * PAGE_SIZE,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(tmp_dir.path().join("db"), [1; 32], None, options).unwrap();
+        let mut expected = HashMap::new();
+        for i in 0..500u32 {
+            let key = i.to_le_bytes().to_vec();
+            let value = vec![i as u8; 1000 + i as usize * 37 % 4000];
+            db.set(key.clone(), value.clone()).unwrap();
+            expected.insert(key, value);
+        }
+        // Larger than the budget on its own
+        db.set(b"large".to_vec(), vec![1; 50000]).unwrap();
+        expected.insert(b"large".to_vec(), vec![1; 50000]);
+
+        let max_bytes = 20000;
+        let mut scanned = HashMap::new();
+        let mut pages = 0;
+        let (mut pairs, mut cursor) = db.scan_bounded(max_bytes).unwrap();
+        loop {
+            pages += 1;
+            let bytes: usize = pairs
+                .iter()
+                .map(|(key, value)| key.len() + value.len())
+                .sum();
+            assert!(bytes <= max_bytes || pairs.len() == 1);
+            for (key, value) in pairs {
+                assert!(scanned.insert(key, value).is_none());
+            }
+            match cursor {
+                Some(next) => (pairs, cursor) = db.scan_bounded_from(next, max_bytes).unwrap(),
+                None => break,
+            }
+        }
+        assert_eq!(scanned, expected);
+        let total: usize = expected
+            .iter()
+            .map(|(key, value)| key.len() + value.len())
+            .sum();
+        assert!(pages > total / max_bytes);
+
+        // A budget that fits everything takes a single page
+        let (pairs, cursor) = db.scan_bounded(usize::MAX).unwrap();
+        assert_eq!(pairs.len(), expected.len());
+        assert_eq!(cursor, None);
+    }
+
+    #[test]
+    fn test_sanity_db_drain() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let options = Options {
+            store_keys: true,
+            sector_size: 16 * PAGE_SIZE,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(tmp_dir.path().join("db"), [1; 32], None, options).unwrap();
+        let mut expected = HashMap::new();
+        for i in 0..1000u32 {
+            let key = i.to_le_bytes().to_vec();
+            let value = vec![i as u8; i as usize % 700];
+            db.set(key.clone(), value.clone()).unwrap();
+            expected.insert((None, key), value);
+        }
+        for i in (0..1000u32).step_by(7) {
+            db.delete(i.to_le_bytes().to_vec()).unwrap();
+            expected.remove(&(None, i.to_le_bytes().to_vec()));
+        }
+        db.flush_changes().unwrap();
+        let receiver = db.subscribe();
+
+        let drained = db
+            .drain()
+            .unwrap()
+            .map(|entry| entry.map(|(column, key, value)| ((column, key), value)))
+            .collect::<Result<HashMap<_, _>, _>>()
+            .unwrap();
+        assert_eq!(drained, expected);
+        assert_eq!(db.len().unwrap(), 0);
+        assert!(db.iter().unwrap().next().is_none());
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.num_value_sectors, 0);
+        assert_eq!(stats.num_ht_sectors, 1);
+        assert_eq!(
+            stats.num_free_sectors,
+            (stats.file_size - FIRST_SECTOR_OFFSET) / (16 * PAGE_SIZE) - 1
+        );
+
+        db.flush_changes().unwrap();
+        let deleted = receiver
+            .try_iter()
+            .filter_map(|mutation| match mutation {
+                Mutation::Delete { key } => Some(key),
+                _ => None,
+            })
+            .collect::<HashSet<_>>();
+        assert_eq!(deleted, expected.into_keys().map(|(_, key)| key).collect());
+
+        // The table is usable afterwards
+        db.set(b"key".to_vec(), b"value".to_vec()).unwrap();
+        assert_eq!(db.get(b"key".to_vec()).unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_sanity_db_salt_mismatch() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let db_path = tmp_dir.path().join("db");
+        let mut db = HashTable::new(db_path.clone(), [1; 32], None).unwrap();
+        db.set(b"key".to_vec(), b"value".to_vec()).unwrap();
+        db.flush_changes().unwrap();
+        drop(db);
+
+        assert!(matches!(
+            HashTable::new(db_path.clone(), [2; 32], None),
+            Err(StoreError::SaltMismatch)
+        ));
+        assert!(matches!(
+            HashTable::open_read_only(db_path.clone(), [2; 32]),
+            Err(StoreError::SaltMismatch)
+        ));
+
+        // The files created before the fingerprint was stored take the salt they're opened with
+        let mut file = OpenOptions::new().write(true).open(&db_path).unwrap();
+        file.seek(SeekFrom::Start(SALT_FINGERPRINT_OFFSET)).unwrap();
+        file.write_all(&[0; 8]).unwrap();
+        drop(file);
+        let mut db = HashTable::new(db_path.clone(), [1; 32], None).unwrap();
+        db.flush_changes().unwrap();
+        drop(db);
+        assert!(matches!(
+            HashTable::new(db_path.clone(), [2; 32], None),
+            Err(StoreError::SaltMismatch)
+        ));
+        let mut db = HashTable::new(db_path, [1; 32], None).unwrap();
+        assert_eq!(db.get(b"key".to_vec()).unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_sanity_db_get_many_duplicates() {
+        /// Counts the reads.
+        #[derive(Debug, Default)]
+        struct CountingDevice {
+            bytes: MemoryDevice,
+            reads: AtomicU64,
+        }
+
+        impl BlockDevice for CountingDevice {
+            fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+                self.reads.fetch_add(1, Ordering::SeqCst);
+                self.bytes.read_at(buf, offset)
+            }
+
+            fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+                self.bytes.write_at(buf, offset)
+            }
+
+            fn len(&self) -> io::Result<u64> {
+                self.bytes.len()
+            }
+
+            fn set_len(&self, len: u64) -> io::Result<()> {
+                self.bytes.set_len(len)
+            }
+
+            fn sync(&self, durability: Durability) -> io::Result<()> {
+                self.bytes.sync(durability)
+            }
+        }
+
+        let device = Arc::new(CountingDevice::default());
+        let open = || HashTable::open_device(device.clone(), [1; 32], Options::default()).unwrap();
+        let (hot, other, missing) = (b"hot".to_vec(), b"other".to_vec(), b"missing".to_vec());
+        let mut db = open();
+        // Three value slots each
+        db.set(hot.clone(), vec![1; 300]).unwrap();
+        db.set(other.clone(), vec![2; 300]).unwrap();
+        db.flush_changes().unwrap();
+        drop(db);
+
+        // Every batch is read by a table opened anew, so that they all start from the same state
+        let reads = |read: &mut dyn FnMut(&mut HashTable) -> Vec<Option<Vec<u8>>>| {
+            let mut db = open();
+            let before = device.reads.load(Ordering::SeqCst);
+            let values = read(&mut db);
+            (values, device.reads.load(Ordering::SeqCst) - before)
+        };
+        let (values, duplicated_reads) = reads(&mut |db| {
+            db.get_many(&[
+                hot.clone(),
+                other.clone(),
+                hot.clone(),
+                missing.clone(),
+                hot.clone(),
+                missing.clone(),
+            ])
+            .unwrap()
+        });
+        assert_eq!(
+            values,
+            [
+                Some(vec![1; 300]),
+                Some(vec![2; 300]),
+                Some(vec![1; 300]),
+                None,
+                Some(vec![1; 300]),
+                None
+            ]
+        );
+        // The repeated keys are read once
+        let distinct = [hot.clone(), other.clone(), missing.clone()];
+        let (_, distinct_reads) = reads(&mut |db| db.get_many(&distinct).unwrap());
+        assert_eq!(duplicated_reads, distinct_reads);
+
+        // Every `get` reads the beginning of the values region in the header anew, while the
+        // batch reads it once
+        let (_, get_reads) = reads(&mut |db| {
+            distinct
+                .iter()
+                .map(|key| db.get(key.clone()).unwrap())
+                .collect()
+        });
+        assert!(
+            distinct_reads < get_reads,
+            "{} {}",
+            distinct_reads,
+            get_reads
+        );
+    }
+
+    #[test]
+    fn test_sanity_db_space_amplification() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let amplification = |name: &str, value_len: usize| {
+            let options = Options {
+                sector_size: 16 * PAGE_SIZE,
+                ..Options::default()
+            };
+            let mut db =
+                HashTable::new_with_options(tmp_dir.path().join(name), [1; 32], None, options)
+                    .unwrap();
+            assert_eq!(db.space_amplification().unwrap(), f64::INFINITY);
+            for i in 0..10000u32 {
+                db.set(i.to_le_bytes().to_vec(), vec![i as u8; value_len])
+                    .unwrap();
+            }
+            db.space_amplification().unwrap()
+        };
+
+        // The record with the header and the checksum fills exactly one value slot
+        let one_slot_len = VALUE_SIZE as usize - VALUE_PAYLOAD_OFFSET - VALUE_CHECKSUM_LEN;
+        let one_slot = amplification("one_slot", one_slot_len);
+        // One byte more takes two slots
+        let two_slots = amplification("two_slots", one_slot_len + 1);
+        // The hash table slots, the sectors that are not full and the header add to the padding
+        let padding = VALUE_SIZE as f64 / one_slot_len as f64;
+        assert!((padding..padding * 2.0).contains(&one_slot), "{}", one_slot);
+        let padding = 2.0 * VALUE_SIZE as f64 / (one_slot_len + 1) as f64;
+        assert!(
+            (padding..padding * 2.0).contains(&two_slots),
+            "{}",
+            two_slots
+        );
+    }
+
+    #[test]
+    fn test_sanity_db_key_hasher() {
+        /// Pads the key with zeroes, and counts the calls.
+        #[derive(Debug)]
+        struct IdentityHasher(std::sync::atomic::AtomicU64);
+
+        impl KeyHasher for IdentityHasher {
+            fn tag(&self) -> u8 {
+                7
+            }
+
+            fn hash(&self, _salt: &[u8; 32], key: &[u8]) -> [u8; HASH_LEN] {
+                self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let mut hash = [0; HASH_LEN];
+                hash[..key.len()].copy_from_slice(key);
+                hash
+            }
+        }
+
+        let hasher = Arc::new(IdentityHasher(0.into()));
+        let calls = || hasher.0.load(std::sync::atomic::Ordering::SeqCst);
+        let tmp_dir = TempDir::new("example").unwrap();
+        let db_path = tmp_dir.path().join("db");
+        let options = Options {
+            hasher: hasher.clone(),
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(db_path.clone(), [1; 32], None, options.clone()).unwrap();
+        db.set(b"key".to_vec(), b"value".to_vec()).unwrap();
+        assert_eq!(calls(), 1);
+        assert_eq!(db.get(b"key".to_vec()).unwrap(), Some(b"value".to_vec()));
+        assert_eq!(calls(), 2);
+
+        let mut hash = [0; HASH_LEN];
+        hash[..3].copy_from_slice(b"key");
+        assert_ne!(db.seek(hash).unwrap().1, NO_VALUE);
+        db.flush_changes().unwrap();
+        drop(db);
+
+        assert!(matches!(
+            HashTable::new(db_path.clone(), [1; 32], None),
+            Err(StoreError::HasherMismatch {
+                configured: 0,
+                stored: 7
+            })
+        ));
+        let mut db = HashTable::new_with_options(db_path, [1; 32], None, options).unwrap();
+        assert_eq!(db.get(b"key".to_vec()).unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_sanity_db_delete_strategies() {
+        // Inserts, deletes and reinserts random keys, checking every lookup against a model
+        fn mixed_ops(
+            db: &mut HashTable,
+            expected: &mut HashMap<Vec<u8>, Vec<u8>>,
+            rng: &mut rand::rngs::StdRng,
+        ) {
+            for i in 0..6000 {
+                let key = rng.gen_range(0..500u32).to_le_bytes().to_vec();
+                match rng.gen_range(0..10) {
+                    0..=4 => {
+                        let value = vec![rng.gen(); rng.gen_range(1..200)];
+                        db.set(key.clone(), value.clone()).unwrap();
+                        expected.insert(key.clone(), value);
+                    }
+                    5..=8 => {
+                        db.delete(key.clone()).unwrap();
+                        expected.remove(&key);
+                    }
+                    _ => {}
+                }
+                assert_eq!(db.get(key.clone()).unwrap().as_ref(), expected.get(&key));
+                if i % 1000 == 0 {
+                    db.flush_changes().unwrap();
+                }
+            }
+            for key in (0..500u32).map(|key| key.to_le_bytes().to_vec()) {
+                assert_eq!(db.get(key.clone()).unwrap().as_ref(), expected.get(&key));
+            }
+            assert_eq!(db.len().unwrap(), expected.len() as u64);
+            assert!(db.verify().unwrap().is_consistent());
+        }
+
+        fn tombstones(db: &mut HashTable) -> u64 {
+            let sectors = db.ht_mapping.values().copied().collect::<Vec<_>>();
+            sectors
+                .into_iter()
+                .map(|offset| db.tx.get_num(&*db.file, offset + 40).unwrap())
+                .sum()
+        }
+
+        for (first, second) in [
+            (DeleteStrategy::BackwardShift, DeleteStrategy::Tombstone),
+            (DeleteStrategy::Tombstone, DeleteStrategy::BackwardShift),
+        ] {
+            let tmp_dir = TempDir::new("example").unwrap();
+            let db_path = tmp_dir.path().join("db");
+            let options = Options {
+                sector_size: 2 * PAGE_SIZE,
+                delete_strategy: first,
+                ..Options::default()
+            };
+            let mut rng = rand::rngs::StdRng::seed_from_u64(546);
+            let mut expected = HashMap::new();
+            let mut db =
+                HashTable::new_with_options(db_path.clone(), [1; 32], None, options.clone())
+                    .unwrap();
+            mixed_ops(&mut db, &mut expected, &mut rng);
+            // The sectors are rebuilt long before their tombstones would fill them
+            let max_tombstones =
+                db.ht_mapping.len() as u64 * db.slots_in_sector() * TOMBSTONE_REBUILD_PERCENT / 100;
+            assert_eq!(tombstones(&mut db) > 0, first == DeleteStrategy::Tombstone);
+            assert!(tombstones(&mut db) < max_tombstones);
+            db.flush_changes().unwrap();
+            drop(db);
+
+            // The tombstones left in the file are handled regardless of the strategy
+            let options = Options {
+                delete_strategy: second,
+                ..options
+            };
+            let mut db = HashTable::new_with_options(db_path, [1; 32], None, options).unwrap();
+            mixed_ops(&mut db, &mut expected, &mut rng);
+        }
+    }
+
+    #[test]
+    fn test_sanity_db_warmup() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let db_path = tmp_dir.path().join("db");
+        let options = Options {
+            sector_size: 4 * PAGE_SIZE,
+            page_cache_bytes: 1 << 20,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(db_path.clone(), [0; 32], None, options.clone()).unwrap();
+        for i in 0..5000u32 {
+            db.set(i.to_le_bytes().to_vec(), vec![i as u8; 100])
+                .unwrap();
+        }
+        for i in (0..5000u32).step_by(3) {
+            db.delete(i.to_le_bytes().to_vec()).unwrap();
+        }
+        db.flush_changes().unwrap();
+        drop(db);
+
+        let mut db = HashTable::new_with_options(db_path, [0; 32], None, options).unwrap();
+        let (ht_sectors, delmap_sectors) = (db.ht_mapping.len(), db.delmap_mapping.len());
+        assert!(ht_sectors > 1 && delmap_sectors > 0);
+        assert_eq!(db.warmup(false).unwrap(), ht_sectors as u64 * 4 * PAGE_SIZE);
+        assert_eq!(
+            db.warmup(true).unwrap(),
+            (ht_sectors + delmap_sectors) as u64 * 4 * PAGE_SIZE
+        );
+        let sectors = db.ht_mapping.values().copied().collect::<Vec<_>>();
+        for page in sectors
+            .iter()
+            .flat_map(|&offset| (0..4).map(move |i| offset + i * PAGE_SIZE))
+        {
+            assert!(db.tx.cache.get(page).is_some());
+        }
+
+        for i in 0..5000u32 {
+            let expected = (i % 3 != 0).then(|| vec![i as u8; 100]);
+            assert_eq!(db.get(i.to_le_bytes().to_vec()).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_sanity_db_rollback() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let db_path = tmp_dir.path().join("db");
+        let options = Options {
+            sector_size: 2 * PAGE_SIZE,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(db_path.clone(), [0; 32], None, options.clone()).unwrap();
+        let mut expected = HashMap::new();
+        for i in 0..1000u32 {
+            db.set(i.to_le_bytes().to_vec(), vec![i as u8; 100])
                 .unwrap();
+            expected.insert(i, vec![i as u8; 100]);
+        }
+        db.flush_changes().unwrap();
+        let sectors = (db.ht_mapping.len(), db.values_mapping.len());
+        let mutations = db.subscribe();
+
+        // Enough to resize the hash table sectors and allocate the values sectors
+        for i in 0..1000u32 {
+            db.delete(i.to_le_bytes().to_vec()).unwrap();
+        }
+        for i in 1000..3000u32 {
+            db.set(i.to_le_bytes().to_vec(), vec![1; 200]).unwrap();
+        }
+        assert_ne!((db.ht_mapping.len(), db.values_mapping.len()), sectors);
+        db.rollback().unwrap();
+
+        assert_eq!((db.ht_mapping.len(), db.values_mapping.len()), sectors);
+        assert_eq!(db.len().unwrap(), 1000);
+        for i in 0..3000u32 {
+            assert_eq!(
+                db.get(i.to_le_bytes().to_vec()).unwrap().as_ref(),
+                expected.get(&i)
+            );
+        }
+        assert!(db.verify().unwrap().is_consistent());
+
+        // The table keeps working from the flushed state
+        for i in 500..1500u32 {
+            db.set(i.to_le_bytes().to_vec(), vec![2; 150]).unwrap();
+            expected.insert(i, vec![2; 150]);
+        }
+        db.flush_changes().unwrap();
+        // Only the mutations after the rollback are sent
+        let sent = mutations.try_iter().collect::<Vec<_>>();
+        assert_eq!(sent.len(), 1000);
+        assert!(sent.iter().all(|mutation| matches!(
+            mutation,
+            Mutation::Set { value, .. } if *value == vec![2; 150]
+        )));
+        drop(db);
+        let mut db = HashTable::new_with_options(db_path, [0; 32], None, options).unwrap();
+        assert_eq!(db.len().unwrap(), 1500);
+        for i in 0..3000u32 {
+            assert_eq!(
+                db.get(i.to_le_bytes().to_vec()).unwrap().as_ref(),
+                expected.get(&i)
+            );
+        }
+        assert!(db.verify().unwrap().is_consistent());
+    }
+
+    #[test]
+    fn test_sanity_db_hash_len_compaction() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let options = Options {
+            hash_len: 20,
+            offset_width: 6,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(tmp_dir.path().join("db"), [0; 32], None, options).unwrap();
+        for i in 0..3000u32 {
+            db.set(i.to_le_bytes().to_vec(), vec![i as u8; 50]).unwrap();
+        }
+        // Deleting two records out of three moves the ones in between to the end of the values
+        // region, and rewrites their slots with the new offsets
+        for i in (0..3000u32).filter(|i| i % 3 != 0) {
+            db.delete(i.to_le_bytes().to_vec()).unwrap();
+        }
+        assert!(db.value_moves > 0);
+        for i in 0..3000u32 {
+            let expected = if i % 3 == 0 {
+                Some(vec![i as u8; 50])
+            } else {
+                None
+            };
+            assert_eq!(db.get(i.to_le_bytes().to_vec()).unwrap(), expected);
+        }
+        db.flush_changes().unwrap();
+        assert!(db.verify().unwrap().is_consistent());
+    }
+
+    #[test]
+    fn test_sanity_db_hash_len() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let db_path = tmp_dir.path().join("db");
+        let options = Options {
+            sector_size: 4 * PAGE_SIZE,
+            hash_len: 20,
+            offset_width: 6,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(db_path.clone(), [0; 32], None, options.clone()).unwrap();
+        // The 26-byte slots don't divide the pages, so some of the space at the end of every page
+        // is unused
+        assert_eq!(
+            db.slots_in_sector(),
+            (PAGE_SIZE - 64) / 26 + 3 * (PAGE_SIZE / 26)
+        );
+        assert!(db.slots_in_sector() > (4 * PAGE_SIZE - 64) / SLOT_SIZE);
+
+        // Enough keys to resize the sectors, so that the entries are moved between the slots
+        let mut expected = HashMap::new();
+        for i in 0..5000u32 {
+            db.set(i.to_le_bytes().to_vec(), vec![i as u8; 50]).unwrap();
+            expected.insert(i, vec![i as u8; 50]);
+        }
+        for i in (0..5000u32).step_by(4) {
+            db.delete(i.to_le_bytes().to_vec()).unwrap();
+            expected.remove(&i);
+        }
+        assert!(db.ht_mapping.len() > 1);
+        let hash = db.get_hash(&1u32.to_le_bytes());
+        assert!(hash[20..].iter().all(|&byte| byte == 0));
+        db.flush_changes().unwrap();
+        assert!(db.verify().unwrap().is_consistent());
+        let snapshot = db.read_snapshot().unwrap();
+        assert_eq!(
+            snapshot.get(1u32.to_le_bytes().to_vec()).unwrap(),
+            Some(vec![1; 50])
+        );
+        drop(db);
 
+        let default_layout = Options {
+            sector_size: 4 * PAGE_SIZE,
+            ..Options::default()
+        };
+        assert!(matches!(
+            HashTable::new_with_options(db_path.clone(), [0; 32], None, default_layout),
+            Err(StoreError::SlotLayoutMismatch {
+                configured: (HASH_LEN, 6),
+                stored: (20, 6),
+            })
+        ));
+        let mut db = HashTable::new_with_options(db_path, [0; 32], None, options).unwrap();
+        assert_eq!(db.len().unwrap(), expected.len() as u64);
+        for i in 0..5000u32 {
             assert_eq!(
-                new_logical_offset,
-                sector_logical_offset + SECTOR_SIZE - VALUE_SIZE
+                db.get(i.to_le_bytes().to_vec()).unwrap().as_ref(),
+                expected.get(&i)
             );
-            self.free_sector(sector_physical_offset - VALUE_SIZE);
-            self.values_mapping.remove(&sector_logical_offset);
         }
+    }
 
-        if new_logical_offset
-            % ((SECTOR_SIZE - FIRST_SLOT_OFFSET) / DELMAP_ENTRY_SIZE * DELS_PER_DELMAP * VALUE_SIZE)
-            == 0
-        {
-            // The page that was holding the delmap being moved is now free
-            let (&sector_logical_offset, &sector_physical_offset) = self
-                .delmap_mapping
-                .range(..=logical_offset)
-                .next_back()
+    #[test]
+    fn test_sanity_db_probe_stats() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        // Nothing is recorded by default
+        let mut db = HashTable::new(tmp_dir.path().join("off"), [0; 32], None).unwrap();
+        db.set(b"key".to_vec(), b"value".to_vec()).unwrap();
+        assert!(db.get(b"key".to_vec()).unwrap().is_some());
+        assert_eq!(db.probe_stats(), ProbeStats::default());
+
+        let options = Options {
+            probe_stats: true,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(tmp_dir.path().join("db"), [0; 32], None, options.clone())
                 .unwrap();
+        assert_eq!(db.probe_stats(), ProbeStats::default());
+        // A third of the slots of the sector
+        for i in 0..10000u32 {
+            db.set(i.to_le_bytes().to_vec(), vec![1; 10]).unwrap();
+        }
+        for i in 0..10000u32 {
+            assert!(db.get(i.to_le_bytes().to_vec()).unwrap().is_some());
+        }
+        let stats = db.probe_stats();
+        assert_eq!(stats.samples, metrics::PROBE_WINDOW);
+        assert!(stats.avg >= 1.0 && stats.avg < 2.0);
+        assert!(stats.p99 <= stats.max);
+
+        /// Only the leading bytes the sectors are chosen by vary, so every key of a sector starts
+        /// probing at the same slot.
+        #[derive(Debug)]
+        struct ClusteringHasher;
+
+        impl KeyHasher for ClusteringHasher {
+            fn tag(&self) -> u8 {
+                1
+            }
 
-            assert_eq!(
-                new_logical_offset,
-                sector_logical_offset
-                    + (SECTOR_SIZE - FIRST_SLOT_OFFSET) / DELMAP_ENTRY_SIZE
-                        * DELS_PER_DELMAP
-                        * VALUE_SIZE
-            );
-            self.free_sector(sector_physical_offset - FIRST_SLOT_OFFSET);
+            fn hash(&self, salt: &[u8; 32], key: &[u8]) -> [u8; HASH_LEN] {
+                let mut hash = hasher::Blake3Hasher.hash(salt, key);
+                hash[18..].fill(0);
+                hash
+            }
         }
 
-        ret
+        let options = Options {
+            hasher: Arc::new(ClusteringHasher),
+            ..options
+        };
+        let mut db =
+            HashTable::new_with_options(tmp_dir.path().join("clustered"), [0; 32], None, options)
+                .unwrap();
+        for i in 0..1000u32 {
+            db.set(i.to_le_bytes().to_vec(), vec![1; 10]).unwrap();
+        }
+        for i in 0..1000u32 {
+            assert!(db.get(i.to_le_bytes().to_vec()).unwrap().is_some());
+        }
+        let clustered = db.probe_stats();
+        assert!(clustered.avg > 100.0);
+        assert!(clustered.max >= 1000);
     }
 
-    fn get_value(&mut self, logical_offset: u64) -> [u8; VALUE_SIZE as usize] {
-        let (sector_logical_offset, sector_physical_offset) = self
-            .values_mapping
-            .range(..=logical_offset)
-            .next_back()
+    #[test]
+    fn test_sanity_db_compact() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let db_path = tmp_dir.path().join("db");
+        // Small sectors, so that the values take many of them, and a custom slot layout, so that
+        // the slots of the moved values are encoded with it
+        let options = Options {
+            sector_size: 4 * PAGE_SIZE,
+            hash_len: 20,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(db_path.clone(), [0; 32], None, options.clone()).unwrap();
+        for i in 0..5000u32 {
+            db.set(i.to_le_bytes().to_vec(), vec![i as u8; 50]).unwrap();
+        }
+        db.flush().unwrap();
+        for i in (0..5000u32).step_by(2) {
+            db.delete(i.to_le_bytes().to_vec()).unwrap();
+        }
+        // The writes leave no credit for the deletes to compact anything
+        assert_eq!(db.value_moves, 0);
+
+        let first = db
+            .tx
+            .get_num(&*db.file, FIRST_VALUE_LOGICAL_OFFSET)
             .unwrap();
+        let next = db.tx.get_num(&*db.file, NEXT_VALUE_LOGICAL_OFFSET).unwrap();
+        let mut live_slots = 0;
+        for offset in (first..next).step_by(VALUE_SIZE as usize) {
+            if !db.is_value_at_offset_deleted(offset).unwrap() {
+                live_slots += 1;
+            }
+        }
+        let sectors_before = db.values_mapping.len() as u64;
 
-        self.tx
-            .get(
-                &mut self.file,
-                sector_physical_offset + logical_offset - sector_logical_offset,
-                VALUE_SIZE,
+        // In the middle of a batch of changes
+        db.compact().unwrap();
+        let first = db
+            .tx
+            .get_num(&*db.file, FIRST_VALUE_LOGICAL_OFFSET)
+            .unwrap();
+        let next = db.tx.get_num(&*db.file, NEXT_VALUE_LOGICAL_OFFSET).unwrap();
+        assert_eq!(next - first, live_slots * VALUE_SIZE);
+        for offset in (first..next).step_by(VALUE_SIZE as usize) {
+            assert!(!db.is_value_at_offset_deleted(offset).unwrap());
+        }
+        // The region doesn't have to start at the beginning of a sector, so it can take one more
+        // sector than its length needs
+        let span = db.values_sector_span();
+        let minimum = (live_slots * VALUE_SIZE - 1) / span + 1;
+        let sectors = db.values_mapping.len() as u64;
+        assert!(sectors <= minimum + 1, "{} {}", sectors, minimum);
+        assert!(sectors < sectors_before, "{} {}", sectors, sectors_before);
+        assert_eq!(db.pending_compaction(), 0);
+
+        let check = |db: &mut HashTable| {
+            for i in 0..5000u32 {
+                let expected = (i % 2 == 1).then(|| vec![i as u8; 50]);
+                assert_eq!(db.get(i.to_le_bytes().to_vec()).unwrap(), expected);
+            }
+            assert!(db.verify().unwrap().is_consistent());
+        };
+        check(&mut db);
+        db.set(b"after".to_vec(), b"compact".to_vec()).unwrap();
+        db.flush().unwrap();
+        check(&mut db);
+        drop(db);
+
+        let mut db = HashTable::new_with_options(db_path, [0; 32], None, options).unwrap();
+        check(&mut db);
+        assert_eq!(
+            db.get(b"after".to_vec()).unwrap(),
+            Some(b"compact".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_sanity_db_wal_replay_chunks() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("db");
+        let wal_path = tmp_dir.path().join("wal");
+        let mut db = HashTable::new(path.clone(), [0; 32], None).unwrap();
+        db.set(b"committed".to_vec(), b"1".to_vec()).unwrap();
+        db.flush_changes().unwrap();
+        for i in 0..5000u32 {
+            db.set(i.to_le_bytes().to_vec(), vec![i as u8; 1000])
+                .unwrap();
+        }
+        db.write_to_log(&mut File::create(&wal_path).unwrap())
+            .unwrap();
+        drop(db);
+        let wal = std::fs::read(&wal_path).unwrap();
+
+        let options = Options {
+            replay_chunk_bytes: 256 << 10,
+            ..Options::default()
+        };
+        let open = || {
+            HashTable::new_with_options(
+                path.clone(),
+                [0; 32],
+                Some(&mut File::open(&wal_path).unwrap()),
+                options.clone(),
             )
-            .try_into()
             .unwrap()
+        };
+
+        // A log with a broken checksum is not applied, even the chunks before it
+        let mut broken = wal.clone();
+        *broken.last_mut().unwrap() ^= 1;
+        std::fs::write(&wal_path, &broken).unwrap();
+        let mut db = open();
+        assert_eq!(db.tx.replay_peak_bytes, 0);
+        assert_eq!(db.get(b"committed".to_vec()).unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(0u32.to_le_bytes().to_vec()).unwrap(), None);
+        drop(db);
+
+        std::fs::write(&wal_path, &wal).unwrap();
+        let mut db = open();
+        // No more than a chunk and the largest change are held at once, a small part of the log
+        assert!(wal.len() as u64 > 10 * options.replay_chunk_bytes);
+        assert!(
+            db.tx.replay_peak_bytes < options.replay_chunk_bytes + options.sector_size,
+            "{}",
+            db.tx.replay_peak_bytes
+        );
+        assert_eq!(db.get(b"committed".to_vec()).unwrap(), Some(b"1".to_vec()));
+        for i in 0..5000u32 {
+            assert_eq!(
+                db.get(i.to_le_bytes().to_vec()).unwrap(),
+                Some(vec![i as u8; 1000])
+            );
+        }
+        assert!(db.verify().unwrap().is_consistent());
     }
 
-    fn write_value(&mut self, data: [u8; VALUE_SIZE as usize]) -> u64 {
-        let cur_offset = self.tx.get_num(&mut self.file, NEXT_VALUE_LOGICAL_OFFSET);
-        let mut next_value_physical_offset =
-            self.tx.get_num(&mut self.file, NEXT_VALUE_PHYSICAL_OFFSET);
-        let mut next_delmap_physical_offset =
-            self.tx.get_num(&mut self.file, NEXT_DELMAP_PHYSICAL_OFFSET);
+    #[test]
+    fn test_sanity_db_write_once() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let db_path = tmp_dir.path().join("db");
+        let options = Options {
+            enable_compaction: false,
+            store_keys: true,
+            sector_size: 4 * PAGE_SIZE,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(db_path.clone(), [0; 32], None, options.clone()).unwrap();
+        let mut expected = HashMap::new();
+        for i in 0..3000u32 {
+            db.set(i.to_le_bytes().to_vec(), vec![i as u8; 100])
+                .unwrap();
+            expected.insert(i.to_le_bytes().to_vec(), vec![i as u8; 100]);
+        }
+        // Overwriting and deleting leaves the old records in the values region
+        for i in (0..3000u32).step_by(3) {
+            db.set(i.to_le_bytes().to_vec(), vec![1; 10]).unwrap();
+            expected.insert(i.to_le_bytes().to_vec(), vec![1; 10]);
+        }
+        for i in (1..3000u32).step_by(3) {
+            db.delete(i.to_le_bytes().to_vec()).unwrap();
+            expected.remove(i.to_le_bytes().as_slice());
+        }
+        db.compact().unwrap();
+        assert_eq!(db.value_moves, 0);
+        assert_eq!(db.pending_compaction(), 0);
+        assert_eq!(
+            db.tx
+                .get_num(&*db.file, FIRST_VALUE_LOGICAL_OFFSET)
+                .unwrap(),
+            0
+        );
+        db.flush().unwrap();
+
+        let check = |db: &mut HashTable| {
+            assert!(db.delmap_mapping.is_empty());
+            assert!(db.values_mapping.len() > 1);
+            let file_size = db.tx.get_num(&*db.file, 0).unwrap();
+            for sector in (FIRST_SECTOR_OFFSET..file_size).step_by(4 * PAGE_SIZE as usize) {
+                let page_type = db.tx.get_num(&*db.file, sector + 48).unwrap();
+                assert_ne!(page_type, PAGE_TYPE_DELMAP);
+            }
+            for i in 0..3000u32 {
+                let key = i.to_le_bytes().to_vec();
+                assert_eq!(db.get(key.clone()).unwrap(), expected.get(&key).cloned());
+            }
+            let location = db.locate(0u32.to_le_bytes().to_vec()).unwrap().unwrap();
+            assert_eq!(location.delmap_offset, None);
+            // Only the live records are iterated over
+            let pairs = db
+                .iter()
+                .unwrap()
+                .collect::<Result<HashMap<_, _>, _>>()
+                .unwrap();
+            assert_eq!(pairs, expected);
+            assert!(db.verify().unwrap().is_consistent());
+        };
+        check(&mut db);
+        drop(db);
 
-        self.tx.set(
-            NEXT_VALUE_LOGICAL_OFFSET,
-            (cur_offset + VALUE_SIZE).to_le_bytes().to_vec(),
+        let compacted = Options {
+            enable_compaction: true,
+            ..options.clone()
+        };
+        assert!(matches!(
+            HashTable::new_with_options(db_path.clone(), [0; 32], None, compacted),
+            Err(StoreError::CompactionMismatch {
+                configured: true,
+                stored: false
+            })
+        ));
+        let mut db = HashTable::new_with_options(db_path, [0; 32], None, options).unwrap();
+        check(&mut db);
+    }
+
+    #[test]
+    fn test_sanity_db_empty_value() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        for (name, options) in [
+            ("plain", Options::default()),
+            (
+                "fields",
+                Options {
+                    store_seq: true,
+                    store_keys: true,
+                    ..Options::default()
+                },
+            ),
+        ] {
+            let db_path = tmp_dir.path().join(name);
+            let mut db =
+                HashTable::new_with_options(db_path.clone(), [0; 32], None, options.clone())
+                    .unwrap();
+            db.set(b"empty".to_vec(), vec![]).unwrap();
+            // Every length around the boundaries of the first slots
+            for len in 0..3 * VALUE_SIZE as usize {
+                db.set(len.to_le_bytes().to_vec(), vec![len as u8; len])
+                    .unwrap();
+            }
+
+            let check = |db: &mut HashTable| {
+                assert_eq!(db.get(b"empty".to_vec()).unwrap(), Some(vec![]));
+                assert!(db.contains_key(b"empty".to_vec()).unwrap());
+                assert_eq!(db.get(b"missing".to_vec()).unwrap(), None);
+                assert!(!db.contains_key(b"missing".to_vec()).unwrap());
+                let location = db.locate(b"empty".to_vec()).unwrap().unwrap();
+                assert_eq!(location.num_slots, 1);
+                for len in 0..3 * VALUE_SIZE as usize {
+                    assert_eq!(
+                        db.get(len.to_le_bytes().to_vec()).unwrap(),
+                        Some(vec![len as u8; len]),
+                        "{}",
+                        len
+                    );
+                }
+            };
+            check(&mut db);
+            db.flush().unwrap();
+            check(&mut db);
+            assert_eq!(
+                db.read_snapshot().unwrap().get(b"empty".to_vec()).unwrap(),
+                Some(vec![])
+            );
+            drop(db);
+
+            let mut db = HashTable::new_with_options(db_path, [0; 32], None, options).unwrap();
+            check(&mut db);
+            db.delete(b"empty".to_vec()).unwrap();
+            assert_eq!(db.get(b"empty".to_vec()).unwrap(), None);
+            assert!(!db.contains_key(b"empty".to_vec()).unwrap());
+            assert!(db.verify().unwrap().is_consistent());
+        }
+    }
+
+    #[cfg(all(feature = "preallocate", target_os = "linux"))]
+    #[test]
+    fn test_sanity_db_preallocate() {
+        use std::os::unix::fs::MetadataExt;
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        let db_path = tmp_dir.path().join("db");
+        let options = Options {
+            sector_size: 4 * PAGE_SIZE,
+            preallocate_bytes: 16 << 20,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(db_path.clone(), [0; 32], None, options.clone()).unwrap();
+        // The space is reserved, but the file is only as long as the header and the first sector
+        let metadata = std::fs::metadata(&db_path).unwrap();
+        assert!(metadata.blocks() * 512 >= options.preallocate_bytes);
+        assert_eq!(metadata.len(), FIRST_SECTOR_OFFSET + options.sector_size);
+        assert_eq!(
+            db.tx.get_num(&*db.file, 0).unwrap(),
+            FIRST_SECTOR_OFFSET + options.sector_size
         );
 
-        if next_value_physical_offset % SECTOR_SIZE == FIRST_SECTOR_OFFSET {
-            next_value_physical_offset = self.allocate_sector(
-                vec![
-                    cur_offset.to_le_bytes().to_vec(),
-                    vec![0u8; 40],
-                    PAGE_TYPE_VALUES.to_le_bytes().to_vec(),
-                    vec![0u8; 8],
-                    vec![0u8; 64],
-                ],
-                VALUE_SIZE,
-                VALUE_SIZE,
-            ) + VALUE_SIZE;
-            self.values_mapping
-                .insert(cur_offset, next_value_physical_offset);
+        // Growing within the reserved range leaves the file and the header in agreement
+        for i in 0..2000u32 {
+            db.set(i.to_le_bytes().to_vec(), vec![i as u8; 100])
+                .unwrap();
         }
+        db.flush().unwrap();
+        let file_size = db.tx.get_num(&*db.file, 0).unwrap();
+        assert!(file_size < options.preallocate_bytes);
+        assert!(file_size > FIRST_SECTOR_OFFSET + 4 * options.sector_size);
+        let metadata = std::fs::metadata(&db_path).unwrap();
+        assert_eq!(metadata.len(), file_size);
+        assert!(metadata.blocks() * 512 >= options.preallocate_bytes);
+        drop(db);
+
+        let mut db = HashTable::new_with_options(db_path, [0; 32], None, options).unwrap();
+        for i in 0..2000u32 {
+            assert_eq!(
+                db.get(i.to_le_bytes().to_vec()).unwrap(),
+                Some(vec![i as u8; 100])
+            );
+        }
+        assert!(db.verify().unwrap().is_consistent());
+    }
 
-        self.tx.set(next_value_physical_offset, data.to_vec());
-        next_value_physical_offset += VALUE_SIZE;
-        self.tx.set(
-            NEXT_VALUE_PHYSICAL_OFFSET,
-            next_value_physical_offset.to_le_bytes().to_vec(),
+    #[test]
+    fn test_sanity_db_update_in_place() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let db_path = tmp_dir.path().join("db");
+        let options = Options {
+            store_seq: true,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(db_path.clone(), [0; 32], None, options.clone()).unwrap();
+        db.set(b"key".to_vec(), vec![1; 100]).unwrap();
+        db.set(b"other".to_vec(), vec![2; 100]).unwrap();
+        db.flush().unwrap();
+        let next_value_logical =
+            |db: &mut HashTable| db.tx.get_num(&*db.file, NEXT_VALUE_LOGICAL_OFFSET).unwrap();
+        let next = next_value_logical(&mut db);
+        let location = db.locate(b"key".to_vec()).unwrap().unwrap();
+        let pending_compaction = db.pending_compaction();
+
+        // The same number of slots, the record is overwritten where it is
+        db.set(b"key".to_vec(), vec![3; 100]).unwrap();
+        assert_eq!(next_value_logical(&mut db), next);
+        assert_eq!(db.locate(b"key".to_vec()).unwrap().unwrap(), location);
+        assert_eq!(db.pending_compaction(), pending_compaction);
+        assert_eq!(db.get(b"key".to_vec()).unwrap(), Some(vec![3; 100]));
+        assert_eq!(db.get_with_seq(b"key".to_vec()).unwrap().unwrap().1, 3);
+        // A shorter value in as many slots
+        db.set(b"key".to_vec(), vec![4; 90]).unwrap();
+        assert_eq!(next_value_logical(&mut db), next);
+        assert_eq!(db.get(b"key".to_vec()).unwrap(), Some(vec![4; 90]));
+        db.flush().unwrap();
+        assert_eq!(db.get(b"other".to_vec()).unwrap(), Some(vec![2; 100]));
+
+        // More slots, the record is written anew and the old one deleted
+        db.set(b"key".to_vec(), vec![5; 1000]).unwrap();
+        assert!(next_value_logical(&mut db) > next);
+        let moved = db.locate(b"key".to_vec()).unwrap().unwrap();
+        assert_ne!(moved.logical_offset, location.logical_offset);
+        assert!(db
+            .is_value_at_offset_deleted(location.logical_offset)
+            .unwrap());
+        db.flush().unwrap();
+        assert!(db.verify().unwrap().is_consistent());
+        drop(db);
+
+        let mut db = HashTable::new_with_options(db_path, [0; 32], None, options).unwrap();
+        assert_eq!(db.get(b"key".to_vec()).unwrap(), Some(vec![5; 1000]));
+        assert_eq!(db.get(b"other".to_vec()).unwrap(), Some(vec![2; 100]));
+    }
+
+    #[test]
+    fn test_sanity_db_inline_values() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let db_path = tmp_dir.path().join("db");
+        let options = Options {
+            inline_values: true,
+            sector_size: 4 * PAGE_SIZE,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(db_path.clone(), [0; 32], None, options.clone()).unwrap();
+        let value = |i: u32| vec![i as u8; i as usize % 6];
+        // Enough keys to resize the hash table sectors, which moves the inline values around
+        for i in 0..3000u32 {
+            db.set(i.to_le_bytes().to_vec(), value(i)).unwrap();
+        }
+        assert!(db.ht_mapping.len() > 1);
+        let next_value_logical =
+            |db: &mut HashTable| db.tx.get_num(&*db.file, NEXT_VALUE_LOGICAL_OFFSET).unwrap();
+        assert_eq!(next_value_logical(&mut db), 0);
+        let value_reads = db.value_reads;
+        for i in 0..3000u32 {
+            assert_eq!(db.get(i.to_le_bytes().to_vec()).unwrap(), Some(value(i)));
+        }
+        assert_eq!(db.value_reads, value_reads);
+        assert!(
+            db.locate(5u32.to_le_bytes().to_vec())
+                .unwrap()
+                .unwrap()
+                .inline
         );
 
-        let offset_within_delmap = (cur_offset / VALUE_SIZE) % DELS_PER_DELMAP;
-        if offset_within_delmap == 0 {
-            if next_delmap_physical_offset % SECTOR_SIZE == FIRST_SECTOR_OFFSET {
-                next_delmap_physical_offset = self.allocate_sector(
-                    vec![
-                        cur_offset.to_le_bytes().to_vec(),
-                        vec![0u8; 40],
-                        PAGE_TYPE_DELMAP.to_le_bytes().to_vec(),
-                        vec![0u8; 8],
-                    ],
-                    FIRST_SLOT_OFFSET,
-                    DELMAP_ENTRY_SIZE,
-                ) + FIRST_SLOT_OFFSET;
-                self.delmap_mapping
-                    .insert(cur_offset, next_delmap_physical_offset);
+        // A value that outgrows the slot moves to the values region, and back
+        let key = 5u32.to_le_bytes().to_vec();
+        db.set(key.clone(), vec![7; 100]).unwrap();
+        assert_eq!(db.get(key.clone()).unwrap(), Some(vec![7; 100]));
+        let location = db.locate(key.clone()).unwrap().unwrap();
+        assert!(!location.inline);
+        assert_eq!(next_value_logical(&mut db), location.num_slots * VALUE_SIZE);
+        db.set(key.clone(), vec![8; 5]).unwrap();
+        assert_eq!(db.get(key.clone()).unwrap(), Some(vec![8; 5]));
+        assert!(db.locate(key.clone()).unwrap().unwrap().inline);
+        assert!(db
+            .is_value_at_offset_deleted(location.logical_offset)
+            .unwrap());
+
+        // The other ways to write and remove the values
+        db.entry(key.clone())
+            .unwrap()
+            .and_modify(|value| value.push(9))
+            .unwrap();
+        assert_eq!(db.get(key.clone()).unwrap(), Some(vec![8, 8, 8, 8, 8, 9]));
+        db.set_batch(vec![
+            (key.clone(), vec![1; 1000]),
+            (b"batch".to_vec(), vec![2]),
+        ])
+        .unwrap();
+        assert_eq!(db.get(key.clone()).unwrap(), Some(vec![1; 1000]));
+        assert_eq!(db.get_and_delete(b"batch".to_vec()).unwrap(), Some(vec![2]));
+        db.delete(1u32.to_le_bytes().to_vec()).unwrap();
+        assert_eq!(db.get(1u32.to_le_bytes().to_vec()).unwrap(), None);
+        assert_eq!(db.len().unwrap(), 2999);
+        db.flush().unwrap();
+        assert_eq!(
+            db.read_snapshot()
+                .unwrap()
+                .get(2u32.to_le_bytes().to_vec())
+                .unwrap(),
+            Some(value(2))
+        );
+        assert!(db.verify().unwrap().is_consistent());
+        drop(db);
+
+        let without = Options {
+            inline_values: false,
+            ..options.clone()
+        };
+        assert!(matches!(
+            HashTable::new_with_options(db_path.clone(), [0; 32], None, without),
+            Err(StoreError::InlineValuesMismatch {
+                configured: false,
+                stored: true
+            })
+        ));
+        let mut db = HashTable::new_with_options(db_path, [0; 32], None, options).unwrap();
+        assert_eq!(db.get(key).unwrap(), Some(vec![1; 1000]));
+        for i in 2..3000u32 {
+            if i != 5 {
+                assert_eq!(db.get(i.to_le_bytes().to_vec()).unwrap(), Some(value(i)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_sanity_db_inline_values_walks() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let options = Options {
+            inline_values: true,
+            store_keys: true,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(tmp_dir.path().join("db"), [1; 32], None, options).unwrap();
+        db.set(b"stored".to_vec(), vec![1; 100]).unwrap();
+        // Only the values written without the keys are stored inline
+        db.options.store_keys = false;
+        db.set(b"inline".to_vec(), vec![2; 3]).unwrap();
+        db.options.store_keys = true;
+        assert!(db.locate(b"inline".to_vec()).unwrap().unwrap().inline);
+        assert!(!db.locate(b"stored".to_vec()).unwrap().unwrap().inline);
+
+        // The inline values are walked, and have no keys
+        assert_eq!(db.iter_keys().unwrap().count(), 2);
+        assert!(db.space_amplification().unwrap().is_finite());
+        let before = db.tx.changes.clone();
+        assert!(matches!(
+            db.rehash([2; 32]),
+            Err(StoreError::KeyNotStored { .. })
+        ));
+        assert!(matches!(db.drain(), Err(StoreError::KeyNotStored { .. })));
+        assert!(matches!(
+            db.export(&mut vec![]),
+            Err(StoreError::KeyNotStored { .. })
+        ));
+        assert!(db.tx.changes == before);
+        assert_eq!(db.len().unwrap(), 2);
+        assert_eq!(db.get(b"stored".to_vec()).unwrap(), Some(vec![1; 100]));
+        assert_eq!(db.get(b"inline".to_vec()).unwrap(), Some(vec![2; 3]));
+
+        // Once the inline value is gone, all the keys are stored
+        db.delete(b"inline".to_vec()).unwrap();
+        db.rehash([2; 32]).unwrap();
+        assert_eq!(db.get(b"stored".to_vec()).unwrap(), Some(vec![1; 100]));
+        let drained = db.drain().unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(drained, vec![(None, b"stored".to_vec(), vec![1; 100])]);
+        assert_eq!(db.len().unwrap(), 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_sanity_db_direct_io() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("db");
+        let options = Options {
+            direct_io: true,
+            flush_threads: 4,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(path.clone(), [0; 32], None, options.clone()).unwrap();
+        assert!(db.tx.direct.is_some());
+        for i in 0..10000u32 {
+            db.set(i.to_le_bytes().to_vec(), vec![i as u8; i as usize % 300])
+                .unwrap();
+        }
+        db.flush_changes().unwrap();
+        drop(db);
+
+        let mut db = HashTable::new_with_options(path, [0; 32], None, options).unwrap();
+        for i in 0..10000u32 {
+            assert_eq!(
+                db.get(i.to_le_bytes().to_vec()).unwrap(),
+                Some(vec![i as u8; i as usize % 300])
+            );
+        }
+        assert_eq!(
+            db.tx.page.as_ref().unwrap().page.as_ptr() as u64 % PAGE_SIZE,
+            0
+        );
+    }
+
+    #[test]
+    fn test_sanity_db_columns() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("db");
+        let mut db = HashTable::new(path.clone(), [0; 32], None).unwrap();
+        db.set(b"key".to_vec(), b"table".to_vec()).unwrap();
+        db.column(1).set(b"key".to_vec(), b"one".to_vec()).unwrap();
+        db.column(2).set(b"key".to_vec(), b"two".to_vec()).unwrap();
+        assert_eq!(db.get(b"key".to_vec()).unwrap(), Some(b"table".to_vec()));
+        assert_eq!(
+            db.column(1).get(b"key".to_vec()).unwrap(),
+            Some(b"one".to_vec())
+        );
+        assert_eq!(
+            db.column(2).get(b"key".to_vec()).unwrap(),
+            Some(b"two".to_vec())
+        );
+        assert_eq!(db.column(3).get(b"key".to_vec()).unwrap(), None);
+        assert_eq!(db.len().unwrap(), 3);
+
+        db.column(1).delete(b"key".to_vec()).unwrap();
+        assert_eq!(db.column(1).get(b"key".to_vec()).unwrap(), None);
+        assert_eq!(
+            db.column(2).get(b"key".to_vec()).unwrap(),
+            Some(b"two".to_vec())
+        );
+        assert_eq!(db.get(b"key".to_vec()).unwrap(), Some(b"table".to_vec()));
+        db.flush_changes().unwrap();
+        drop(db);
+
+        let mut db = HashTable::new(path, [0; 32], None).unwrap();
+        assert_eq!(
+            db.column(2).get(b"key".to_vec()).unwrap(),
+            Some(b"two".to_vec())
+        );
+        assert_eq!(db.column(1).get(b"key".to_vec()).unwrap(), None);
+        assert_eq!(db.len().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_sanity_db_columns_walks() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let options = Options {
+            store_keys: true,
+            ..Options::default()
+        };
+        let open = |name: &str| {
+            HashTable::new_with_options(tmp_dir.path().join(name), [0; 32], None, options.clone())
+                .unwrap()
+        };
+        let check = |db: &mut HashTable| {
+            assert_eq!(db.get(b"key".to_vec()).unwrap(), Some(b"table".to_vec()));
+            assert_eq!(
+                db.column(1).get(b"key".to_vec()).unwrap(),
+                Some(b"one".to_vec())
+            );
+            assert_eq!(
+                db.column(2).get(b"key".to_vec()).unwrap(),
+                Some(b"two".to_vec())
+            );
+            assert_eq!(db.len().unwrap(), 3);
+        };
+        let mut db = open("db");
+        db.set(b"key".to_vec(), b"table".to_vec()).unwrap();
+        db.column(1).set(b"key".to_vec(), b"one".to_vec()).unwrap();
+        db.column(2).set(b"key".to_vec(), b"two".to_vec()).unwrap();
+
+        // The keys are hashed anew in their columns
+        db.rehash([1; 32]).unwrap();
+        check(&mut db);
+
+        let mut dump = vec![];
+        assert_eq!(db.export(&mut dump).unwrap(), 3);
+        let mut imported = open("imported");
+        assert_eq!(imported.import(&mut &dump[..]).unwrap(), 3);
+        check(&mut imported);
+
+        let mut merged = open("merged");
+        assert_eq!(
+            merged
+                .merge_from(&mut db, MergeConflict::Overwrite)
+                .unwrap(),
+            3
+        );
+        check(&mut merged);
+        assert_eq!(
+            merged.content_digest().unwrap(),
+            imported.content_digest().unwrap()
+        );
+
+        // The same pair in another column is another pair
+        let mut other = open("other");
+        other.set(b"key".to_vec(), b"table".to_vec()).unwrap();
+        other
+            .column(2)
+            .set(b"key".to_vec(), b"one".to_vec())
+            .unwrap();
+        other
+            .column(1)
+            .set(b"key".to_vec(), b"two".to_vec())
+            .unwrap();
+        assert_ne!(
+            other.content_digest().unwrap(),
+            imported.content_digest().unwrap()
+        );
+
+        let drained = db
+            .drain()
+            .unwrap()
+            .collect::<Result<HashSet<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            drained,
+            HashSet::from([
+                (None, b"key".to_vec(), b"table".to_vec()),
+                (Some(1), b"key".to_vec(), b"one".to_vec()),
+                (Some(2), b"key".to_vec(), b"two".to_vec()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_sanity_db_get_into() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let value = |i: u32| vec![i as u8; (i as usize * 37) % 1000];
+        let mut out = Vec::with_capacity(1000);
+        let buffer = out.as_ptr();
+        for (name, options) in [
+            ("plain", Options::default()),
+            (
+                "fields",
+                Options {
+                    store_seq: true,
+                    store_keys: true,
+                    ..Options::default()
+                },
+            ),
+            (
+                "transforms",
+                Options {
+                    transforms: vec![Arc::new(Tag(1)), Arc::new(Xor(0xff))],
+                    ..Options::default()
+                },
+            ),
+        ] {
+            let mut db =
+                HashTable::new_with_options(tmp_dir.path().join(name), [0; 32], None, options)
+                    .unwrap();
+            for i in 0..300u32 {
+                db.set(i.to_le_bytes().to_vec(), value(i)).unwrap();
+            }
+            db.flush_changes().unwrap();
+            for i in 0..310u32 {
+                let key = i.to_le_bytes().to_vec();
+                let found = db.get_into(key.clone(), &mut out).unwrap();
+                assert_eq!(found.then(|| out.clone()), db.get(key).unwrap());
+            }
+        }
+        // The capacity of `out` was enough for every value, so it was never reallocated
+        assert_eq!(out.as_ptr(), buffer);
+        assert_eq!(out.capacity(), 1000);
+    }
+
+    #[test]
+    fn test_sanity_db_seek_fixed() {
+        // The probe as it was before the slots were read into arrays, with an allocation each
+        fn seek_allocating(db: &mut HashTable, hash: [u8; HASH_LEN]) -> (u64, u64) {
+            let mut slot = db.get_slot(&hash);
+            let sector_offset = *db.ht_mapping.range(..=hash).next_back().unwrap().1;
+            let mut first_tombstone = None;
+            for _ in 0..db.slots_in_sector() {
+                let offset = db.layout.slot_offset(sector_offset, slot);
+                let data = db.tx.get(&*db.file, offset, db.layout.slot_size()).unwrap();
+                let value = db.layout.extract_value(&data);
+                if value == TOMBSTONE {
+                    first_tombstone.get_or_insert(offset);
+                } else if value == NO_VALUE {
+                    return (first_tombstone.unwrap_or(offset), NO_VALUE);
+                } else if db.layout.extract_hash(&data) == hash {
+                    return (offset, value);
+                }
+                slot = (slot + 1) % db.slots_in_sector();
+            }
+            (first_tombstone.unwrap(), NO_VALUE)
+        }
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        for (name, hash_len) in [("full", HASH_LEN), ("short", 20)] {
+            let options = Options {
+                hash_len,
+                ..Options::default()
+            };
+            let mut db =
+                HashTable::new_with_options(tmp_dir.path().join(name), [0; 32], None, options)
+                    .unwrap();
+            for i in 0..2000u32 {
+                db.set(i.to_le_bytes().to_vec(), vec![i as u8; 10]).unwrap();
+            }
+            for i in (0..2000u32).step_by(3) {
+                db.delete(i.to_le_bytes().to_vec()).unwrap();
+            }
+            for flush in [false, true] {
+                if flush {
+                    db.flush_changes().unwrap();
+                }
+                for i in 0..2500u32 {
+                    let hash = db.get_hash(&i.to_le_bytes());
+                    let expected = seek_allocating(&mut db, hash);
+                    assert_eq!(db.seek(hash).unwrap(), expected);
+                    assert_eq!(expected.1 != NO_VALUE, i < 2000 && i % 3 != 0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_sanity_db_merge_from() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let options = || Options {
+            store_keys: true,
+            ..Options::default()
+        };
+        let key = |i: u32| i.to_le_bytes().to_vec();
+        let value = |i: u32, tag: u8| vec![tag; (i as usize * 13) % 500];
+        for conflict in [MergeConflict::KeepExisting, MergeConflict::Overwrite] {
+            let dir = tmp_dir.path().join(format!("{:?}", conflict));
+            std::fs::create_dir(&dir).unwrap();
+            let mut db =
+                HashTable::new_with_options(dir.join("db"), [1; 32], None, options()).unwrap();
+            let mut other =
+                HashTable::new_with_options(dir.join("other"), [2; 32], None, options()).unwrap();
+            // The keys 0..6000 are in `db`, 4000..10000 in `other`, and 2000..3000 were deleted
+            // from `other`, so that its values region holds dead records
+            for i in 0..6000 {
+                db.set(key(i), value(i, 1)).unwrap();
+            }
+            for i in (2000..3000).chain(4000..10000) {
+                other.set(key(i), value(i, 2)).unwrap();
+            }
+            for i in 2000..3000 {
+                other.delete(key(i)).unwrap();
+            }
+            other.flush_changes().unwrap();
+
+            let merged = db.merge_from(&mut other, conflict).unwrap();
+            assert_eq!(
+                merged,
+                match conflict {
+                    MergeConflict::KeepExisting => 4000,
+                    MergeConflict::Overwrite => 6000,
+                }
+            );
+            assert!(db.tx.changes.is_empty());
+            assert_eq!(db.len().unwrap(), 10000);
+            for i in 0..10000 {
+                let tag = if i < 4000 || (i < 6000 && conflict == MergeConflict::KeepExisting) {
+                    1
+                } else {
+                    2
+                };
+                assert_eq!(db.get(key(i)).unwrap(), Some(value(i, tag)));
+            }
+            // The table merged from is left as it was
+            assert_eq!(other.len().unwrap(), 6000);
+            assert_eq!(other.get(key(2500)).unwrap(), None);
+            assert!(db.verify().unwrap().is_consistent());
+        }
+
+        let mut db =
+            HashTable::new_with_options(tmp_dir.path().join("db"), [1; 32], None, options())
+                .unwrap();
+        let mut other = HashTable::new(tmp_dir.path().join("other"), [2; 32], None).unwrap();
+        other.set(key(1), value(1, 2)).unwrap();
+        assert!(matches!(
+            db.merge_from(&mut other, MergeConflict::Overwrite),
+            Err(StoreError::KeyNotStored { .. })
+        ));
+    }
+
+    #[test]
+    fn test_sanity_db_sector_percent() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        // The highest occupancy of the first sector before it's split
+        let fill_until_split = |name: &str, options: Options| {
+            let mut db =
+                HashTable::new_with_options(tmp_dir.path().join(name), [0; 32], None, options)
+                    .unwrap();
+            let mut peak = 0;
+            let mut i = 0u32;
+            while db.ht_mapping.len() == 1 {
+                peak = db.sector_occupancies().unwrap()[0];
+                db.set(i.to_le_bytes().to_vec(), i.to_le_bytes().to_vec())
+                    .unwrap();
+                i += 1;
+            }
+            for j in 0..i + 100 {
+                let expected = (j < i).then(|| j.to_le_bytes().to_vec());
+                assert_eq!(db.get(j.to_le_bytes().to_vec()).unwrap(), expected);
             }
-            next_delmap_physical_offset += DELMAP_ENTRY_SIZE;
-            self.tx.set(
-                NEXT_DELMAP_PHYSICAL_OFFSET,
-                next_delmap_physical_offset.to_le_bytes().to_vec(),
+            (peak, db.slots_in_sector())
+        };
+        let sector_size = 64 << 10;
+
+        let (peak, capacity) = fill_until_split(
+            "default",
+            Options {
+                sector_size,
+                ..Options::default()
+            },
+        );
+        assert!(peak < capacity * DEFAULT_MAX_SECTOR_PERCENT / 100);
+        let (high_peak, capacity) = fill_until_split(
+            "high",
+            Options {
+                sector_size,
+                max_sector_percent: 98,
+                early_sector_percent: 98,
+                ..Options::default()
+            },
+        );
+        assert_eq!(high_peak, capacity * 98 / 100 - 1);
+        assert!(high_peak > peak);
+    }
+
+    #[test]
+    fn test_sanity_db_adaptive_resize() {
+        #[derive(Debug)]
+        struct TestClock(AtomicU64);
+
+        impl Clock for TestClock {
+            fn now_unix_secs(&self) -> u64 {
+                self.0.load(Ordering::SeqCst)
+            }
+        }
+
+        let clock = Arc::new(TestClock(1000.into()));
+        let tmp_dir = TempDir::new("example").unwrap();
+        let options = Options {
+            resize_spacing_writes: 100,
+            adaptive_resize: true,
+            clock: clock.clone(),
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(tmp_dir.path().join("db"), [0; 32], None, options).unwrap();
+        let mut next_key = 0u32;
+        let mut write = |db: &mut HashTable, n: u32| {
+            for _ in 0..n {
+                db.set(next_key.to_le_bytes().to_vec(), vec![1]).unwrap();
+                next_key += 1;
+            }
+        };
+        let next_window = || {
+            clock
+                .0
+                .fetch_add(ADAPTIVE_RESIZE_WINDOW_SECS, Ordering::SeqCst);
+        };
+
+        write(&mut db, 10);
+        assert_eq!(db.early_sector_percent, DEFAULT_EARLY_SECTOR_PERCENT);
+        // Every quiet window raises the threshold, up to the maximum
+        for step in 1..=10 {
+            next_window();
+            write(&mut db, 1);
+            assert_eq!(
+                db.early_sector_percent,
+                (DEFAULT_EARLY_SECTOR_PERCENT + step * ADAPTIVE_RESIZE_STEP_PERCENT)
+                    .min(DEFAULT_MAX_SECTOR_PERCENT)
             );
         }
-        let mut cur_delmap = self.tx.get(
-            &mut self.file,
-            next_delmap_physical_offset - DELMAP_ENTRY_SIZE,
-            DELMAP_ENTRY_SIZE,
+        // A busy window resets it
+        write(&mut db, 200);
+        next_window();
+        write(&mut db, 1);
+        assert_eq!(db.early_sector_percent, DEFAULT_EARLY_SECTOR_PERCENT);
+        // Rewriting the present keys occupies no new slots
+        for i in 0..200u32 {
+            db.set(i.to_le_bytes().to_vec(), vec![2]).unwrap();
+        }
+        next_window();
+        write(&mut db, 1);
+        assert_eq!(
+            db.early_sector_percent,
+            DEFAULT_EARLY_SECTOR_PERCENT + ADAPTIVE_RESIZE_STEP_PERCENT
         );
-        cur_delmap[offset_within_delmap as usize / 8] |= (1 << (offset_within_delmap % 8)) as u8;
-        self.tx
-            .set(next_delmap_physical_offset - DELMAP_ENTRY_SIZE, cur_delmap);
+        assert_eq!(db.len().unwrap(), next_key as u64);
+    }
 
-        cur_offset
+    #[test]
+    fn test_sanity_db_unmapped_value() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut db = HashTable::new(tmp_dir.path().join("db"), [0; 32], None).unwrap();
+        for i in 0..10u32 {
+            db.set(i.to_le_bytes().to_vec(), vec![1; 10]).unwrap();
+        }
+        // The slots still point into the values region, but no sector maps it anymore
+        db.values_mapping.clear();
+        db.delmap_mapping.clear();
+
+        let key = 5u32.to_le_bytes().to_vec();
+        let expected_hash = db.get_hash(&key);
+        match db.get(key.clone()) {
+            Err(StoreError::DanglingValue { hash, offset }) => {
+                assert_eq!(hash, expected_hash);
+                assert_eq!(offset, 5 * VALUE_SIZE);
+            }
+            result => panic!("expected a dangling value error, got {:?}", result),
+        }
+        let mut out = vec![];
+        assert!(matches!(
+            db.get_into(key.clone(), &mut out),
+            Err(StoreError::DanglingValue { .. })
+        ));
+        let unmapped = |result: Result<_, StoreError>| {
+            matches!(
+                result,
+                Err(StoreError::Corruption {
+                    offset: FIRST_VALUE_LOGICAL_OFFSET,
+                    reason: UNMAPPED_VALUE | UNMAPPED_DELMAP,
+                })
+            )
+        };
+        assert!(unmapped(db.delete(key.clone())));
+        assert!(unmapped(db.locate(key).map(|_| ())));
+        assert!(unmapped(db.iter().unwrap().next().unwrap().map(|_| ())));
     }
 
-    fn delete_value(&mut self, logical_offset: u64) {
-        let (sector_logical_offset, sector_physical_offset) = self
-            .delmap_mapping
-            .range(..=logical_offset)
-            .next_back()
-            .unwrap();
-        let file_offset = sector_physical_offset
-            + (logical_offset - sector_logical_offset) / VALUE_SIZE / DELS_PER_DELMAP
-                * DELMAP_ENTRY_SIZE;
+    #[test]
+    fn test_sanity_db_bloom() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("db");
+        let options = || Options {
+            expected_keys: 5000,
+            delete_strategy: DeleteStrategy::Tombstone,
+            ..Options::default()
+        };
+        let key = |i: u32| i.to_le_bytes().to_vec();
+        // The number of the keys from 5000 on that the filter doesn't rule out
+        let false_positives = |db: &HashTable| {
+            (5000..15000)
+                .filter(|&i| {
+                    db.bloom
+                        .as_ref()
+                        .unwrap()
+                        .may_contain(&db.get_hash(&key(i)))
+                })
+                .count()
+        };
 
-        let offset_within_delmap = (logical_offset / VALUE_SIZE) % DELS_PER_DELMAP;
+        let mut db = HashTable::new_with_options(path.clone(), [0; 32], None, options()).unwrap();
+        for i in 0..4000 {
+            db.set(key(i), vec![i as u8; 10]).unwrap();
+        }
+        for i in 4000..5000 {
+            db.ht_set(key(i), 1).unwrap();
+        }
+        for i in (0..5000).step_by(7) {
+            db.delete(key(i)).unwrap();
+        }
+        let check = |db: &mut HashTable| {
+            for i in 0..5000 {
+                assert!(
+                    db.bloom
+                        .as_ref()
+                        .unwrap()
+                        .may_contain(&db.get_hash(&key(i)))
+                        || i % 7 == 0
+                );
+                let present = i % 7 != 0;
+                assert_eq!(db.contains_key(key(i)).unwrap(), present);
+                assert_eq!(db.ht_get(key(i)).unwrap().is_some(), present);
+            }
+            for i in 5000..6000 {
+                assert_eq!(db.get(key(i)).unwrap(), None);
+                assert!(!db.contains_key(key(i)).unwrap());
+            }
+            assert_eq!(db.get(key(1)).unwrap(), Some(vec![1; 10]));
+            assert!(false_positives(db) < 300);
+        };
+        check(&mut db);
+        db.flush_changes().unwrap();
+        drop(db);
+
+        // The filter is rebuilt from the hash table on open, without the deleted keys
+        let mut db = HashTable::new_with_options(path, [0; 32], None, options()).unwrap();
+        check(&mut db);
+        let deleted_passing = (0..5000)
+            .step_by(7)
+            .filter(|&i| {
+                db.bloom
+                    .as_ref()
+                    .unwrap()
+                    .may_contain(&db.get_hash(&key(i)))
+            })
+            .count();
+        assert!(deleted_passing < 30);
+
+        db.clear().unwrap();
+        assert_eq!(false_positives(&db), 0);
+        assert!(!db
+            .bloom
+            .as_ref()
+            .unwrap()
+            .may_contain(&db.get_hash(&key(1))));
 
-        let mut cur_delmap = self.tx.get(&mut self.file, file_offset, DELMAP_ENTRY_SIZE);
-        cur_delmap[offset_within_delmap as usize / 8] &= !((1 << (offset_within_delmap % 8)) as u8);
-        self.tx.set(file_offset, cur_delmap);
+        // Rolling the clear back refills the filter
+        db.rollback().unwrap();
+        check(&mut db);
     }
 
-    // `prelude` should be split into vectors of the same size / alignment as will later be used by
-    // the user of the page. It is expected that the prelude will have 8 bytes vectors at offsets
-    // 48 and 56, the one at 48 containing the type of the page.
-    fn allocate_sector(
-        &mut self,
-        prelude: Vec<Vec<u8>>,
-        expected_prelude_size: u64,
-        el_size: u64,
-    ) -> u64 {
-        let mut file_size = self.tx.get_num(&mut self.file, 0);
-
-        let cur_free_offset = self.tx.get_num(&mut self.file, FREE_LIST_OFFSET);
-        let ret = if cur_free_offset != 0 {
-            let new_free_offset = self.tx.get_num(&mut self.file, cur_free_offset + 56);
-            self.tx
-                .set(FREE_LIST_OFFSET, new_free_offset.to_le_bytes().to_vec());
-            cur_free_offset
-        } else {
-            self.file.seek(SeekFrom::Start(file_size)).expect(IO_ERROR);
-            self.file
-                .write_all(vec![0; SECTOR_SIZE as usize].as_ref())
-                .expect(IO_ERROR);
+    #[test]
+    fn test_sanity_db_iter_values_by_logical() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let value = |i: u32| vec![i as u8; (i as usize * 31) % 700];
+        for (name, enable_compaction) in [("compaction", true), ("no_compaction", false)] {
+            let options = Options {
+                enable_compaction,
+                ..Options::default()
+            };
+            let mut db =
+                HashTable::new_with_options(tmp_dir.path().join(name), [0; 32], None, options)
+                    .unwrap();
+            let mut live = BTreeMap::new();
+            for i in 0..1000u32 {
+                db.set(i.to_le_bytes().to_vec(), value(i)).unwrap();
+                live.insert(i, value(i));
+            }
+            for i in (0..1000u32).step_by(3) {
+                db.delete(i.to_le_bytes().to_vec()).unwrap();
+                live.remove(&i);
+            }
+            for i in (1..1000u32).step_by(5) {
+                db.set(i.to_le_bytes().to_vec(), value(i + 1)).unwrap();
+                live.insert(i, value(i + 1));
+            }
 
-            file_size += SECTOR_SIZE;
-            self.tx.set(0, file_size.to_le_bytes().to_vec());
+            let entries = db
+                .iter_values_by_logical()
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert!(entries.windows(2).all(|pair| pair[0].0 < pair[1].0));
+            let mut values = entries
+                .into_iter()
+                .map(|(_, value)| value)
+                .collect::<Vec<_>>();
+            let mut expected = live.into_values().collect::<Vec<_>>();
+            values.sort();
+            expected.sort();
+            assert_eq!(values, expected);
+        }
+    }
 
-            file_size - SECTOR_SIZE
+    #[test]
+    fn test_sanity_db_barrier() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("db");
+        let options = || Options {
+            durability: Durability::Full,
+            ..Options::default()
         };
+        let key = |i: u32| i.to_le_bytes().to_vec();
 
-        self.tx.reset_sector(ret);
+        let mut db = HashTable::new_with_options(path.clone(), [0; 32], None, options()).unwrap();
+        for batch in 0..5u32 {
+            for i in batch * 1000..(batch + 1) * 1000 {
+                db.set(key(i), vec![batch as u8; 100]).unwrap();
+            }
+            db.flush_changes_no_sync().unwrap();
+        }
+        assert_eq!(db.tx.syncs, 0);
+        db.barrier().unwrap();
+        assert_eq!(db.tx.syncs, 1);
+        drop(db);
+        let mut db = HashTable::new_with_options(path.clone(), [0; 32], None, options()).unwrap();
+        assert_eq!(db.len().unwrap(), 5000);
+        for i in 0..5000 {
+            assert_eq!(db.get(key(i)).unwrap(), Some(vec![(i / 1000) as u8; 100]));
+        }
+        drop(db);
 
-        let mut offset = ret;
-        for v in prelude {
-            let v_len = v.len() as u64;
-            self.tx.set(offset, v);
-            offset += v_len;
+        // The commits through the log defer their syncs the same way
+        let wal = || WalConfig {
+            path: tmp_dir.path().join("wal"),
+            sync: Durability::Full,
+        };
+        let mut db = HashTable::new_with_wal(path.clone(), [0; 32], wal(), options()).unwrap();
+        let syncs = db.tx.syncs;
+        for batch in 0..5u32 {
+            for i in batch * 1000..(batch + 1) * 1000 {
+                db.delete(key(i)).unwrap();
+                db.set(key(i + 5000), vec![batch as u8; 100]).unwrap();
+            }
+            db.commit_no_sync().unwrap();
+        }
+        assert_eq!(db.tx.syncs, syncs);
+        db.barrier().unwrap();
+        assert_eq!(db.tx.syncs, syncs + 2);
+        drop(db);
+        let mut db = HashTable::new_with_wal(path, [0; 32], wal(), options()).unwrap();
+        assert_eq!(db.len().unwrap(), 5000);
+        for i in 0..5000 {
+            assert_eq!(db.get(key(i)).unwrap(), None);
+            assert_eq!(
+                db.get(key(i + 5000)).unwrap(),
+                Some(vec![(i / 1000) as u8; 100])
+            );
         }
+    }
+
+    #[test]
+    fn test_sanity_db_append() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut db = HashTable::new(tmp_dir.path().join("db"), [0; 32], None).unwrap();
+        let next_value_logical = |db: &mut HashTable| db.stats().unwrap().next_value_logical;
+        // The number of value slots written since the last flush
+        let value_slots_written = |db: &HashTable| {
+            db.tx
+                .changes
+                .range(FIRST_SECTOR_OFFSET..)
+                .filter(|(_, data)| data.len() == VALUE_SIZE as usize)
+                .count()
+        };
 
-        assert_eq!(offset - ret, expected_prelude_size);
+        // 100 bytes take two slots with the header and the checksum, and so do 110
+        db.set(b"log".to_vec(), vec![1; 100]).unwrap();
+        db.flush_changes().unwrap();
+        let end = next_value_logical(&mut db);
+        db.append(b"log".to_vec(), &[2; 10]).unwrap();
+        assert_eq!(next_value_logical(&mut db), end);
+        let expected = [vec![1; 100], vec![2; 10]].concat();
+        assert_eq!(db.get(b"log".to_vec()).unwrap(), Some(expected.clone()));
+
+        // Crossing into a third slot moves the record
+        db.append(b"log".to_vec(), &[3; 200]).unwrap();
+        assert!(next_value_logical(&mut db) >= end + 3 * VALUE_SIZE);
+        let expected = [expected, vec![3; 200]].concat();
+        assert_eq!(db.get(b"log".to_vec()).unwrap(), Some(expected.clone()));
+        assert_eq!(db.len().unwrap(), 1);
+
+        // Appending to a large value only writes the slots around the suffix
+        let large = vec![4; 10000];
+        db.set(b"large".to_vec(), large.clone()).unwrap();
+        db.flush_changes().unwrap();
+        db.append(b"large".to_vec(), &[5; 3]).unwrap();
+        assert_eq!(value_slots_written(&db), 2);
+        assert_eq!(
+            db.get(b"large".to_vec()).unwrap(),
+            Some([large, vec![5; 3]].concat())
+        );
 
-        while offset % SECTOR_SIZE != FIRST_SECTOR_OFFSET {
-            self.tx.set(offset, vec![0u8; el_size as usize]);
-            offset += el_size;
+        // An absent key is set to the suffix
+        db.append(b"new".to_vec(), b"abc").unwrap();
+        assert_eq!(db.get(b"new".to_vec()).unwrap(), Some(b"abc".to_vec()));
+        db.flush_changes().unwrap();
+        drop(db);
+        let mut db = HashTable::new(tmp_dir.path().join("db"), [0; 32], None).unwrap();
+        assert_eq!(db.get(b"log".to_vec()).unwrap(), Some(expected));
+        assert!(db.verify().unwrap().is_consistent());
+
+        // With the keys and the sequence numbers stored, and with transforms
+        for (name, options) in [
+            (
+                "fields",
+                Options {
+                    store_keys: true,
+                    store_seq: true,
+                    ..Options::default()
+                },
+            ),
+            (
+                "transforms",
+                Options {
+                    transforms: vec![Arc::new(Xor(0xff))],
+                    ..Options::default()
+                },
+            ),
+        ] {
+            let mut db =
+                HashTable::new_with_options(tmp_dir.path().join(name), [0; 32], None, options)
+                    .unwrap();
+            let mut expected = vec![];
+            for i in 0..50u8 {
+                db.append(b"key".to_vec(), &[i; 7]).unwrap();
+                expected.extend_from_slice(&[i; 7]);
+                assert_eq!(db.get(b"key".to_vec()).unwrap(), Some(expected.clone()));
+            }
+            assert_eq!(db.len().unwrap(), 1);
+            assert!(db.verify().unwrap().is_consistent());
         }
-
-        ret
     }
 
-    fn free_sector(&mut self, offset: u64) {
-        assert_eq!(offset & (SECTOR_SIZE - 1), FIRST_SECTOR_OFFSET);
-        self.tx
-            .set(offset + 48, PAGE_TYPE_FREE.to_le_bytes().to_vec());
-        let cur_free_offset = self.tx.get_num(&mut self.file, FREE_LIST_OFFSET);
-        self.tx
-            .set(offset + 56, cur_free_offset.to_le_bytes().to_vec());
-        self.tx.set(FREE_LIST_OFFSET, offset.to_le_bytes().to_vec());
-    }
+    #[test]
+    fn test_sanity_db_probe_strategy() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let key = |i: u32| i.to_le_bytes().to_vec();
+        for (name, probe_strategy) in [
+            ("linear", ProbeStrategy::Linear),
+            ("double", ProbeStrategy::DoubleHashing),
+        ] {
+            let path = tmp_dir.path().join(name);
+            let options = Options {
+                sector_size: 64 << 10,
+                max_sector_percent: 98,
+                early_sector_percent: 98,
+                probe_strategy,
+                ..Options::default()
+            };
+            let mut db =
+                HashTable::new_with_options(path.clone(), [0; 32], None, options.clone()).unwrap();
+            // Fill the single sector up to just below its resize, and churn it
+            let count = (db.slots_in_sector() * 98 / 100 - 1) as u32;
+            for i in 0..count {
+                db.set(key(i), key(i)).unwrap();
+            }
+            assert_eq!(db.ht_mapping.len(), 1);
+            assert_eq!(db.sector_occupancies().unwrap()[0], count as u64);
+            // The tombstones count towards the resize, so the churn may split the sector
+            for round in 0..3u32 {
+                for i in (round..count).step_by(3) {
+                    db.delete(key(i)).unwrap();
+                }
+                for i in (round..count).step_by(3) {
+                    db.set(key(i), key(i + round)).unwrap();
+                }
+            }
+            assert_eq!(db.len().unwrap(), count as u64);
+            db.flush_changes().unwrap();
+
+            let check = |db: &mut HashTable| {
+                let snapshot = db.read_snapshot().unwrap();
+                for i in 0..count + 100 {
+                    let expected = (i < count).then(|| key(i + i % 3));
+                    assert_eq!(db.get(key(i)).unwrap(), expected);
+                    assert_eq!(snapshot.get(key(i)).unwrap(), expected);
+                }
+                assert!(db.verify().unwrap().is_consistent());
+            };
+            check(&mut db);
+            drop(db);
+            let mut db = HashTable::new_with_options(path, [0; 32], None, options).unwrap();
+            check(&mut db);
+        }
 
-    fn extract_value(data: &Vec<u8>) -> u64 {
-        let mut buf = [0u8; 8];
-        buf[..6].copy_from_slice(&data[HASH_LEN..SLOT_SIZE as usize]);
-        u64::from_le_bytes(buf)
+        // The strategy is stored in the header
+        let other = Options {
+            sector_size: 64 << 10,
+            ..Options::default()
+        };
+        assert!(matches!(
+            HashTable::new_with_options(tmp_dir.path().join("double"), [0; 32], None, other),
+            Err(StoreError::ProbeStrategyMismatch {
+                configured: ProbeStrategy::Linear,
+                stored: ProbeStrategy::DoubleHashing,
+            })
+        ));
     }
 
-    fn get_hash(&self, key: &Vec<u8>) -> [u8; HASH_LEN] {
-        let full_hash: [u8; 32] =
-            blake3::hash([self.salt.as_ref(), key.as_ref()].concat().as_ref()).into();
-        full_hash[..HASH_LEN].try_into().unwrap()
+    #[test]
+    fn test_sanity_db_free_sector_count() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut db = HashTable::new(tmp_dir.path().join("db"), [0; 32], None).unwrap();
+        let sector = |i: u64| FIRST_SECTOR_OFFSET + i * DEFAULT_SECTOR_SIZE;
+        assert_eq!(db.free_sector_count().unwrap(), 0);
+        for _ in 1..=10 {
+            db.allocate_sector(vec![vec![0u8; VALUE_SIZE as usize]], VALUE_SIZE, VALUE_SIZE)
+                .unwrap();
+        }
+        for i in [9, 4, 10, 8] {
+            db.free_sector(sector(i)).unwrap();
+        }
+        assert_eq!(db.free_sector_count().unwrap(), 4);
+        db.allocate_sector(vec![vec![0u8; VALUE_SIZE as usize]], VALUE_SIZE, VALUE_SIZE)
+            .unwrap();
+        assert_eq!(db.free_sector_count().unwrap(), 3);
+        assert_eq!(db.stats().unwrap().num_free_sectors, 3);
     }
 
-    fn get_slot(hash: &[u8; 26]) -> u64 {
-        let mut slice: [u8; 8] = [0; 8];
-        slice.copy_from_slice(&hash[18..26]);
-        u64::from_le_bytes(slice) % SLOTS_IN_SECTOR
+    #[test]
+    fn test_sanity_db_free_sector_count_cycle() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut db = HashTable::new(tmp_dir.path().join("db"), [0; 32], None).unwrap();
+        let sector = |i: u64| FIRST_SECTOR_OFFSET + i * DEFAULT_SECTOR_SIZE;
+        for _ in 1..=10 {
+            db.allocate_sector(vec![vec![0u8; VALUE_SIZE as usize]], VALUE_SIZE, VALUE_SIZE)
+                .unwrap();
+        }
+        for i in [9, 4, 10] {
+            db.free_sector(sector(i)).unwrap();
+        }
+        // 10 -> 4 -> 9 -> 10
+        db.tx.set(sector(9) + 56, sector(10).to_le_bytes().to_vec());
+        assert!(matches!(
+            db.free_sector_count(),
+            Err(StoreError::Corruption {
+                offset: FREE_LIST_OFFSET,
+                ..
+            })
+        ));
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::*;
-    use rand::Rng;
-    use tempdir::TempDir;
 
     #[test]
-    fn test_sanity_db_free_list() {
+    fn test_sanity_db_op_deadline() {
+        // Every reading of the clock advances it by a millisecond
+        #[derive(Debug)]
+        struct TestClock(AtomicU64);
+
+        impl Clock for TestClock {
+            fn now_unix_secs(&self) -> u64 {
+                self.now().as_secs()
+            }
+
+            fn now(&self) -> Duration {
+                Duration::from_millis(self.0.fetch_add(1, Ordering::SeqCst))
+            }
+        }
+
         let tmp_dir = TempDir::new("example").unwrap();
-        let mut db = HashTable::new(
-            tmp_dir.path().join("db"),
-            rand::thread_rng().gen::<[u8; 32]>(),
-            None,
-        );
+        let options = Options {
+            clock: Arc::new(TestClock(0.into())),
+            op_deadline: Some(Duration::from_millis(5)),
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(tmp_dir.path().join("db"), [0; 32], None, options).unwrap();
+        db.set(b"key".to_vec(), b"value".to_vec()).unwrap();
+        assert_eq!(db.get(b"key".to_vec()).unwrap(), Some(b"value".to_vec()));
+
+        // Plant a cluster of 1500 slots, i.e. 12 pages, from the slot 0 of the only sector
+        let hash = |i: u64| {
+            let mut hash = [0; HASH_LEN];
+            hash[..8].copy_from_slice(&(i + 1).to_le_bytes());
+            hash
+        };
+        assert_eq!(db.ht_mapping.len(), 1);
+        let layout = db.layout;
+        let mut slot = 0;
+        for i in 0..1500 {
+            // Skip the slot of the key set above
+            while layout.extract_value(
+                &db.read_ht_slot(layout.slot_offset(FIRST_SECTOR_OFFSET, slot))
+                    .unwrap(),
+            ) != NO_VALUE
+            {
+                slot += 1;
+            }
+            let offset = layout.slot_offset(FIRST_SECTOR_OFFSET, slot);
+            db.tx.set(offset, layout.encode(&hash(i), 1));
+            slot += 1;
+        }
+        let absent = {
+            let mut absent = [0; HASH_LEN];
+            absent[..8].copy_from_slice(&u64::MAX.to_le_bytes());
+            absent
+        };
 
-        for i in 0..4 {
-            assert_eq!(
-                db.allocate_sector(vec![vec![0u8; VALUE_SIZE as usize]], VALUE_SIZE, VALUE_SIZE),
-                (1 + i) * SECTOR_SIZE + FIRST_SECTOR_OFFSET
-            );
+        assert!(matches!(
+            db.seek_until(absent, db.op_deadline()),
+            Err(StoreError::Timeout)
+        ));
+        assert!(matches!(db.seek_present(absent), Err(StoreError::Timeout)));
+        // The delete walks to the end of the cluster before it shifts anything back
+        let before = db.tx.changes.clone();
+        assert!(matches!(
+            db.delete_by_hash(hash(0)),
+            Err(StoreError::Timeout)
+        ));
+        assert!(db.tx.changes == before);
+        assert_eq!(db.seek(hash(0)).unwrap().1, 1);
+        // So does a batch, here for an absent key that is probed for from the start of the cluster
+        let key = (0u32..)
+            .map(|i| i.to_le_bytes().to_vec())
+            .find(|key| db.get_slot(&db.get_hash(key)) < 100)
+            .unwrap();
+        assert!(matches!(
+            db.delete_batch(vec![key]),
+            Err(StoreError::Timeout)
+        ));
+        assert!(db.tx.changes == before);
+
+        // Without the deadline, the probing goes to the end of the cluster
+        db.options.op_deadline = None;
+        assert_eq!(db.seek_present(absent).unwrap(), NO_VALUE);
+        assert_eq!(db.get(b"key".to_vec()).unwrap(), Some(b"value".to_vec()));
+
+        // A set that may resize the sector reads all of it before writing anything, and so does a
+        // delete whose tombstone makes the sector rebuilt. Only the counters are raised, since
+        // they're all the decisions read.
+        let options = Options {
+            clock: Arc::new(TestClock(0.into())),
+            op_deadline: Some(Duration::from_millis(5)),
+            delete_strategy: DeleteStrategy::Tombstone,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(tmp_dir.path().join("db2"), [0; 32], None, options)
+                .unwrap();
+        db.set(b"key".to_vec(), b"value".to_vec()).unwrap();
+        let occupancy =
+            |db: &mut HashTable| db.tx.get_num(&*db.file, FIRST_SECTOR_OFFSET + 32).unwrap();
+        let full = db.slots_in_sector() * db.options.max_sector_percent / 100;
+        db.tx
+            .set(FIRST_SECTOR_OFFSET + 32, full.to_le_bytes().to_vec());
+        let before = db.tx.changes.clone();
+        assert!(matches!(
+            db.set(b"other".to_vec(), b"value".to_vec()),
+            Err(StoreError::Timeout)
+        ));
+        assert!(db.tx.changes == before);
+        assert_eq!(db.len().unwrap(), 1);
+        assert_eq!(occupancy(&mut db), full);
+        assert_eq!(db.get(b"other".to_vec()).unwrap(), None);
+
+        let tombstones = db.slots_in_sector() * TOMBSTONE_REBUILD_PERCENT / 100 - 1;
+        db.tx
+            .set(FIRST_SECTOR_OFFSET + 32, 1u64.to_le_bytes().to_vec());
+        db.tx
+            .set(FIRST_SECTOR_OFFSET + 40, tombstones.to_le_bytes().to_vec());
+        let before = db.tx.changes.clone();
+        assert!(matches!(
+            db.delete(b"key".to_vec()),
+            Err(StoreError::Timeout)
+        ));
+        assert!(db.tx.changes == before);
+        assert_eq!(db.len().unwrap(), 1);
+        assert_eq!(occupancy(&mut db), 1);
+        assert_eq!(db.get(b"key".to_vec()).unwrap(), Some(b"value".to_vec()));
+
+        // Without the deadline, the delete rebuilds the sector
+        db.options.op_deadline = None;
+        db.delete(b"key".to_vec()).unwrap();
+        assert_eq!(db.len().unwrap(), 0);
+        assert_eq!(occupancy(&mut db), 0);
+        assert_eq!(db.get(b"key".to_vec()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_sanity_db_lifetime_bytes_written() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("db");
+        let key = |i: u32| i.to_le_bytes().to_vec();
+
+        let mut db = HashTable::new(path.clone(), [0; 32], None).unwrap();
+        assert_eq!(db.lifetime_bytes_written(), 0);
+        // Nothing to write
+        db.flush_changes().unwrap();
+        assert_eq!(db.lifetime_bytes_written(), 0);
+        for i in 0..10 {
+            let before = db.lifetime_bytes_written();
+            db.set(key(i), vec![i as u8; 100]).unwrap();
+            db.flush_changes().unwrap();
+            // At least the header, the hash table slot and the value
+            assert!(db.lifetime_bytes_written() >= before + 2 * PAGE_SIZE);
         }
+        let written = db.lifetime_bytes_written();
+        assert!(written >= 10 * PAGE_SIZE);
+        drop(db);
+
+        let db = HashTable::new(path.clone(), [0; 32], None).unwrap();
+        assert_eq!(db.lifetime_bytes_written(), written);
+        drop(db);
+
+        // The commits count the log as well
+        let wal = || WalConfig {
+            path: tmp_dir.path().join("wal"),
+            sync: Durability::None,
+        };
+        let mut db =
+            HashTable::new_with_wal(path.clone(), [0; 32], wal(), Options::default()).unwrap();
+        let before = db.lifetime_bytes_written();
+        assert!(before >= written);
+        db.set(key(10), vec![10; 100]).unwrap();
+        let flushed = db.tx.flush_bytes();
+        db.commit().unwrap();
+        let committed = db.lifetime_bytes_written();
+        assert!(committed > before + flushed);
+        drop(db);
+        let db = HashTable::new_with_wal(path, [0; 32], wal(), Options::default()).unwrap();
+        assert!(db.lifetime_bytes_written() >= committed);
+    }
 
-        for i in 0..4 {
-            db.free_sector(2 * SECTOR_SIZE + FIRST_SECTOR_OFFSET);
-            db.free_sector(4 * SECTOR_SIZE + FIRST_SECTOR_OFFSET);
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "page type mismatch")]
+    fn test_sanity_db_page_type_check() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut db = HashTable::new(tmp_dir.path().join("db"), [0; 32], None).unwrap();
+        db.set(b"key".to_vec(), vec![1; 100]).unwrap();
+        db.get_value(0).unwrap();
+
+        // Map the values to the delmap sector, as a confused offset computation would
+        let delmap_sector = *db.delmap_mapping.values().next().unwrap() - FIRST_SLOT_OFFSET;
+        db.values_mapping.insert(0, delmap_sector + VALUE_SIZE);
+        let _ = db.get_value(0);
+    }
 
-            assert_eq!(
-                db.allocate_sector(vec![vec![0u8; VALUE_SIZE as usize]], VALUE_SIZE, VALUE_SIZE),
-                4 * SECTOR_SIZE + FIRST_SECTOR_OFFSET
-            );
+    #[test]
+    fn test_sanity_db_delete_batch() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let key = |i: u32| i.to_le_bytes().to_vec();
+        let deleted = |i: u32| (1..5).contains(&(i % 5));
+        let populate = |name: &str| {
+            let mut db = HashTable::new(tmp_dir.path().join(name), [0; 32], None).unwrap();
+            for i in 0..2000 {
+                db.set(key(i), vec![i as u8; 10 + i as usize % 300])
+                    .unwrap();
+            }
+            db
+        };
+        // In reverse, so that the deletes one by one compact the records later deletes drop. The
+        // keys from 2000 on are absent.
+        let to_delete = (0..2400).rev().filter(|&i| deleted(i));
 
-            assert_eq!(
-                db.allocate_sector(vec![vec![0u8; VALUE_SIZE as usize]], VALUE_SIZE, VALUE_SIZE),
-                2 * SECTOR_SIZE + FIRST_SECTOR_OFFSET
-            );
+        let mut one_by_one = populate("one_by_one");
+        for i in to_delete.clone() {
+            one_by_one.delete(key(i)).unwrap();
+        }
+        let mut batched = populate("batched");
+        batched
+            .delete_batch(to_delete.clone().map(key).collect())
+            .unwrap();
+        // The compaction gets the same credit, and advances as far, but moves fewer live records
+        let relocations = |db: &HashTable| db.metrics_snapshot().relocations;
+        assert!(relocations(&batched) < relocations(&one_by_one));
+        assert!(batched.value_moves <= one_by_one.value_moves + 1);
 
+        for db in [&mut one_by_one, &mut batched] {
             assert_eq!(
-                db.allocate_sector(vec![vec![0u8; VALUE_SIZE as usize]], VALUE_SIZE, VALUE_SIZE),
-                (5 + i) * SECTOR_SIZE + FIRST_SECTOR_OFFSET
+                db.len().unwrap(),
+                2000 - to_delete.clone().filter(|&i| i < 2000).count() as u64
             );
+            for i in 0..2000 {
+                let expected = (!deleted(i)).then(|| vec![i as u8; 10 + i as usize % 300]);
+                assert_eq!(db.get(key(i)).unwrap(), expected);
+            }
+            assert!(db.verify().unwrap().is_consistent());
+            db.flush_changes().unwrap();
         }
+        assert_eq!(
+            Records::new(&mut one_by_one)
+                .unwrap()
+                .map(|record| record.unwrap().1.header.hash)
+                .collect::<std::collections::BTreeSet<_>>(),
+            Records::new(&mut batched)
+                .unwrap()
+                .map(|record| record.unwrap().1.header.hash)
+                .collect::<std::collections::BTreeSet<_>>()
+        );
     }
 
     #[test]
-    fn test_sanity_db_values() {
-        #[cfg(debug_assertions)]
-        const ITERS: usize = 20000;
-        #[cfg(not(debug_assertions))]
-        const ITERS: usize = 500000;
+    fn test_sanity_db_group_commit() {
+        #[derive(Debug)]
+        struct TestClock(AtomicU64);
+
+        impl Clock for TestClock {
+            fn now_unix_secs(&self) -> u64 {
+                self.0.load(Ordering::SeqCst)
+            }
+        }
 
         let tmp_dir = TempDir::new("example").unwrap();
-        let mut db = HashTable::new(
-            tmp_dir.path().join("db"),
-            rand::thread_rng().gen::<[u8; 32]>(),
-            None,
-        );
+        let path = tmp_dir.path().join("db");
+        let wal = WalConfig {
+            path: tmp_dir.path().join("wal"),
+            sync: Durability::Full,
+        };
+        let clock = Arc::new(TestClock(1000.into()));
+        let open = |max_bytes| {
+            let options = Options {
+                clock: clock.clone(),
+                group_commit: Some(GroupCommit {
+                    max_bytes,
+                    max_interval: Duration::from_secs(60),
+                }),
+                ..Options::default()
+            };
+            HashTable::new_with_wal(path.clone(), [0; 32], wal.clone(), options).unwrap()
+        };
+        let key = |i: u8| vec![i; 8];
+        let wal_len = || std::fs::metadata(&wal.path).unwrap().len();
+        // Only the steps of `commit_group` up to the crash: the group is committed in the log,
+        // but not flushed
+        let crash_after_marker = |mut db: HashTable| {
+            let (mut wal_file, _) = db.wal.take().unwrap();
+            let group = db.group.take().unwrap();
+            wal_file.seek(SeekFrom::Start(group.log_len)).unwrap();
+            TableTransaction::write_commit_marker(
+                &mut wal_file,
+                IoRetry::default(),
+                group.records,
+                group.checksums,
+            )
+            .unwrap();
+        };
 
-        let mut byte: u8 = 17;
-        let mut first_offset = db.write_value([byte; 128]);
-        let mut next_offset = first_offset + 128;
-        let mut next_del_offset = first_offset;
-        let mut next_del_byte = byte;
+        let mut db = open(u64::MAX);
+        for i in 1..=3 {
+            db.set(key(i), vec![i; 100]).unwrap();
+            db.commit().unwrap();
+        }
+        assert_eq!(db.group.as_ref().unwrap().records, 3);
+        assert!(wal_len() > 3 * 100);
+        // A rollback goes back to the last commit of the group
+        db.set(key(9), vec![9; 100]).unwrap();
+        db.rollback().unwrap();
+        assert_eq!(db.get(key(9)).unwrap(), None);
+        assert_eq!(db.get(key(3)).unwrap(), Some(vec![3; 100]));
+        db.commit_group().unwrap();
+        assert!(db.group.is_none());
+        assert_eq!(wal_len(), 8 + 8 + 32);
+
+        // The commits of a group that isn't committed as a whole are lost
+        db.set(key(4), vec![4; 100]).unwrap();
+        db.commit().unwrap();
+        db.set(key(5), vec![5; 100]).unwrap();
+        drop(db);
+        let mut db = open(u64::MAX);
+        for i in 1..=3 {
+            assert_eq!(db.get(key(i)).unwrap(), Some(vec![i; 100]));
+        }
+        assert_eq!(db.get(key(4)).unwrap(), None);
+        assert_eq!(db.get(key(5)).unwrap(), None);
 
-        for iter in 0..(ITERS * 3) {
-            byte = (byte + 1) % 250;
+        // A group committed in the log is replayed whole
+        for i in 6..=7 {
+            db.set(key(i), vec![i; 100]).unwrap();
+            db.commit().unwrap();
+        }
+        crash_after_marker(db);
+        let mut db = open(u64::MAX);
+        assert_eq!(db.get(key(6)).unwrap(), Some(vec![6; 100]));
+        assert_eq!(db.get(key(7)).unwrap(), Some(vec![7; 100]));
+        assert_eq!(wal_len(), 8 + 8 + 32);
+
+        // A group with a corrupted record isn't replayed at all, even the records that are whole
+        for i in 10..=11 {
+            db.set(key(i), vec![i; 100]).unwrap();
+            db.commit().unwrap();
+        }
+        crash_after_marker(db);
+        let mut wal_file = OpenOptions::new().write(true).open(&wal.path).unwrap();
+        wal_file.seek(SeekFrom::Start(8 + 8 + 16)).unwrap();
+        wal_file.write_all(&[0xff; 8]).unwrap();
+        drop(wal_file);
+        let mut db = open(u64::MAX);
+        assert_eq!(db.get(key(7)).unwrap(), Some(vec![7; 100]));
+        assert_eq!(db.get(key(10)).unwrap(), None);
+        assert_eq!(db.get(key(11)).unwrap(), None);
+
+        // The group is flushed once it's old enough
+        db.set(key(12), vec![12; 100]).unwrap();
+        db.commit().unwrap();
+        assert!(db.group.is_some());
+        clock.0.fetch_add(60, Ordering::SeqCst);
+        db.set(key(13), vec![13; 100]).unwrap();
+        db.commit().unwrap();
+        assert!(db.group.is_none());
+        assert_eq!(wal_len(), 8 + 8 + 32);
+        drop(db);
+
+        // Or once the log is large enough
+        let mut db = open(1);
+        db.set(key(14), vec![14; 100]).unwrap();
+        db.commit().unwrap();
+        assert!(db.group.is_none());
+        drop(db);
+        let mut db = open(u64::MAX);
+        for i in 12..=14 {
+            assert_eq!(db.get(key(i)).unwrap(), Some(vec![i; 100]));
+        }
+    }
 
-            assert_eq!(db.write_value([byte; 128]), next_offset);
-            next_offset += 128;
+    #[test]
+    fn test_sanity_db_io_retry() {
+        /// Fails the next `failures` reads and writes with an error of the `kind`.
+        #[derive(Debug, Default)]
+        struct FlakyDevice {
+            bytes: MemoryDevice,
+            failures: std::sync::atomic::AtomicU32,
+            kind: std::sync::Mutex<Option<io::ErrorKind>>,
+        }
 
-            if iter >= ITERS {
-                assert_eq!(db.get_value(next_del_offset), [next_del_byte; 128]);
-                next_del_byte = (next_del_byte + 1) % 250;
+        impl FlakyDevice {
+            fn fail(&self, failures: u32, kind: io::ErrorKind) {
+                *self.kind.lock().unwrap() = Some(kind);
+                self.failures.store(failures, Ordering::SeqCst);
+            }
 
-                if (next_del_offset / 128) % 2 == 1 {
-                    db.delete_value(next_del_offset);
+            fn maybe_fail(&self) -> io::Result<()> {
+                let failed = self
+                    .failures
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                    .is_ok();
+                if failed {
+                    return Err(self.kind.lock().unwrap().unwrap().into());
                 }
-                next_del_offset += 128;
+                Ok(())
             }
+        }
 
-            if iter >= ITERS * 2 {
-                let maybe_offsets = db.move_one_value();
-                if (first_offset / 128) % 2 == 0 {
-                    assert_eq!(maybe_offsets, Some((first_offset, next_offset)));
-                    next_offset += 128;
-                } else {
-                    assert_eq!(maybe_offsets, None);
-                }
+        impl BlockDevice for FlakyDevice {
+            fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+                self.maybe_fail()?;
+                self.bytes.read_at(buf, offset)
+            }
 
-                first_offset += 128;
+            fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+                self.maybe_fail()?;
+                self.bytes.write_at(buf, offset)
+            }
+
+            fn len(&self) -> io::Result<u64> {
+                self.bytes.len()
+            }
+
+            fn set_len(&self, len: u64) -> io::Result<()> {
+                self.bytes.set_len(len)
+            }
+
+            fn sync(&self, durability: Durability) -> io::Result<()> {
+                self.bytes.sync(durability)
             }
         }
+
+        let device = Arc::new(FlakyDevice::default());
+        let options = Options {
+            io_retry: IoRetry {
+                max_retries: 2,
+                backoff: Duration::from_micros(10),
+            },
+            ..Options::default()
+        };
+        let mut db = HashTable::open_device(device.clone(), [0; 32], options).unwrap();
+        db.set(vec![1, 2, 3], vec![4, 5, 6]).unwrap();
+
+        // The page writes and then the page reads fail twice, and succeed on the last retry
+        device.fail(2, io::ErrorKind::WouldBlock);
+        db.flush_changes().unwrap();
+        assert_eq!(device.failures.load(Ordering::SeqCst), 0);
+        device.fail(2, io::ErrorKind::Interrupted);
+        assert_eq!(db.get(vec![1, 2, 3]).unwrap(), Some(vec![4, 5, 6]));
+        assert_eq!(device.failures.load(Ordering::SeqCst), 0);
+
+        // One more failure than there are retries is returned
+        db.set(vec![7], vec![8]).unwrap();
+        device.fail(3, io::ErrorKind::TimedOut);
+        assert!(matches!(
+            db.flush_changes(),
+            Err(StoreError::Io {
+                offset: Some(_),
+                ..
+            })
+        ));
+        assert_eq!(device.failures.load(Ordering::SeqCst), 0);
+
+        // And so is the first error that isn't transient
+        db.set(vec![7], vec![9]).unwrap();
+        device.fail(2, io::ErrorKind::Other);
+        assert!(db.flush_changes().is_err());
+        assert_eq!(device.failures.load(Ordering::SeqCst), 1);
     }
 
     #[test]
-    fn test_sanity_db_get_set() {
-        let tmp_dir = TempDir::new("example").unwrap();
-        let mut db = HashTable::new(
-            tmp_dir.path().join("db"),
-            rand::thread_rng().gen::<[u8; 32]>(),
-            None,
-        );
+    fn test_sanity_db_content_digest() {
+        let key = |i: u32| i.to_le_bytes().to_vec();
+        let value = |i: u32| vec![i as u8; 1 + i as usize % 300];
+        let options = |store_keys| Options {
+            store_keys,
+            sector_size: 16 * PAGE_SIZE,
+            ..Options::default()
+        };
 
-        db.set(vec![1, 2, 3, 4], vec![5, 6, 7, 8]);
-        assert_eq!(db.get(vec![1, 2, 3, 4]), Some(vec![5, 6, 7, 8]));
-        assert_eq!(db.get(vec![1, 2, 3, 5]), None);
+        // The same pairs set in opposite orders, one table also going through overwrites and
+        // deletes of other keys, which the compaction then moves the live records around for
+        let mut forward = HashTable::new_in_memory_with_options([1; 32], options(true)).unwrap();
+        let empty = forward.content_digest().unwrap();
+        for i in 0..2000 {
+            forward.set(key(i), value(i)).unwrap();
+        }
+        let mut backward = HashTable::new_in_memory_with_options([2; 32], options(true)).unwrap();
+        for i in 2000..4000 {
+            backward.set(key(i), value(i)).unwrap();
+        }
+        for i in (0..2000).rev() {
+            backward.set(key(i), vec![0; 10]).unwrap();
+            backward.set(key(i), value(i)).unwrap();
+        }
+        for i in 2000..4000 {
+            backward.delete(key(i)).unwrap();
+        }
+        backward.flush_changes().unwrap();
+        assert_ne!(forward.values_mapping, backward.values_mapping);
+        let digest = forward.content_digest().unwrap();
+        assert_ne!(digest, empty);
+        assert_eq!(backward.content_digest().unwrap(), digest);
+
+        // Any change of the contents changes the digest
+        forward.set(key(0), value(1)).unwrap();
+        assert_ne!(forward.content_digest().unwrap(), digest);
+        forward.set(key(0), value(0)).unwrap();
+        assert_eq!(forward.content_digest().unwrap(), digest);
+        forward.delete(key(1)).unwrap();
+        assert_ne!(forward.content_digest().unwrap(), digest);
+
+        // Without the keys, the pairs are identified by the salted hashes, whether their values
+        // are inline or not
+        let digest_without_keys =
+            |salt: [u8; 32], inline_values, order: &mut dyn Iterator<Item = u32>| {
+                let options = Options {
+                    inline_values,
+                    ..options(false)
+                };
+                let mut db = HashTable::new_in_memory_with_options(salt, options).unwrap();
+                for i in order {
+                    db.set(key(i), value(i)).unwrap();
+                }
+                db.content_digest().unwrap()
+            };
+        let digest = digest_without_keys([1; 32], false, &mut (0..1000));
+        assert_eq!(
+            digest_without_keys([1; 32], true, &mut (0..1000).rev()),
+            digest
+        );
+        assert_ne!(digest_without_keys([2; 32], false, &mut (0..1000)), digest);
+
+        // Equal pair digests add up rather than cancel out, and the carries cross the bytes
+        let pair_digest = [7; 32];
+        let mut once = [0; 32];
+        add_pair_digest(&mut once, &pair_digest);
+        let mut twice = once;
+        add_pair_digest(&mut twice, &pair_digest);
+        assert_eq!(twice, [14; 32]);
+        let mut digest = [0xff; 32];
+        digest[31] = 0;
+        add_pair_digest(&mut digest, &[1; 32]);
+        let mut expected = [1; 32];
+        expected[0] = 0;
+        expected[31] = 2;
+        assert_eq!(digest, expected);
     }
 }
@@ -40,7 +40,7 @@ fn new_hash_table(exp_dir: &Path) -> HashTable {
         "Creating hash table at {}",
         path.as_os_str().to_str().unwrap()
     );
-    HashTable::new(path, salt, None)
+    HashTable::new_or_panic(path, salt, None)
 }
 
 fn genenrate_data(exp_dir: &Path) {
@@ -60,12 +60,12 @@ fn genenrate_data(exp_dir: &Path) {
         assert_eq!(value, default_rdb.get(key.clone()).unwrap().unwrap());
         settings_rdb.put(key.clone(), value.clone()).unwrap();
         assert_eq!(value, settings_rdb.get(key.clone()).unwrap().unwrap());
-        hdb.set(key.clone(), value.clone());
-        assert_eq!(value, hdb.get(key.clone()).unwrap());
+        hdb.set_or_panic(key.clone(), value.clone());
+        assert_eq!(value, hdb.get_or_panic(key.clone()).unwrap());
     }
     let elapsed = start.elapsed().as_nanos() / NUM_ELEMS as u128;
     println!("Generated data in {}", elapsed);
-    hdb.flush_changes();
+    hdb.flush_changes_or_panic();
 }
 
 fn rdb_read(db: &DB, data: &[Vec<u8>]) -> u128 {
@@ -81,7 +81,7 @@ fn ht_read(db: &mut HashTable, data: &[Vec<u8>]) -> u128 {
     let start = Instant::now();
     for _ in 0..NUM_ITER {
         let index = rand::thread_rng().gen_range(0..data.len());
-        db.get(data[index].clone()).unwrap();
+        db.get_or_panic(data[index].clone()).unwrap();
     }
     start.elapsed().as_nanos() / NUM_ITER
 }
@@ -93,7 +93,7 @@ fn read_data(default_rdb: &DB, setting_rdb: &DB, hdb: &mut HashTable) -> (Vec<Ve
         let key = key.to_vec();
         let value = value.to_vec();
         assert_eq!(setting_rdb.get(key.clone()).unwrap().unwrap(), value);
-        assert_eq!(hdb.get(key.clone()).unwrap(), value);
+        assert_eq!(hdb.get_or_panic(key.clone()).unwrap(), value);
         total_size += key.len() + value.len();
         data.push(key);
     }
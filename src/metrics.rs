@@ -0,0 +1,223 @@
+//! Counters of the operations done on a table, for monitoring.
+//!
+//! The counters are always kept: an increment is a relaxed atomic add on memory owned by the
+//! table, which is negligible next to the hashing and the IO of every operation. With the
+//! `prometheus` feature, `MetricsCollector` exports them to a `prometheus::Registry`.
+//!
+//! With `Options::probe_stats`, the table also keeps the number of slots probed by its latest
+//! `PROBE_WINDOW` seeks, see `HashTable::probe_stats`.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::HashTable;
+
+/// The number of the latest seeks `HashTable::probe_stats` is computed over.
+pub const PROBE_WINDOW: usize = 4096;
+
+/// The counters of a table, shared with the collectors through `HashTable::metrics`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    gets: AtomicU64,
+    sets: AtomicU64,
+    deletes: AtomicU64,
+    resizes: AtomicU64,
+    relocations: AtomicU64,
+}
+
+/// The values of the counters at one point in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Number of keys looked up, by `get` and the like.
+    pub gets: u64,
+    /// Number of keys set, by `set` and the like.
+    pub sets: u64,
+    /// Number of calls to `delete`, whether the key was present or not.
+    pub deletes: u64,
+    /// Number of hash table sectors resized.
+    pub resizes: u64,
+    /// Number of values moved by the compaction.
+    pub relocations: u64,
+}
+
+/// The distribution of the number of slots probed by the latest seeks, see
+/// `HashTable::probe_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ProbeStats {
+    pub avg: f64,
+    pub p99: u64,
+    pub max: u64,
+    /// The number of seeks the statistics are computed over, at most `PROBE_WINDOW`.
+    pub samples: usize,
+}
+
+/// The number of slots probed by the latest `PROBE_WINDOW` seeks, as a ring buffer.
+#[derive(Debug, Default)]
+pub(crate) struct ProbeWindow {
+    lengths: Vec<u32>,
+    /// The index the next length is recorded at once the window is full.
+    next: usize,
+}
+
+impl ProbeWindow {
+    pub(crate) fn record(&mut self, probes: u64) {
+        let probes = probes.min(u32::MAX as u64) as u32;
+        if self.lengths.len() < PROBE_WINDOW {
+            self.lengths.push(probes);
+        } else {
+            self.lengths[self.next] = probes;
+            self.next = (self.next + 1) % PROBE_WINDOW;
+        }
+    }
+
+    fn stats(&self) -> ProbeStats {
+        if self.lengths.is_empty() {
+            return ProbeStats::default();
+        }
+        let mut lengths = self.lengths.clone();
+        lengths.sort_unstable();
+        let n = lengths.len();
+        ProbeStats {
+            avg: lengths.iter().map(|&probes| probes as f64).sum::<f64>() / n as f64,
+            // The nearest rank, `ceil(0.99 * n)`
+            p99: lengths[(n * 99 - 1) / 100] as u64,
+            max: lengths[n - 1] as u64,
+            samples: n,
+        }
+    }
+}
+
+impl Metrics {
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            gets: self.gets.load(Ordering::Relaxed),
+            sets: self.sets.load(Ordering::Relaxed),
+            deletes: self.deletes.load(Ordering::Relaxed),
+            resizes: self.resizes.load(Ordering::Relaxed),
+            relocations: self.relocations.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn add_gets(&self, n: u64) {
+        self.gets.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_sets(&self, n: u64) {
+        self.sets.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_delete(&self) {
+        self.deletes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_resize(&self) {
+        self.resizes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_relocation(&self) {
+        self.relocations.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl HashTable {
+    /// Returns the current values of the counters, see the module documentation.
+    pub fn metrics_snapshot(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    /// Returns the distribution of the number of hash table slots probed by the latest
+    /// `PROBE_WINDOW` seeks, whether they were made to read or to write. A healthy distribution
+    /// of the hashes over the slots keeps the average close to 1, and a growing one means the
+    /// entries cluster, e.g. because of a poor `KeyHasher`. The reinserts of the resizes aren't
+    /// counted. Without `Options::probe_stats`, nothing is recorded and the statistics are empty.
+    pub fn probe_stats(&self) -> ProbeStats {
+        self.probe_window
+            .as_ref()
+            .map_or_else(ProbeStats::default, ProbeWindow::stats)
+    }
+
+    /// Returns the counters of the table, which can outlive it, e.g. to be registered with a
+    /// `MetricsCollector`.
+    pub fn metrics(&self) -> std::sync::Arc<Metrics> {
+        self.metrics.clone()
+    }
+}
+
+#[cfg(feature = "prometheus")]
+pub use self::collector::MetricsCollector;
+
+#[cfg(feature = "prometheus")]
+mod collector {
+    use std::sync::Arc;
+
+    use prometheus::core::{Collector, Desc};
+    use prometheus::proto::MetricFamily;
+    use prometheus::IntCounter;
+
+    use super::{Metrics, MetricsSnapshot};
+
+    /// Exports the counters of a table as prometheus counters named `<prefix>_gets_total` etc.
+    /// The counters are brought up to date on every scrape.
+    pub struct MetricsCollector {
+        metrics: Arc<Metrics>,
+        gets: IntCounter,
+        sets: IntCounter,
+        deletes: IntCounter,
+        resizes: IntCounter,
+        relocations: IntCounter,
+    }
+
+    impl MetricsCollector {
+        pub fn new(metrics: Arc<Metrics>, prefix: &str) -> prometheus::Result<Self> {
+            let counter = |name: &str, help: &str| {
+                IntCounter::new(format!("{}_{}_total", prefix, name), help)
+            };
+            Ok(Self {
+                metrics,
+                gets: counter("gets", "Number of keys looked up")?,
+                sets: counter("sets", "Number of keys set")?,
+                deletes: counter("deletes", "Number of keys deleted")?,
+                resizes: counter("resizes", "Number of hash table sectors resized")?,
+                relocations: counter("relocations", "Number of values moved by the compaction")?,
+            })
+        }
+
+        fn counters(&self) -> [&IntCounter; 5] {
+            [
+                &self.gets,
+                &self.sets,
+                &self.deletes,
+                &self.resizes,
+                &self.relocations,
+            ]
+        }
+    }
+
+    impl Collector for MetricsCollector {
+        fn desc(&self) -> Vec<&Desc> {
+            self.counters()
+                .into_iter()
+                .flat_map(|counter| counter.desc())
+                .collect()
+        }
+
+        fn collect(&self) -> Vec<MetricFamily> {
+            let MetricsSnapshot {
+                gets,
+                sets,
+                deletes,
+                resizes,
+                relocations,
+            } = self.metrics.snapshot();
+            let values = [gets, sets, deletes, resizes, relocations];
+            self.counters()
+                .into_iter()
+                .zip(values)
+                .flat_map(|(counter, value)| {
+                    // The table only ever increments its counters, so this never goes negative
+                    counter.inc_by(value.saturating_sub(counter.get()));
+                    counter.collect()
+                })
+                .collect()
+        }
+    }
+}
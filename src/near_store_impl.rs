@@ -0,0 +1,170 @@
+//! An implementation of near_store's `Database` trait over `HashTable`, so that the table can be
+//! used as the storage of nearcore without going through RocksDB.
+//!
+//! All the columns share the flat keyspace of the table: every key is prefixed with a one-byte
+//! tag of its column before it is hashed. The iteration ops and `DBOp::DeleteAll` have to walk the
+//! whole table, so they require all the values to have been written with `Options::store_keys`.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Mutex;
+
+use near_store::db::{DBIterator, DBOp, DBTransaction, Database, StoreStatistics};
+use near_store::DBCol;
+
+use crate::{HashTable, StoreError};
+
+/// The size of the refcount at the end of the values of the refcounted columns.
+const REFCOUNT_LEN: usize = 8;
+
+/// A `HashTable` shared between the threads of nearcore, see the module documentation.
+pub struct NearStoreDatabase {
+    table: Mutex<HashTable>,
+}
+
+impl NearStoreDatabase {
+    pub fn new(table: HashTable) -> Self {
+        Self {
+            table: Mutex::new(table),
+        }
+    }
+
+    pub fn into_inner(self) -> HashTable {
+        self.table.into_inner().expect("table lock poisoned")
+    }
+
+    fn column_key(col: DBCol, key: &[u8]) -> Vec<u8> {
+        [&[col as u8], key].concat()
+    }
+
+    /// Returns all the entries of the column whose keys start with `prefix`, without the column
+    /// tag. The entries are collected upfront, since the iterators of the table borrow it, and
+    /// sorted by key, so that the iterators yield them in the same order as with RocksDB.
+    fn collect_column(
+        table: &mut HashTable,
+        col: DBCol,
+        prefix: &[u8],
+    ) -> Result<BTreeMap<Vec<u8>, Vec<u8>>, StoreError> {
+        let mut entries = BTreeMap::new();
+        for entry in table.iter()? {
+            let (key, value) = entry?;
+            if key.first() == Some(&(col as u8)) && key[1..].starts_with(prefix) {
+                entries.insert(key[1..].to_vec(), value);
+            }
+        }
+        Ok(entries)
+    }
+
+    fn iter_column<'a>(&'a self, col: DBCol, prefix: &[u8]) -> DBIterator<'a> {
+        let mut table = self.table.lock().expect("table lock poisoned");
+        match Self::collect_column(&mut table, col, prefix) {
+            Ok(entries) => Box::new(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| Ok((key.into(), value.into()))),
+            ),
+            Err(err) => Box::new(std::iter::once(Err(err.into()))),
+        }
+    }
+
+    /// Adds the refcount at the end of `delta` to the one of the stored value, the way the merge
+    /// operator of the refcounted columns in nearcore does. The value is deleted once its refcount
+    /// drops to zero.
+    fn update_refcount(
+        table: &mut HashTable,
+        key: Vec<u8>,
+        delta: Vec<u8>,
+    ) -> Result<(), StoreError> {
+        let (payload, refcount) = match table.get(key.clone())? {
+            Some(old) => {
+                let (old_payload, old_refcount) = split_refcount(&old);
+                let (payload, refcount) = split_refcount(&delta);
+                let payload = if payload.is_empty() {
+                    old_payload
+                } else {
+                    payload
+                };
+                (payload.to_vec(), old_refcount + refcount)
+            }
+            None => {
+                let (payload, refcount) = split_refcount(&delta);
+                (payload.to_vec(), refcount)
+            }
+        };
+        if refcount == 0 {
+            table.delete(key)
+        } else {
+            table.set(key, [payload, refcount.to_le_bytes().to_vec()].concat())
+        }
+    }
+
+    fn apply_ops(table: &mut HashTable, ops: Vec<DBOp>) -> Result<(), StoreError> {
+        for op in ops {
+            match op {
+                DBOp::Set { col, key, value } | DBOp::Insert { col, key, value } => {
+                    table.set(Self::column_key(col, &key), value)
+                }
+                DBOp::UpdateRefcount { col, key, value } => {
+                    Self::update_refcount(table, Self::column_key(col, &key), value)
+                }
+                DBOp::Delete { col, key } => table.delete(Self::column_key(col, &key)),
+                DBOp::DeleteAll { col } => {
+                    Self::collect_column(table, col, &[]).and_then(|entries| {
+                        entries
+                            .into_iter()
+                            .try_for_each(|(key, _)| table.delete(Self::column_key(col, &key)))
+                    })
+                }
+            }?;
+        }
+        Ok(())
+    }
+}
+
+fn split_refcount(value: &[u8]) -> (&[u8], i64) {
+    if value.len() < REFCOUNT_LEN {
+        return (value, 0);
+    }
+    let (payload, refcount) = value.split_at(value.len() - REFCOUNT_LEN);
+    (payload, i64::from_le_bytes(refcount.try_into().unwrap()))
+}
+
+impl Database for NearStoreDatabase {
+    fn get_raw_bytes(&self, col: DBCol, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let mut table = self.table.lock().expect("table lock poisoned");
+        Ok(table.get(Self::column_key(col, key))?)
+    }
+
+    fn iter<'a>(&'a self, col: DBCol) -> DBIterator<'a> {
+        self.iter_column(col, &[])
+    }
+
+    fn iter_prefix<'a>(&'a self, col: DBCol, key_prefix: &'a [u8]) -> DBIterator<'a> {
+        self.iter_column(col, key_prefix)
+    }
+
+    fn iter_raw_bytes<'a>(&'a self, col: DBCol) -> DBIterator<'a> {
+        self.iter_column(col, &[])
+    }
+
+    /// Applies all the ops of the batch, and then flushes the changes once. If an op fails, the
+    /// ones applied before it are rolled back, so that no part of the batch is written by a later
+    /// `write` or `flush`.
+    fn write(&self, batch: DBTransaction) -> io::Result<()> {
+        let mut table = self.table.lock().expect("table lock poisoned");
+        if let Err(err) = Self::apply_ops(&mut table, batch.ops) {
+            table.rollback()?;
+            return Err(err.into());
+        }
+        Ok(table.flush_changes()?)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let mut table = self.table.lock().expect("table lock poisoned");
+        Ok(table.flush_changes()?)
+    }
+
+    fn get_store_statistics(&self) -> Option<StoreStatistics> {
+        None
+    }
+}
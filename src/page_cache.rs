@@ -0,0 +1,78 @@
+//! A bounded cache of the pages read from the database file, evicting the least recently used
+//! page first. See `Options::page_cache_bytes`.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::PAGE_SIZE;
+
+pub(crate) struct PageCache {
+    /// The maximum number of pages cached, zero disables the cache.
+    capacity: usize,
+    /// The cached pages by their offsets, together with the time they were last used.
+    pages: HashMap<u64, (Vec<u8>, u64)>,
+    /// The offsets of the cached pages by the time they were last used.
+    by_last_use: BTreeMap<u64, u64>,
+    clock: u64,
+}
+
+impl PageCache {
+    pub(crate) fn new(capacity_bytes: u64) -> Self {
+        Self {
+            capacity: (capacity_bytes / PAGE_SIZE) as usize,
+            pages: HashMap::new(),
+            by_last_use: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// Returns the page at `offset`, which must be page-aligned, and marks it as used.
+    pub(crate) fn get(&mut self, offset: u64) -> Option<&[u8]> {
+        let (page, last_use) = self.pages.get_mut(&offset)?;
+        self.by_last_use.remove(last_use);
+        self.clock += 1;
+        *last_use = self.clock;
+        self.by_last_use.insert(self.clock, offset);
+        Some(page)
+    }
+
+    /// Caches a copy of the `page` at `offset`, which must be page-aligned. Nothing is copied when
+    /// the cache is disabled.
+    pub(crate) fn insert(&mut self, offset: u64, page: &[u8]) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(offset);
+        if self.pages.len() == self.capacity {
+            let (_, evicted) = self.by_last_use.pop_first().unwrap();
+            self.pages.remove(&evicted);
+        }
+        self.clock += 1;
+        self.pages.insert(offset, (page.to_vec(), self.clock));
+        self.by_last_use.insert(self.clock, offset);
+    }
+
+    /// Drops the page at `offset`, which must be page-aligned, if it's cached.
+    pub(crate) fn remove(&mut self, offset: u64) {
+        if let Some((_, last_use)) = self.pages.remove(&offset) {
+            self.by_last_use.remove(&last_use);
+        }
+    }
+
+    /// Drops all the cached pages that intersect the range `start..end`.
+    pub(crate) fn remove_range(&mut self, start: u64, end: u64) {
+        let offsets = self
+            .pages
+            .keys()
+            .copied()
+            .filter(|&offset| offset + PAGE_SIZE > start && offset < end)
+            .collect::<Vec<_>>();
+        for offset in offsets {
+            self.remove(offset);
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.pages.len()
+    }
+}
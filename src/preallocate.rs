@@ -0,0 +1,52 @@
+//! Reserving the disk space of the file up front, see `Options::preallocate_bytes`.
+//!
+//! The space is reserved with `fallocate(FALLOC_FL_KEEP_SIZE)`, so the size of the file, and the
+//! size in the header, only grow as the sectors are allocated. The end of the reserved range is
+//! stored in the header, and a sector allocated within it is zeroed with
+//! `fallocate(FALLOC_FL_ZERO_RANGE)`, which marks the reserved blocks as reading as zeros
+//! without writing them, rather than written out. A shrink gives the space past the new end of
+//! the file back to the file system, after which the stored end is stale; zeroing a range
+//! allocates whatever blocks it's missing, so that only costs the benefit of the reservation.
+
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+
+use nix::fcntl::{fallocate, FallocateFlags};
+
+use crate::{HashTable, StoreError, PREALLOCATED_END_OFFSET};
+
+/// Reserves the first `len` bytes of the file without changing its size.
+pub(crate) fn preallocate(file: &File, len: u64) -> Result<(), StoreError> {
+    fallocate(
+        file.as_raw_fd(),
+        FallocateFlags::FALLOC_FL_KEEP_SIZE,
+        0,
+        len as i64,
+    )
+    .map_err(|err| StoreError::at(0)(err.into()))
+}
+
+impl HashTable {
+    /// Grows the file by the sector at `offset`, its current end, if the sector is within the
+    /// reserved range, and returns whether it did. The sector then reads as zeros. Returns false
+    /// if the file system can't zero a range, or the database isn't in a file, so that the
+    /// sector is written out instead.
+    pub(crate) fn grow_into_preallocated(&mut self, offset: u64) -> Result<bool, StoreError> {
+        let preallocated_end = self.tx.get_num(&*self.file, PREALLOCATED_END_OFFSET)?;
+        let file = match self.file.as_file() {
+            Some(file) => file,
+            None => return Ok(false),
+        };
+        if offset + self.options.sector_size > preallocated_end {
+            return Ok(false);
+        }
+        // Also zeroes whatever a crash might have left past the logical end of the file
+        Ok(fallocate(
+            file.as_raw_fd(),
+            FallocateFlags::FALLOC_FL_ZERO_RANGE,
+            offset as i64,
+            self.options.sector_size as i64,
+        )
+        .is_ok())
+    }
+}
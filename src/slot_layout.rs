@@ -0,0 +1,179 @@
+//! How the entries are laid out in the slots of the hash table sectors, see `Options::hash_len`
+//! and `Options::offset_width`.
+//!
+//! A slot is `hash[hash_len] || value_le[offset_width]`. The slots are packed after the prelude
+//! of the sector, except that a slot never straddles two pages, since the transaction reads and
+//! writes within a single page: a slot that wouldn't fit in the rest of a page starts the next
+//! one. With the default 32-byte slots, which divide the page, the slots are simply contiguous.
+//!
+//! With `Options::inline_values`, the top bit of `value_le` tags a value stored in the slot
+//! itself rather than in the values region: the top byte is `0x80 | len`, and the `len` bytes of
+//! the value are the lowest ones. This leaves the offsets one bit less. A tombstone has the top
+//! byte `0xff` with the default 6-byte values, which no inline value can have.
+
+use crate::{ProbeStrategy, FIRST_SLOT_OFFSET, HASH_LEN, PAGE_SIZE, TOMBSTONE};
+
+/// The size of the largest slot, with whole hashes and 8-byte values.
+pub(crate) const MAX_SLOT_SIZE: usize = HASH_LEN + 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct SlotLayout {
+    /// The number of leading bytes of the hashes that are stored. The rest of the bytes of every
+    /// hash are zeroed, see `truncate`.
+    pub(crate) hash_len: usize,
+    /// The number of bytes of the value, `offset + 1`, stored after the hash.
+    pub(crate) offset_width: usize,
+    /// Whether the values may be stored inline, see the module documentation.
+    pub(crate) inline_values: bool,
+    /// The sequence of slots probed for a hash, see `probe_step`.
+    pub(crate) probe: ProbeStrategy,
+}
+
+impl SlotLayout {
+    pub(crate) fn slot_size(&self) -> u64 {
+        (self.hash_len + self.offset_width) as u64
+    }
+
+    /// Zeroes the bytes of the hash past `hash_len`, so that the hashes in memory compare the
+    /// same as the ones stored in the slots.
+    pub(crate) fn truncate(&self, mut hash: [u8; HASH_LEN]) -> [u8; HASH_LEN] {
+        hash[self.hash_len..].fill(0);
+        hash
+    }
+
+    pub(crate) fn extract_hash(&self, slot: &[u8]) -> [u8; HASH_LEN] {
+        let mut hash = [0; HASH_LEN];
+        hash[..self.hash_len].copy_from_slice(&slot[..self.hash_len]);
+        hash
+    }
+
+    pub(crate) fn extract_value(&self, slot: &[u8]) -> u64 {
+        let mut buf = [0u8; 8];
+        buf[..self.offset_width]
+            .copy_from_slice(&slot[self.hash_len..self.hash_len + self.offset_width]);
+        u64::from_le_bytes(buf)
+    }
+
+    pub(crate) fn encode(&self, hash: &[u8; HASH_LEN], value: u64) -> Vec<u8> {
+        [
+            &hash[..self.hash_len],
+            &value.to_le_bytes()[..self.offset_width],
+        ]
+        .concat()
+    }
+
+    /// The largest value that can be stored inline.
+    pub(crate) fn inline_capacity(&self) -> usize {
+        self.offset_width - 1
+    }
+
+    /// Returns the value of the slot that stores `value` inline, which must fit.
+    pub(crate) fn encode_inline(&self, value: &[u8]) -> u64 {
+        assert!(value.len() <= self.inline_capacity());
+        let mut bytes = [0u8; 8];
+        bytes[..value.len()].copy_from_slice(value);
+        bytes[self.offset_width - 1] = 0x80 | value.len() as u8;
+        u64::from_le_bytes(bytes)
+    }
+
+    /// Whether a slot with the value `slot_value` stores its value inline.
+    pub(crate) fn is_inline(&self, slot_value: u64) -> bool {
+        self.inline_values
+            && slot_value & (1 << (8 * self.offset_width - 1)) != 0
+            && slot_value != TOMBSTONE
+    }
+
+    /// Returns the value stored inline in a slot with the value `slot_value`, if it is one.
+    pub(crate) fn inline_value(&self, slot_value: u64) -> Option<Vec<u8>> {
+        if !self.is_inline(slot_value) {
+            return None;
+        }
+        let bytes = slot_value.to_le_bytes();
+        let len = (bytes[self.offset_width - 1] & 0x7f) as usize;
+        Some(bytes[..len].to_vec())
+    }
+
+    /// The slot the probing for the hash starts at. The sector is chosen by the leading bytes of
+    /// the hash, so the slot is chosen by the trailing ones.
+    pub(crate) fn home_slot(&self, hash: &[u8; HASH_LEN], slots_in_sector: u64) -> u64 {
+        let mut slice: [u8; 8] = [0; 8];
+        slice.copy_from_slice(&hash[self.hash_len - 8..self.hash_len]);
+        u64::from_le_bytes(slice) % slots_in_sector
+    }
+
+    /// The distance between the consecutive slots probed for the hash, see
+    /// `Options::probe_strategy`. It's coprime with the number of slots, so that the probing
+    /// visits every slot of the sector before coming back to the home slot.
+    pub(crate) fn probe_step(&self, hash: &[u8; HASH_LEN], slots_in_sector: u64) -> u64 {
+        match self.probe {
+            ProbeStrategy::Linear => 1,
+            ProbeStrategy::DoubleHashing => {
+                // The 8 bytes before the ones of the home slot, which are stored since the hashes
+                // are at least 16 bytes long
+                let mut slice: [u8; 8] = [0; 8];
+                slice.copy_from_slice(&hash[self.hash_len - 16..self.hash_len - 8]);
+                let mut step = u64::from_le_bytes(slice) % (slots_in_sector.max(2) - 1) + 1;
+                while gcd(step, slots_in_sector) != 1 {
+                    step += 1;
+                }
+                step
+            }
+        }
+    }
+
+    /// The number of slots in a sector of `sector_size` bytes.
+    pub(crate) fn slots_in_sector(&self, sector_size: u64) -> u64 {
+        self.first_page_slots() + (sector_size / PAGE_SIZE - 1) * self.slots_per_page()
+    }
+
+    /// The offset of the slot with the index `slot` of the sector at `sector_offset`.
+    pub(crate) fn slot_offset(&self, sector_offset: u64, slot: u64) -> u64 {
+        let first_page_slots = self.first_page_slots();
+        if slot < first_page_slots {
+            return sector_offset + FIRST_SLOT_OFFSET + slot * self.slot_size();
+        }
+        let slot = slot - first_page_slots;
+        let slots_per_page = self.slots_per_page();
+        sector_offset
+            + (1 + slot / slots_per_page) * PAGE_SIZE
+            + slot % slots_per_page * self.slot_size()
+    }
+
+    /// The index of the slot at `offset` within the sector at `sector_offset`, the inverse of
+    /// `slot_offset`.
+    pub(crate) fn slot_index(&self, sector_offset: u64, offset: u64) -> u64 {
+        let within = offset - sector_offset;
+        if within < PAGE_SIZE {
+            return (within - FIRST_SLOT_OFFSET) / self.slot_size();
+        }
+        self.first_page_slots()
+            + (within / PAGE_SIZE - 1) * self.slots_per_page()
+            + within % PAGE_SIZE / self.slot_size()
+    }
+
+    fn first_page_slots(&self) -> u64 {
+        (PAGE_SIZE - FIRST_SLOT_OFFSET) / self.slot_size()
+    }
+
+    fn slots_per_page(&self) -> u64 {
+        PAGE_SIZE / self.slot_size()
+    }
+}
+
+impl Default for SlotLayout {
+    fn default() -> Self {
+        Self {
+            hash_len: HASH_LEN,
+            offset_width: 6,
+            inline_values: false,
+            probe: ProbeStrategy::Linear,
+        }
+    }
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
@@ -0,0 +1,224 @@
+//! Reads of the flushed state of the table that don't need `&mut HashTable`, so that they can be
+//! served from other threads while the table is being written to.
+//!
+//! A `ReadSnapshot` keeps the mappings of the sectors as of the last flush, and reads the file
+//! through the `BlockDevice` of the table, bypassing the transaction and its caches.
+//! The pending changes live in the memory of the table, so the snapshot doesn't see them, and
+//! the file only changes when the table is flushed. The flushes update the sectors in place, so
+//! the snapshot can't read the file once it's been flushed again: every flush increments a
+//! counter shared with the snapshots before it writes anything, and a read that overlaps a flush
+//! or follows one fails with `StoreError::SnapshotStale`, like a seqlock. A snapshot has to be
+//! taken again after every flush.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::slot_layout::SlotLayout;
+use crate::{
+    BlockDevice, Clock, HashTable, KeyHasher, Record, StoreError, ValueHeader, ValueTransform,
+    FIRST_VALUE_LOGICAL_OFFSET, HASH_LEN, NO_VALUE, TOMBSTONE, UNMAPPED_VALUE,
+    VALUE_CHECKSUM_MISMATCH, VALUE_SIZE,
+};
+
+/// The mappings of the sectors as of the last flush, which a `ReadSnapshot` reads the file
+/// through and `HashTable::rollback` restores.
+pub(crate) struct FlushedMappings {
+    pub(crate) ht_mapping: BTreeMap<[u8; HASH_LEN], u64>,
+    pub(crate) values_mapping: BTreeMap<u64, u64>,
+    pub(crate) delmap_mapping: BTreeMap<u64, u64>,
+}
+
+impl FlushedMappings {
+    pub(crate) fn new(
+        ht_mapping: &BTreeMap<[u8; HASH_LEN], u64>,
+        values_mapping: &BTreeMap<u64, u64>,
+        delmap_mapping: &BTreeMap<u64, u64>,
+    ) -> Self {
+        Self {
+            ht_mapping: ht_mapping.clone(),
+            values_mapping: values_mapping.clone(),
+            delmap_mapping: delmap_mapping.clone(),
+        }
+    }
+}
+
+/// A read-only view of the table as of the last flush, see the module documentation.
+pub struct ReadSnapshot {
+    file: Arc<dyn BlockDevice>,
+    salt: [u8; 32],
+    sector_size: u64,
+    transforms: Vec<Arc<dyn ValueTransform>>,
+    clock: Arc<dyn Clock>,
+    hasher: Arc<dyn KeyHasher>,
+    layout: SlotLayout,
+    mappings: Arc<FlushedMappings>,
+    flushes: Arc<AtomicU64>,
+    /// The value of `flushes` when the snapshot was taken.
+    taken_at_flush: u64,
+}
+
+impl HashTable {
+    /// Returns a snapshot of the table as of the last flush, see the `snapshot` module.
+    pub fn read_snapshot(&self) -> Result<ReadSnapshot, StoreError> {
+        Ok(ReadSnapshot {
+            file: self.file.clone(),
+            salt: self.salt,
+            sector_size: self.options.sector_size,
+            transforms: self.options.transforms.clone(),
+            clock: self.options.clock.clone(),
+            hasher: self.options.hasher.clone(),
+            layout: self.layout,
+            mappings: self.flushed_mappings.clone(),
+            flushes: self.flushes.clone(),
+            taken_at_flush: self.flushes.load(Ordering::SeqCst),
+        })
+    }
+}
+
+impl ReadSnapshot {
+    /// Same as `HashTable::get`, except that the expired values are not deleted. Fails with
+    /// `StoreError::SnapshotStale` once the table has been flushed since the snapshot was taken.
+    pub fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>, StoreError> {
+        self.check_fresh()?;
+        let value = self.read_value(&key);
+        // A flush that started during the read may have changed the pages it read, and whatever
+        // was read from them, including the errors, is meaningless
+        self.check_fresh()?;
+        value
+    }
+
+    /// Whether the table has been flushed since the snapshot was taken, after which `get` fails.
+    pub fn is_stale(&self) -> bool {
+        self.flushes.load(Ordering::SeqCst) != self.taken_at_flush
+    }
+
+    fn check_fresh(&self) -> Result<(), StoreError> {
+        if self.is_stale() {
+            Err(StoreError::SnapshotStale)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn read_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+        let hash = self.layout.truncate(self.hasher.hash(&self.salt, key));
+        let slot_value = match self.seek(hash)? {
+            Some(slot_value) => slot_value,
+            None => return Ok(None),
+        };
+        if let Some(value) = self.layout.inline_value(slot_value) {
+            return self.decode(value).map(Some);
+        }
+        let offset = slot_value - 1;
+        let first_value_logical = self.read_num(FIRST_VALUE_LOGICAL_OFFSET)?;
+        if offset < first_value_logical {
+            return Err(StoreError::DanglingValue { hash, offset });
+        }
+
+        let first_slot = self.read_value_slot(offset)?;
+        let header = ValueHeader::parse(&first_slot);
+        if (header.len as usize) < header.payload_offset() {
+            return Err(StoreError::Corruption {
+                offset: self.value_physical_offset(offset)?,
+                reason: "value shorter than its header",
+            });
+        }
+        let mut slots = vec![first_slot];
+        for i in 1..header.num_slots() {
+            slots.push(self.read_value_slot(offset + i * VALUE_SIZE)?);
+        }
+        let record = Record::parse(&slots.concat()).ok_or(StoreError::Corruption {
+            offset: self.value_physical_offset(offset)?,
+            reason: VALUE_CHECKSUM_MISMATCH,
+        })?;
+
+        let now = self.clock.now_unix_secs();
+        let expired = record
+            .header
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= now);
+        if expired {
+            return Ok(None);
+        }
+        self.decode(record.payload).map(Some)
+    }
+
+    fn decode(&self, mut value: Vec<u8>) -> Result<Vec<u8>, StoreError> {
+        for transform in self.transforms.iter().rev() {
+            value = transform.decode(value)?;
+        }
+        Ok(value)
+    }
+
+    /// Same as `HashTable::seek`, but returns the value of the slot of the hash if it's
+    /// present.
+    fn seek(&self, hash: [u8; HASH_LEN]) -> Result<Option<u64>, StoreError> {
+        let slots_in_sector = self.layout.slots_in_sector(self.sector_size);
+        let mut slot = self.layout.home_slot(&hash, slots_in_sector);
+        let step = self.layout.probe_step(&hash, slots_in_sector);
+        // unwrap here is safe, because the ht_mapping always contains 0x0
+        let sector_offset = *self
+            .mappings
+            .ht_mapping
+            .range(..=hash)
+            .next_back()
+            .unwrap()
+            .1;
+
+        let mut data = vec![0u8; self.layout.slot_size() as usize];
+        for _ in 0..slots_in_sector {
+            let offset = self.layout.slot_offset(sector_offset, slot);
+            self.read_at(&mut data, offset)?;
+
+            let value = self.layout.extract_value(&data);
+            if value == NO_VALUE {
+                return Ok(None);
+            }
+            if value != TOMBSTONE && self.layout.extract_hash(&data) == hash {
+                return Ok(Some(value));
+            }
+
+            slot = (slot + step) % slots_in_sector;
+        }
+        Err(StoreError::SectorFull {
+            offset: sector_offset,
+        })
+    }
+
+    fn read_value_slot(
+        &self,
+        logical_offset: u64,
+    ) -> Result<[u8; VALUE_SIZE as usize], StoreError> {
+        let mut data = [0u8; VALUE_SIZE as usize];
+        self.read_at(&mut data, self.value_physical_offset(logical_offset)?)?;
+        Ok(data)
+    }
+
+    /// Unlike the table, the snapshot can read a slot that points outside of the values sectors,
+    /// if it read the slot while it was being flushed.
+    fn value_physical_offset(&self, logical_offset: u64) -> Result<u64, StoreError> {
+        let (sector_logical_offset, sector_physical_offset) = self
+            .mappings
+            .values_mapping
+            .range(..=logical_offset)
+            .next_back()
+            .ok_or(StoreError::Corruption {
+                offset: FIRST_VALUE_LOGICAL_OFFSET,
+                reason: UNMAPPED_VALUE,
+            })?;
+        Ok(sector_physical_offset + logical_offset - sector_logical_offset)
+    }
+
+    fn read_num(&self, offset: u64) -> Result<u64, StoreError> {
+        let mut data = [0u8; 8];
+        self.read_at(&mut data, offset)?;
+        Ok(u64::from_le_bytes(data))
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<(), StoreError> {
+        self.file
+            .read_at(buf, offset)
+            .map_err(StoreError::at(offset))
+    }
+}
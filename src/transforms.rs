@@ -0,0 +1,114 @@
+//! Transformations applied to the values on their way to and from the disk.
+//!
+//! `Options::transforms` is an ordered pipeline: `set` passes the value through the `encode` of
+//! every transform in order, and `get` passes the stored bytes through the `decode` of every
+//! transform in the reverse order. The length stored in the record is the length of the fully
+//! encoded value.
+
+use std::fmt;
+use std::io;
+
+/// A reversible transformation of the values, such as compression or encryption.
+/// `decode(encode(value))` must return `value`.
+pub trait ValueTransform: fmt::Debug + Send + Sync {
+    fn encode(&self, value: Vec<u8>) -> io::Result<Vec<u8>>;
+
+    fn decode(&self, value: Vec<u8>) -> io::Result<Vec<u8>>;
+}
+
+/// LZ4 compression. The uncompressed size is prepended to the compressed data.
+#[cfg(feature = "lz4")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Lz4;
+
+#[cfg(feature = "lz4")]
+impl ValueTransform for Lz4 {
+    fn encode(&self, value: Vec<u8>) -> io::Result<Vec<u8>> {
+        Ok(lz4_flex::compress_prepend_size(&value))
+    }
+
+    fn decode(&self, value: Vec<u8>) -> io::Result<Vec<u8>> {
+        lz4_flex::decompress_size_prepended(&value)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+/// Zstandard compression with the given compression level.
+#[cfg(feature = "zstd")]
+#[derive(Clone, Copy, Debug)]
+pub struct Zstd {
+    pub level: i32,
+}
+
+#[cfg(feature = "zstd")]
+impl Default for Zstd {
+    fn default() -> Self {
+        Self {
+            level: zstd::DEFAULT_COMPRESSION_LEVEL,
+        }
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl ValueTransform for Zstd {
+    fn encode(&self, value: Vec<u8>) -> io::Result<Vec<u8>> {
+        zstd::encode_all(&value[..], self.level)
+    }
+
+    fn decode(&self, value: Vec<u8>) -> io::Result<Vec<u8>> {
+        zstd::decode_all(&value[..])
+    }
+}
+
+/// ChaCha20 encryption. Every value is encrypted with a fresh random nonce, which is stored in
+/// front of the ciphertext.
+#[cfg(feature = "chacha20")]
+#[derive(Clone)]
+pub struct ChaCha20 {
+    key: [u8; 32],
+}
+
+#[cfg(feature = "chacha20")]
+impl ChaCha20 {
+    const NONCE_LEN: usize = 12;
+
+    pub fn new(key: [u8; 32]) -> Self {
+        Self { key }
+    }
+
+    fn apply(&self, nonce: &[u8], data: &mut [u8]) {
+        use chacha20::cipher::{KeyIvInit, StreamCipher};
+        let mut cipher = chacha20::ChaCha20::new(&self.key.into(), nonce.into());
+        cipher.apply_keystream(data);
+    }
+}
+
+#[cfg(feature = "chacha20")]
+impl fmt::Debug for ChaCha20 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Never print the key
+        f.debug_struct("ChaCha20").finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "chacha20")]
+impl ValueTransform for ChaCha20 {
+    fn encode(&self, mut value: Vec<u8>) -> io::Result<Vec<u8>> {
+        let nonce = rand::random::<[u8; Self::NONCE_LEN]>();
+        self.apply(&nonce, &mut value);
+        Ok([nonce.as_ref(), &value].concat())
+    }
+
+    fn decode(&self, value: Vec<u8>) -> io::Result<Vec<u8>> {
+        if value.len() < Self::NONCE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "encrypted value is shorter than the nonce",
+            ));
+        }
+        let (nonce, data) = value.split_at(Self::NONCE_LEN);
+        let mut data = data.to_vec();
+        self.apply(nonce, &mut data);
+        Ok(data)
+    }
+}
@@ -0,0 +1,220 @@
+//! A consistency check of the hash table against the values region, for validating a database
+//! before trusting it, e.g. after a crash.
+
+use std::fmt;
+
+use crate::{
+    HashTable, StoreError, ValueHeader, FIRST_VALUE_LOGICAL_OFFSET, HASH_LEN, LEN_OFFSET,
+    NEXT_VALUE_LOGICAL_OFFSET, NO_VALUE, TOMBSTONE, VALUE_SIZE,
+};
+
+/// An inconsistency found by `HashTable::verify`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Discrepancy {
+    /// The lowest hash of the sector in the file differs from the one it's mapped by.
+    Median {
+        sector_offset: u64,
+        mapped: [u8; HASH_LEN],
+        stored: [u8; HASH_LEN],
+    },
+    /// The occupancy counter of the sector differs from the number of its occupied slots.
+    Occupancy {
+        sector_offset: u64,
+        stored: u64,
+        actual: u64,
+    },
+    /// The slot holds a hash outside of the range of hashes of its sector.
+    HashOutsideSector { slot_offset: u64 },
+    /// The slot points to a value slot outside of the values region.
+    ValueOutOfRange {
+        slot_offset: u64,
+        logical_offset: u64,
+    },
+    /// The slot points to a value slot that is marked deleted in the delmap.
+    ValueDeleted {
+        slot_offset: u64,
+        logical_offset: u64,
+    },
+    /// The number of entries in the header differs from the number of occupied slots.
+    Len { stored: u64, actual: u64 },
+}
+
+impl fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Discrepancy::Median {
+                sector_offset,
+                mapped,
+                stored,
+            } => write!(
+                f,
+                "sector at {} is mapped by {:02x?} but starts with {:02x?}",
+                sector_offset, mapped, stored
+            ),
+            Discrepancy::Occupancy {
+                sector_offset,
+                stored,
+                actual,
+            } => write!(
+                f,
+                "sector at {} has occupancy {} but {} occupied slots",
+                sector_offset, stored, actual
+            ),
+            Discrepancy::HashOutsideSector { slot_offset } => {
+                write!(f, "slot at {} holds a hash of another sector", slot_offset)
+            }
+            Discrepancy::ValueOutOfRange {
+                slot_offset,
+                logical_offset,
+            } => write!(
+                f,
+                "slot at {} points to {} outside of the values region",
+                slot_offset, logical_offset
+            ),
+            Discrepancy::ValueDeleted {
+                slot_offset,
+                logical_offset,
+            } => write!(
+                f,
+                "slot at {} points to {} which is marked deleted",
+                slot_offset, logical_offset
+            ),
+            Discrepancy::Len { stored, actual } => write!(
+                f,
+                "the header has {} entries but {} slots are occupied",
+                stored, actual
+            ),
+        }
+    }
+}
+
+/// The result of `HashTable::verify`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub discrepancies: Vec<Discrepancy>,
+    /// The number of occupied hash table slots checked.
+    pub slots_checked: u64,
+}
+
+impl VerifyReport {
+    pub fn is_consistent(&self) -> bool {
+        self.discrepancies.is_empty()
+    }
+}
+
+impl HashTable {
+    /// Checks that every hash table sector starts with the hash it's mapped by and has the
+    /// occupancy it stores, and that every occupied slot holds a hash of its sector and, unless
+    /// its value is inline, points to a record in the values region whose slots are all live in
+    /// the delmap. Reads the whole hash table and the first slot of every record. The
+    /// inconsistencies are reported rather than returned as errors, only IO errors fail the check.
+    pub fn verify(&mut self) -> Result<VerifyReport, StoreError> {
+        let first_value_logical = self.tx.get_num(&*self.file, FIRST_VALUE_LOGICAL_OFFSET)?;
+        let next_value_logical = self.tx.get_num(&*self.file, NEXT_VALUE_LOGICAL_OFFSET)?;
+        let in_region = |offset: u64| (first_value_logical..next_value_logical).contains(&offset);
+
+        let sectors = self
+            .ht_mapping
+            .iter()
+            .map(|(&median, &offset)| (median, offset))
+            .collect::<Vec<_>>();
+        let mut report = VerifyReport::default();
+        for (i, &(median, sector_offset)) in sectors.iter().enumerate() {
+            let next_median = sectors.get(i + 1).map(|&(median, _)| median);
+            let stored: [u8; HASH_LEN] = self
+                .tx
+                .get(&*self.file, sector_offset, HASH_LEN as u64)?
+                .try_into()
+                .unwrap();
+            if stored != median {
+                report.discrepancies.push(Discrepancy::Median {
+                    sector_offset,
+                    mapped: median,
+                    stored,
+                });
+            }
+
+            let mut occupied_slots = vec![];
+            for slot in 0..self.slots_in_sector() {
+                let slot_offset = self.layout.slot_offset(sector_offset, slot);
+                let data = self
+                    .tx
+                    .get(&*self.file, slot_offset, self.layout.slot_size())?;
+                let value = self.layout.extract_value(&data);
+                if value != NO_VALUE && value != TOMBSTONE {
+                    let hash = self.layout.extract_hash(&data);
+                    occupied_slots.push((slot_offset, hash, value));
+                }
+            }
+
+            let occupancy = self.tx.get_num(&*self.file, sector_offset + 32)?;
+            if occupancy != occupied_slots.len() as u64 {
+                report.discrepancies.push(Discrepancy::Occupancy {
+                    sector_offset,
+                    stored: occupancy,
+                    actual: occupied_slots.len() as u64,
+                });
+            }
+            report.slots_checked += occupied_slots.len() as u64;
+
+            for (slot_offset, hash, value) in occupied_slots {
+                let past_next = next_median.is_some_and(|next| hash >= next);
+                if hash < median || past_next {
+                    report
+                        .discrepancies
+                        .push(Discrepancy::HashOutsideSector { slot_offset });
+                }
+                // An inline value has no record to check
+                if self.layout.is_inline(value) {
+                    continue;
+                }
+                if let Some(discrepancy) = self.verify_record(slot_offset, value - 1, &in_region)? {
+                    report.discrepancies.push(discrepancy);
+                }
+            }
+        }
+
+        let len = self.tx.get_num(&*self.file, LEN_OFFSET)?;
+        if len != report.slots_checked {
+            report.discrepancies.push(Discrepancy::Len {
+                stored: len,
+                actual: report.slots_checked,
+            });
+        }
+        Ok(report)
+    }
+
+    /// Checks that all the slots of the record at `logical_offset` are in the values region and,
+    /// with `Options::enable_compaction`, live in the delmap, and returns the first inconsistency
+    /// found.
+    fn verify_record(
+        &mut self,
+        slot_offset: u64,
+        logical_offset: u64,
+        in_region: &impl Fn(u64) -> bool,
+    ) -> Result<Option<Discrepancy>, StoreError> {
+        if !in_region(logical_offset) {
+            return Ok(Some(Discrepancy::ValueOutOfRange {
+                slot_offset,
+                logical_offset,
+            }));
+        }
+        let num_slots = ValueHeader::parse(&self.get_value(logical_offset)?).num_slots();
+        for i in 0..num_slots {
+            let offset = logical_offset + i * VALUE_SIZE;
+            if !in_region(offset) {
+                return Ok(Some(Discrepancy::ValueOutOfRange {
+                    slot_offset,
+                    logical_offset: offset,
+                }));
+            }
+            if self.options.enable_compaction && self.is_value_at_offset_deleted(offset)? {
+                return Ok(Some(Discrepancy::ValueDeleted {
+                    slot_offset,
+                    logical_offset: offset,
+                }));
+            }
+        }
+        Ok(None)
+    }
+}
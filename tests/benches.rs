@@ -1,15 +1,37 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Instant;
 
-use fast_kv_store::HashTable;
+use fast_kv_store::{HashTable, Options};
 use rand::seq::SliceRandom;
 use rand::Rng;
 use tempdir::TempDir;
 
+/// Counts the allocations of the benchmarks. The counter is shared by the tests running in
+/// parallel, so the benchmarks reading it should be run alone.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
 #[test]
 fn ht_benchmark_write() {
     let tmp_dir = TempDir::new("example").unwrap();
     let salt = rand::thread_rng().gen::<[u8; 32]>();
-    let mut db = HashTable::new(tmp_dir.path().join("db"), salt, None);
+    let mut db = HashTable::new_or_panic(tmp_dir.path().join("db"), salt, None);
 
     let num_elems = 10_000;
     let num_iter = 1_000_000;
@@ -37,7 +59,7 @@ fn ht_benchmark_write() {
         let index = indexes[i];
         let key = &keys[index];
         let value = &values[index];
-        db.set(key.clone(), value.clone());
+        db.set_or_panic(key.clone(), value.clone());
     }
     let duration = start.elapsed();
     println!(
@@ -48,7 +70,7 @@ fn ht_benchmark_write() {
     for i in 0..num_elems {
         let key = &keys[i];
         let value = &values[i];
-        assert_eq!(value, &db.get(key.clone()).unwrap());
+        assert_eq!(value, &db.get_or_panic(key.clone()).unwrap());
     }
 
     let indexes: Vec<usize> = (0..num_elems).collect();
@@ -57,7 +79,7 @@ fn ht_benchmark_write() {
         let index = indexes.choose(&mut rand::thread_rng()).unwrap().clone();
         let key = &keys[index];
         let value = &values[index];
-        db.set(key.clone(), value.clone());
+        db.set_or_panic(key.clone(), value.clone());
     }
     let duration = start.elapsed();
     println!(
@@ -65,3 +87,182 @@ fn ht_benchmark_write() {
         duration.as_nanos() / num_iter
     );
 }
+
+#[test]
+fn ht_benchmark_resize_spacing() {
+    let num_inserts = 300_000;
+
+    println!();
+    println!("spacing\tresizes\tmin gap\tavg gap\tmax gap\ttotal ms");
+    for spacing in [0, 4_096, 8_192, 16_383, 32_766] {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let salt = rand::thread_rng().gen::<[u8; 32]>();
+        let options = Options {
+            resize_spacing_writes: spacing,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options_or_panic(tmp_dir.path().join("db"), salt, None, options);
+
+        // Every insert is of a new key, so a change of `writes_since_resize` by anything other
+        // than one means the insert resized a sector.
+        let mut resizes = vec![];
+        let start = Instant::now();
+        for i in 0..num_inserts {
+            let before = db.writes_since_resize();
+            db.ht_set_or_panic(rand::thread_rng().gen::<[u8; 32]>().to_vec(), 1);
+            if db.writes_since_resize() != before + 1 {
+                resizes.push(i);
+            }
+        }
+        let duration = start.elapsed();
+
+        let gaps = resizes.windows(2).map(|w| w[1] - w[0]).collect::<Vec<_>>();
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            spacing,
+            resizes.len(),
+            gaps.iter().min().unwrap_or(&0),
+            gaps.iter().sum::<usize>() / gaps.len().max(1),
+            gaps.iter().max().unwrap_or(&0),
+            duration.as_millis()
+        );
+    }
+}
+
+#[test]
+fn ht_benchmark_page_cache() {
+    let num_elems = 100_000u32;
+    let num_reads = 1_000_000;
+
+    println!();
+    for page_cache_bytes in [0, 256 << 20] {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let salt = rand::thread_rng().gen::<[u8; 32]>();
+        let options = Options {
+            page_cache_bytes,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(tmp_dir.path().join("db"), salt, None, options).unwrap();
+        for i in 0..num_elems {
+            db.set_or_panic(i.to_le_bytes().to_vec(), vec![0; 100]);
+        }
+        db.flush_changes_or_panic();
+
+        let start = Instant::now();
+        for _ in 0..num_reads {
+            let key = rand::thread_rng().gen_range(0..num_elems).to_le_bytes();
+            db.get_or_panic(key.to_vec()).unwrap();
+        }
+        let duration = start.elapsed();
+        println!(
+            "HashTable read with a {} byte page cache {}ns",
+            page_cache_bytes,
+            duration.as_nanos() / num_reads
+        );
+    }
+}
+
+#[test]
+fn ht_benchmark_get_into() {
+    let num_elems = 100_000u32;
+    let num_reads = 1_000_000;
+
+    let tmp_dir = TempDir::new("example").unwrap();
+    let salt = rand::thread_rng().gen::<[u8; 32]>();
+    let mut db = HashTable::new_or_panic(tmp_dir.path().join("db"), salt, None);
+    for i in 0..num_elems {
+        db.set_or_panic(i.to_le_bytes().to_vec(), vec![0; 100]);
+    }
+    db.flush_changes_or_panic();
+
+    println!();
+    let start = Instant::now();
+    for _ in 0..num_reads {
+        let key = rand::thread_rng().gen_range(0..num_elems).to_le_bytes();
+        db.get_or_panic(key.to_vec()).unwrap();
+    }
+    let duration = start.elapsed();
+    println!("HashTable get {}ns", duration.as_nanos() / num_reads);
+
+    let mut out = Vec::with_capacity(100);
+    let start = Instant::now();
+    for _ in 0..num_reads {
+        let key = rand::thread_rng().gen_range(0..num_elems).to_le_bytes();
+        assert!(db.get_into(key.to_vec(), &mut out).unwrap());
+    }
+    let duration = start.elapsed();
+    println!("HashTable get_into {}ns", duration.as_nanos() / num_reads);
+}
+
+#[test]
+fn ht_benchmark_seek_allocations() {
+    let num_elems = 100_000u32;
+    let num_reads = 1_000_000;
+
+    let tmp_dir = TempDir::new("example").unwrap();
+    let salt = rand::thread_rng().gen::<[u8; 32]>();
+    let mut db = HashTable::new_or_panic(tmp_dir.path().join("db"), salt, None);
+    for i in 0..num_elems {
+        db.set_or_panic(i.to_le_bytes().to_vec(), vec![0; 100]);
+    }
+    db.flush_changes_or_panic();
+
+    let keys: Vec<_> = (0..num_reads)
+        .map(|_| {
+            // Half of the keys are absent, which probe up to the first empty slot
+            let key = rand::thread_rng().gen_range(0..2 * num_elems);
+            key.to_le_bytes().to_vec()
+        })
+        .collect();
+
+    println!();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    // The keys are moved into the lookups, so that cloning them doesn't count
+    for key in keys {
+        db.contains_key(key).unwrap();
+    }
+    let duration = start.elapsed();
+    println!(
+        "HashTable contains_key {}ns, {:.2} allocations",
+        duration.as_nanos() / num_reads as u128,
+        (ALLOCATIONS.load(Ordering::Relaxed) - allocations) as f64 / num_reads as f64
+    );
+}
+
+#[test]
+fn ht_benchmark_bloom_negative_lookups() {
+    let num_elems = 100_000u32;
+    let num_reads = 1_000_000u32;
+
+    println!();
+    for expected_keys in [0, num_elems as u64] {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let salt = rand::thread_rng().gen::<[u8; 32]>();
+        let options = Options {
+            expected_keys,
+            ..Options::default()
+        };
+        let mut db =
+            HashTable::new_with_options(tmp_dir.path().join("db"), salt, None, options).unwrap();
+        for i in 0..num_elems {
+            db.set_or_panic(i.to_le_bytes().to_vec(), vec![0; 100]);
+        }
+        db.flush_changes_or_panic();
+
+        let start = Instant::now();
+        for i in 0..num_reads {
+            assert!(db
+                .get_or_panic((num_elems + i).to_le_bytes().to_vec())
+                .is_none());
+        }
+        let duration = start.elapsed();
+        println!(
+            "HashTable expected_keys={} negative get {}ns",
+            expected_keys,
+            duration.as_nanos() / num_reads as u128
+        );
+    }
+}
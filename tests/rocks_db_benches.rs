@@ -37,8 +37,8 @@ fn genenrate_data(
         assert_eq!(value, default_rdb.get(key.clone()).unwrap().unwrap());
         settings_rdb.put(key.clone(), value.clone()).unwrap();
         assert_eq!(value, settings_rdb.get(key.clone()).unwrap().unwrap());
-        hdb.set(key.clone(), value.clone());
-        assert_eq!(value, hdb.get(key.clone()).unwrap());
+        hdb.set_or_panic(key.clone(), value.clone());
+        assert_eq!(value, hdb.get_or_panic(key.clone()).unwrap());
         data.push((key, value));
     }
     println!(
@@ -63,7 +63,7 @@ fn ht_read(db: &mut HashTable, data: &[(Vec<u8>, Vec<u8>)]) -> u128 {
     for _ in 0..NUM_ITER {
         let index = rand::thread_rng().gen_range(0..data.len());
         let (key, _value) = &data[index];
-        db.get(key.clone()).unwrap();
+        db.get_or_panic(key.clone()).unwrap();
     }
     start.elapsed().as_nanos() / NUM_ITER
 }
@@ -140,7 +140,7 @@ fn benchmark_read() {
         let mut hdb = {
             let salt = rand::thread_rng().gen::<[u8; 32]>();
             let path = exp_dir.join("hdb");
-            HashTable::new(path, salt, None)
+            HashTable::new_or_panic(path, salt, None)
         };
 
         let (data, total_size) = genenrate_data(num_elems, &default_rdb, &settings_rdb, &mut hdb);
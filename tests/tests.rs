@@ -38,7 +38,7 @@ fn test_fuzzy_db_ht_consistency() {
 
     let tmp_dir = TempDir::new("example").unwrap();
     let salt = rand::thread_rng().gen::<[u8; 32]>();
-    let mut db = HashTable::new(tmp_dir.path().join("db"), salt, None);
+    let mut db = HashTable::new_or_panic(tmp_dir.path().join("db"), salt, None);
 
     let mut map: HashMap<Vec<u8>, u64> = HashMap::new();
     let mut all_keys = vec![];
@@ -66,26 +66,32 @@ fn test_fuzzy_db_ht_consistency() {
         let adjusted_key = [vec![32], key.clone()].concat();
 
         if odd <= 1 {
-            assert_eq!(db.ht_get(adjusted_key.clone()), map.get(&key).cloned());
+            assert_eq!(
+                db.ht_get_or_panic(adjusted_key.clone()),
+                map.get(&key).cloned()
+            );
         }
         if odd != 0 {
             if want_delete {
                 map.remove(&key);
-                db.ht_delete(adjusted_key.clone());
+                db.ht_delete_or_panic(adjusted_key.clone());
                 deletes += 1;
             } else {
                 map.insert(key.clone(), value.clone());
-                db.ht_set(adjusted_key.clone(), value);
+                db.ht_set_or_panic(adjusted_key.clone(), value);
                 inserts += 1;
             }
         }
         if odd <= 1 {
-            assert_eq!(db.ht_get(adjusted_key.clone()), map.get(&key).cloned());
+            assert_eq!(
+                db.ht_get_or_panic(adjusted_key.clone()),
+                map.get(&key).cloned()
+            );
             reads += 1;
         }
 
         if (iter + 1) % COMMIT_EVERY == 0 {
-            db.flush_changes();
+            db.flush_changes_or_panic();
         }
 
         if (iter + 1) % 1000000 == 0 {
@@ -131,7 +137,7 @@ fn test_fuzzy_storage_consistency() {
 
     let tmp_dir = TempDir::new("example").unwrap();
     let salt = rand::thread_rng().gen::<[u8; 32]>();
-    let mut db = HashTable::new(tmp_dir.path().join("db"), salt, None);
+    let mut db = HashTable::new_or_panic(tmp_dir.path().join("db"), salt, None);
 
     let mut map: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
     let mut all_keys = vec![];
@@ -170,31 +176,32 @@ fn test_fuzzy_storage_consistency() {
         if odd != 0 {
             if want_delete {
                 map.remove(&key);
-                db.delete(key.clone());
+                db.delete_or_panic(key.clone());
                 deletes += 1;
             } else {
                 map.insert(key.clone(), value.clone());
-                db.set(key.clone(), value);
+                db.set_or_panic(key.clone(), value);
                 inserts += 1;
             }
         }
         if read_iter {
-            assert_eq!(db.get(key.clone()), map.get(&key).cloned());
+            assert_eq!(db.get_or_panic(key.clone()), map.get(&key).cloned());
             reads += 1;
+            assert_eq!(db.len().unwrap(), map.len() as u64);
         }
 
         if (iter + 1) % RECREATE_EVERY == 0 {
             println!("WRITING TO WAL ...");
-            db.write_to_log(&mut open_file(&tmp_dir.path().join("wal"), true));
+            db.write_to_log_or_panic(&mut open_file(&tmp_dir.path().join("wal"), true));
             println!("RECREATING ...");
-            db = HashTable::new(
+            db = HashTable::new_or_panic(
                 tmp_dir.path().join("db"),
                 salt,
                 Some(&mut open_file(&tmp_dir.path().join("wal"), false)),
             );
             println!("DONE");
         } else if (iter + 1) % COMMIT_EVERY == 0 {
-            db.flush_changes();
+            db.flush_changes_or_panic();
         }
 
         if (iter + 1) % 100000 == 0 {